@@ -1,7 +1,10 @@
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::process;
 
 use protobuf;
 use protobuf::compiler_plugin;
+use protobuf::Message;
 use protobuf_codegen::code_writer::CodeWriter;
 use protobuf::descriptor::*;
 use protobuf::descriptorx::*;
@@ -48,18 +51,65 @@ fn snake_name(name: &str) -> String {
     snake_method_name
 }
 
+// Field numbers of `FileDescriptorProto.service` and `ServiceDescriptorProto.method`
+// used in `SourceCodeInfo` location paths.
+const FILE_SERVICE_FIELD_NUMBER: i32 = 6;
+const SERVICE_METHOD_FIELD_NUMBER: i32 = 2;
+
+/// Leading comments of an element at the given path in source file.
+fn source_comments(file: &FileDescriptorProto, path: &[i32]) -> Option<String> {
+    file.get_source_code_info().get_location().iter()
+        .filter(|l| l.get_path() == path)
+        .map(|l| l.get_leading_comments())
+        .filter(|c| !c.is_empty())
+        .next()
+        .map(|c| c.to_owned())
+}
+
+/// Write comments from .proto file as rustdoc.
+fn write_doc_comments(w: &mut CodeWriter, comments: &Option<String>) {
+    if let &Some(ref comments) = comments {
+        for line in comments.trim_end().lines() {
+            w.write_line(&format!("///{}", line.trim_end()));
+        }
+    }
+}
+
+// bytes as content of byte string literal
+fn escape_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("\\x{:02x}", b)).collect()
+}
+
 struct MethodGen<'a> {
     proto: &'a MethodDescriptorProto,
+    service_name: String,
     service_path: String,
     root_scope: &'a RootScope<'a>,
+    // path from generated module to modules generated by rust-protobuf
+    messages_prefix: String,
+    customize: &'a Customize,
+    comments: Option<String>,
 }
 
 impl<'a> MethodGen<'a> {
-    fn new(proto: &'a MethodDescriptorProto, service_path: String, root_scope: &'a RootScope<'a>) -> MethodGen<'a> {
+    fn new(
+        proto: &'a MethodDescriptorProto,
+        service_name: String,
+        service_path: String,
+        root_scope: &'a RootScope<'a>,
+        messages_prefix: String,
+        customize: &'a Customize,
+        comments: Option<String>)
+        -> MethodGen<'a>
+    {
         MethodGen {
             proto: proto,
+            service_name: service_name,
             service_path: service_path,
             root_scope: root_scope,
+            messages_prefix: messages_prefix,
+            customize: customize,
+            comments: comments,
         }
     }
 
@@ -67,12 +117,22 @@ impl<'a> MethodGen<'a> {
         snake_name(self.proto.get_name())
     }
 
+    fn message_type(&self, proto_type: &str) -> String {
+        if let Some(path) = extern_message_path(&self.customize.extern_paths, proto_type) {
+            return path;
+        }
+        if let Some(path) = extern_message_path(&well_known_types_paths(), proto_type) {
+            return path;
+        }
+        format!("{}{}", self.messages_prefix, self.root_scope.find_message(proto_type).rust_fq_name())
+    }
+
     fn input_message(&self) -> String {
-        format!("super::{}", self.root_scope.find_message(self.proto.get_input_type()).rust_fq_name())
+        self.message_type(self.proto.get_input_type())
     }
 
     fn output_message(&self) -> String {
-        format!("super::{}", self.root_scope.find_message(self.proto.get_output_type()).rust_fq_name())
+        self.message_type(self.proto.get_output_type())
     }
 
     fn input(&self) -> String {
@@ -89,13 +149,80 @@ impl<'a> MethodGen<'a> {
         }
     }
 
+    fn sig_with_param_names(&self, o: &str, p: &str) -> String {
+        format!("{}(&self, {}: ::grpc::RequestOptions, {}: {}) -> {}",
+                self.snake_name(), o, p, self.input(), self.output())
+    }
+
     fn sig(&self) -> String {
-        format!("{}(&self, o: ::grpc::RequestOptions, p: {}) -> {}",
-                self.snake_name(), self.input(), self.output())
+        self.sig_with_param_names("o", "p")
+    }
+
+    fn write_unimplemented(&self, w: &mut CodeWriter) {
+        let response = match self.proto.get_server_streaming() {
+            false => "::grpc::SingleResponse",
+            true => "::grpc::StreamingResponse",
+        };
+        w.write_line(&format!("{}::err(::grpc::rt::unimplemented_error(\"{}/{}\"))",
+            response, self.service_path, self.proto.get_name()));
     }
 
     fn write_intf(&self, w: &mut CodeWriter) {
-        w.fn_def(&self.sig())
+        write_doc_comments(w, &self.comments);
+        // default implementation allows adding methods to proto
+        // without breaking existing service implementations
+        w.def_fn(&self.sig_with_param_names("_o", "_p"), |w| {
+            self.write_unimplemented(w);
+        });
+    }
+
+    // type of mock field holding method implementation
+    fn mock_field_type(&self) -> String {
+        format!("::std::option::Option<::std::boxed::Box<Fn(::grpc::RequestOptions, {}) -> {} + Send + Sync>>",
+            self.input(), self.output())
+    }
+
+    fn write_mock_field(&self, w: &mut CodeWriter) {
+        w.pub_field_decl(&self.snake_name(), &self.mock_field_type());
+    }
+
+    fn write_mock(&self, w: &mut CodeWriter) {
+        w.def_fn(&self.sig(), |w| {
+            w.write_line(&format!("match self.{} {{", self.snake_name()));
+            w.indented(|w| {
+                w.write_line("Some(ref f) => f(o, p),");
+                w.write_line("None => {");
+                w.indented(|w| {
+                    w.write_line("drop((o, p));");
+                    self.write_unimplemented(w);
+                });
+                w.write_line("}");
+            });
+            w.write_line("}");
+        });
+    }
+
+    // full name like `/package.Service/Method`
+    fn full_name(&self) -> String {
+        format!("{}/{}", self.service_path, self.proto.get_name())
+    }
+
+    // constant with full name, like `METHOD_LONG_TESTS_ECHO`
+    fn name_const(&self) -> String {
+        format!("METHOD_{}_{}",
+            snake_name(&self.service_name).to_uppercase(),
+            snake_name(self.proto.get_name()).to_uppercase())
+    }
+
+    // constant with `::grpc::Method`
+    fn method_const(&self) -> String {
+        format!("{}_DESCRIPTOR", self.name_const())
+    }
+
+    fn write_consts(&self, w: &mut CodeWriter) {
+        w.write_line(&format!("pub const {}: &'static str = \"{}\";", self.name_const(), self.full_name()));
+        w.write_line(&format!("pub const {}: ::grpc::Method = ::grpc::Method {{ name: {}, streaming: ::grpc::rt::GrpcStreaming::{} }};",
+            self.method_const(), self.name_const(), self.streaming_upper()));
     }
 
     fn descriptor_field_name(&self) -> String {
@@ -125,6 +252,7 @@ impl<'a> MethodGen<'a> {
     }
 
     fn write_client(&self, w: &mut CodeWriter) {
+        write_doc_comments(w, &self.comments);
         w.def_fn(&self.sig(), |w| {
             w.write_line(&format!("self.grpc_client.call_{}(o, p, self.{}.clone())",
                 self.streaming_lower(),
@@ -132,11 +260,32 @@ impl<'a> MethodGen<'a> {
         });
     }
 
+    fn idempotency_level(&self) -> &'static str {
+        match self.proto.get_options().get_idempotency_level() {
+            MethodOptions_IdempotencyLevel::IDEMPOTENCY_UNKNOWN => "IdempotencyUnknown",
+            MethodOptions_IdempotencyLevel::NO_SIDE_EFFECTS => "NoSideEffects",
+            MethodOptions_IdempotencyLevel::IDEMPOTENT => "Idempotent",
+        }
+    }
+
+    fn write_options(&self, w: &mut CodeWriter) {
+        let serialized = self.proto.get_options().write_to_bytes().expect("write_to_bytes");
+        w.block("options: ::grpc::MethodOptions {", "},", |w| {
+            w.field_entry("idempotency_level", &format!("::grpc::IdempotencyLevel::{}", self.idempotency_level()));
+            w.field_entry("serialized", &format!("b\"{}\"", escape_bytes(&serialized)));
+        });
+    }
+
     fn write_descriptor(&self, w: &mut CodeWriter, before: &str, after: &str) {
         w.block(&format!("{}{}", before, "::grpc::rt::MethodDescriptor {"), &format!("{}{}", "}", after), |w| {
-            w.field_entry("name", &format!("\"{}/{}\".to_string()", self.service_path, self.proto.get_name()));
+            w.field_entry("name", &format!("{}.to_string()", self.name_const()));
             w.field_entry("streaming", &format!("::grpc::rt::GrpcStreaming::{}", self.streaming_upper()));
-            w.field_entry("req_marshaller", "Box::new(::grpc::protobuf::MarshallerProtobuf)");
+            self.write_options(w);
+            let req_marshaller = match self.customize.validate.unwrap_or(false) {
+                true => "::grpc::protobuf::MarshallerProtobufValidate",
+                false => "::grpc::protobuf::MarshallerProtobuf",
+            };
+            w.field_entry("req_marshaller", &format!("Box::new({})", req_marshaller));
             w.field_entry("resp_marshaller", "Box::new(::grpc::protobuf::MarshallerProtobuf)");
         });
     }
@@ -148,18 +297,37 @@ struct ServiceGen<'a> {
     methods: Vec<MethodGen<'a>>,
     service_path: String,
     _package: String,
+    comments: Option<String>,
 }
 
 impl<'a> ServiceGen<'a> {
-    fn new(proto: &'a ServiceDescriptorProto, file: &FileDescriptorProto, root_scope: &'a RootScope) -> ServiceGen<'a> {
+    fn new(
+        proto: &'a ServiceDescriptorProto,
+        index: usize,
+        file: &FileDescriptorProto,
+        root_scope: &'a RootScope,
+        messages_prefix: &str,
+        customize: &'a Customize)
+        -> ServiceGen<'a>
+    {
         let service_path =
             if file.get_package().is_empty() {
                 format!("/{}", proto.get_name())
             } else {
                 format!("/{}.{}", file.get_package(), proto.get_name())
             };
-        let methods = proto.get_method().into_iter()
-            .map(|m| MethodGen::new(m, service_path.clone(), root_scope))
+        let service_location = [FILE_SERVICE_FIELD_NUMBER, index as i32];
+        let methods = proto.get_method().into_iter().enumerate()
+            .map(|(i, m)| {
+                let method_location = [
+                    service_location[0], service_location[1],
+                    SERVICE_METHOD_FIELD_NUMBER, i as i32,
+                ];
+                let comments = source_comments(file, &method_location);
+                MethodGen::new(
+                    m, proto.get_name().to_owned(), service_path.clone(), root_scope,
+                    messages_prefix.to_owned(), customize, comments)
+            })
             .collect();
 
         ServiceGen {
@@ -168,6 +336,7 @@ impl<'a> ServiceGen<'a> {
             methods: methods,
             service_path: service_path,
             _package: file.get_package().to_string(),
+            comments: source_comments(file, &service_location),
         }
     }
 
@@ -186,7 +355,32 @@ impl<'a> ServiceGen<'a> {
         format!("{}Server", self.intf_name())
     }
 
+    // mock struct name
+    fn mock_name(&self) -> String {
+        format!("{}Mock", self.intf_name())
+    }
+
+    // constant with all methods, like `METHODS_LONG_TESTS`
+    fn methods_const(&self) -> String {
+        format!("METHODS_{}", snake_name(self.proto.get_name()).to_uppercase())
+    }
+
+    fn write_consts(&self, w: &mut CodeWriter) {
+        for method in &self.methods {
+            method.write_consts(w);
+        }
+        w.write_line("");
+        w.write_line(&format!("pub const {}: &'static [::grpc::Method] = &[", self.methods_const()));
+        w.indented(|w| {
+            for method in &self.methods {
+                w.write_line(&format!("{},", method.method_const()));
+            }
+        });
+        w.write_line("];");
+    }
+
     fn write_intf(&self, w: &mut CodeWriter) {
+        write_doc_comments(w, &self.comments);
         w.pub_trait(&self.intf_name(), |w| {
             for (i, method) in self.methods.iter().enumerate() {
                 if i != 0 {
@@ -211,6 +405,8 @@ impl<'a> ServiceGen<'a> {
     }
 
     fn write_client(&self, w: &mut CodeWriter) {
+        write_doc_comments(w, &self.comments);
+        w.write_line("#[derive(Clone)]");
         w.pub_struct(&self.client_name(), |w| {
             w.field_decl("grpc_client", "::grpc::Client");
             for method in &self.methods {
@@ -231,6 +427,13 @@ impl<'a> ServiceGen<'a> {
 
             w.write_line("");
 
+            let sig = "with_channel(channel: ::grpc::Channel) -> Self";
+            w.pub_fn(sig, |w| {
+                w.write_line(&format!("{}::with_client(::grpc::Client::with_channel(channel))", self.client_name()));
+            });
+
+            w.write_line("");
+
             let sig = "new_plain(host: &str, port: u16, conf: ::grpc::ClientConf) -> ::grpc::Result<Self>";
             w.pub_fn(sig, |w| {
                 w.write_line("::grpc::Client::new_plain(host, port, conf).map(|c| {");
@@ -302,7 +505,36 @@ impl<'a> ServiceGen<'a> {
         });
     }
 
-    fn write(&self, w: &mut CodeWriter) {
+    fn write_mock(&self, w: &mut CodeWriter) {
+        w.write_line(&format!("/// `{}` implementation for tests.", self.intf_name()));
+        w.write_line("///");
+        w.write_line("/// Methods call closures stored in fields of the same name,");
+        w.write_line("/// methods without closure fail with `Unimplemented` status.");
+        w.write_line("#[derive(Default)]");
+        w.pub_struct(&self.mock_name(), |w| {
+            for method in &self.methods {
+                method.write_mock_field(w);
+            }
+        });
+
+        w.write_line("");
+
+        w.impl_for_block(self.intf_name(), &self.mock_name(), |w| {
+            for (i, method) in self.methods.iter().enumerate() {
+                if i != 0 {
+                    w.write_line("");
+                }
+
+                method.write_mock(w);
+            }
+        });
+    }
+
+    fn write(&self, w: &mut CodeWriter, customize: &Customize) {
+        w.comment("methods");
+        w.write_line("");
+        self.write_consts(w);
+        w.write_line("");
         w.comment("interface");
         w.write_line("");
         self.write_intf(w);
@@ -314,12 +546,151 @@ impl<'a> ServiceGen<'a> {
         w.comment("server");
         w.write_line("");
         self.write_server(w);
+        if customize.mock.unwrap_or(false) {
+            w.write_line("");
+            w.comment("mock");
+            w.write_line("");
+            self.write_mock(w);
+        }
+    }
+}
+
+/// Code generation options.
+#[derive(Debug, Default, Clone)]
+pub struct Customize {
+    /// Generate services of all files into single file `packages_grpc.rs`
+    /// with nested modules mirroring protobuf packages,
+    /// instead of `xxx_grpc.rs` file per `xxx.proto` file.
+    ///
+    /// Only services are nested: messages are still generated by rust-protobuf
+    /// into file per `.proto` file, and generated services reference them
+    /// as `super::xxx::Message` relative to the module containing `packages_grpc`,
+    /// so these files must be declared as sibling modules of `packages_grpc`.
+    pub module_per_package: Option<bool>,
+    /// Rust paths of messages generated elsewhere, e.g. in another crate,
+    /// as pairs of protobuf package or message name and Rust path,
+    /// e.g. `("google.protobuf", "::protobuf::well_known_types")`.
+    ///
+    /// Generated code references these paths instead of
+    /// messages generated by rust-protobuf next to the services.
+    /// Well-known types from `google.protobuf` package are mapped
+    /// to `::protobuf::well_known_types` unless overridden here.
+    pub extern_paths: Vec<(String, String)>,
+    /// Validate requests with `::grpc::protobuf::Validate` trait,
+    /// which must be implemented for all request messages.
+    pub validate: Option<bool>,
+    /// Generate `XxxMock` struct implementing service trait with closures,
+    /// to be used in place of client in tests.
+    pub mock: Option<bool>,
+    /// Derive `Serialize` and `Deserialize` for messages.
+    ///
+    /// Messages are generated by rust-protobuf, so this option only
+    /// enables rust-protobuf `serde_derive` when messages are generated
+    /// by `protoc-rust-grpc`; protoc plugin accepts it to allow sharing
+    /// parameter with `--rust_opt=serde_derive=true`, but ignores it.
+    pub serde: Option<bool>,
+}
+
+impl Customize {
+    /// Parse plugin parameter passed with `--rust-grpc_opt`, like
+    /// `module_per_package=true,extern_path=.google.protobuf=::protobuf::well_known_types`.
+    ///
+    /// Boolean options without value are set to `true`,
+    /// `extern_path` can be specified several times.
+    pub fn parse_from_parameter(parameter: &str) -> Result<Customize, String> {
+        fn parse_bool(name: &str, value: Option<&str>) -> Result<bool, String> {
+            match value {
+                None | Some("true") => Ok(true),
+                Some("false") => Ok(false),
+                Some(v) => Err(format!("invalid value of {}: {}", name, v)),
+            }
+        }
+
+        let mut customize = Customize::default();
+        for option in parameter.split(',').map(|o| o.trim()).filter(|o| !o.is_empty()) {
+            let mut parts = option.splitn(2, '=');
+            let name = parts.next().unwrap();
+            let value = parts.next();
+            match name {
+                "module_per_package" => {
+                    customize.module_per_package = Some(parse_bool(name, value)?);
+                }
+                "validate" => {
+                    customize.validate = Some(parse_bool(name, value)?);
+                }
+                "mock" => {
+                    customize.mock = Some(parse_bool(name, value)?);
+                }
+                "serde" => {
+                    customize.serde = Some(parse_bool(name, value)?);
+                }
+                "extern_path" => {
+                    let mut path = value.unwrap_or("").splitn(2, '=');
+                    match (path.next(), path.next()) {
+                        (Some(proto), Some(rust)) if !rust.is_empty() => {
+                            customize.extern_paths.push((proto.to_owned(), rust.to_owned()));
+                        }
+                        _ => return Err(format!("extern_path must be like .package=::rust::path: {}", option)),
+                    }
+                }
+                _ => return Err(format!("unknown option: {}", name)),
+            }
+        }
+        Ok(customize)
+    }
+}
+
+// well-known types are not generated by rust-protobuf,
+// it references messages shipped with protobuf crate instead
+fn well_known_types_paths() -> Vec<(String, String)> {
+    vec![("google.protobuf".to_owned(), "::protobuf::well_known_types".to_owned())]
+}
+
+// Rust path of message configured with `Customize::extern_paths`,
+// longest matching protobuf name wins
+fn extern_message_path(extern_paths: &[(String, String)], proto_type: &str) -> Option<String> {
+    let proto_type = proto_type.trim_start_matches('.');
+    let mut best: Option<(&str, &str)> = None;
+    for &(ref proto_name, ref rust_path) in extern_paths {
+        let proto_name = proto_name.trim_start_matches('.');
+        let matches = proto_name.is_empty()
+            || proto_type == proto_name
+            || proto_type.starts_with(&format!("{}.", proto_name));
+        if matches && best.map_or(true, |(b, _)| proto_name.len() > b.len()) {
+            best = Some((proto_name, rust_path));
+        }
+    }
+    best.map(|(proto_name, rust_path)| {
+        if proto_type == proto_name {
+            rust_path.to_owned()
+        } else {
+            let rem = &proto_type[if proto_name.is_empty() { 0 } else { proto_name.len() + 1 }..];
+            // rust-protobuf names nested messages `Outer_Inner`
+            format!("{}::{}", rust_path, rem.replace('.', "_"))
+        }
+    })
+}
+
+/// Name of file generated when `Customize::module_per_package` is set.
+pub const PACKAGES_FILE_NAME: &'static str = "packages_grpc.rs";
+
+fn write_services(
+    w: &mut CodeWriter,
+    file: &FileDescriptorProto,
+    root_scope: &RootScope,
+    messages_prefix: &str,
+    customize: &Customize)
+{
+    for (i, service) in file.get_service().iter().enumerate() {
+        w.write_line("");
+        ServiceGen::new(service, i, file, root_scope, messages_prefix, customize).write(w, customize);
     }
 }
 
 fn gen_file(
     file: &FileDescriptorProto,
     root_scope: &RootScope,
+    customize: &Customize,
 ) -> Option<compiler_plugin::GenResult>
 {
     if file.get_service().is_empty() {
@@ -334,10 +705,7 @@ fn gen_file(
         w.write_generated();
         w.write_line("");
 
-        for service in file.get_service() {
-            w.write_line("");
-            ServiceGen::new(service, file, root_scope).write(&mut w);
-        }
+        write_services(&mut w, file, root_scope, "super::", customize);
     }
 
     Some(compiler_plugin::GenResult {
@@ -346,35 +714,241 @@ fn gen_file(
     })
 }
 
+/// Files with services of a protobuf package and its subpackages
+#[derive(Default)]
+struct PackageModule<'a> {
+    files: Vec<&'a FileDescriptorProto>,
+    subpackages: BTreeMap<String, PackageModule<'a>>,
+}
+
+impl<'a> PackageModule<'a> {
+    fn add(&mut self, package: &[&str], file: &'a FileDescriptorProto) {
+        match package.split_first() {
+            None => self.files.push(file),
+            Some((first, rem)) => {
+                self.subpackages.entry(first.to_string()).or_insert_with(Default::default).add(rem, file)
+            }
+        }
+    }
+
+    fn write(&self, w: &mut CodeWriter, root_scope: &RootScope, customize: &Customize, depth: usize) {
+        // one `super::` to leave `packages_grpc` module and one per nesting level
+        let messages_prefix = "super::".repeat(depth + 1);
+        for file in &self.files {
+            write_services(w, file, root_scope, &messages_prefix, customize);
+        }
+        for (name, subpackage) in &self.subpackages {
+            w.write_line("");
+            w.pub_mod(&package_component_to_rust_mod(name), |w| {
+                subpackage.write(w, root_scope, customize, depth + 1);
+            });
+        }
+    }
+}
+
+fn package_component_to_rust_mod(component: &str) -> String {
+    static KEYWORDS: &'static [&'static str] = &[
+        "as", "box", "break", "const", "continue", "crate", "else", "enum", "extern",
+        "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+        "mut", "pub", "ref", "return", "self", "static", "struct", "super", "trait",
+        "true", "type", "unsafe", "use", "where", "while",
+    ];
+    if KEYWORDS.contains(&component) {
+        format!("{}_", component)
+    } else {
+        component.to_owned()
+    }
+}
+
+fn gen_packages(
+    files: &[&FileDescriptorProto],
+    root_scope: &RootScope,
+    customize: &Customize)
+    -> compiler_plugin::GenResult
+{
+    let mut root = PackageModule::default();
+    for file in files {
+        let package: Vec<&str> = file.get_package().split('.').filter(|c| !c.is_empty()).collect();
+        root.add(&package, file);
+    }
+
+    let mut v = Vec::new();
+    {
+        let mut w = CodeWriter::new(&mut v);
+        w.write_generated();
+        w.write_line("");
+
+        root.write(&mut w, root_scope, customize, 0);
+    }
+
+    compiler_plugin::GenResult {
+        name: PACKAGES_FILE_NAME.to_owned(),
+        content: v,
+    }
+}
+
 pub fn gen(file_descriptors: &[FileDescriptorProto], files_to_generate: &[String])
         -> Vec<compiler_plugin::GenResult>
+{
+    gen_customized(file_descriptors, files_to_generate, &Customize::default())
+}
+
+pub fn gen_customized(
+    file_descriptors: &[FileDescriptorProto],
+    files_to_generate: &[String],
+    customize: &Customize)
+    -> Vec<compiler_plugin::GenResult>
 {
     let files_map: HashMap<&str, &FileDescriptorProto> =
         file_descriptors.iter().map(|f| (f.get_name(), f)).collect();
 
     let root_scope = RootScope { file_descriptors: file_descriptors };
 
-    let mut results = Vec::new();
-
-    for file_name in files_to_generate {
-        let file = files_map[&file_name[..]];
+    let files: Vec<&FileDescriptorProto> = files_to_generate.iter()
+        .map(|file_name| files_map[&file_name[..]])
+        .filter(|file| !file.get_service().is_empty())
+        .collect();
 
-        if file.get_service().is_empty() {
-            continue;
+    if customize.module_per_package.unwrap_or(false) {
+        if files.is_empty() {
+            return Vec::new();
         }
+        return vec![gen_packages(&files, &root_scope, customize)];
+    }
+
+    let mut results = Vec::new();
 
-        results.extend(gen_file(file, &root_scope).into_iter());
+    for file in files {
+        results.extend(gen_file(file, &root_scope, customize).into_iter());
     }
 
     results
 }
 
 pub fn protoc_gen_grpc_rust_main() {
-    compiler_plugin::plugin_main(gen);
+    compiler_plugin::plugin_main_2(|r| {
+        let customize = match Customize::parse_from_parameter(r.parameter) {
+            Ok(customize) => customize,
+            Err(e) => {
+                eprintln!("protoc-gen-rust-grpc: {}", e);
+                process::exit(1);
+            }
+        };
+        gen_customized(r.file_descriptors, r.files_to_generate, &customize)
+    });
 }
 
 #[cfg(test)]
 mod test {
+    use protobuf::descriptor::*;
+
+    #[test]
+    fn test_source_comments() {
+        let mut location = SourceCodeInfo_Location::new();
+        location.set_path(vec![6, 0, 2, 1]);
+        location.set_leading_comments(" Echo request\n".to_owned());
+        let mut file = FileDescriptorProto::new();
+        file.mut_source_code_info().mut_location().push(location);
+
+        assert_eq!(Some(" Echo request\n".to_owned()), super::source_comments(&file, &[6, 0, 2, 1]));
+        assert_eq!(None, super::source_comments(&file, &[6, 0]));
+    }
+
+    #[test]
+    fn test_extern_message_path() {
+        let extern_paths = vec![
+            ("google.protobuf".to_owned(), "::protobuf::well_known_types".to_owned()),
+            (".foo".to_owned(), "::foo".to_owned()),
+            ("foo.bar.Baz".to_owned(), "::baz::Baz".to_owned()),
+        ];
+        assert_eq!(
+            Some("::protobuf::well_known_types::Empty".to_owned()),
+            super::extern_message_path(&extern_paths, ".google.protobuf.Empty"));
+        assert_eq!(
+            Some("::foo::Outer_Inner".to_owned()),
+            super::extern_message_path(&extern_paths, ".foo.Outer.Inner"));
+        assert_eq!(
+            Some("::baz::Baz".to_owned()),
+            super::extern_message_path(&extern_paths, ".foo.bar.Baz"));
+        assert_eq!(None, super::extern_message_path(&extern_paths, ".foobar.Qux"));
+    }
+
+    #[test]
+    fn test_parse_from_parameter() {
+        let customize = super::Customize::parse_from_parameter(
+            "module_per_package=true, validate,extern_path=.foo=::foo::bar").unwrap();
+        assert_eq!(Some(true), customize.module_per_package);
+        assert_eq!(Some(true), customize.validate);
+        assert_eq!(vec![(".foo".to_owned(), "::foo::bar".to_owned())], customize.extern_paths);
+
+        let customize = super::Customize::parse_from_parameter("").unwrap();
+        assert_eq!(None, customize.module_per_package);
+
+        assert!(super::Customize::parse_from_parameter("validate=yes").is_err());
+        assert!(super::Customize::parse_from_parameter("extern_path=.foo").is_err());
+        assert!(super::Customize::parse_from_parameter("unknown=true").is_err());
+
+        let customize = super::Customize::parse_from_parameter(
+            "serde=true,module_per_package=true,mock=true").unwrap();
+        assert_eq!(Some(true), customize.serde);
+        assert_eq!(Some(true), customize.module_per_package);
+        assert_eq!(Some(true), customize.mock);
+    }
+
+    #[test]
+    fn test_gen_mock() {
+        let mut method = MethodDescriptorProto::new();
+        method.set_name("Echo".to_owned());
+        method.set_input_type(".foo.Req".to_owned());
+        method.set_output_type(".foo.Resp".to_owned());
+        let mut service = ServiceDescriptorProto::new();
+        service.set_name("Foo".to_owned());
+        service.mut_method().push(method);
+        let mut file = FileDescriptorProto::new();
+        file.set_name("foo.proto".to_owned());
+        file.set_package("foo".to_owned());
+        file.mut_service().push(service);
+
+        let mut customize = super::Customize::default();
+        customize.extern_paths.push(("foo".to_owned(), "::foo".to_owned()));
+        customize.mock = Some(true);
+
+        let results = super::gen_customized(&[file], &["foo.proto".to_owned()], &customize);
+        assert_eq!(1, results.len());
+        let content = String::from_utf8(results[0].content.clone()).unwrap();
+        assert!(content.contains("pub struct FooMock {"), "{}", content);
+        assert!(content.contains("impl Foo for FooMock {"), "{}", content);
+        assert!(content.contains("pub echo: ::std::option::Option<"), "{}", content);
+    }
+
+    #[test]
+    fn test_escape_bytes() {
+        assert_eq!("", super::escape_bytes(b""));
+        assert_eq!("\\x01\\xaa", super::escape_bytes(&[1, 0xaa]));
+    }
+
+    #[test]
+    fn test_package_component_to_rust_mod() {
+        assert_eq!("foo", super::package_component_to_rust_mod("foo"));
+        assert_eq!("type_", super::package_component_to_rust_mod("type"));
+    }
+
+    #[test]
+    fn test_method_consts() {
+        let mut proto = MethodDescriptorProto::new();
+        proto.set_name("echo".to_owned());
+        proto.set_server_streaming(true);
+        let root_scope = ::protobuf::descriptorx::RootScope { file_descriptors: &[] };
+        let customize = super::Customize::default();
+        let method = super::MethodGen::new(
+            &proto, "LongTests".to_owned(), "/LongTests".to_owned(), &root_scope,
+            String::new(), &customize, None);
+
+        assert_eq!("/LongTests/echo", method.full_name());
+        assert_eq!("METHOD_LONG_TESTS_ECHO", method.name_const());
+        assert_eq!("METHOD_LONG_TESTS_ECHO_DESCRIPTOR", method.method_const());
+    }
+
     #[test]
     fn test_snake_name() {
         let cases = vec![
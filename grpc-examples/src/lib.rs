@@ -9,3 +9,10 @@ pub mod helloworld_grpc;
 
 pub mod route_guide;
 pub mod route_guide_grpc;
+
+pub mod operations;
+pub mod operations_grpc;
+pub mod status;
+
+pub mod pagination;
+pub mod pagination_grpc;
@@ -0,0 +1,147 @@
+//! `OperationsClient` against in-process `google.longrunning.Operations` server.
+
+extern crate futures;
+extern crate grpc;
+extern crate grpc_examples;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::Future;
+
+use grpc_examples::helloworld::HelloReply;
+use grpc_examples::operations::GetOperationRequest;
+use grpc_examples::operations::Operation;
+use grpc_examples::operations_grpc;
+use grpc_examples::status::Status;
+
+use grpc::well_known_types::pack_any;
+
+
+/// Operations done after given number of polls
+#[derive(Default, Clone)]
+struct OperationsImpl {
+    done_after: HashMap<String, (usize, Operation)>,
+    polls: Arc<Mutex<Vec<(String, Instant)>>>,
+}
+
+impl OperationsImpl {
+    fn add(&mut self, polls: usize, done: Operation) {
+        self.done_after.insert(done.get_name().to_owned(), (polls, done));
+    }
+
+    fn poll_times(&self, name: &str) -> Vec<Instant> {
+        self.polls.lock().unwrap().iter()
+            .filter(|&&(ref n, _)| n == name)
+            .map(|&(_, t)| t)
+            .collect()
+    }
+}
+
+impl operations_grpc::Operations for OperationsImpl {
+    fn get_operation(&self, _o: grpc::RequestOptions, p: GetOperationRequest)
+        -> grpc::SingleResponse<Operation>
+    {
+        let polled = {
+            let mut polls = self.polls.lock().unwrap();
+            polls.push((p.get_name().to_owned(), Instant::now()));
+            polls.iter().filter(|&&(ref n, _)| n == p.get_name()).count()
+        };
+        match self.done_after.get(p.get_name()) {
+            Some(&(polls, ref done)) if polled >= polls => grpc::SingleResponse::completed(done.clone()),
+            Some(..) => grpc::SingleResponse::completed(pending(p.get_name())),
+            None => grpc::SingleResponse::err(grpc::Error::GrpcMessage(grpc::GrpcMessageError {
+                grpc_status: grpc::GrpcStatus::NotFound as i32,
+                grpc_message: p.get_name().to_owned(),
+            })),
+        }
+    }
+}
+
+fn pending(name: &str) -> Operation {
+    let mut op = Operation::new();
+    op.set_name(name.to_owned());
+    op
+}
+
+fn reply(message: &str) -> HelloReply {
+    let mut reply = HelloReply::new();
+    reply.set_message(message.to_owned());
+    reply
+}
+
+fn start(
+    operations: OperationsImpl)
+    -> (grpc::Server, OperationsImpl, grpc::OperationsClient<Operation, GetOperationRequest>)
+{
+    let mut server = grpc::ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_service(operations_grpc::OperationsServer::new_service_def(operations.clone()));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let client = grpc::Client::new_plain("127.0.0.1", port, grpc::ClientConf::new()).expect("client");
+    let mut operations_client = grpc::OperationsClient::for_client(client);
+    operations_client.poll_policy = grpc::PollPolicy {
+        initial_delay: Duration::from_millis(20),
+        max_delay: Duration::from_secs(1),
+        delay_multiplier: 2.0,
+        timeout: Some(Duration::from_secs(10)),
+    };
+    (server, operations, operations_client)
+}
+
+#[test]
+fn wait_response_polls_with_backoff() {
+    let mut done = pending("ok");
+    done.set_done(true);
+    done.set_response(pack_any(&reply("done")).unwrap());
+    let mut operations = OperationsImpl::default();
+    operations.add(3, done);
+    let (_server, operations, client) = start(operations);
+
+    let started = grpc::SingleResponse::completed(pending("ok"));
+    let response: HelloReply = client.wait_response(grpc::RequestOptions::new(), started)
+        .wait()
+        .unwrap();
+    assert_eq!("done", response.get_message());
+
+    let polls = operations.poll_times("ok");
+    assert_eq!(3, polls.len());
+    // delays of 40ms and 80ms after initial 20ms
+    assert!(polls[1] - polls[0] >= Duration::from_millis(35), "{:?}", polls[1] - polls[0]);
+    assert!(polls[2] - polls[1] >= Duration::from_millis(70), "{:?}", polls[2] - polls[1]);
+}
+
+#[test]
+fn wait_response_fails_with_operation_error() {
+    let mut status = Status::new();
+    status.set_code(grpc::GrpcStatus::ResourceExhausted as i32);
+    status.set_message("quota".to_owned());
+    status.mut_details().push(pack_any(&reply("retry later")).unwrap());
+    let mut done = pending("fail");
+    done.set_done(true);
+    done.set_error(status);
+    let mut operations = OperationsImpl::default();
+    operations.add(1, done);
+    let (_server, _operations, client) = start(operations);
+
+    let started = grpc::SingleResponse::completed(pending("fail"));
+    match client.wait_response::<HelloReply>(grpc::RequestOptions::new(), started).wait() {
+        Err(grpc::Error::GrpcMessage(ref e)) => {
+            assert_eq!(grpc::GrpcStatus::ResourceExhausted as i32, e.grpc_status);
+            assert_eq!("quota", e.grpc_message);
+        }
+        r => panic!("expecting operation error: {:?}", r),
+    }
+
+    let started = grpc::SingleResponse::completed(pending("fail"));
+    let op = client.wait(grpc::RequestOptions::new(), started).wait().unwrap();
+    let details: Vec<HelloReply> = client.error_details(&op).unwrap();
+    assert_eq!(vec![reply("retry later")], details);
+    // details of other types are skipped
+    assert!(client.error_details::<Operation>(&op).unwrap().is_empty());
+}
@@ -0,0 +1,89 @@
+//! `Paginator` against in-process server of a paginated method.
+
+extern crate futures;
+extern crate grpc;
+extern crate grpc_examples;
+
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use futures::Stream;
+
+use grpc_examples::pagination::Book;
+use grpc_examples::pagination::ListBooksRequest;
+use grpc_examples::pagination::ListBooksResponse;
+use grpc_examples::pagination_grpc;
+use grpc_examples::pagination_grpc::Library;
+
+
+/// Library listing books of a shelf with page token being offset
+#[derive(Clone)]
+struct LibraryImpl {
+    books: Vec<String>,
+    requests: Arc<Mutex<Vec<ListBooksRequest>>>,
+}
+
+impl Library for LibraryImpl {
+    fn list_books(&self, _o: grpc::RequestOptions, p: ListBooksRequest)
+        -> grpc::SingleResponse<ListBooksResponse>
+    {
+        self.requests.lock().unwrap().push(p.clone());
+
+        let start: usize = if p.get_page_token().is_empty() {
+            0
+        } else {
+            p.get_page_token().parse().unwrap()
+        };
+        let end = ::std::cmp::min(start + p.get_page_size() as usize, self.books.len());
+
+        let mut resp = ListBooksResponse::new();
+        for name in &self.books[start..end] {
+            let mut book = Book::new();
+            book.set_name(format!("{}/{}", p.get_shelf(), name));
+            resp.mut_books().push(book);
+        }
+        if end < self.books.len() {
+            resp.set_next_page_token(end.to_string());
+        }
+        grpc::SingleResponse::completed(resp)
+    }
+}
+
+#[test]
+fn list_all_pages() {
+    let library = LibraryImpl {
+        books: vec!["a", "b", "c", "d", "e"].into_iter().map(|b| b.to_owned()).collect(),
+        requests: Default::default(),
+    };
+
+    let mut server = grpc::ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_service(pagination_grpc::LibraryServer::new_service_def(library.clone()));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let client = pagination_grpc::LibraryClient::new_plain("127.0.0.1", port, grpc::ClientConf::new())
+        .expect("client");
+    let paginator = grpc::Paginator::new(move |o, req| client.list_books(o, req));
+
+    let mut req = ListBooksRequest::new();
+    req.set_shelf("fiction".to_owned());
+    req.set_page_size(2);
+    let books: Vec<String> = paginator.list::<Book>(grpc::RequestOptions::new(), req)
+        .wait()
+        .map(|book| book.map(|book| book.get_name().to_owned()))
+        .collect::<grpc::Result<_>>()
+        .unwrap();
+    assert_eq!(
+        vec!["fiction/a", "fiction/b", "fiction/c", "fiction/d", "fiction/e"],
+        books);
+
+    // token of previous page is passed, other fields are kept
+    let requests = library.requests.lock().unwrap();
+    let tokens: Vec<&str> = requests.iter().map(|r| r.get_page_token()).collect();
+    assert_eq!(vec!["", "2", "4"], tokens);
+    for r in requests.iter() {
+        assert_eq!("fiction", r.get_shelf());
+        assert_eq!(2, r.get_page_size());
+    }
+}
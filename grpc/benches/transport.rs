@@ -0,0 +1,176 @@
+//! Benchmarks of client and server running in-process over loopback.
+//!
+//! HPACK is implemented in `httpbis`, so header encoding and decoding
+//! are measured by unary calls with many metadata entries.
+
+#[macro_use]
+extern crate criterion;
+extern crate futures;
+extern crate bytes;
+extern crate grpc;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use criterion::Benchmark;
+use criterion::Criterion;
+use criterion::Throughput;
+
+use futures::future::Future;
+use futures::stream::Stream;
+
+use bytes::Bytes;
+
+use grpc::*;
+use grpc::rt::*;
+use grpc::for_test::*;
+
+
+const BIND_HOST: &str = "127.0.0.1";
+
+const STREAM_MESSAGES: usize = 1000;
+const STREAM_MESSAGE_SIZE: usize = 100;
+
+fn string_string_method(name: &str, streaming: GrpcStreaming)
+    -> Arc<MethodDescriptor<String, String>>
+{
+    Arc::new(MethodDescriptor {
+        name: name.to_owned(),
+        streaming: streaming,
+        options: Default::default(),
+        req_marshaller: Box::new(MarshallerString),
+        resp_marshaller: Box::new(MarshallerString),
+    })
+}
+
+/// Server with unary `/bench/Echo`, server streaming `/bench/Stream`
+/// and client streaming `/bench/Upload` methods on random port.
+fn new_server() -> Server {
+    let methods = vec![
+        ServerMethod::new(
+            string_string_method("/bench/Echo", GrpcStreaming::Unary),
+            MethodHandlerUnary::new(|_o, s| SingleResponse::completed(s)),
+        ),
+        ServerMethod::new(
+            string_string_method("/bench/Stream", GrpcStreaming::ServerStreaming),
+            MethodHandlerServerStreaming::new(|_o, s: String| {
+                StreamingResponse::iter_with_metadata(
+                    Metadata::new(),
+                    (0..STREAM_MESSAGES).map(move |_| s.clone()))
+            }),
+        ),
+        ServerMethod::new(
+            string_string_method("/bench/Upload", GrpcStreaming::ClientStreaming),
+            MethodHandlerClientStreaming::new(|_o, req: StreamingRequest<String>| {
+                SingleResponse::no_metadata(req.0.fold(0, |n, _| Ok::<_, Error>(n + 1))
+                    .map(|n: usize| n.to_string()))
+            }),
+        ),
+    ];
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_service(ServerServiceDefinition::new("/bench", methods));
+    server.build().expect("server")
+}
+
+fn new_client(server: &Server, conf: ClientConf) -> Client {
+    let port = server.local_addr().port().expect("port");
+    Client::new_plain(BIND_HOST, port, conf).expect("client")
+}
+
+fn unary(c: &mut Criterion) {
+    let server = new_server();
+    let client = new_client(&server, Default::default());
+    let method = string_string_method("/bench/Echo", GrpcStreaming::Unary);
+
+    c.bench_function("unary", move |b| b.iter(|| {
+        client.call_unary(RequestOptions::new(), "ping".to_owned(), method.clone())
+            .wait_drop_metadata()
+            .expect("call")
+    }));
+}
+
+fn unary_metadata(c: &mut Criterion) {
+    let server = new_server();
+    let client = new_client(&server, Default::default());
+    let method = string_string_method("/bench/Echo", GrpcStreaming::Unary);
+
+    let mut metadata = Metadata::new();
+    for i in 0..32 {
+        metadata.add(
+            MetadataKey::from(format!("x-bench-{}", i)),
+            Bytes::from(format!("value-{}", i)));
+    }
+
+    c.bench_function("unary_metadata", move |b| b.iter(|| {
+        let mut options = RequestOptions::new();
+        options.metadata = metadata.clone();
+        client.call_unary(options, "ping".to_owned(), method.clone())
+            .wait_drop_metadata()
+            .expect("call")
+    }));
+}
+
+fn server_streaming(c: &mut Criterion) {
+    let server = new_server();
+    let client = new_client(&server, Default::default());
+    let method = string_string_method("/bench/Stream", GrpcStreaming::ServerStreaming);
+    let message = "x".repeat(STREAM_MESSAGE_SIZE);
+
+    c.bench("server_streaming", Benchmark::new("server_streaming", move |b| b.iter(|| {
+        let (_, items, _) = client.call_server_streaming(
+            RequestOptions::new(), message.clone(), method.clone())
+                .collect()
+                .wait()
+                .expect("call");
+        assert_eq!(STREAM_MESSAGES, items.len());
+    })).throughput(Throughput::Bytes((STREAM_MESSAGES * STREAM_MESSAGE_SIZE) as u32)));
+}
+
+/// Client streaming upload with given write strategy.
+fn client_streaming_with(c: &mut Criterion, name: &str, write_strategy: WriteStrategy) {
+    let server = new_server();
+    let mut conf = ClientConf::new();
+    conf.write_strategy = write_strategy;
+    let client = new_client(&server, conf);
+    let method = string_string_method("/bench/Upload", GrpcStreaming::ClientStreaming);
+    let message = "x".repeat(STREAM_MESSAGE_SIZE);
+
+    c.bench(name, Benchmark::new(name, move |b| b.iter(|| {
+        let message = message.clone();
+        let req = StreamingRequest::iter((0..STREAM_MESSAGES).map(move |_| message.clone()));
+        let count = client.call_client_streaming(RequestOptions::new(), req, method.clone())
+            .wait_drop_metadata()
+            .expect("call");
+        assert_eq!(STREAM_MESSAGES.to_string(), count);
+    })).throughput(Throughput::Bytes((STREAM_MESSAGES * STREAM_MESSAGE_SIZE) as u32)));
+}
+
+fn client_streaming(c: &mut Criterion) {
+    client_streaming_with(c, "client_streaming_immediate", WriteStrategy::Immediate);
+    client_streaming_with(
+        c, "client_streaming_batch", WriteStrategy::batch(Duration::from_millis(1)));
+}
+
+fn framing(c: &mut Criterion) {
+    let message = vec![17u8; STREAM_MESSAGE_SIZE];
+    let mut frames = Vec::new();
+    for _ in 0..STREAM_MESSAGES {
+        frames.extend(write_grpc_frame_to_vec(&message));
+    }
+
+    c.bench("framing", Benchmark::new("write", move |b| b.iter(|| {
+        let mut r = Vec::new();
+        for _ in 0..STREAM_MESSAGES {
+            write_grpc_frame(&mut r, &message);
+        }
+        r
+    })).with_function("parse", move |b| b.iter(|| {
+        let parsed = parse_grpc_frames_completely(&frames).expect("parse");
+        assert_eq!(STREAM_MESSAGES, parsed.len());
+    })).throughput(Throughput::Bytes((STREAM_MESSAGES * STREAM_MESSAGE_SIZE) as u32)));
+}
+
+
+criterion_group!(benches, unary, unary_metadata, server_streaming, client_streaming, framing);
+criterion_main!(benches);
@@ -0,0 +1,250 @@
+//! Authorization of server calls.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use error::Error;
+use error::GrpcMessageError;
+use grpc::GrpcStatus;
+use interceptor::*;
+use metadata::Metadata;
+use req::*;
+use resp::*;
+
+
+/// Authenticated caller.
+///
+/// Set in `RequestOptions` by authentication interceptor,
+/// e. g. from verified token claims or peer certificate
+/// (see `PeerIdentity::from_certificate`).
+#[derive(Debug, Clone, Default)]
+pub struct PeerIdentity {
+    /// Principal, e. g. `sub` claim of a token
+    pub subject: String,
+    /// Audiences the credentials were issued for
+    pub audiences: Vec<String>,
+    /// DNS names from subject alternative names of peer certificate
+    pub dns_names: Vec<String>,
+    /// URIs from subject alternative names of peer certificate
+    pub uris: Vec<String>,
+}
+
+impl PeerIdentity {
+    /// SPIFFE ID, the first `spiffe://` URI.
+    pub fn spiffe_id(&self) -> Option<&str> {
+        self.uris.iter().map(|u| u.as_str()).find(|u| u.starts_with("spiffe://"))
+    }
+}
+
+/// Reason to reject a call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthorizationError {
+    /// Caller is not authenticated or credentials are invalid
+    Unauthenticated(String),
+    /// Caller is not allowed to call the method
+    PermissionDenied(String),
+}
+
+impl From<AuthorizationError> for Error {
+    fn from(e: AuthorizationError) -> Error {
+        let (status, message) = match e {
+            AuthorizationError::Unauthenticated(m) => (GrpcStatus::Unauthenticated, m),
+            AuthorizationError::PermissionDenied(m) => (GrpcStatus::PermissionDenied, m),
+        };
+        Error::GrpcMessage(GrpcMessageError {
+            grpc_status: status as i32,
+            grpc_message: message,
+        })
+    }
+}
+
+/// Decides if a call is allowed before it is dispatched to the handler.
+pub trait Authorizer : Send + Sync + 'static {
+    fn authorize(&self, method: &str, metadata: &Metadata, peer: Option<&PeerIdentity>)
+        -> Result<(), AuthorizationError>;
+}
+
+
+enum Rule {
+    Allow,
+    Deny,
+    RequireAudience(String),
+}
+
+/// Simple authorizer with allow and deny lists.
+///
+/// Patterns are full method names (`/package.Service/Method`),
+/// services (`/package.Service/*`) or `*` for any method.
+/// Deny rules take precedence over allow rules.
+pub struct AccessPolicy {
+    rules: Vec<(String, Rule)>,
+    allow_by_default: bool,
+}
+
+pub(crate) fn pattern_matches(pattern: &str, method: &str) -> bool {
+    if pattern == "*" {
+        true
+    } else if pattern.ends_with("/*") {
+        method.starts_with(&pattern[..pattern.len() - 1])
+    } else {
+        pattern == method
+    }
+}
+
+impl AccessPolicy {
+    /// Policy allowing only methods from allow list.
+    pub fn deny_by_default() -> AccessPolicy {
+        AccessPolicy {
+            rules: Vec::new(),
+            allow_by_default: false,
+        }
+    }
+
+    /// Policy allowing all methods not in deny list.
+    pub fn allow_by_default() -> AccessPolicy {
+        AccessPolicy {
+            rules: Vec::new(),
+            allow_by_default: true,
+        }
+    }
+
+    pub fn allow(&mut self, pattern: &str) {
+        self.rules.push((pattern.to_owned(), Rule::Allow));
+    }
+
+    pub fn deny(&mut self, pattern: &str) {
+        self.rules.push((pattern.to_owned(), Rule::Deny));
+    }
+
+    /// Require caller authenticated with credentials issued for `audience`.
+    pub fn require_audience(&mut self, pattern: &str, audience: &str) {
+        self.rules.push((pattern.to_owned(), Rule::RequireAudience(audience.to_owned())));
+    }
+}
+
+impl Authorizer for AccessPolicy {
+    fn authorize(&self, method: &str, _metadata: &Metadata, peer: Option<&PeerIdentity>)
+        -> Result<(), AuthorizationError>
+    {
+        let mut allowed = self.allow_by_default;
+
+        for &(ref pattern, ref rule) in &self.rules {
+            if !pattern_matches(pattern, method) {
+                continue;
+            }
+            match *rule {
+                Rule::Deny => {
+                    return Err(AuthorizationError::PermissionDenied(
+                        format!("method {} is denied", method)));
+                }
+                Rule::Allow => allowed = true,
+                Rule::RequireAudience(ref audience) => {
+                    let peer = match peer {
+                        Some(peer) => peer,
+                        None => {
+                            return Err(AuthorizationError::Unauthenticated(
+                                "authentication required".to_owned()));
+                        }
+                    };
+                    if !peer.audiences.iter().any(|a| a == audience) {
+                        return Err(AuthorizationError::PermissionDenied(
+                            format!("credentials are not issued for audience {}", audience)));
+                    }
+                }
+            }
+        }
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(AuthorizationError::PermissionDenied(format!("method {} is not allowed", method)))
+        }
+    }
+}
+
+
+/// Server interceptor which consults `Authorizer` before dispatching calls.
+///
+/// Must be added after the authentication interceptor, if any.
+pub struct AuthorizationInterceptor {
+    authorizer: Arc<Authorizer>,
+}
+
+impl AuthorizationInterceptor {
+    pub fn new(authorizer: Arc<Authorizer>) -> AuthorizationInterceptor {
+        AuthorizationInterceptor { authorizer: authorizer }
+    }
+}
+
+impl ServerInterceptor for AuthorizationInterceptor {
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>
+    {
+        match self.authorizer.authorize(method, &o.metadata, o.peer_identity.as_ref()) {
+            Ok(()) => next.call(o, req),
+            Err(e) => {
+                debug!("call to {} rejected: {:?}", method, e);
+                StreamingResponse::err(e.into())
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn authorize(policy: &AccessPolicy, method: &str, peer: Option<&PeerIdentity>)
+        -> Result<(), AuthorizationError>
+    {
+        policy.authorize(method, &Metadata::new(), peer)
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let mut policy = AccessPolicy::deny_by_default();
+        policy.allow("/a.S/*");
+        policy.deny("/a.S/Admin");
+        policy.allow("/b.S/Get");
+
+        assert!(authorize(&policy, "/a.S/Get", None).is_ok());
+        assert!(authorize(&policy, "/a.S/Admin", None).is_err());
+        assert!(authorize(&policy, "/b.S/Get", None).is_ok());
+        assert!(authorize(&policy, "/b.S/Put", None).is_err());
+        assert!(authorize(&policy, "/a.SS/Get", None).is_err());
+    }
+
+    #[test]
+    fn audience() {
+        let mut policy = AccessPolicy::allow_by_default();
+        policy.require_audience("*", "api");
+
+        let api = PeerIdentity {
+            subject: "u".to_owned(),
+            audiences: vec!["api".to_owned()],
+            ..Default::default()
+        };
+        let other = PeerIdentity {
+            subject: "u".to_owned(),
+            audiences: vec!["other".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(authorize(&policy, "/a.S/Get", Some(&api)).is_ok());
+        match authorize(&policy, "/a.S/Get", Some(&other)) {
+            Err(AuthorizationError::PermissionDenied(..)) => {}
+            r => panic!("{:?}", r),
+        }
+        match authorize(&policy, "/a.S/Get", None) {
+            Err(AuthorizationError::Unauthenticated(..)) => {}
+            r => panic!("{:?}", r),
+        }
+    }
+}
@@ -0,0 +1,1166 @@
+//! Distribution of calls between connections to resolved addresses.
+
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::Weak;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::Async;
+use futures::Poll;
+use futures::future;
+use futures::future::Future;
+use futures::stream::Stream;
+
+use httpbis;
+
+use rand;
+use rand::Rng;
+
+use serde_json;
+
+use error::Error;
+use error::ErrorKind;
+use error::GrpcMessageError;
+use experiments::Experiments;
+use futures_grpc::GrpcFuture;
+use grpc::GrpcStatus;
+use load_report::LoadReport;
+use metadata::Metadata;
+use result;
+use stream_item::ItemOrMetadata;
+use timer;
+
+
+/// How calls are distributed between addresses of a host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BalancingPolicy {
+    /// All calls are sent to a single address, which is replaced
+    /// only when it is removed by resolver.
+    PickFirst,
+    /// Calls are sent to all addresses in turn.
+    RoundRobin,
+    /// Calls are sent to addresses in proportion to weights
+    /// supplied by resolver.
+    WeightedRoundRobin,
+    /// Call is sent to one of two random addresses
+    /// which has fewer outstanding calls.
+    LeastRequest,
+    /// Calls are sent to addresses in proportion to their capacity
+    /// computed from load reports sent by `LoadReportInterceptor`,
+    /// queries per second divided by utilization.
+    ///
+    /// Addresses without recent reports get the average weight.
+    /// Selected by service config only with `load_weighted_round_robin` experiment.
+    LoadWeightedRoundRobin,
+    /// Calls with the same value of request metadata key are sent
+    /// to the same address (consistent hashing), so servers can keep
+    /// per-session state. Calls without the key are sent to random address.
+    ///
+    /// When addresses are added or removed, only calls hashed
+    /// to the affected addresses move.
+    RingHash {
+        metadata_key: String,
+    },
+}
+
+impl Default for BalancingPolicy {
+    fn default() -> BalancingPolicy {
+        BalancingPolicy::PickFirst
+    }
+}
+
+impl BalancingPolicy {
+    /// Policy by name used in service config, e. g. `round_robin`.
+    pub fn from_name(name: &str) -> Option<BalancingPolicy> {
+        match name {
+            "pick_first" => Some(BalancingPolicy::PickFirst),
+            "round_robin" => Some(BalancingPolicy::RoundRobin),
+            "weighted_round_robin" => Some(BalancingPolicy::WeightedRoundRobin),
+            "least_request" => Some(BalancingPolicy::LeastRequest),
+            "load_weighted_round_robin" => Some(BalancingPolicy::LoadWeightedRoundRobin),
+            _ => None,
+        }
+    }
+
+    /// Whether policy can be selected by service config.
+    fn is_enabled(&self, experiments: &Experiments) -> bool {
+        match *self {
+            BalancingPolicy::LoadWeightedRoundRobin => experiments.is_enabled("load_weighted_round_robin"),
+            _ => true,
+        }
+    }
+
+    /// Policy from `loadBalancingConfig` entry, e. g. `{"round_robin": {}}`.
+    fn from_config(name: &str, config: &serde_json::Value) -> Option<BalancingPolicy> {
+        match name {
+            "ring_hash_experimental" => {
+                config.get("requestHashHeader")
+                    .and_then(|h| h.as_str())
+                    .map(|h| BalancingPolicy::RingHash { metadata_key: h.to_lowercase() })
+            }
+            name => BalancingPolicy::from_name(name),
+        }
+    }
+
+    /// Policy selected in JSON service config, `None` if config doesn't select policy.
+    ///
+    /// The first supported policy from `loadBalancingConfig` list is used,
+    /// or policy named by deprecated `loadBalancingPolicy` field.
+    /// `RingHash` is selected by `ring_hash_experimental` config
+    /// with `requestHashHeader` field.
+    pub fn from_service_config(json: &str) -> result::Result<Option<BalancingPolicy>> {
+        BalancingPolicy::from_service_config_with(json, &Experiments::resolve(&[]))
+    }
+
+    /// Policy selected in service config, among policies enabled by `experiments`.
+    pub(crate) fn from_service_config_with(json: &str, experiments: &Experiments)
+        -> result::Result<Option<BalancingPolicy>>
+    {
+        let config: serde_json::Value = serde_json::from_str(json)
+            .map_err(|_| Error::Other("invalid service config"))?;
+
+        if let Some(configs) = config.get("loadBalancingConfig").and_then(|c| c.as_array()) {
+            for c in configs {
+                // each config is an object with single key, policy name
+                let policy = c.as_object()
+                    .and_then(|c| c.iter().next())
+                    .and_then(|(name, config)| BalancingPolicy::from_config(name, config))
+                    .filter(|p| p.is_enabled(experiments));
+                if policy.is_some() {
+                    return Ok(policy);
+                }
+            }
+            return Err(Error::Other("no supported policy in loadBalancingConfig"));
+        }
+
+        match config.get("loadBalancingPolicy").and_then(|p| p.as_str()) {
+            Some(name) => {
+                // names are case-insensitive in this field
+                BalancingPolicy::from_name(&name.to_lowercase())
+                    .filter(|p| p.is_enabled(experiments))
+                    .map(Some)
+                    .ok_or(Error::Other("unsupported loadBalancingPolicy"))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+
+/// Address with weight used by `WeightedRoundRobin` policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WeightedAddr {
+    pub addr: SocketAddr,
+    pub weight: u32,
+}
+
+impl WeightedAddr {
+    /// Address with weight 1.
+    pub fn new(addr: SocketAddr) -> WeightedAddr {
+        WeightedAddr {
+            addr: addr,
+            weight: 1,
+        }
+    }
+}
+
+
+/// Passive health checking: addresses failing calls are temporarily
+/// excluded from balancing.
+///
+/// Ejected address is returned to rotation after ejection time.
+/// Ejection time grows when address is ejected again soon after return.
+#[derive(Debug, Clone)]
+pub struct OutlierDetectionConf {
+    /// Eject address after this many consecutive failed calls.
+    pub consecutive_failures: Option<u32>,
+    /// Eject address if percent of successful calls during `interval`
+    /// is lower than this.
+    pub success_rate_percent: Option<u32>,
+    /// Success rate is not checked for address with fewer calls during `interval`.
+    pub success_rate_min_calls: u32,
+    /// How often success rate is checked.
+    pub interval: Duration,
+    pub base_ejection_time: Duration,
+    pub max_ejection_time: Duration,
+    /// Max percent of addresses ejected at the same time.
+    /// At least one address can be ejected.
+    pub max_ejection_percent: u32,
+}
+
+impl Default for OutlierDetectionConf {
+    fn default() -> OutlierDetectionConf {
+        OutlierDetectionConf {
+            consecutive_failures: Some(5),
+            success_rate_percent: None,
+            success_rate_min_calls: 100,
+            interval: Duration::from_secs(10),
+            base_ejection_time: Duration::from_secs(30),
+            max_ejection_time: Duration::from_secs(300),
+            max_ejection_percent: 10,
+        }
+    }
+}
+
+/// Call results of a subchannel.
+#[derive(Default, Debug)]
+struct Health {
+    consecutive_failures: u32,
+    // since last success rate check
+    successes: u32,
+    failures: u32,
+    ejected_until: Option<Instant>,
+    // recent ejections, decremented on each check when not ejected
+    ejections: u32,
+}
+
+impl Health {
+    fn is_ejected(&self, now: Instant) -> bool {
+        match self.ejected_until {
+            Some(until) => until > now,
+            None => false,
+        }
+    }
+
+    fn record(&mut self, failed: bool) {
+        if failed {
+            self.failures += 1;
+            self.consecutive_failures += 1;
+        } else {
+            self.successes += 1;
+            self.consecutive_failures = 0;
+        }
+    }
+
+    fn eject(&mut self, conf: &OutlierDetectionConf, now: Instant) {
+        self.ejections += 1;
+        let time = cmp::min(conf.base_ejection_time * self.ejections, conf.max_ejection_time);
+        self.ejected_until = Some(now + time);
+        self.consecutive_failures = 0;
+        self.successes = 0;
+        self.failures = 0;
+    }
+
+    fn success_rate_too_low(&self, conf: &OutlierDetectionConf) -> bool {
+        let calls = self.successes + self.failures;
+        match conf.success_rate_percent {
+            Some(percent) if calls >= cmp::max(conf.success_rate_min_calls, 1) => {
+                (self.successes as u64) * 100 < (percent as u64) * (calls as u64)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether error counts as failure for outlier detection,
+/// i. e. it likely indicates problem with the server rather than with the request.
+fn is_failure(e: &Error) -> bool {
+    match *e {
+        Error::GrpcMessage(GrpcMessageError { grpc_status, .. }) => {
+            grpc_status == GrpcStatus::Unavailable as i32
+                || grpc_status == GrpcStatus::Internal as i32
+                || grpc_status == GrpcStatus::Unknown as i32
+                || grpc_status == GrpcStatus::DataLoss as i32
+                || grpc_status == GrpcStatus::DeadlineExceeded as i32
+        }
+        Error::Io(..) | Error::Http(..) => true,
+        Error::WithContext(_, ref e) => is_failure(e),
+        _ => false,
+    }
+}
+
+
+/// Connectivity state of a subchannel, as observed by calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectivityState {
+    /// No calls finished yet.
+    Idle,
+    Ready,
+    /// The last call failed with transport error.
+    TransientFailure,
+}
+
+impl fmt::Display for ConnectivityState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ConnectivityState::Idle => "IDLE",
+            ConnectivityState::Ready => "READY",
+            ConnectivityState::TransientFailure => "TRANSIENT_FAILURE",
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Connectivity {
+    state: ConnectivityState,
+    last_error: Option<String>,
+}
+
+/// Snapshot of a connection of a channel, see `Channel::connections`.
+#[derive(Debug, Clone)]
+pub struct ConnectionState {
+    pub addr: SocketAddr,
+    /// `IDLE`, `READY` or `TRANSIENT_FAILURE`
+    pub state: String,
+    /// Calls in progress on this connection
+    pub active_calls: usize,
+    /// Excluded from balancing by outlier detection
+    pub ejected: bool,
+    pub last_error: Option<String>,
+    /// Last load report sent by server
+    pub load_report: Option<LoadReport>,
+}
+
+/// Connection to a single address.
+pub(crate) struct Subchannel {
+    pub addr: SocketAddr,
+    pub client: httpbis::Client,
+    weight: AtomicUsize,
+    outstanding: AtomicUsize,
+    health: Mutex<Health>,
+    connectivity: Mutex<Connectivity>,
+    /// Last load report and when it was received.
+    load: Mutex<Option<(LoadReport, Instant)>>,
+}
+
+impl Subchannel {
+    /// Update connectivity state after call finished with `error`.
+    fn observe(&self, error: Option<&Error>) {
+        let mut connectivity = self.connectivity.lock().unwrap();
+        match error {
+            Some(e) if e.kind() == ErrorKind::Transport => {
+                connectivity.state = ConnectivityState::TransientFailure;
+                connectivity.last_error = Some(format!("{}", e));
+            }
+            _ => connectivity.state = ConnectivityState::Ready,
+        }
+    }
+
+    /// Store load report from trailing metadata of a call.
+    fn observe_load(&self, trailing_metadata: &Metadata) {
+        if let Some(report) = LoadReport::from_metadata(trailing_metadata) {
+            *self.load.lock().unwrap() = Some((report, Instant::now()));
+        }
+    }
+
+    /// Weight computed from recent load report, `None` if there's no such report.
+    fn load_weight(&self, now: Instant) -> Option<f64> {
+        let expiration = Duration::from_secs(LOAD_REPORT_EXPIRATION_SECS);
+        match *self.load.lock().unwrap() {
+            Some((ref report, received)) if now.duration_since(received) < expiration => load_weight(report),
+            _ => None,
+        }
+    }
+
+    /// Add subchannel address, state and last connection error
+    /// to `UNAVAILABLE` errors, so failures can be traced to a backend.
+    ///
+    /// Status errors get it in the message, transport errors
+    /// are wrapped in `Error::WithContext` keeping their kind and source.
+    pub fn describe_error(&self, e: Error) -> Error {
+        match e {
+            Error::Io(..) | Error::Http(..) => {
+                let context = self.context();
+                Error::WithContext(context, Box::new(e))
+            }
+            Error::GrpcMessage(ref m) if m.grpc_status == GrpcStatus::Unavailable as i32 => {
+                Error::GrpcMessage(GrpcMessageError {
+                    grpc_status: m.grpc_status,
+                    grpc_message: format!("{}; {}", m.grpc_message, self.context()),
+                })
+            }
+            e => e,
+        }
+    }
+
+    /// Address, state and last connection error of subchannel.
+    fn context(&self) -> String {
+        let connectivity = self.connectivity.lock().unwrap();
+        let mut context = format!("subchannel: {}, state: {}", self.addr, connectivity.state);
+        if let Some(ref last_error) = connectivity.last_error {
+            context.push_str(&format!(", last connection error: {}", last_error));
+        }
+        if self.health.lock().unwrap().is_ejected(Instant::now()) {
+            context.push_str(", ejected by outlier detection");
+        }
+        context
+    }
+}
+
+/// Outstanding call on a subchannel, counted until dropped.
+pub(crate) struct Outstanding {
+    pub subchannel: Arc<Subchannel>,
+}
+
+impl Outstanding {
+    fn new(subchannel: Arc<Subchannel>) -> Outstanding {
+        subchannel.outstanding.fetch_add(1, Ordering::SeqCst);
+        Outstanding { subchannel: subchannel }
+    }
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        self.subchannel.outstanding.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn establish_timed_out() -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::TimedOut, "connection establishment timed out"))
+}
+
+/// Connect to subchannel, updating its connectivity state.
+///
+/// Connection fails if it's not ready within `timeout`.
+fn connect(s: &Arc<Subchannel>, timeout: Option<Duration>) -> GrpcFuture<()> {
+    let s = s.clone();
+    let connected: GrpcFuture<()> = Box::new(s.client.wait_for_connect().map_err(Error::from));
+    let connected: GrpcFuture<()> = match timeout {
+        Some(timeout) => {
+            let timed_out = timer::sleep(timeout).and_then(|()| Err::<(), _>(establish_timed_out()));
+            Box::new(connected.select(timed_out).map(|(r, _)| r).map_err(|(e, _)| e))
+        }
+        None => connected,
+    };
+    Box::new(connected.then(move |r| {
+        match r {
+            Ok(()) => debug!("connected to {}", s.addr),
+            Err(ref e) => warn!("failed to connect to {}: {:?}", s.addr, e),
+        }
+        s.observe(r.as_ref().err());
+        r.map_err(|e| s.describe_error(e))
+    }))
+}
+
+fn no_addresses() -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::Unavailable as i32,
+        grpc_message: "no addresses to connect to".to_owned(),
+    })
+}
+
+/// Index of address for `n`-th call, each address is picked `weight` times per round.
+///
+/// Addresses with zero weight are not picked, unless all weights are zero.
+fn pick_weighted(weights: &[usize], n: usize) -> usize {
+    let total: usize = weights.iter().sum();
+    if total == 0 {
+        return n % weights.len();
+    }
+    let mut n = n % total;
+    for (i, &w) in weights.iter().enumerate() {
+        if n < w {
+            return i;
+        }
+        n -= w;
+    }
+    unreachable!()
+}
+
+/// Load reports older than this are not used for weighting.
+const LOAD_REPORT_EXPIRATION_SECS: u64 = 180;
+/// Errors per second are counted as this much utilization per query per second.
+const ERROR_UTILIZATION_PENALTY: f64 = 1.0;
+/// Weight of the address with the highest capacity.
+const MAX_LOAD_WEIGHT: f64 = 100.0;
+
+/// Capacity of a backend from its load report, queries per second per unit of utilization.
+fn load_weight(report: &LoadReport) -> Option<f64> {
+    let utilization = if report.application_utilization > 0.0 {
+        report.application_utilization
+    } else {
+        report.cpu_utilization
+    };
+    if report.rps_fractional <= 0.0 || utilization <= 0.0 {
+        return None;
+    }
+    let penalty = report.eps / report.rps_fractional * ERROR_UTILIZATION_PENALTY;
+    Some(report.rps_fractional / (utilization + penalty))
+}
+
+/// Weights for `pick_weighted` from capacities, unknown ones replaced with average.
+fn scale_load_weights(weights: &[Option<f64>]) -> Vec<usize> {
+    let known: Vec<f64> = weights.iter().filter_map(|&w| w).collect();
+    if known.is_empty() {
+        return vec![1; weights.len()];
+    }
+    let mean = known.iter().sum::<f64>() / known.len() as f64;
+    let max = known.iter().cloned().fold(0.0, f64::max);
+    weights.iter()
+        .map(|w| cmp::max((w.unwrap_or(mean) / max * MAX_LOAD_WEIGHT).round() as usize, 1))
+        .collect()
+}
+
+/// Index of address with fewer outstanding calls of two.
+fn pick_least(outstanding: &[usize], a: usize, b: usize) -> usize {
+    if outstanding[b] < outstanding[a] { b } else { a }
+}
+
+/// Points on the ring per unit of address weight.
+const RING_POINTS_PER_WEIGHT: usize = 100;
+const RING_MAX_POINTS_PER_ADDR: usize = 10000;
+
+/// FNV-1a with MurmurHash3 finalizer to spread similar keys over the ring.
+///
+/// Stable across processes unlike `DefaultHasher`,
+/// so all clients map keys to the same addresses.
+fn ring_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^= hash >> 33;
+    hash
+}
+
+/// Sorted points of addresses on the hash ring, as `(hash, address index)`.
+fn build_ring(addrs: &[(SocketAddr, usize)]) -> Vec<(u64, usize)> {
+    let mut ring = Vec::new();
+    for (i, &(addr, weight)) in addrs.iter().enumerate() {
+        let points = cmp::min(cmp::max(weight, 1) * RING_POINTS_PER_WEIGHT, RING_MAX_POINTS_PER_ADDR);
+        for point in 0..points {
+            ring.push((ring_hash(format!("{}_{}", addr, point).as_bytes()), i));
+        }
+    }
+    ring.sort();
+    ring
+}
+
+/// Index of the first address clockwise from `hash` which is `available`.
+fn ring_owner<F>(ring: &[(u64, usize)], hash: u64, available: F) -> Option<usize>
+    where F : Fn(usize) -> bool
+{
+    let start = match ring.binary_search_by_key(&hash, |&(h, _)| h) {
+        Ok(i) | Err(i) => i,
+    };
+    ring[start..].iter().chain(ring[..start].iter())
+        .map(|&(_, i)| i)
+        .find(|&i| available(i))
+}
+
+/// Opens HTTP/2 connection to address.
+pub(crate) type Connector = Fn(&SocketAddr) -> result::Result<httpbis::Client> + Send + Sync;
+
+/// Starts resolution of channel host in background, updating balancer addresses.
+pub(crate) type ReResolve = Fn() + Send + Sync;
+
+struct ReResolution {
+    resolve: Box<ReResolve>,
+    min_interval: Duration,
+    last: Option<Instant>,
+}
+
+pub(crate) struct Balancer {
+    policy: BalancingPolicy,
+    outlier_detection: Option<OutlierDetectionConf>,
+    /// Limit of waiting for connection in `warm_up` and `connect_any`.
+    establish_timeout: Option<Duration>,
+    connector: Box<Connector>,
+    subchannels: RwLock<Vec<Arc<Subchannel>>>,
+    /// Built by `update` for `RingHash` policy.
+    ring: RwLock<Vec<(u64, usize)>>,
+    next: AtomicUsize,
+    last_success_rate_check: Mutex<Instant>,
+    re_resolution: Mutex<Option<ReResolution>>,
+    shut_down: AtomicBool,
+}
+
+impl Balancer {
+    pub fn new(
+        policy: BalancingPolicy,
+        outlier_detection: Option<OutlierDetectionConf>,
+        establish_timeout: Option<Duration>,
+        connector: Box<Connector>,
+        addrs: Vec<WeightedAddr>)
+        -> result::Result<Balancer>
+    {
+        let balancer = Balancer {
+            policy: policy,
+            outlier_detection: outlier_detection,
+            establish_timeout: establish_timeout,
+            connector: connector,
+            subchannels: RwLock::new(Vec::new()),
+            ring: RwLock::new(Vec::new()),
+            next: AtomicUsize::new(0),
+            last_success_rate_check: Mutex::new(Instant::now()),
+            re_resolution: Mutex::new(None),
+            shut_down: AtomicBool::new(false),
+        };
+        balancer.update(addrs)?;
+        Ok(balancer)
+    }
+
+    /// Replace the set of addresses.
+    ///
+    /// Connections to addresses which are still present are kept.
+    /// Addresses with zero weight are excluded. Addresses which cannot
+    /// be connected are skipped; if none can, addresses are not replaced
+    /// and the first error is returned.
+    pub fn update(&self, addrs: Vec<WeightedAddr>) -> result::Result<()> {
+        let addrs: Vec<_> = addrs.into_iter()
+            .filter(|a| {
+                if a.weight == 0 {
+                    debug!("excluding {} with zero weight", a.addr);
+                }
+                a.weight != 0
+            })
+            .collect();
+
+        let current = self.subchannels.read().unwrap().clone();
+
+        let addrs = match self.policy {
+            BalancingPolicy::PickFirst => {
+                let current = current.first().map(|s| s.addr);
+                match current.and_then(|c| addrs.iter().find(|a| a.addr == c).cloned()) {
+                    Some(current) => vec![current],
+                    None => addrs.into_iter().take(1).collect(),
+                }
+            }
+            _ => addrs,
+        };
+
+        // connector may block, so it is not run under lock
+        let mut connected = Vec::new();
+        let mut first_error = None;
+        for a in &addrs {
+            if current.iter().any(|s| s.addr == a.addr) {
+                continue;
+            }
+            debug!("connecting to {}", a.addr);
+            match (self.connector)(&a.addr) {
+                Ok(client) => connected.push(Arc::new(Subchannel {
+                    addr: a.addr,
+                    client: client,
+                    weight: AtomicUsize::new(a.weight as usize),
+                    outstanding: AtomicUsize::new(0),
+                    health: Mutex::new(Health::default()),
+                    connectivity: Mutex::new(Connectivity {
+                        state: ConnectivityState::Idle,
+                        last_error: None,
+                    }),
+                    load: Mutex::new(None),
+                })),
+                Err(e) => {
+                    warn!("failed to connect to {}: {:?}", a.addr, e);
+                    if first_error.is_none() {
+                        first_error = Some(e);
+                    }
+                }
+            }
+        }
+
+        let mut subchannels = self.subchannels.write().unwrap();
+        if self.is_shut_down() {
+            return Ok(());
+        }
+
+        // subchannels may have been replaced by concurrent update
+        let mut updated = Vec::with_capacity(addrs.len());
+        for a in addrs {
+            let existing = subchannels.iter().chain(connected.iter()).find(|s| s.addr == a.addr);
+            if let Some(s) = existing {
+                s.weight.store(a.weight as usize, Ordering::Relaxed);
+                updated.push(s.clone());
+            }
+        }
+
+        if updated.is_empty() {
+            if let Some(e) = first_error {
+                return Err(e);
+            }
+        }
+
+        if let BalancingPolicy::RingHash { .. } = self.policy {
+            let addrs: Vec<_> = updated.iter()
+                .map(|s| (s.addr, s.weight.load(Ordering::Relaxed)))
+                .collect();
+            *self.ring.write().unwrap() = build_ring(&addrs);
+        }
+
+        let removed = mem::replace(&mut *subchannels, updated);
+        // drop clients outside of lock
+        drop(subchannels);
+        drop(removed);
+        Ok(())
+    }
+
+    /// Close connections and ignore further address updates.
+    ///
+    /// Connections used by calls in progress are closed
+    /// when these calls are dropped.
+    pub fn shutdown(&self) {
+        // release resolver and its threads, outside of lock
+        let re_resolution = self.re_resolution.lock().unwrap().take();
+        drop(re_resolution);
+        let subchannels = {
+            let mut subchannels = self.subchannels.write().unwrap();
+            self.shut_down.store(true, Ordering::SeqCst);
+            self.ring.write().unwrap().clear();
+            mem::replace(&mut *subchannels, Vec::new())
+        };
+        // drop clients outside of lock
+        drop(subchannels);
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Connect to all current addresses.
+    ///
+    /// Future fails with the first connection error.
+    pub fn warm_up(&self) -> GrpcFuture<()> {
+        let connects: Vec<_> = self.subchannels.read().unwrap().iter()
+            .map(|s| connect(s, self.establish_timeout))
+            .collect();
+        Box::new(future::join_all(connects).map(|_| ()))
+    }
+
+    /// Connect to any of current addresses.
+    ///
+    /// Future fails with the last connection error if none can be connected.
+    pub fn connect_any(&self) -> GrpcFuture<()> {
+        let connects: Vec<_> = self.subchannels.read().unwrap().iter()
+            .map(|s| connect(s, self.establish_timeout))
+            .collect();
+        if connects.is_empty() {
+            return Box::new(future::err(no_addresses()));
+        }
+        Box::new(future::select_ok(connects).map(|_| ()))
+    }
+
+    /// Select connection for a call with given request metadata.
+    pub fn pick(&self, metadata: &Metadata) -> result::Result<Outstanding> {
+        let subchannels = self.subchannels.read().unwrap();
+        if subchannels.is_empty() {
+            return Err(no_addresses());
+        }
+
+        let now = Instant::now();
+        let is_ejected = |s: &Subchannel| {
+            self.outlier_detection.is_some() && s.health.lock().unwrap().is_ejected(now)
+        };
+
+        if let BalancingPolicy::RingHash { ref metadata_key } = self.policy {
+            if let Some(value) = metadata.get(metadata_key) {
+                let ring = self.ring.read().unwrap();
+                // skip ejected addresses, keeping keys of other addresses in place
+                let i = ring_owner(&ring, ring_hash(value), |i| !is_ejected(&subchannels[i]))
+                    .unwrap_or(ring[0].1);
+                return Ok(Outstanding::new(subchannels[i].clone()));
+            }
+        }
+
+        let mut candidates: Vec<&Arc<Subchannel>> = Vec::with_capacity(subchannels.len());
+        candidates.extend(subchannels.iter().filter(|s| !is_ejected(s)));
+        // if all addresses are ejected, use all of them
+        if candidates.is_empty() {
+            candidates.extend(subchannels.iter());
+        }
+
+        let i = match self.policy {
+            BalancingPolicy::PickFirst | BalancingPolicy::RoundRobin => {
+                self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()
+            }
+            BalancingPolicy::WeightedRoundRobin => {
+                let weights: Vec<_> = candidates.iter()
+                    .map(|s| s.weight.load(Ordering::Relaxed))
+                    .collect();
+                pick_weighted(&weights, self.next.fetch_add(1, Ordering::Relaxed))
+            }
+            BalancingPolicy::LoadWeightedRoundRobin => {
+                let weights: Vec<_> = candidates.iter()
+                    .map(|s| s.load_weight(now))
+                    .collect();
+                pick_weighted(&scale_load_weights(&weights), self.next.fetch_add(1, Ordering::Relaxed))
+            }
+            BalancingPolicy::LeastRequest => {
+                let outstanding: Vec<_> = candidates.iter()
+                    .map(|s| s.outstanding.load(Ordering::SeqCst))
+                    .collect();
+                let mut rng = rand::thread_rng();
+                let a = rng.gen_range(0, candidates.len());
+                let b = rng.gen_range(0, candidates.len());
+                pick_least(&outstanding, a, b)
+            }
+            BalancingPolicy::RingHash { .. } => {
+                rand::thread_rng().gen_range(0, candidates.len())
+            }
+        };
+
+        Ok(Outstanding::new(candidates[i].clone()))
+    }
+
+    /// State of all connections.
+    pub fn connections(&self) -> Vec<ConnectionState> {
+        let now = Instant::now();
+        self.subchannels.read().unwrap().iter()
+            .map(|s| {
+                let connectivity = s.connectivity.lock().unwrap();
+                ConnectionState {
+                    addr: s.addr,
+                    state: connectivity.state.to_string(),
+                    active_calls: s.outstanding.load(Ordering::SeqCst),
+                    ejected: self.outlier_detection.is_some()
+                        && s.health.lock().unwrap().is_ejected(now),
+                    last_error: connectivity.last_error.clone(),
+                    load_report: s.load.lock().unwrap().as_ref().map(|&(ref r, _)| r.clone()),
+                }
+            })
+            .collect()
+    }
+
+    /// Resolve addresses again with `resolve` when all addresses fail,
+    /// but not more often than `min_interval`.
+    pub fn set_re_resolve(&self, resolve: Box<ReResolve>, min_interval: Duration) {
+        *self.re_resolution.lock().unwrap() = Some(ReResolution {
+            resolve: resolve,
+            min_interval: min_interval,
+            last: None,
+        });
+    }
+
+    fn all_failed(&self) -> bool {
+        self.subchannels.read().unwrap().iter().all(|s| {
+            s.connectivity.lock().unwrap().state == ConnectivityState::TransientFailure
+        })
+    }
+
+    /// Resolve host again, unless it was resolved recently.
+    ///
+    /// Only triggered when all addresses failed: GOAWAY is handled
+    /// inside `httpbis`, which doesn't report it to the gRPC layer.
+    fn maybe_re_resolve(&self) {
+        let mut re_resolution = self.re_resolution.lock().unwrap();
+        let re_resolution = match *re_resolution {
+            Some(ref mut re_resolution) => re_resolution,
+            None => return,
+        };
+        let now = Instant::now();
+        if let Some(last) = re_resolution.last {
+            if now.duration_since(last) < re_resolution.min_interval {
+                return;
+            }
+        }
+        re_resolution.last = Some(now);
+        info!("all addresses failed, resolving again");
+        (re_resolution.resolve)();
+    }
+
+    /// Record result of a call for outlier detection and re-resolution.
+    pub fn record(&self, subchannel: &Subchannel, error: Option<&Error>) {
+        subchannel.observe(error);
+        let failed = error.map(is_failure).unwrap_or(false);
+
+        if error.map(|e| e.kind() == ErrorKind::Transport).unwrap_or(false) && self.all_failed() {
+            self.maybe_re_resolve();
+        }
+
+        let conf = match self.outlier_detection {
+            Some(ref conf) => conf,
+            None => return,
+        };
+        let now = Instant::now();
+
+        let subchannels = self.subchannels.read().unwrap();
+        let max_ejected = cmp::max(1, subchannels.len() * conf.max_ejection_percent as usize / 100);
+        let mut ejected = subchannels.iter()
+            .filter(|s| s.health.lock().unwrap().is_ejected(now))
+            .count();
+
+        {
+            let mut health = subchannel.health.lock().unwrap();
+            health.record(failed);
+            if let Some(limit) = conf.consecutive_failures {
+                if health.consecutive_failures >= limit && !health.is_ejected(now) && ejected < max_ejected {
+                    warn!("ejecting {} after {} consecutive failures", subchannel.addr, limit);
+                    health.eject(conf, now);
+                    ejected += 1;
+                }
+            }
+        }
+
+        {
+            let mut last = self.last_success_rate_check.lock().unwrap();
+            if now.duration_since(*last) < conf.interval {
+                return;
+            }
+            *last = now;
+        }
+
+        for s in subchannels.iter() {
+            let mut health = s.health.lock().unwrap();
+            if health.is_ejected(now) {
+                continue;
+            }
+            if health.success_rate_too_low(conf) && ejected < max_ejected {
+                warn!("ejecting {} because of low success rate", s.addr);
+                health.eject(conf, now);
+                ejected += 1;
+            } else {
+                health.ejections = health.ejections.saturating_sub(1);
+                health.successes = 0;
+                health.failures = 0;
+            }
+        }
+    }
+}
+
+/// Records result of call and load report when response stream ends.
+pub(crate) struct RecordResult<S> {
+    pub stream: S,
+    pub balancer: Arc<Balancer>,
+    pub call: Outstanding,
+}
+
+impl<S, T> Stream for RecordResult<S>
+    where
+        S : Stream<Item=ItemOrMetadata<T>, Error=Error>,
+        T : Send + 'static,
+{
+    type Item = ItemOrMetadata<T>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ItemOrMetadata<T>>, Error> {
+        match self.stream.poll() {
+            Ok(Async::Ready(Some(ItemOrMetadata::TrailingMetadata(metadata)))) => {
+                self.call.subchannel.observe_load(&metadata);
+                Ok(Async::Ready(Some(ItemOrMetadata::TrailingMetadata(metadata))))
+            }
+            Ok(Async::Ready(None)) => {
+                self.balancer.record(&self.call.subchannel, None);
+                Ok(Async::Ready(None))
+            }
+            Err(e) => {
+                self.balancer.record(&self.call.subchannel, Some(&e));
+                Err(self.call.subchannel.describe_error(e))
+            }
+            r => r,
+        }
+    }
+}
+
+
+/// Receiver of address updates from `Resolver::watch`.
+#[derive(Clone)]
+pub struct AddressUpdates {
+    balancer: Weak<Balancer>,
+}
+
+impl AddressUpdates {
+    pub(crate) fn new(balancer: &Arc<Balancer>) -> AddressUpdates {
+        AddressUpdates {
+            balancer: Arc::downgrade(balancer),
+        }
+    }
+
+    /// Channel no longer exists.
+    pub fn is_closed(&self) -> bool {
+        match self.balancer.upgrade() {
+            Some(balancer) => balancer.is_shut_down(),
+            None => true,
+        }
+    }
+
+    /// Replace addresses used by the channel.
+    ///
+    /// Returns `false` if the channel no longer exists,
+    /// so the resolver should stop watching.
+    pub fn update(&self, addrs: Vec<SocketAddr>) -> bool {
+        self.update_weighted(addrs.into_iter().map(WeightedAddr::new).collect())
+    }
+
+    /// Replace addresses and their weights.
+    pub fn update_weighted(&self, addrs: Vec<WeightedAddr>) -> bool {
+        match self.balancer.upgrade() {
+            Some(balancer) => {
+                if let Err(e) = balancer.update(addrs) {
+                    warn!("failed to update addresses: {:?}", e);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tls_api_stub;
+
+    #[test]
+    fn weighted() {
+        let picks: Vec<_> = (0..7).map(|n| pick_weighted(&[1, 3, 0], n)).collect();
+        assert_eq!(vec![0, 1, 1, 1, 0, 1, 1], picks);
+        assert_eq!(1, pick_weighted(&[0, 0], 3));
+    }
+
+    #[test]
+    fn update_skips_failed_and_zero_weight() {
+        let ok: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let failing: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let zero: SocketAddr = "127.0.0.1:3".parse().unwrap();
+        let connector = move |addr: &SocketAddr| {
+            if *addr == failing {
+                return Err(Error::Other("connect failed"));
+            }
+            let tls: httpbis::ClientTlsOption<tls_api_stub::TlsConnector> = httpbis::ClientTlsOption::Plain;
+            httpbis::Client::new_expl(addr, tls, Default::default()).map_err(Error::from)
+        };
+        let addrs = vec![
+            WeightedAddr::new(ok),
+            WeightedAddr::new(failing),
+            WeightedAddr { addr: zero, weight: 0 },
+        ];
+        let balancer = Balancer::new(
+            BalancingPolicy::WeightedRoundRobin, None, None, Box::new(connector), addrs)
+            .expect("balancer");
+        let addrs: Vec<_> = balancer.connections().iter().map(|c| c.addr).collect();
+        assert_eq!(vec![ok], addrs);
+
+        assert!(balancer.update(vec![WeightedAddr::new(failing)]).is_err());
+        let addrs: Vec<_> = balancer.connections().iter().map(|c| c.addr).collect();
+        assert_eq!(vec![ok], addrs);
+    }
+
+    #[test]
+    fn load_weights() {
+        let mut report = LoadReport::default();
+        assert_eq!(None, load_weight(&report));
+        report.rps_fractional = 100.0;
+        report.cpu_utilization = 0.5;
+        assert_eq!(Some(200.0), load_weight(&report));
+        report.eps = 50.0;
+        assert_eq!(Some(100.0), load_weight(&report));
+        report.eps = 0.0;
+        report.application_utilization = 0.25;
+        assert_eq!(Some(400.0), load_weight(&report));
+
+        assert_eq!(vec![1, 1], scale_load_weights(&[None, None]));
+        assert_eq!(vec![100, 25, 63], scale_load_weights(&[Some(400.0), Some(100.0), None]));
+    }
+
+    #[test]
+    fn least() {
+        assert_eq!(1, pick_least(&[3, 1, 2], 0, 1));
+        assert_eq!(2, pick_least(&[3, 1, 2], 2, 0));
+        assert_eq!(0, pick_least(&[1, 1], 0, 1));
+    }
+
+    #[test]
+    fn service_config() {
+        let policy = |json| BalancingPolicy::from_service_config(json).unwrap();
+        assert_eq!(None, policy("{}"));
+        assert_eq!(
+            Some(BalancingPolicy::LeastRequest),
+            policy(r#"{"loadBalancingConfig": [{"unknown": {}}, {"least_request": {}}]}"#));
+        assert_eq!(
+            Some(BalancingPolicy::RoundRobin),
+            policy(r#"{"loadBalancingPolicy": "ROUND_ROBIN"}"#));
+        assert_eq!(
+            Some(BalancingPolicy::RingHash { metadata_key: "session-id".to_owned() }),
+            policy(r#"{"loadBalancingConfig": [{"ring_hash_experimental": {"requestHashHeader": "Session-Id"}}]}"#));
+        assert!(BalancingPolicy::from_service_config(r#"{"loadBalancingConfig": [{"x": {}}]}"#).is_err());
+    }
+
+    #[test]
+    fn service_config_experiment() {
+        let json = r#"{"loadBalancingConfig": [{"load_weighted_round_robin": {}}, {"round_robin": {}}]}"#;
+        let policy = |experiments: &[&str]| {
+            let experiments: Vec<_> = experiments.iter().map(|e| e.to_string()).collect();
+            BalancingPolicy::from_service_config_with(json, &Experiments::resolve(&experiments)).unwrap()
+        };
+        assert_eq!(Some(BalancingPolicy::RoundRobin), policy(&["-load_weighted_round_robin"]));
+        assert_eq!(Some(BalancingPolicy::LoadWeightedRoundRobin), policy(&["load_weighted_round_robin"]));
+    }
+
+    #[test]
+    fn ring() {
+        let addrs: Vec<(SocketAddr, usize)> = (1..5)
+            .map(|i| (format!("10.0.0.{}:1", i).parse().unwrap(), 1))
+            .collect();
+        let ring = build_ring(&addrs);
+        assert_eq!(4 * RING_POINTS_PER_WEIGHT, ring.len());
+
+        let owner = |ring: &[(u64, usize)], key: u32| {
+            ring_owner(ring, ring_hash(format!("session-{}", key).as_bytes()), |_| true).unwrap()
+        };
+
+        // keys are spread over all addresses
+        let mut counts = vec![0; addrs.len()];
+        for key in 0..1000 {
+            counts[owner(&ring, key)] += 1;
+        }
+        assert!(counts.iter().all(|&c| c > 100), "{:?}", counts);
+
+        // removing an address moves only its keys
+        let ring_without_last = build_ring(&addrs[..3]);
+        for key in 0..1000 {
+            let before = owner(&ring, key);
+            if before != 3 {
+                assert_eq!(before, owner(&ring_without_last, key));
+            }
+        }
+    }
+
+    #[test]
+    fn ejection_time_grows() {
+        let conf = OutlierDetectionConf::default();
+        let now = Instant::now();
+        let mut health = Health::default();
+
+        health.eject(&conf, now);
+        assert!(health.is_ejected(now + conf.base_ejection_time - Duration::from_secs(1)));
+        assert!(!health.is_ejected(now + conf.base_ejection_time));
+
+        health.eject(&conf, now);
+        assert!(health.is_ejected(now + conf.base_ejection_time));
+
+        for _ in 0..20 {
+            health.eject(&conf, now);
+        }
+        assert!(!health.is_ejected(now + conf.max_ejection_time));
+    }
+
+    #[test]
+    fn success_rate() {
+        let conf = OutlierDetectionConf {
+            success_rate_percent: Some(80),
+            success_rate_min_calls: 10,
+            ..Default::default()
+        };
+        let mut health = Health::default();
+        for i in 0..9 {
+            health.record(i % 2 == 0);
+        }
+        // too few calls
+        assert!(!health.success_rate_too_low(&conf));
+        health.record(false);
+        assert!(health.success_rate_too_low(&conf));
+
+        let mut health = Health::default();
+        for i in 0..10 {
+            health.record(i < 2);
+        }
+        assert!(!health.success_rate_too_low(&conf));
+        assert_eq!(0, health.consecutive_failures);
+    }
+}
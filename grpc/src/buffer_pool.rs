@@ -0,0 +1,169 @@
+//! Pool of buffers frames are written into.
+//!
+//! Frames are split off large buffers, so many small frames
+//! share one allocation, which is freed when all of them are dropped.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use bytes::Bytes;
+use bytes::BytesMut;
+
+
+/// Counters of `BufferPool`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Frames written into existing buffer.
+    pub hits: u64,
+    /// Frames which needed a new allocation.
+    pub misses: u64,
+}
+
+impl BufferPoolStats {
+    /// Fraction of frames written without allocation, 0 if there were no frames.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+/// Buffers sent and received frames are written into,
+/// shared by calls of clients and servers configured with it.
+pub struct BufferPool {
+    buffer_size: usize,
+    max_buffers: usize,
+    free: Mutex<Vec<BytesMut>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("buffer_size", &self.buffer_size)
+            .field("max_buffers", &self.max_buffers)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl Default for BufferPool {
+    /// 16 buffers of 64KiB.
+    fn default() -> BufferPool {
+        BufferPool::new(65536, 16)
+    }
+}
+
+impl BufferPool {
+    /// Pool keeping up to `max_buffers` partially used buffers of `buffer_size` bytes.
+    /// Frames larger than `buffer_size` are allocated individually.
+    pub fn new(buffer_size: usize, max_buffers: usize) -> BufferPool {
+        BufferPool {
+            buffer_size: buffer_size,
+            max_buffers: max_buffers,
+            free: Mutex::new(Vec::new()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            hits: self.hits.load(Ordering::Relaxed) as u64,
+            misses: self.misses.load(Ordering::Relaxed) as u64,
+        }
+    }
+
+    /// Frame of `len` bytes written by `write`.
+    pub(crate) fn frame<F : FnOnce(&mut BytesMut)>(&self, len: usize, write: F) -> Bytes {
+        if len > self.buffer_size {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            let mut buf = BytesMut::with_capacity(len);
+            write(&mut buf);
+            return buf.freeze();
+        }
+
+        let mut buf = match self.free.lock().unwrap().pop() {
+            Some(buf) if buf.capacity() >= len => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(self.buffer_size)
+            }
+        };
+        write(&mut buf);
+        let written = buf.len();
+        let frame = buf.split_to(written).freeze();
+
+        if buf.capacity() > 0 {
+            let mut free = self.free.lock().unwrap();
+            if free.len() < self.max_buffers {
+                free.push(buf);
+            }
+        }
+
+        frame
+    }
+
+    /// Concatenation of `a` and `b`.
+    pub(crate) fn concat(&self, a: &[u8], b: &[u8]) -> Bytes {
+        self.frame(a.len() + b.len(), |buf| {
+            buf.extend_from_slice(a);
+            buf.extend_from_slice(b);
+        })
+    }
+}
+
+/// Append received `data` to buffered incomplete frame.
+pub(crate) fn append(pool: &Option<Arc<BufferPool>>, buf: &mut Bytes, data: Bytes) {
+    if buf.is_empty() {
+        *buf = data;
+        return;
+    }
+    match *pool {
+        Some(ref pool) => *buf = pool.concat(buf, &data),
+        None => buf.extend_from_slice(&data),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames_share_buffer() {
+        let pool = BufferPool::new(64, 1);
+
+        let a = pool.concat(&[1; 30], &[2; 10]);
+        let b = pool.concat(&[3; 10], b"");
+        let c = pool.concat(&[4; 20], b"");
+        assert_eq!(40, a.len());
+        assert_eq!(&[3u8; 10][..], &b[..]);
+        assert_eq!(&[4u8; 20][..], &c[..]);
+        assert_eq!(BufferPoolStats { hits: 1, misses: 2 }, pool.stats());
+
+        // larger than buffer
+        assert_eq!(65, pool.concat(&[5; 64], b"a").len());
+        assert_eq!(BufferPoolStats { hits: 1, misses: 3 }, pool.stats());
+        assert_eq!(0.25, pool.stats().hit_rate());
+    }
+
+    #[test]
+    fn append_to_empty() {
+        let pool = Some(Arc::new(BufferPool::new(64, 1)));
+        let mut buf = Bytes::new();
+        append(&pool, &mut buf, Bytes::from_static(b"ab"));
+        assert_eq!(BufferPoolStats::default(), pool.as_ref().unwrap().stats());
+        append(&pool, &mut buf, Bytes::from_static(b"cd"));
+        assert_eq!(&b"abcd"[..], &buf[..]);
+        assert_eq!(BufferPoolStats { hits: 0, misses: 1 }, pool.as_ref().unwrap().stats());
+    }
+}
@@ -0,0 +1,297 @@
+//! Client-side response cache.
+//!
+//! Responses of methods declared with `idempotency_level = NO_SIDE_EFFECTS`,
+//! or called with such `RequestOptions::idempotency_level`, are cached when server allows it with `cache-control: max-age=N`
+//! response metadata, similarly to HTTP GET responses.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::str;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use futures::future;
+use futures::future::Future;
+use futures::stream;
+use futures::stream::Stream;
+
+use futures_grpc::GrpcFuture;
+use interceptor::*;
+use metadata::Metadata;
+use method::IdempotencyLevel;
+use req::*;
+use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+
+
+/// Metadata key of response caching policy.
+pub const CACHE_CONTROL: &'static str = "cache-control";
+
+/// Larger `max-age` sent by server is reduced to this.
+fn max_max_age() -> Duration {
+    Duration::from_secs(365 * 24 * 3600)
+}
+
+/// Cached call: method name and serialized request messages.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub method: String,
+    pub request: Vec<Bytes>,
+}
+
+/// Storage of cached responses.
+pub trait ResponseCache : Send + Sync + 'static {
+    /// Serialized response messages, `None` if not cached or expired.
+    fn get(&self, key: &CacheKey) -> Option<Vec<Bytes>>;
+
+    /// Store response messages for `ttl`.
+    fn put(&self, key: CacheKey, response: Vec<Bytes>, ttl: Duration);
+}
+
+
+struct LruEntry {
+    response: Vec<Bytes>,
+    expires: Instant,
+    last_used: u64,
+}
+
+struct LruState {
+    entries: HashMap<CacheKey, LruEntry>,
+    // incremented on each access
+    clock: u64,
+}
+
+/// In-memory cache which evicts least recently used entries
+/// when it holds more than `capacity` responses.
+pub struct LruResponseCache {
+    capacity: usize,
+    state: Mutex<LruState>,
+}
+
+impl LruResponseCache {
+    pub fn new(capacity: usize) -> LruResponseCache {
+        LruResponseCache {
+            capacity: capacity,
+            state: Mutex::new(LruState {
+                entries: HashMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    /// Number of cached responses, including expired but not yet evicted.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ResponseCache for LruResponseCache {
+    fn get(&self, key: &CacheKey) -> Option<Vec<Bytes>> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+
+        if let Some(entry) = state.entries.get_mut(key) {
+            if entry.expires > Instant::now() {
+                entry.last_used = clock;
+                return Some(entry.response.clone());
+            }
+        }
+        // expired
+        state.entries.remove(key);
+        None
+    }
+
+    fn put(&self, key: CacheKey, response: Vec<Bytes>, ttl: Duration) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let expires = match now.checked_add(ttl) {
+            Some(expires) => expires,
+            None => return,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+
+        state.entries.insert(key, LruEntry {
+            response: response,
+            expires: expires,
+            last_used: clock,
+        });
+
+        if state.entries.len() > self.capacity {
+            state.entries.retain(|_, e| e.expires > now);
+        }
+        while state.entries.len() > self.capacity {
+            let lru = state.entries.iter()
+                .min_by_key(|&(_, e)| e.last_used)
+                .map(|(k, _)| k.clone())
+                .expect("not empty");
+            state.entries.remove(&lru);
+        }
+    }
+}
+
+
+/// Time to live from `cache-control` metadata,
+/// `None` if response must not be cached.
+fn max_age(metadata: &Metadata) -> Option<Duration> {
+    let value = match metadata.get(CACHE_CONTROL).map(str::from_utf8) {
+        Some(Ok(value)) => value,
+        _ => return None,
+    };
+
+    let mut r = None;
+    for directive in value.split(',').map(|d| d.trim().to_lowercase()) {
+        if directive == "no-store" || directive == "no-cache" {
+            return None;
+        }
+        if directive.starts_with("max-age=") {
+            match directive["max-age=".len()..].parse::<u64>() {
+                Ok(0) | Err(_) => return None,
+                Ok(seconds) => r = Some(cmp::min(Duration::from_secs(seconds), max_max_age())),
+            }
+        }
+    }
+    r
+}
+
+/// Client interceptor which serves repeated calls from the cache.
+///
+/// Only methods without side effects are cached. Cached responses
+/// are returned without initial and trailing metadata.
+pub struct CachingInterceptor {
+    cache: Arc<ResponseCache>,
+}
+
+impl CachingInterceptor {
+    pub fn new(cache: Arc<ResponseCache>) -> CachingInterceptor {
+        CachingInterceptor { cache: cache }
+    }
+}
+
+impl ClientInterceptor for CachingInterceptor {
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>
+    {
+        let idempotency_level = o.idempotency_level
+            .unwrap_or(next.method_options().idempotency_level);
+        if idempotency_level != IdempotencyLevel::NoSideEffects {
+            return next.call(o, req);
+        }
+
+        let cache = self.cache.clone();
+        let method = method.to_owned();
+
+        // request must be complete to compute the key
+        StreamingResponse::new(req.0.collect().and_then(move |request| {
+            let key = CacheKey { method: method, request: request };
+
+            if let Some(response) = cache.get(&key) {
+                debug!("cached response for {}", key.method);
+                return Box::new(future::ok(
+                    (Metadata::new(), GrpcStreamWithTrailingMetadata::iter(response))))
+                        as GrpcFuture<_>;
+            }
+
+            let resp = next.call(o, StreamingRequest::iter(key.request.clone()));
+            Box::new(resp.0.and_then(move |(metadata, stream)| {
+                let ttl = match max_age(&metadata) {
+                    Some(ttl) => ttl,
+                    None => return Box::new(future::ok((metadata, stream))) as GrpcFuture<_>,
+                };
+
+                // only successfully completed responses are stored
+                Box::new(stream.collect_with_metadata().map(move |(response, trailing)| {
+                    cache.put(key, response.clone(), ttl);
+                    let stream = GrpcStreamWithTrailingMetadata::stream_with_trailing_metadata(
+                        stream::iter_ok(response), future::ok(trailing));
+                    (metadata, stream)
+                }))
+            }))
+        }))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+
+    use metadata::MetadataKey;
+
+    fn key(request: &'static [u8]) -> CacheKey {
+        CacheKey {
+            method: "/test/Get".to_owned(),
+            request: vec![Bytes::from_static(request)],
+        }
+    }
+
+    fn cache_control(value: &'static str) -> Metadata {
+        let mut metadata = Metadata::new();
+        metadata.add(MetadataKey::from(CACHE_CONTROL), Bytes::from_static(value.as_bytes()));
+        metadata
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let cache = LruResponseCache::new(2);
+        let ttl = Duration::from_secs(100);
+        cache.put(key(b"a"), vec![Bytes::from_static(b"1")], ttl);
+        cache.put(key(b"b"), vec![Bytes::from_static(b"2")], ttl);
+        assert!(cache.get(&key(b"a")).is_some());
+        cache.put(key(b"c"), vec![Bytes::from_static(b"3")], ttl);
+
+        assert_eq!(2, cache.len());
+        assert_eq!(Some(vec![Bytes::from_static(b"1")]), cache.get(&key(b"a")));
+        assert_eq!(None, cache.get(&key(b"b")));
+        assert_eq!(Some(vec![Bytes::from_static(b"3")]), cache.get(&key(b"c")));
+    }
+
+    #[test]
+    fn lru_expires() {
+        let cache = LruResponseCache::new(2);
+        cache.put(key(b"a"), vec![Bytes::from_static(b"1")], Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(None, cache.get(&key(b"a")));
+        assert_eq!(0, cache.len());
+    }
+
+    #[test]
+    fn parse_max_age() {
+        assert_eq!(Some(Duration::from_secs(60)), max_age(&cache_control("max-age=60")));
+        assert_eq!(Some(Duration::from_secs(5)), max_age(&cache_control("public, Max-Age=5")));
+        assert_eq!(None, max_age(&cache_control("max-age=60, no-store")));
+        assert_eq!(None, max_age(&cache_control("max-age=0")));
+        assert_eq!(None, max_age(&cache_control("max-age=x")));
+        assert_eq!(None, max_age(&cache_control("public")));
+        assert_eq!(None, max_age(&Metadata::new()));
+        assert_eq!(Some(max_max_age()), max_age(&cache_control("max-age=18446744073709551615")));
+    }
+
+    #[test]
+    fn lru_ttl_overflow() {
+        let cache = LruResponseCache::new(2);
+        cache.put(key(b"a"), vec![Bytes::from_static(b"1")], Duration::from_secs(u64::MAX));
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+//! Per-call timing and size statistics collected by client.
+//!
+//! `httpbis` does not report HTTP/2 flow control acknowledgements,
+//! so bytes are counted when they are passed to or received from
+//! the HTTP/2 stream. Stream reset is reported as `Error::Http`
+//! in `CallEvent::End`.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::Async;
+use futures::Poll;
+use futures::stream::Stream;
+
+use error::Error;
+
+
+/// Timing and size statistics of a client call.
+///
+/// Retried calls are counted as a whole: times are measured from
+/// start of the first attempt and sizes are summed over attempts.
+#[derive(Debug, Clone, Default)]
+pub struct CallStats {
+    /// Number of attempts made, more than one if the call was retried.
+    pub attempts: u32,
+    /// Time from call start until HTTP/2 stream started consuming request.
+    /// This includes waiting for connection establishment.
+    pub queue_time: Option<Duration>,
+    /// Time from call start until response headers were received.
+    pub time_to_first_byte: Option<Duration>,
+    /// Time from call start until response stream of the last attempt completed.
+    /// `None` if call is still in progress.
+    pub total_time: Option<Duration>,
+    /// Number of bytes of serialized request messages, excluding gRPC framing
+    pub request_bytes: u64,
+    /// Number of bytes of serialized response messages, excluding gRPC framing
+    pub response_bytes: u64,
+    /// Number of bytes of request HTTP/2 DATA frames,
+    /// including gRPC framing and compression
+    pub request_wire_bytes: u64,
+    /// Number of bytes of response HTTP/2 DATA frames,
+    /// including gRPC framing and compression
+    pub response_wire_bytes: u64,
+}
+
+
+/// Event in lifecycle of a client call.
+#[derive(Debug)]
+pub enum CallEvent<'a> {
+    /// Call or its retry attempt started, before address is picked.
+    Start { method: &'a str },
+    /// Request message is passed to HTTP/2 stream.
+    RequestMessage { bytes: usize, wire_bytes: usize },
+    /// Response headers received.
+    ResponseHeaders,
+    /// Response HTTP/2 DATA frame received.
+    ResponseData { wire_bytes: usize },
+    /// Response message decoded.
+    ResponseMessage { bytes: usize },
+    /// Response stream ended or call failed.
+    End { error: Option<&'a Error> },
+}
+
+/// Receiver of call events, configured in `ClientConf::stats_handlers`.
+///
+/// Handlers are invoked synchronously on event loop thread,
+/// so they should be fast.
+pub trait StatsHandler : fmt::Debug + Send + Sync + 'static {
+    fn handle(&self, event: &CallEvent);
+}
+
+#[derive(Default)]
+struct CallStatsState {
+    started: Option<Instant>,
+    stats: CallStats,
+}
+
+impl CallStatsState {
+    fn elapsed(&self) -> Option<Duration> {
+        self.started.map(|started| started.elapsed())
+    }
+}
+
+/// Collector of call statistics.
+///
+/// Pass a clone in `RequestOptions::call_stats` and read
+/// statistics with `get` during or after the call,
+/// or use `Client::call_unary_with_stats`.
+#[derive(Clone, Default)]
+pub struct CallStatsCollector {
+    state: Arc<Mutex<CallStatsState>>,
+}
+
+impl fmt::Debug for CallStatsCollector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("CallStatsCollector").field(&self.get()).finish()
+    }
+}
+
+impl CallStatsCollector {
+    pub fn new() -> CallStatsCollector {
+        Default::default()
+    }
+
+    /// Snapshot of statistics collected so far.
+    pub fn get(&self) -> CallStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+}
+
+impl StatsHandler for CallStatsCollector {
+    fn handle(&self, event: &CallEvent) {
+        let mut state = self.state.lock().unwrap();
+        match *event {
+            CallEvent::Start { .. } => {
+                if state.started.is_none() {
+                    state.started = Some(Instant::now());
+                }
+                state.stats.attempts += 1;
+                // call continues with new attempt
+                state.stats.total_time = None;
+            }
+            CallEvent::RequestMessage { bytes, wire_bytes } => {
+                if state.stats.queue_time.is_none() {
+                    state.stats.queue_time = state.elapsed();
+                }
+                state.stats.request_bytes += bytes as u64;
+                state.stats.request_wire_bytes += wire_bytes as u64;
+            }
+            CallEvent::ResponseHeaders => {
+                if state.stats.time_to_first_byte.is_none() {
+                    state.stats.time_to_first_byte = state.elapsed();
+                }
+            }
+            CallEvent::ResponseData { wire_bytes } => {
+                state.stats.response_wire_bytes += wire_bytes as u64;
+            }
+            CallEvent::ResponseMessage { bytes } => {
+                state.stats.response_bytes += bytes as u64;
+            }
+            CallEvent::End { .. } => {
+                if state.stats.total_time.is_none() {
+                    state.stats.total_time = state.elapsed();
+                }
+            }
+        }
+    }
+}
+
+
+/// Handlers of single call.
+#[derive(Clone)]
+pub(crate) struct CallObserver {
+    handlers: Arc<Vec<Arc<StatsHandler>>>,
+}
+
+impl CallObserver {
+    pub fn new(handlers: Vec<Arc<StatsHandler>>) -> CallObserver {
+        CallObserver { handlers: Arc::new(handlers) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    pub fn event(&self, event: &CallEvent) {
+        for handler in &*self.handlers {
+            handler.handle(event);
+        }
+    }
+}
+
+/// Stream wrapper which marks call finished when stream ends or fails.
+pub(crate) struct FinishOnEnd<S> {
+    pub stream: S,
+    pub observer: CallObserver,
+}
+
+impl<S : Stream<Error=Error>> Stream for FinishOnEnd<S> {
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, Error> {
+        match self.stream.poll() {
+            Ok(Async::Ready(None)) => {
+                self.observer.event(&CallEvent::End { error: None });
+                Ok(Async::Ready(None))
+            }
+            Err(e) => {
+                self.observer.event(&CallEvent::End { error: Some(&e) });
+                Err(e)
+            }
+            r => r,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retry_attempts_accumulate() {
+        let collector = CallStatsCollector::new();
+        for _ in 0..2 {
+            collector.handle(&CallEvent::Start { method: "/test/Echo" });
+            collector.handle(&CallEvent::RequestMessage { bytes: 3, wire_bytes: 8 });
+            collector.handle(&CallEvent::End { error: None });
+        }
+        let stats = collector.get();
+        assert_eq!(2, stats.attempts);
+        assert_eq!(6, stats.request_bytes);
+        assert_eq!(16, stats.request_wire_bytes);
+        assert!(stats.queue_time.is_some());
+        assert!(stats.total_time.is_some());
+    }
+}
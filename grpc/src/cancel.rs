@@ -0,0 +1,233 @@
+//! Call cancellation.
+//!
+//! Server cancels a call when client resets the stream, connection
+//! is closed or deadline passes, so handlers can stop their work.
+//! Client cancels a call when `RequestOptions::cancellation` is cancelled.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use bytes::Bytes;
+
+use futures::Async;
+use futures::Poll;
+use futures::future;
+use futures::future::Either;
+use futures::future::Future;
+use futures::stream::Stream;
+use futures::sync::oneshot;
+
+use error::Error;
+use error::GrpcMessageError;
+use futures_grpc::GrpcFuture;
+use grpc::GrpcStatus;
+use interceptor::*;
+use req::*;
+use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use stream_item::ItemOrMetadata;
+
+
+struct CancellationShared {
+    cancelled: AtomicBool,
+    waiters: Mutex<Vec<oneshot::Sender<()>>>,
+}
+
+/// Signal that a call is cancelled, shared by clones.
+#[derive(Clone)]
+pub struct Cancellation {
+    shared: Arc<CancellationShared>,
+}
+
+impl fmt::Debug for Cancellation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cancellation")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl Cancellation {
+    /// New cancellation, initially not cancelled.
+    pub fn new() -> Cancellation {
+        Cancellation {
+            shared: Arc::new(CancellationShared {
+                cancelled: AtomicBool::new(false),
+                waiters: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn cancel(&self) {
+        if self.shared.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for tx in self.shared.waiters.lock().unwrap().drain(..) {
+            // receiver may be already dropped
+            drop(tx.send(()));
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Future which resolves when cancelled.
+    pub fn cancelled(&self) -> GrpcFuture<()> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut waiters = self.shared.waiters.lock().unwrap();
+            if !self.is_cancelled() {
+                waiters.push(tx);
+                return Box::new(rx.map_err(Error::from));
+            }
+        }
+        Box::new(future::ok(()))
+    }
+}
+
+pub fn cancelled() -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::Cancelled as i32,
+        grpc_message: "call cancelled".to_owned(),
+    })
+}
+
+/// Response failing with `CANCELLED` when cancellation is cancelled before it is complete.
+pub fn with_cancellation<T : Send + 'static>(resp: StreamingResponse<T>, cancellation: &Cancellation)
+    -> StreamingResponse<T>
+{
+    StreamingResponse::new(resp.0.select2(cancellation.cancelled()).then(|r| {
+        match r {
+            Ok(Either::A(((metadata, stream), cancelled))) => {
+                let stream = CancellableStream { stream: stream.0, cancelled };
+                Ok((metadata, GrpcStreamWithTrailingMetadata::new(stream)))
+            }
+            Err(Either::A((e, _))) => Err(e),
+            Ok(Either::B(..)) | Err(Either::B(..)) => Err(cancelled()),
+        }
+    }))
+}
+
+struct CancellableStream<S> {
+    stream: S,
+    cancelled: GrpcFuture<()>,
+}
+
+impl<S : Stream<Error=Error>> Stream for CancellableStream<S> {
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, Error> {
+        match self.cancelled.poll() {
+            Ok(Async::NotReady) => self.stream.poll(),
+            Ok(Async::Ready(..)) | Err(..) => Err(cancelled()),
+        }
+    }
+}
+
+/// Cancels call if dropped before call is complete.
+struct CancelOnDrop {
+    cancellation: Option<Cancellation>,
+}
+
+impl CancelOnDrop {
+    fn complete(&mut self) {
+        self.cancellation = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(ref cancellation) = self.cancellation {
+            cancellation.cancel();
+        }
+    }
+}
+
+struct CancelOnDropStream<S> {
+    stream: S,
+    guard: CancelOnDrop,
+}
+
+impl<S, T> Stream for CancelOnDropStream<S>
+    where S : Stream<Item=ItemOrMetadata<T>, Error=Error>
+{
+    type Item = ItemOrMetadata<T>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ItemOrMetadata<T>>, Error> {
+        let r = self.stream.poll();
+        match r {
+            // trailing metadata is the last item, stream may be dropped after it
+            Ok(Async::Ready(Some(ItemOrMetadata::TrailingMetadata(..))))
+                | Ok(Async::Ready(None))
+                | Err(..) => self.guard.complete(),
+            _ => {}
+        }
+        r
+    }
+}
+
+/// Response cancelling call if dropped before it is complete,
+/// e. g. when client resets the stream.
+pub fn cancel_on_drop<T : Send + 'static>(resp: StreamingResponse<T>, cancellation: Cancellation)
+    -> StreamingResponse<T>
+{
+    let mut guard = CancelOnDrop { cancellation: Some(cancellation) };
+    StreamingResponse::new(resp.0.then(move |r| {
+        match r {
+            Ok((metadata, stream)) => {
+                let stream = CancelOnDropStream { stream: stream.0, guard };
+                Ok((metadata, GrpcStreamWithTrailingMetadata::new(stream)))
+            }
+            Err(e) => {
+                guard.complete();
+                Err(e)
+            }
+        }
+    }))
+}
+
+/// Client interceptor cancelling calls when the parent call is cancelled.
+pub struct PropagateCancellation {
+    pub cancellation: Cancellation,
+}
+
+impl ClientInterceptor for PropagateCancellation {
+    fn intercept(
+        &self,
+        _method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>
+    {
+        // own cancellation of the call is handled by `ClientNext`
+        let resp = next.call(o, req);
+        with_cancellation(resp, &self.cancellation)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cancel() {
+        let cancellation = Cancellation::new();
+        let mut cancelled = cancellation.cancelled();
+        assert!(!cancellation.is_cancelled());
+        let poll = future::lazy(|| Ok::<_, ()>(cancelled.poll().unwrap())).wait().unwrap();
+        assert_eq!(Async::NotReady, poll);
+
+        cancellation.cancel();
+        assert!(cancellation.is_cancelled());
+        cancelled.wait().unwrap();
+        cancellation.cancelled().wait().unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+//! Cache of channels shared by clients created in different places.
+
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use tls_api;
+use tls_api_stub;
+
+use client::Channel;
+use client::Client;
+use client::ClientConf;
+use result;
+use target::ClientTarget;
+
+
+/// Channels of a pool not used for this long are closed.
+fn default_idle_timeout() -> Duration {
+    Duration::from_secs(300)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ChannelKey {
+    target: ClientTarget,
+    /// Type of TLS connector, `None` for plaintext
+    tls_connector: Option<TypeId>,
+}
+
+struct PooledChannel {
+    channel: Channel,
+    last_used: Instant,
+}
+
+impl PooledChannel {
+    /// Not used by any client or call for `idle_timeout`.
+    fn is_idle(&self, now: Instant, idle_timeout: Duration) -> bool {
+        !self.channel.is_shared()
+            && now.duration_since(self.last_used) >= idle_timeout
+            && self.channel.connections().iter().all(|c| c.active_calls == 0)
+    }
+}
+
+/// Channels by target, so clients created ad hoc for the same backend
+/// share a connection instead of opening one each.
+///
+/// Channels are created with configuration the pool was created with.
+/// Channel is closed when it is not used by any client or call
+/// for idle timeout, which is checked when a channel is requested
+/// or `evict_idle` is called.
+pub struct ChannelPool {
+    conf: ClientConf,
+    idle_timeout: Duration,
+    channels: Mutex<HashMap<ChannelKey, PooledChannel>>,
+}
+
+impl ChannelPool {
+    /// Pool with idle timeout of 5 minutes.
+    pub fn new(conf: ClientConf) -> ChannelPool {
+        ChannelPool {
+            conf: conf,
+            idle_timeout: default_idle_timeout(),
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_idle_timeout(self, idle_timeout: Duration) -> ChannelPool {
+        ChannelPool { idle_timeout, ..self }
+    }
+
+    /// Channel connected to specified target, created if not cached.
+    ///
+    /// Plaintext and TLS channels with different connector types
+    /// to the same target are different channels.
+    /// Channel is created while the pool is locked,
+    /// so concurrent requests for the same target wait for it.
+    pub fn channel<C : tls_api::TlsConnector + 'static>(&self, target: &ClientTarget)
+        -> result::Result<Channel>
+    {
+        let key = ChannelKey {
+            target: target.clone(),
+            tls_connector: if target.tls { Some(TypeId::of::<C>()) } else { None },
+        };
+
+        let now = Instant::now();
+        let mut channels = self.channels.lock().unwrap();
+        self.evict_idle_locked(&mut channels, now);
+
+        if let Some(pooled) = channels.get_mut(&key) {
+            pooled.last_used = now;
+            return Ok(pooled.channel.clone());
+        }
+
+        let channel = Channel::new_target::<C>(target, self.conf.clone())?;
+        channels.insert(key, PooledChannel {
+            channel: channel.clone(),
+            last_used: now,
+        });
+        Ok(channel)
+    }
+
+    /// Plaintext channel connected to specified host and port.
+    pub fn channel_plain(&self, host: &str, port: u16) -> result::Result<Channel> {
+        let target = ClientTarget {
+            host: host.to_owned(),
+            port: port,
+            tls: false,
+        };
+        self.channel::<tls_api_stub::TlsConnector>(&target)
+    }
+
+    /// Channel connected to target specified as string, see `ClientTarget::parse`.
+    pub fn channel_url<C : tls_api::TlsConnector + 'static>(&self, url: &str)
+        -> result::Result<Channel>
+    {
+        self.channel::<C>(&ClientTarget::parse(url)?)
+    }
+
+    /// Client using pooled channel connected to specified target.
+    pub fn client<C : tls_api::TlsConnector + 'static>(&self, target: &ClientTarget)
+        -> result::Result<Client>
+    {
+        self.channel::<C>(target).map(Client::with_channel)
+    }
+
+    /// Number of channels in the pool, including idle not yet evicted.
+    pub fn len(&self) -> usize {
+        self.channels.lock().unwrap().len()
+    }
+
+    /// Close and remove idle channels, return number of removed channels.
+    pub fn evict_idle(&self) -> usize {
+        let mut channels = self.channels.lock().unwrap();
+        self.evict_idle_locked(&mut channels, Instant::now())
+    }
+
+    fn evict_idle_locked(&self, channels: &mut HashMap<ChannelKey, PooledChannel>, now: Instant)
+        -> usize
+    {
+        let idle_timeout = self.idle_timeout;
+        let before = channels.len();
+        channels.retain(|key, pooled| {
+            if !pooled.is_idle(now, idle_timeout) {
+                return true;
+            }
+            debug!("closing idle channel to {}:{}", key.target.host, key.target.port);
+            pooled.channel.shutdown();
+            false
+        });
+        before - channels.len()
+    }
+}
@@ -0,0 +1,131 @@
+//! Interceptor which injects faults into calls, useful to test
+//! retry and deadline handling.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use futures::future::Future;
+use futures::stream;
+use futures::stream::Stream;
+
+use rand;
+use rand::Rng;
+
+use error::Error;
+use error::GrpcMessageError;
+use grpc::GrpcStatus;
+use interceptor::*;
+use req::*;
+use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use timer;
+
+
+/// Which faults are injected and how often.
+///
+/// Percentages are independent, e.g. a call may be both delayed and aborted.
+#[derive(Debug, Clone)]
+pub struct ChaosConf {
+    /// Percent of calls delayed by `latency` before start
+    pub latency_percent: u32,
+    pub latency: Duration,
+    /// Percent of calls failed with `error_status` without being sent to the peer
+    /// (on client) or dispatched to the handler (on server)
+    pub error_percent: u32,
+    pub error_status: i32,
+    /// Percent of calls with response stream aborted after random number of messages
+    pub abort_percent: u32,
+}
+
+impl Default for ChaosConf {
+    fn default() -> ChaosConf {
+        ChaosConf {
+            latency_percent: 0,
+            latency: Duration::from_millis(0),
+            error_percent: 0,
+            error_status: GrpcStatus::Unavailable as i32,
+            abort_percent: 0,
+        }
+    }
+}
+
+/// Fault injecting interceptor.
+///
+/// Can be used both as client and server interceptor.
+pub struct ChaosInterceptor {
+    conf: ChaosConf,
+}
+
+impl ChaosInterceptor {
+    pub fn new(conf: ChaosConf) -> ChaosInterceptor {
+        ChaosInterceptor { conf }
+    }
+
+    fn roll(percent: u32) -> bool {
+        rand::thread_rng().gen_range(0, 100) < percent
+    }
+
+    fn call<T, F>(&self, method: &str, call: F) -> StreamingResponse<T>
+        where
+            T : Send + 'static,
+            F : FnOnce() -> StreamingResponse<T> + Send + 'static,
+    {
+        if ChaosInterceptor::roll(self.conf.error_percent) {
+            debug!("chaos: failing call {}", method);
+            return StreamingResponse::err(Error::GrpcMessage(GrpcMessageError {
+                grpc_status: self.conf.error_status,
+                grpc_message: "error injected by chaos interceptor".to_owned(),
+            }));
+        }
+
+        let resp = if ChaosInterceptor::roll(self.conf.latency_percent) {
+            debug!("chaos: delaying call {}", method);
+            StreamingResponse::new(timer::sleep(self.conf.latency).and_then(move |()| call().0))
+        } else {
+            call()
+        };
+
+        if ChaosInterceptor::roll(self.conf.abort_percent) {
+            let messages = rand::thread_rng().gen_range(0, 3);
+            debug!("chaos: aborting call {} after {} messages", method, messages);
+            StreamingResponse::new(resp.0.map(move |(metadata, stream)| {
+                let aborted = stream.0
+                    .take(messages)
+                    .chain(stream::once(Err(Error::GrpcMessage(GrpcMessageError {
+                        grpc_status: GrpcStatus::Aborted as i32,
+                        grpc_message: "stream aborted by chaos interceptor".to_owned(),
+                    }))));
+                (metadata, GrpcStreamWithTrailingMetadata::new(aborted))
+            }))
+        } else {
+            resp
+        }
+    }
+}
+
+impl ClientInterceptor for ChaosInterceptor {
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>
+    {
+        self.call(method, move || next.call(o, req))
+    }
+}
+
+impl ServerInterceptor for ChaosInterceptor {
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>
+    {
+        self.call(method, move || next.call(o, req))
+    }
+}
@@ -0,0 +1,408 @@
+//! Client circuit breaker.
+//!
+//! Circuit of a method opens when too many recent calls failed,
+//! then calls fail fast with `UNAVAILABLE` until circuit half-opens
+//! after a timeout and lets a few probe calls through. Circuit closes
+//! if all probes succeed, and opens again otherwise.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use futures::Async;
+use futures::Poll;
+use futures::future::Future;
+use futures::stream::Stream;
+
+use auth::pattern_matches;
+use error::Error;
+use error::GrpcMessageError;
+use grpc::GrpcStatus;
+use interceptor::*;
+use metadata::Metadata;
+use req::*;
+use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use stream_item::ItemOrMetadata;
+
+
+/// Number of buckets of rolling window.
+const WINDOW_BUCKETS: u32 = 10;
+
+/// When circuit opens and closes.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConf {
+    /// Error rate is computed over calls finished in this period
+    pub window: Duration,
+    /// Circuit opens when at least `min_calls` finished in the window
+    /// and at least `error_percent` of them failed
+    pub min_calls: u32,
+    pub error_percent: u32,
+    /// Calls over this number in progress fail fast
+    pub max_concurrent_calls: Option<usize>,
+    /// Time circuit stays open before half-opening
+    pub open_duration: Duration,
+    /// Number of probe calls let through when circuit is half-open
+    pub probe_calls: u32,
+    /// Calls failed with these statuses are counted as failures,
+    /// other calls as successes
+    pub failure_status_codes: Vec<i32>,
+}
+
+impl Default for CircuitBreakerConf {
+    fn default() -> CircuitBreakerConf {
+        CircuitBreakerConf {
+            window: Duration::from_secs(10),
+            min_calls: 20,
+            error_percent: 50,
+            max_concurrent_calls: None,
+            open_duration: Duration::from_secs(5),
+            probe_calls: 1,
+            failure_status_codes: vec![
+                GrpcStatus::Unknown as i32,
+                GrpcStatus::DeadlineExceeded as i32,
+                GrpcStatus::Internal as i32,
+                GrpcStatus::Unavailable as i32,
+            ],
+        }
+    }
+}
+
+/// Successes and failures in the window, in buckets of `window / WINDOW_BUCKETS`.
+#[derive(Default)]
+struct Window {
+    buckets: Vec<(Instant, u32, u32)>,
+}
+
+impl Window {
+    fn add(&mut self, conf: &CircuitBreakerConf, now: Instant, failure: bool) {
+        let bucket_len = conf.window / WINDOW_BUCKETS;
+        let window = conf.window;
+        self.buckets.retain(|&(start, _, _)| now < start + window);
+        let new_bucket = match self.buckets.last() {
+            Some(&(start, _, _)) => now >= start + bucket_len,
+            None => true,
+        };
+        if new_bucket {
+            self.buckets.push((now, 0, 0));
+        }
+        let last = self.buckets.last_mut().unwrap();
+        if failure {
+            last.2 += 1;
+        } else {
+            last.1 += 1;
+        }
+    }
+
+    /// Number of calls and failures.
+    fn counts(&self) -> (u32, u32) {
+        self.buckets.iter()
+            .fold((0, 0), |(calls, failures), &(_, s, f)| (calls + s + f, failures + f))
+    }
+}
+
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { started: u32, succeeded: u32 },
+}
+
+/// Circuit of a single method.
+struct Circuit {
+    conf: Arc<CircuitBreakerConf>,
+    state: State,
+    window: Window,
+    active: usize,
+}
+
+impl Circuit {
+    fn new(conf: Arc<CircuitBreakerConf>) -> Circuit {
+        Circuit {
+            conf,
+            state: State::Closed,
+            window: Window::default(),
+            active: 0,
+        }
+    }
+
+    fn open(&mut self, now: Instant) {
+        self.state = State::Open { until: now + self.conf.open_duration };
+        self.window = Window::default();
+    }
+
+    /// Start a call, return error message if call should fail fast.
+    fn start(&mut self, now: Instant) -> Result<(), &'static str> {
+        if let Some(max) = self.conf.max_concurrent_calls {
+            if self.active >= max {
+                return Err("too many concurrent calls");
+            }
+        }
+        if let State::Open { until } = self.state {
+            if now < until {
+                return Err("circuit breaker is open");
+            }
+            self.state = State::HalfOpen { started: 0, succeeded: 0 };
+        }
+        if let State::HalfOpen { ref mut started, .. } = self.state {
+            if *started >= self.conf.probe_calls {
+                return Err("circuit breaker is half-open");
+            }
+            *started += 1;
+        }
+        self.active += 1;
+        Ok(())
+    }
+
+    /// Finish a call, `None` if call was canceled.
+    fn finish(&mut self, now: Instant, failure: Option<bool>) {
+        self.active -= 1;
+        match self.state {
+            State::Closed => {
+                if let Some(failure) = failure {
+                    self.window.add(&self.conf, now, failure);
+                    let (calls, failures) = self.window.counts();
+                    if calls >= self.conf.min_calls && failures * 100 >= self.conf.error_percent * calls {
+                        self.open(now);
+                    }
+                }
+            }
+            State::HalfOpen { ref mut started, ref mut succeeded } => {
+                match failure {
+                    Some(true) => {}
+                    Some(false) => *succeeded += 1,
+                    // canceled probe may be retried
+                    None => *started -= 1,
+                }
+            }
+            // calls started before circuit opened
+            State::Open { .. } => {}
+        }
+        if let State::HalfOpen { succeeded, .. } = self.state {
+            if failure == Some(true) {
+                self.open(now);
+            } else if succeeded >= self.conf.probe_calls {
+                self.state = State::Closed;
+            }
+        }
+    }
+}
+
+type Circuits = Arc<Mutex<HashMap<String, Circuit>>>;
+
+/// Call in progress, finished as canceled if dropped before result is known.
+struct CallGuard {
+    circuits: Circuits,
+    method: String,
+    conf: Arc<CircuitBreakerConf>,
+    finished: bool,
+}
+
+impl CallGuard {
+    fn finish(&mut self, failure: Option<bool>) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        if let Some(circuit) = self.circuits.lock().unwrap().get_mut(&self.method) {
+            circuit.finish(Instant::now(), failure);
+        }
+    }
+
+    fn finish_with_error(&mut self, e: &Error) {
+        let failure = self.conf.failure_status_codes.contains(&e.grpc_status());
+        self.finish(Some(failure));
+    }
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        self.finish(None);
+    }
+}
+
+/// Response stream finishing call when it ends.
+struct GuardedStream<S> {
+    stream: S,
+    guard: CallGuard,
+}
+
+impl<S, T> Stream for GuardedStream<S>
+    where
+        S : Stream<Item=ItemOrMetadata<T>, Error=Error>,
+        T : Send + 'static,
+{
+    type Item = ItemOrMetadata<T>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ItemOrMetadata<T>>, Error> {
+        match self.stream.poll() {
+            Ok(Async::Ready(None)) => {
+                self.guard.finish(Some(false));
+                Ok(Async::Ready(None))
+            }
+            Err(e) => {
+                self.guard.finish_with_error(&e);
+                Err(e)
+            }
+            r => r,
+        }
+    }
+}
+
+/// Client interceptor failing calls fast while circuit of the method is open.
+///
+/// Each method has its own circuit, configured by the first matching
+/// pattern, or by default configuration if no pattern matches.
+/// Patterns are the same as in `AccessPolicy`.
+pub struct CircuitBreakerInterceptor {
+    default_conf: Arc<CircuitBreakerConf>,
+    method_confs: Vec<(String, Arc<CircuitBreakerConf>)>,
+    circuits: Circuits,
+}
+
+impl CircuitBreakerInterceptor {
+    pub fn new(conf: CircuitBreakerConf) -> CircuitBreakerInterceptor {
+        CircuitBreakerInterceptor {
+            default_conf: Arc::new(conf),
+            method_confs: Vec::new(),
+            circuits: Default::default(),
+        }
+    }
+
+    /// Use configuration for methods matching pattern.
+    pub fn set_method_conf(&mut self, pattern: &str, conf: CircuitBreakerConf) {
+        self.method_confs.push((pattern.to_owned(), Arc::new(conf)));
+    }
+
+    fn conf(&self, method: &str) -> &Arc<CircuitBreakerConf> {
+        self.method_confs.iter()
+            .find(|&&(ref pattern, _)| pattern_matches(pattern, method))
+            .map_or(&self.default_conf, |&(_, ref conf)| conf)
+    }
+
+    /// Start call, or return error if it should fail fast.
+    fn start(&self, method: &str) -> Result<CallGuard, Error> {
+        let conf = self.conf(method).clone();
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(method.to_owned())
+            .or_insert_with(|| Circuit::new(conf.clone()));
+        match circuit.start(Instant::now()) {
+            Ok(()) => Ok(CallGuard {
+                circuits: self.circuits.clone(),
+                method: method.to_owned(),
+                conf,
+                finished: false,
+            }),
+            Err(message) => Err(Error::GrpcMessage(GrpcMessageError {
+                grpc_status: GrpcStatus::Unavailable as i32,
+                grpc_message: message.to_owned(),
+            })),
+        }
+    }
+}
+
+impl ClientInterceptor for CircuitBreakerInterceptor {
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>
+    {
+        let mut guard = match self.start(method) {
+            Ok(guard) => guard,
+            Err(e) => {
+                debug!("failing call {} fast: {:?}", method, e);
+                return StreamingResponse::err(e);
+            }
+        };
+
+        let resp = next.call(o, req).0.then(move |r| -> Result<(Metadata, GrpcStreamWithTrailingMetadata<Bytes>), Error> {
+            match r {
+                Ok((metadata, stream)) => {
+                    let stream = GuardedStream { stream: stream.0, guard };
+                    Ok((metadata, GrpcStreamWithTrailingMetadata::new(stream)))
+                }
+                Err(e) => {
+                    guard.finish_with_error(&e);
+                    Err(e)
+                }
+            }
+        });
+        StreamingResponse::new(resp)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn conf() -> Arc<CircuitBreakerConf> {
+        Arc::new(CircuitBreakerConf {
+            min_calls: 4,
+            error_percent: 50,
+            probe_calls: 2,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn open_and_close() {
+        let mut circuit = Circuit::new(conf());
+        let now = Instant::now();
+
+        for &failure in &[false, false, true] {
+            circuit.start(now).unwrap();
+            circuit.finish(now, Some(failure));
+        }
+        circuit.start(now).unwrap();
+        circuit.finish(now, Some(true));
+        assert!(circuit.start(now).is_err());
+
+        // half-open with two probes
+        let later = now + Duration::from_secs(5);
+        circuit.start(later).unwrap();
+        circuit.start(later).unwrap();
+        assert!(circuit.start(later).is_err());
+        circuit.finish(later, None);
+        circuit.start(later).unwrap();
+        circuit.finish(later, Some(false));
+        circuit.finish(later, Some(false));
+
+        circuit.start(later).unwrap();
+        assert_eq!(1, circuit.active);
+    }
+
+    #[test]
+    fn failed_probe() {
+        let mut circuit = Circuit::new(conf());
+        let now = Instant::now();
+        circuit.open(now);
+
+        let later = now + Duration::from_secs(5);
+        circuit.start(later).unwrap();
+        circuit.finish(later, Some(true));
+        assert!(circuit.start(later).is_err());
+    }
+
+    #[test]
+    fn window() {
+        let conf = conf();
+        let mut window = Window::default();
+        let now = Instant::now();
+        window.add(&conf, now, true);
+        window.add(&conf, now + Duration::from_millis(500), false);
+        window.add(&conf, now + Duration::from_millis(1500), false);
+        assert_eq!((3, 1), window.counts());
+        assert_eq!(2, window.buckets.len());
+
+        window.add(&conf, now + Duration::from_millis(10100), false);
+        assert_eq!((2, 0), window.counts());
+    }
+}
@@ -1,9 +1,21 @@
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc;
 use std::net::SocketAddr;
+use std::io;
+use std::thread;
+use std::time::Duration;
 
 use bytes::Bytes;
 
+use futures::Async;
+use futures::Poll;
+use futures::future;
+use futures::future::Either;
+use futures::future::Future;
+use futures::future::Shared;
 use futures::stream::Stream;
+use futures::sync::oneshot;
 
 use httpbis;
 use httpbis::Service as HttpbisService;
@@ -11,111 +23,772 @@ use httpbis::HttpScheme;
 use httpbis::Header;
 use httpbis::Headers;
 use httpbis::HttpStreamAfterHeaders;
+use httpbis::DataOrTrailers;
 
 
 use tls_api;
+use tls_api::TlsConnectorBuilder;
+use tls_api_stub;
 
 
 use method::MethodDescriptor;
+use method::MethodOptions;
+use target::ClientTarget;
+use resolver::Resolver;
+use resolver::DefaultResolver;
+use balancer::AddressUpdates;
+use balancer::Balancer;
+use balancer::BalancingPolicy;
+use balancer::ConnectionState;
+use balancer::OutlierDetectionConf;
+use balancer::RecordResult;
+use balancer::Outstanding;
+use balancer::WeightedAddr;
+use experiments::Experiments;
+use metadata::DuplicateKeyPolicy;
+use timer;
 
 use error::*;
 use result;
+use futures_grpc::GrpcFuture;
+use futures_grpc::GrpcStream;
 
-use grpc_frame::*;
+use grpc::GrpcStatus;
+use grpc::content_type;
 use grpc_http_to_response::*;
 
 use req::*;
 use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use call_stats::CallEvent;
+use call_stats::CallStats;
+use call_stats::CallStatsCollector;
+use call_stats::CallObserver;
+use call_stats::FinishOnEnd;
+use call_stats::StatsHandler;
+use interceptor::ClientInterceptor;
+use interceptor::ClientNext;
+use credentials::CallCredentials;
+use credentials::CallCredentialsInterceptor;
+use compression::CodecRegistry;
+use compression::MessageEncoder;
+use write_batch::WriteStrategy;
+use buffer_pool::BufferPool;
+use write_batch::batch_frames;
+use compression::HEADER_GRPC_ENCODING;
+use compression::HEADER_GRPC_ACCEPT_ENCODING;
+use cancel::PropagateCancellation;
+use request_id::PropagateRequestId;
+use deadline::HEADER_GRPC_TIMEOUT;
+use deadline::PropagateDeadline;
+use deadline::encode_timeout;
+use deadline::remaining;
+use inspect::MessageInspector;
+use inspect::MethodInfo;
+use inspect::inspect_received;
+use inspect::inspect_sent;
 
 
 #[derive(Default, Debug, Clone)]
 pub struct ClientConf {
     pub http: httpbis::ClientConf,
+    /// Timeout of each TCP connection attempt, passed to `httpbis`
+    /// as `http.connection_timeout`. Calls waiting for a connection
+    /// fail with `UNAVAILABLE` when it expires.
+    pub connect_timeout: Option<Duration>,
+    /// Timeout of TLS and HTTP/2 handshakes following TCP connection.
+    ///
+    /// `httpbis` doesn't report when TCP connection is established,
+    /// so waiting for a connection (`Channel::warm_up`, `eager_connect`,
+    /// `RequestOptions::wait_for_ready`) fails when connection is not ready
+    /// `connect_timeout` plus this timeout after it started.
+    /// Calls sent without waiting for a connection are limited by their deadline.
+    pub handshake_timeout: Option<Duration>,
+    /// Resolver of host names, `DefaultResolver` if unset.
+    pub resolver: Option<Arc<Resolver>>,
+    /// Number of threads of `DefaultResolver` created when
+    /// `resolver` is unset. Default is 1.
+    pub resolver_threads: Option<usize>,
+    /// Compression of requests, e. g. `"gzip"`. Server must support it.
+    /// Can be overridden per call by `RequestOptions::compression`.
+    pub compression: Option<String>,
+    /// Request messages smaller than this are sent uncompressed
+    /// even if `compression` is set, default is 0.
+    pub compression_min_message_size: Option<usize>,
+    /// Compression codecs in addition to built-in ones.
+    pub codecs: Vec<Arc<Codec>>,
+    /// Max size of received message after decompression, unlimited by default.
+    /// Calls receiving larger messages fail with `RESOURCE_EXHAUSTED`.
+    pub max_receive_message_size: Option<usize>,
+    /// Whether request messages are written immediately or batched.
+    pub write_strategy: WriteStrategy,
+    /// Distribution of calls between addresses of the host.
+    pub balancing_policy: BalancingPolicy,
+    /// Exclude failing addresses from balancing, disabled by default.
+    pub outlier_detection: Option<OutlierDetectionConf>,
+    /// JSON service config. Balancing policy selected by it
+    /// overrides `balancing_policy`. Retries configured by it
+    /// are performed by `RetryInterceptor::from_service_config`.
+    pub service_config: Option<String>,
+    /// When all addresses of the channel fail with transport errors,
+    /// host is resolved again, but not more often than this. Default is 30s.
+    pub min_re_resolution_interval: Option<Duration>,
+    /// Start connecting to all addresses when channel is created rather than
+    /// on the first call. Channel creation doesn't wait for connections,
+    /// connection errors are logged. See also `Channel::warm_up`.
+    pub eager_connect: bool,
+    /// Receivers of events of every call, in addition to
+    /// `RequestOptions::call_stats`.
+    pub stats_handlers: Vec<Arc<StatsHandler>>,
+    /// Experiments to enable, or disable if prefixed with `-`,
+    /// in addition to ones listed in `GRPC_EXPERIMENTS`.
+    pub experiments: Vec<String>,
+    /// Pool of buffers sent and received frames are written into,
+    /// frames are allocated individually if unset.
+    pub buffer_pool: Option<Arc<BufferPool>>,
+    /// Representation of repeated keys of response metadata.
+    pub metadata_duplicate_keys: DuplicateKeyPolicy,
+}
+
+fn default_min_re_resolution_interval() -> Duration {
+    Duration::from_secs(30)
 }
 
 impl ClientConf {
     pub fn new() -> ClientConf {
         Default::default()
     }
+
+    fn balancing_policy(&self) -> result::Result<BalancingPolicy> {
+        let from_service_config = match self.service_config {
+            Some(ref json) => {
+                let experiments = Experiments::resolve(&self.experiments);
+                BalancingPolicy::from_service_config_with(json, &experiments)?
+            }
+            None => None,
+        };
+        Ok(from_service_config.unwrap_or_else(|| self.balancing_policy.clone()))
+    }
 }
 
 
-/// gRPC client implementation.
-/// Used by generated code.
-pub struct Client {
-    client: ::std::sync::Arc<httpbis::Client>,
+/// Sends calls with serialized messages to server.
+///
+/// `Channel` constructors use HTTP/2 transport. Other transports,
+/// e. g. gRPC-Web in browser, can back generated clients
+/// with `Channel::with_transport`.
+pub trait ClientTransport : Send + Sync + 'static {
+    /// Send a request. `method` is a full path like `/package.Service/Method`.
+    fn call(&self, method: &str, options: RequestOptions, req: StreamingRequest<Bytes>)
+        -> StreamingResponse<Bytes>;
+
+    /// Establish connections in advance, see `Channel::warm_up`.
+    fn warm_up(&self) -> GrpcFuture<()> {
+        Box::new(future::ok(()))
+    }
+
+    /// Wait until a call can be sent, retrying failed connections.
+    /// Calls with `RequestOptions::wait_for_ready` are sent after it resolves.
+    fn wait_for_ready(&self) -> GrpcFuture<()> {
+        Box::new(future::ok(()))
+    }
+
+    /// Fail calls in progress and future calls, see `Channel::shutdown`.
+    fn shutdown(&self) {}
+
+    /// State of connections, see `Channel::connections`.
+    fn connections(&self) -> Vec<ConnectionState> {
+        Vec::new()
+    }
+}
+
+
+/// HTTP/2 connection and parameters of requests sent over it.
+struct Http2Transport {
+    balancer: Arc<Balancer>,
     host: String,
     http_scheme: HttpScheme,
+    codecs: Arc<CodecRegistry>,
+    encoder: MessageEncoder,
+    write_strategy: WriteStrategy,
+    max_receive_message_size: Option<usize>,
+    metadata_duplicate_keys: DuplicateKeyPolicy,
+    stats_handlers: Vec<Arc<StatsHandler>>,
+    /// Resolved by `shutdown`.
+    shutdown_rx: Shared<oneshot::Receiver<()>>,
+    shutdown_tx: Mutex<Option<oneshot::Sender<()>>>,
 }
 
-impl Client {
-    /// Create a client connected to specified host and port.
+fn client_shut_down() -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::Unavailable as i32,
+        grpc_message: "client shut down".to_owned(),
+    })
+}
+
+/// Delay before `attempt`-th reconnection: 1s growing 1.6 times up to 120s.
+fn reconnect_backoff(attempt: u32) -> Duration {
+    let secs = 1.6f64.powi(attempt as i32).min(120.0);
+    Duration::from_millis((secs * 1000.0) as u64)
+}
+
+/// Connect to any address of balancer, retrying with backoff until it is shut down.
+fn wait_for_ready(balancer: Arc<Balancer>, attempt: u32) -> GrpcFuture<()> {
+    if balancer.is_shut_down() {
+        return Box::new(future::err(client_shut_down()));
+    }
+    Box::new(balancer.connect_any().or_else(move |e| {
+        let backoff = reconnect_backoff(attempt);
+        debug!("waiting for ready, reconnecting in {:?} after error: {:?}", backoff, e);
+        timer::sleep(backoff).and_then(move |()| wait_for_ready(balancer, attempt + 1))
+    }))
+}
+
+impl ClientTransport for Http2Transport {
+    fn warm_up(&self) -> GrpcFuture<()> {
+        self.balancer.warm_up()
+    }
+
+    fn wait_for_ready(&self) -> GrpcFuture<()> {
+        wait_for_ready(self.balancer.clone(), 0)
+    }
+
+    fn shutdown(&self) {
+        self.balancer.shutdown();
+        if let Some(shutdown_tx) = self.shutdown_tx.lock().unwrap().take() {
+            drop(shutdown_tx.send(()));
+        }
+    }
+
+    fn connections(&self) -> Vec<ConnectionState> {
+        self.balancer.connections()
+    }
+
+    fn call(&self, method: &str, options: RequestOptions, req: StreamingRequest<Bytes>)
+        -> StreamingResponse<Bytes>
+    {
+        let mut handlers = self.stats_handlers.clone();
+        if let Some(ref call_stats) = options.call_stats {
+            handlers.push(Arc::new(call_stats.clone()));
+        }
+        let observer = CallObserver::new(handlers);
+        observer.event(&CallEvent::Start { method: method });
+
+        let encoder = match options.compression {
+            None => Ok(self.encoder.clone()),
+            Some(..) => self.codecs.find_configured(&options.compression)
+                .map(|codec| MessageEncoder { codec, ..self.encoder.clone() }),
+        };
+        let encoder = match encoder {
+            Ok(encoder) => encoder,
+            Err(e) => {
+                observer.event(&CallEvent::End { error: Some(&e) });
+                return StreamingResponse::err(e);
+            }
+        };
+
+        let picked = if self.balancer.is_shut_down() {
+            Err(client_shut_down())
+        } else {
+            self.balancer.pick(&options.metadata)
+        };
+        let call = match picked {
+            Ok(call) => call,
+            Err(e) => {
+                observer.event(&CallEvent::End { error: Some(&e) });
+                return StreamingResponse::err(e);
+            }
+        };
+
+        let cork = options.cork.clone();
+        let message_compression = options.message_compression.clone();
+
+        let mut headers = Headers(vec![
+            Header::new(Bytes::from_static(b":method"), Bytes::from_static(b"POST")),
+            Header::new(Bytes::from_static(b":path"), method.to_owned()),
+            Header::new(Bytes::from_static(b":authority"), self.host.clone()),
+            Header::new(Bytes::from_static(b":scheme"), Bytes::from_static(self.http_scheme.as_bytes())),
+            Header::new(Bytes::from_static(b"content-type"), content_type(&options.content_subtype)),
+            Header::new(Bytes::from_static(b"te"), Bytes::from_static(b"trailers")),
+            Header::new(HEADER_GRPC_ACCEPT_ENCODING, self.codecs.accept_encoding()),
+        ]);
+
+        if let Some(ref codec) = encoder.codec {
+            headers.0.push(Header::new(HEADER_GRPC_ENCODING, codec.name().to_owned()));
+        }
+
+        if let Some(deadline) = options.deadline {
+            headers.0.push(Header::new(HEADER_GRPC_TIMEOUT, encode_timeout(remaining(deadline))));
+        }
+
+        headers.extend(options.metadata.into_headers());
+
+        let request_error = RequestError::default();
+
+        let request_frames = {
+            let observer = observer.clone();
+            let request_error = request_error.clone();
+            let frames = req.0
+                .and_then(move |message| {
+                    let compress = message_compression.as_ref().map(|c| c.compress(&message));
+                    let frame = encoder.encode(&message, compress)?;
+                    observer.event(&CallEvent::RequestMessage {
+                        bytes: message.len(),
+                        wire_bytes: frame.len(),
+                    });
+                    Ok(frame)
+                });
+            batch_frames(Box::new(frames), &self.write_strategy, cork)
+                .map_err(move |e| {
+                    // error only resets the stream, caller gets the original
+                    let http_error = io::Error::new(io::ErrorKind::Other, e.to_string());
+                    *request_error.lock().unwrap() = Some(e);
+                    httpbis::Error::from(http_error)
+                })
+        };
+
+        let http_response_stream = call.subchannel.client
+            .start_request(
+                headers,
+                HttpStreamAfterHeaders::bytes(request_frames));
+        let http_response_stream = if observer.is_empty() {
+            http_response_stream
+        } else {
+            observe_http_response(http_response_stream, observer.clone())
+        };
+
+        let grpc_frames = http_response_to_grpc_frames(
+            http_response_stream,
+            self.codecs.clone(),
+            self.max_receive_message_size,
+            self.encoder.pool.clone(),
+            self.metadata_duplicate_keys);
+        let grpc_frames = fail_with_request_error(grpc_frames, request_error);
+        let grpc_frames = record_result(grpc_frames, self.balancer.clone(), call);
+        let grpc_frames = fail_on_shutdown(grpc_frames, self.shutdown_rx.clone());
+        if observer.is_empty() {
+            grpc_frames
+        } else {
+            observe_grpc_response(grpc_frames, observer)
+        }
+    }
+}
+
+/// Connection to a server, cheap to clone.
+///
+/// Single channel can be shared by clients of several services
+/// to avoid opening a connection per service.
+#[derive(Clone)]
+pub struct Channel {
+    transport: Arc<ClientTransport>,
+}
+
+impl Channel {
+    /// Create a channel connected to specified host and port.
     pub fn new_plain(host: &str, port: u16, conf: ClientConf)
-        -> result::Result<Client>
+        -> result::Result<Channel>
+    {
+        Channel::new_resolved::<tls_api_stub::TlsConnector>(
+            host, port, httpbis::ClientTlsOption::Plain, conf)
+    }
+
+    /// Create a channel connected to specified host and port.
+    pub fn new_tls<C : tls_api::TlsConnector>(host: &str, port: u16, conf: ClientConf)
+        -> result::Result<Channel>
+    {
+        let connector = C::builder()
+            .and_then(|b| b.build())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Channel::new_tls_with_connector(host, port, connector, conf)
+    }
+
+    /// Create a channel connected to specified host and port
+    /// with configured TLS connector.
+    ///
+    /// Certificate verification (custom CA, pinning, accepting invalid
+    /// certificates in tests) is configured on the TLS library before
+    /// building the connector, e. g. with `TlsConnectorBuilder::underlying_mut`.
+    pub fn new_tls_with_connector<C : tls_api::TlsConnector>(
+        host: &str, port: u16, connector: C, conf: ClientConf)
+        -> result::Result<Channel>
+    {
+        let tls = httpbis::ClientTlsOption::Tls(host.to_owned(), Arc::new(connector));
+        Channel::new_resolved(host, port, tls, conf)
+    }
+
+    /// Resolve host and connect according to balancing policy,
+    /// then follow address updates from resolver.
+    fn new_resolved<C : tls_api::TlsConnector>(
+        host: &str, port: u16, tls: httpbis::ClientTlsOption<C>, conf: ClientConf)
+        -> result::Result<Channel>
+    {
+        let resolver = match conf.resolver {
+            Some(ref resolver) => resolver.clone(),
+            None => Arc::new(DefaultResolver::with_threads(conf.resolver_threads.unwrap_or(1))),
+        };
+        let addrs = resolver.resolve_weighted(host, port).wait()?;
+
+        let min_re_resolution_interval = conf.min_re_resolution_interval
+            .unwrap_or_else(default_min_re_resolution_interval);
+
+        let transport = Http2Transport::new(addrs, host, tls, conf)?;
+        let balancer = &transport.balancer;
+
+        // backends may have moved to new addresses
+        let re_resolve = ReResolveThread::spawn(
+            resolver.clone(), host.to_owned(), port, AddressUpdates::new(balancer));
+        let re_resolve = move || re_resolve.re_resolve();
+        balancer.set_re_resolve(Box::new(re_resolve), min_re_resolution_interval);
+
+        resolver.watch(host, port, AddressUpdates::new(balancer));
+        Ok(Channel::with_transport(transport))
+    }
+
+    /// Create a channel connected to specified target.
+    ///
+    /// TLS connector type is only used if `target.tls` is set.
+    pub fn new_target<C : tls_api::TlsConnector>(target: &ClientTarget, conf: ClientConf)
+        -> result::Result<Channel>
+    {
+        if target.tls {
+            Channel::new_tls::<C>(&target.host, target.port, conf)
+        } else {
+            Channel::new_plain(&target.host, target.port, conf)
+        }
+    }
+
+    /// Create a channel connected to target specified as string
+    /// like `https://example.com` or `localhost:50051`.
+    ///
+    /// See `ClientTarget::parse` for rules of TLS and port inference.
+    pub fn new_url<C : tls_api::TlsConnector>(url: &str, conf: ClientConf)
+        -> result::Result<Channel>
+    {
+        Channel::new_target::<C>(&ClientTarget::parse(url)?, conf)
+    }
+
+    /// Create a channel connected to specified address.
+    pub fn new_expl<C : tls_api::TlsConnector>(addr: &SocketAddr, host: &str, tls: httpbis::ClientTlsOption<C>, conf: ClientConf)
+        -> result::Result<Channel>
+    {
+        let transport = Http2Transport::new(vec![WeightedAddr::new(*addr)], host, tls, conf)?;
+        Ok(Channel::with_transport(transport))
+    }
+
+    /// Create a channel sending calls with custom transport.
+    pub fn with_transport(transport: Arc<ClientTransport>) -> Channel {
+        Channel {
+            transport: transport,
+        }
+    }
+
+    /// Transport of this channel, e. g. to wrap it in `RecordingTransport`.
+    pub fn transport(&self) -> Arc<ClientTransport> {
+        self.transport.clone()
+    }
+
+    /// Establish connections (including TLS and HTTP/2 handshakes)
+    /// to all addresses of the channel, so subsequent calls
+    /// don't pay connection latency.
+    ///
+    /// Future fails if any address cannot be connected.
+    pub fn warm_up(&self) -> GrpcFuture<()> {
+        self.transport.warm_up()
+    }
+
+    /// Fail calls in progress and all future calls with
+    /// `UNAVAILABLE: client shut down` and close connections.
+    ///
+    /// Affects all clients sharing this channel. Event loop
+    /// thread of a connection exits when the last call using it is dropped.
+    pub fn shutdown(&self) {
+        self.transport.shutdown()
+    }
+
+    /// Addresses, state and number of active calls of connections
+    /// of this channel, for debugging.
+    pub fn connections(&self) -> Vec<ConnectionState> {
+        self.transport.connections()
+    }
+
+    /// Channel is used by clients or other clones of it.
+    pub(crate) fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.transport) > 1
+    }
+}
+
+/// Thread resolving host again when balancer asks for it.
+///
+/// Thread exits and is joined when balancer releases it,
+/// on shutdown or drop of the channel.
+struct ReResolveThread {
+    tx: Mutex<Option<mpsc::Sender<()>>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ReResolveThread {
+    fn spawn(resolver: Arc<Resolver>, host: String, port: u16, updates: AddressUpdates)
+        -> ReResolveThread
     {
+        let (tx, rx) = mpsc::channel();
+        let spawned = thread::Builder::new()
+            .name("grpc-re-resolve".to_owned())
+            .spawn(move || {
+                while let Ok(()) = rx.recv() {
+                    // requests made while previous resolution was running
+                    while let Ok(()) = rx.try_recv() {}
+                    match resolver.resolve_weighted(&host, port).wait() {
+                        Ok(addrs) => drop(updates.update_weighted(addrs)),
+                        Err(e) => warn!("failed to resolve {}: {:?}", host, e),
+                    }
+                }
+            });
+        let thread = match spawned {
+            Ok(thread) => Some(thread),
+            Err(e) => {
+                warn!("failed to start resolver thread: {}", e);
+                None
+            }
+        };
+        ReResolveThread {
+            tx: Mutex::new(Some(tx)),
+            thread: thread,
+        }
+    }
+
+    fn re_resolve(&self) {
+        if let Some(ref tx) = *self.tx.lock().unwrap() {
+            drop(tx.send(()));
+        }
+    }
+}
+
+impl Drop for ReResolveThread {
+    fn drop(&mut self) {
+        drop(self.tx.lock().unwrap().take());
+        if let Some(thread) = self.thread.take() {
+            // last reference to balancer may be released by the thread itself
+            if thread.thread().id() != thread::current().id() {
+                drop(thread.join());
+            }
+        }
+    }
+}
+
+impl Http2Transport {
+    fn new<C : tls_api::TlsConnector>(
+        addrs: Vec<WeightedAddr>, host: &str, tls: httpbis::ClientTlsOption<C>, conf: ClientConf)
+        -> result::Result<Arc<Http2Transport>>
+    {
+        let codecs = Arc::new(CodecRegistry::new(&conf.codecs));
+        let encoder = MessageEncoder {
+            codec: codecs.find_configured(&conf.compression)?,
+            min_message_size: conf.compression_min_message_size.unwrap_or(0),
+            pool: conf.buffer_pool.clone(),
+        };
+        let max_receive_message_size = conf.max_receive_message_size;
+        let metadata_duplicate_keys = conf.metadata_duplicate_keys;
+        let write_strategy = conf.write_strategy.clone();
+        let eager_connect = conf.eager_connect;
+        let stats_handlers = conf.stats_handlers.clone();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let balancing_policy = conf.balancing_policy()?;
+        let outlier_detection = conf.outlier_detection.clone();
+
         let mut conf = conf;
         conf.http.thread_name =
             Some(conf.http.thread_name.unwrap_or_else(|| "grpc-client-loop".to_owned()));
+        if let Some(timeout) = conf.connect_timeout {
+            conf.http.connection_timeout = Some(timeout);
+        }
+        let establish_timeout = conf.handshake_timeout
+            .map(|timeout| timeout + conf.connect_timeout.unwrap_or(Duration::from_secs(0)));
+
+        let http_scheme = tls.http_scheme();
 
-        httpbis::Client::new_plain(host, port, conf.http)
-            .map(|client| {
-                Client {
-                    client: ::std::sync::Arc::new(client),
-                    host: host.to_owned(),
-                    http_scheme: HttpScheme::Http,
+        let http_conf = conf.http;
+        let connector = move |addr: &SocketAddr| {
+            let tls = match tls {
+                httpbis::ClientTlsOption::Plain => httpbis::ClientTlsOption::Plain,
+                httpbis::ClientTlsOption::Tls(ref host, ref connector) => {
+                    httpbis::ClientTlsOption::Tls(host.clone(), connector.clone())
                 }
-            })
-            .map_err(Error::from)
+            };
+            httpbis::Client::new_expl(addr, tls, http_conf.clone()).map_err(Error::from)
+        };
+
+        let balancer = Balancer::new(
+            balancing_policy, outlier_detection, establish_timeout, Box::new(connector), addrs)?;
+
+        let transport = Arc::new(Http2Transport {
+            balancer: Arc::new(balancer),
+            host: host.to_owned(),
+            http_scheme: http_scheme,
+            codecs: codecs,
+            encoder: encoder,
+            write_strategy: write_strategy,
+            max_receive_message_size: max_receive_message_size,
+            metadata_duplicate_keys: metadata_duplicate_keys,
+            stats_handlers: stats_handlers,
+            shutdown_rx: shutdown_rx.shared(),
+            shutdown_tx: Mutex::new(Some(shutdown_tx)),
+        });
+
+        if eager_connect {
+            // constructor doesn't wait, calls reconnect if it fails
+            let warm_up = transport.warm_up();
+            let spawned = thread::Builder::new()
+                .name("grpc-eager-connect".to_owned())
+                .spawn(move || {
+                    if let Err(e) = warm_up.wait() {
+                        warn!("eager connect failed: {:?}", e);
+                    }
+                });
+            if let Err(e) = spawned {
+                warn!("failed to start eager connect thread: {}", e);
+            }
+        }
+
+        Ok(transport)
     }
+}
 
-    /// Create a clone of this client but refer to same httpbis::Client.
-    pub fn clone(&self)
-        -> Client
-    {
+/// gRPC client implementation.
+/// Used by generated code.
+///
+/// Client can be used from many threads at once: calls are not serialized,
+/// each call is a separate HTTP/2 stream multiplexed on the shared connection.
+/// Streams are allocated by `httpbis` on its event loop thread.
+///
+/// Clones are cheap and share the connection.
+///
+/// Responses returned by calls don't borrow the client: they are
+/// `Send + 'static`, so they can be spawned or stored, and calls
+/// in progress continue after the client is dropped.
+#[derive(Clone)]
+pub struct Client {
+    transport: Arc<ClientTransport>,
+    interceptors: Arc<Vec<Arc<ClientInterceptor>>>,
+    inspectors: Arc<Vec<Arc<MessageInspector>>>,
+}
+
+impl Client {
+    /// Create a client using existing channel.
+    pub fn with_channel(channel: Channel) -> Client {
         Client {
-            client: self.client.clone(),
-            host: self.host.to_owned(),
-            http_scheme: HttpScheme::Http,
+            transport: channel.transport,
+            interceptors: Arc::new(Vec::new()),
+            inspectors: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Channel used by this client, can be used to create clients of other services.
+    pub fn channel(&self) -> Channel {
+        Channel {
+            transport: self.transport.clone(),
+        }
+    }
+
+    /// Connect to all addresses of the channel, see `Channel::warm_up`.
+    pub fn warm_up(&self) -> GrpcFuture<()> {
+        self.channel().warm_up()
+    }
+
+    /// Shut down the channel of this client, see `Channel::shutdown`.
+    pub fn shutdown(&self) {
+        self.channel().shutdown()
+    }
+
+    /// Create a client connected to specified host and port.
+    pub fn new_plain(host: &str, port: u16, conf: ClientConf)
+        -> result::Result<Client>
+    {
+        Channel::new_plain(host, port, conf).map(Client::with_channel)
+    }
+
+    /// Add an interceptor invoked for each call made by this client.
+    ///
+    /// Interceptors are invoked in order they are added.
+    /// Clones of this client created before this call are not affected.
+    pub fn add_interceptor(&mut self, interceptor: Arc<ClientInterceptor>) {
+        Arc::make_mut(&mut self.interceptors).push(interceptor);
+    }
+
+    /// Add an inspector of serialized messages of calls made by this client.
+    ///
+    /// Only calls with method descriptors are inspected,
+    /// not calls of `call_bytes` and `call_unary_bytes`.
+    pub fn add_message_inspector(&mut self, inspector: Arc<MessageInspector>) {
+        Arc::make_mut(&mut self.inspectors).push(inspector);
+    }
+
+    /// Client for calls made by server handler while serving call with given options.
+    ///
+    /// Calls of returned client are limited by deadline of that call
+    /// and cancelled when it is cancelled, so they never outlive it.
+    /// They also have request ID of that call.
+    pub fn for_request(&self, parent: &RequestOptions) -> Client {
+        let mut client = self.clone();
+        if let Some(deadline) = parent.deadline {
+            Arc::make_mut(&mut client.interceptors).insert(0, Arc::new(PropagateDeadline { deadline }));
         }
+        if let Some(ref cancellation) = parent.cancellation {
+            let propagate = PropagateCancellation { cancellation: cancellation.clone() };
+            Arc::make_mut(&mut client.interceptors).insert(0, Arc::new(propagate));
+        }
+        if let Some(ref request_id) = parent.request_id {
+            let propagate = PropagateRequestId { request_id: request_id.clone() };
+            Arc::make_mut(&mut client.interceptors).insert(0, Arc::new(propagate));
+        }
+        client
+    }
+
+    /// Attach credentials metadata to each call of this client.
+    pub fn add_call_credentials(&mut self, credentials: Arc<CallCredentials>) {
+        self.add_interceptor(Arc::new(CallCredentialsInterceptor::new(credentials)));
     }
 
     /// Create a client connected to specified host and port.
     pub fn new_tls<C : tls_api::TlsConnector>(host: &str, port: u16, conf: ClientConf)
         -> result::Result<Client>
     {
-        let mut conf = conf;
-        conf.http.thread_name =
-            Some(conf.http.thread_name.unwrap_or_else(|| "grpc-client-loop".to_owned()));
+        Channel::new_tls::<C>(host, port, conf).map(Client::with_channel)
+    }
 
-        httpbis::Client::new_tls::<C>(host, port, conf.http)
-            .map(|client| {
-                Client {
-                    client: ::std::sync::Arc::new(client),
-                    host: host.to_owned(),
-                    http_scheme: HttpScheme::Https,
-                }
-            })
-            .map_err(Error::from)
+    /// Create a client connected to specified host and port
+    /// with configured TLS connector, see `Channel::new_tls_with_connector`.
+    pub fn new_tls_with_connector<C : tls_api::TlsConnector>(
+        host: &str, port: u16, connector: C, conf: ClientConf)
+        -> result::Result<Client>
+    {
+        Channel::new_tls_with_connector(host, port, connector, conf).map(Client::with_channel)
     }
 
-    pub fn new_expl<C : tls_api::TlsConnector>(addr: &SocketAddr, host: &str, tls: httpbis::ClientTlsOption<C>, conf: ClientConf)
+    /// Create a client connected to specified target.
+    ///
+    /// TLS connector type is only used if `target.tls` is set.
+    pub fn new_target<C : tls_api::TlsConnector>(target: &ClientTarget, conf: ClientConf)
         -> result::Result<Client>
     {
-        let mut conf = conf;
-        conf.http.thread_name =
-            Some(conf.http.thread_name.unwrap_or_else(|| "grpc-client-loop".to_owned()));
+        Channel::new_target::<C>(target, conf).map(Client::with_channel)
+    }
 
-        let http_scheme = tls.http_scheme();
+    /// Create a client connected to target specified as string
+    /// like `https://example.com` or `localhost:50051`.
+    ///
+    /// See `ClientTarget::parse` for rules of TLS and port inference.
+    /// To override inferred values, parse target explicitly
+    /// and use `new_target`.
+    pub fn new_url<C : tls_api::TlsConnector>(url: &str, conf: ClientConf)
+        -> result::Result<Client>
+    {
+        Channel::new_url::<C>(url, conf).map(Client::with_channel)
+    }
 
-        httpbis::Client::new_expl(addr, tls, conf.http)
-            .map(|client| {
-                Client {
-                    client: ::std::sync::Arc::new(client),
-                    host: host.to_owned(),
-                    http_scheme: http_scheme,
-                }
-            })
-            .map_err(Error::from)
+    pub fn new_expl<C : tls_api::TlsConnector>(addr: &SocketAddr, host: &str, tls: httpbis::ClientTlsOption<C>, conf: ClientConf)
+        -> result::Result<Client>
+    {
+        Channel::new_expl(addr, host, tls, conf).map(Client::with_channel)
     }
 
     fn call_impl<Req, Resp>(
@@ -128,51 +801,134 @@ impl Client {
             Req : Send + 'static,
             Resp : Send + 'static,
     {
-        info!("start call {}", method.name);
+        let req = {
+            let method = method.clone();
+            StreamingRequest::new(req.0.and_then(move |req| {
+                Ok(Bytes::from(method.req_marshaller.write(&req)?))
+            }))
+        };
 
-        let mut headers = Headers(vec![
-            Header::new(Bytes::from_static(b":method"), Bytes::from_static(b"POST")),
-            Header::new(Bytes::from_static(b":path"), method.name.clone()),
-            Header::new(Bytes::from_static(b":authority"), self.host.clone()),
-            Header::new(Bytes::from_static(b":scheme"), Bytes::from_static(self.http_scheme.as_bytes())),
-            Header::new(Bytes::from_static(b"content-type"), Bytes::from_static(b"application/grpc")),
-            Header::new(Bytes::from_static(b"te"), Bytes::from_static(b"trailers")),
-        ]);
+        self.call_impl_bytes(options, req, method)
+    }
 
-        headers.extend(options.metadata.into_headers());
+    // single request is serialized before starting the call,
+    // so serialization (and validation) errors are reported as is
+    fn call_impl_single<Req, Resp>(
+        &self,
+        options: RequestOptions,
+        req: Req,
+        method: Arc<MethodDescriptor<Req, Resp>>)
+        -> StreamingResponse<Resp>
+        where
+            Req : Send + 'static,
+            Resp : Send + 'static,
+    {
+        match method.req_marshaller.write(&req) {
+            Ok(req) => self.call_impl_bytes(options, StreamingRequest::once(Bytes::from(req)), method),
+            Err(e) => StreamingResponse::err(e),
+        }
+    }
 
-        let request_frames = {
-            let method = method.clone();
-            req.0
-                .and_then(move |req| {
-                    let grpc_frame = method.req_marshaller.write(&req)?;
-                    Ok(Bytes::from(write_grpc_frame_to_vec(&grpc_frame)))
-                })
-                .map_err(|_e| httpbis::Error::Other("grpc error")) // TODO: preserve error
+    fn call_impl_bytes<Req, Resp>(
+        &self,
+        mut options: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        method: Arc<MethodDescriptor<Req, Resp>>)
+        -> StreamingResponse<Resp>
+        where
+            Req : Send + 'static,
+            Resp : Send + 'static,
+    {
+        if options.content_subtype.is_none() {
+            options.content_subtype = method.req_marshaller.content_subtype().map(|s| s.to_owned());
+        }
+
+        let inspectors = self.inspectors.clone();
+        let info = Arc::new(MethodInfo::of(&method));
+        let req = if inspectors.is_empty() {
+            req
+        } else {
+            let inspectors = inspectors.clone();
+            let info = info.clone();
+            StreamingRequest::new(req.0.inspect(move |m| inspect_sent(&inspectors, &info, m)))
         };
 
-        let http_response_stream = self.client
-            .start_request(
-                headers,
-                HttpStreamAfterHeaders::bytes(request_frames));
+        self.call_serialized(options, req, method.name.clone(), method.options.clone())
+            .and_then_items(move |message| {
+                inspect_received(&inspectors, &info, &message);
+                method.resp_marshaller.read(message)
+            })
+    }
+
+    fn call_serialized(
+        &self,
+        options: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        method: String,
+        method_options: MethodOptions)
+        -> StreamingResponse<Bytes>
+    {
+        info!("start call {}", method);
 
-        let grpc_frames = http_response_to_grpc_frames(http_response_stream);
+        let next = ClientNext {
+            interceptors: self.interceptors.clone(),
+            index: 0,
+            method: method,
+            method_options: method_options,
+            transport: self.transport.clone(),
+        };
 
-        grpc_frames.and_then_items(move |frame| method.resp_marshaller.read(frame))
+        next.call(options, req)
+    }
+
+    /// Call unary method with already serialized request,
+    /// returning response without parsing it.
+    ///
+    /// Method name must be a full path like `/package.Service/Method`.
+    pub fn call_unary_bytes(&self, o: RequestOptions, req: Bytes, method: &str)
+        -> SingleResponse<Bytes>
+    {
+        self.call_bytes(o, StreamingRequest::once(req), method).single()
+    }
+
+    /// Call method of any kind with already serialized requests,
+    /// returning responses without parsing them.
+    ///
+    /// Useful for proxies and for callers which cache serialized requests.
+    pub fn call_bytes(&self, o: RequestOptions, req: StreamingRequest<Bytes>, method: &str)
+        -> StreamingResponse<Bytes>
+    {
+        self.call_serialized(o, req, method.to_owned(), MethodOptions::default())
     }
 
     pub fn call_unary<Req, Resp>(&self, o: RequestOptions, req: Req, method: Arc<MethodDescriptor<Req, Resp>>)
                                  -> SingleResponse<Resp>
             where Req: Send + 'static, Resp: Send + 'static
     {
-        self.call_impl(o, StreamingRequest::once(req), method).single()
+        self.call_impl_single(o, req, method).single()
+    }
+
+    /// Unary call returning statistics of the call with the response.
+    ///
+    /// Statistics are also collected into `RequestOptions::call_stats` if it is set.
+    pub fn call_unary_with_stats<Req, Resp>(&self, mut o: RequestOptions, req: Req, method: Arc<MethodDescriptor<Req, Resp>>)
+                                            -> SingleResponse<(Resp, CallStats)>
+            where Req: Send + 'static, Resp: Send + 'static
+    {
+        let stats = o.call_stats.get_or_insert_with(CallStatsCollector::new).clone();
+        SingleResponse::new(self.call_unary(o, req, method).0.map(move |(metadata, result)| {
+            let result: GrpcFuture<_> = Box::new(result.map(move |(message, trailing)| {
+                ((message, stats.get()), trailing)
+            }));
+            (metadata, result)
+        }))
     }
 
     pub fn call_server_streaming<Req, Resp>(&self, o: RequestOptions, req: Req, method: Arc<MethodDescriptor<Req, Resp>>)
                                             -> StreamingResponse<Resp>
             where Req: Send + 'static, Resp: Send + 'static
     {
-        self.call_impl(o, StreamingRequest::once(req), method)
+        self.call_impl_single(o, req, method)
     }
 
     pub fn call_client_streaming<Req, Resp>(&self, o: RequestOptions, req: StreamingRequest<Req>, method: Arc<MethodDescriptor<Req, Resp>>)
@@ -190,7 +946,144 @@ impl Client {
     }
 }
 
+/// Report response headers and DATA frames to observer.
+fn observe_http_response(resp: httpbis::Response, observer: CallObserver) -> httpbis::Response {
+    httpbis::Response::new(resp.0.map(move |(headers, rem)| {
+        observer.event(&CallEvent::ResponseHeaders);
+        let rem = rem.inspect(move |part| {
+            if let DataOrTrailers::Data(ref data, ..) = *part {
+                observer.event(&CallEvent::ResponseData { wire_bytes: data.len() });
+            }
+        });
+        (headers, HttpStreamAfterHeaders::new(rem))
+    }))
+}
+
+/// Report response messages and end of call to observer.
+fn observe_grpc_response(resp: StreamingResponse<Bytes>, observer: CallObserver)
+    -> StreamingResponse<Bytes>
+{
+    StreamingResponse::new(resp.0.then(move |r| {
+        match r {
+            Ok((metadata, frames)) => {
+                let observer_copy = observer.clone();
+                let frames = frames.map_items(move |frame| {
+                    observer_copy.event(&CallEvent::ResponseMessage { bytes: frame.len() });
+                    frame
+                });
+                let frames = FinishOnEnd { stream: frames.0, observer: observer };
+                Ok((metadata, GrpcStreamWithTrailingMetadata::new(frames)))
+            }
+            Err(e) => {
+                observer.event(&CallEvent::End { error: Some(&e) });
+                Err(e)
+            }
+        }
+    }))
+}
+
+/// Resolves when channel is shut down by `Channel::shutdown`.
+///
+/// Sender is also dropped when the last channel handle is dropped,
+/// which is not a shutdown: calls outlive the client, so then
+/// the future never resolves.
+fn shutdown_signal(shutdown: Shared<oneshot::Receiver<()>>) -> GrpcFuture<()> {
+    Box::new(shutdown.then(|r| match r {
+        Ok(..) => Either::A(future::ok::<(), Error>(())),
+        Err(..) => Either::B(future::empty::<(), Error>()),
+    }))
+}
+
+/// Fail response with `client_shut_down` when channel is shut down.
+fn fail_on_shutdown(resp: StreamingResponse<Bytes>, shutdown: Shared<oneshot::Receiver<()>>)
+    -> StreamingResponse<Bytes>
+{
+    let shutdown_copy = shutdown.clone();
+    StreamingResponse::new(resp.0.select2(shutdown_signal(shutdown)).then(move |r| {
+        match r {
+            Ok(Either::A(((metadata, frames), _))) => {
+                let frames = FailOnShutdown {
+                    stream: frames.0,
+                    shutdown: shutdown_signal(shutdown_copy),
+                };
+                Ok((metadata, GrpcStreamWithTrailingMetadata::new(frames)))
+            }
+            Err(Either::A((e, _))) => Err(e),
+            Ok(Either::B(..)) | Err(Either::B(..)) => Err(client_shut_down()),
+        }
+    }))
+}
+
+struct FailOnShutdown<S> {
+    stream: S,
+    shutdown: GrpcFuture<()>,
+}
+
+impl<S : Stream<Error=Error>> Stream for FailOnShutdown<S> {
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, Error> {
+        match self.shutdown.poll() {
+            Ok(Async::NotReady) => self.stream.poll(),
+            Ok(Async::Ready(..)) | Err(..) => Err(client_shut_down()),
+        }
+    }
+}
+
+/// Error of request stream, saved before it is passed to `httpbis`,
+/// which only uses it to reset the stream.
+type RequestError = Arc<Mutex<Option<Error>>>;
+
+/// Fail the call with request stream error (e. g. `INVALID_ARGUMENT`
+/// from validation) rather than with the stream reset it caused.
+fn fail_with_request_error(resp: StreamingResponse<Bytes>, request_error: RequestError)
+    -> StreamingResponse<Bytes>
+{
+    fn or_request_error(request_error: &RequestError, e: Error) -> Error {
+        request_error.lock().unwrap().take().unwrap_or(e)
+    }
+
+    StreamingResponse::new(resp.0.then(move |r| {
+        match r {
+            Ok((metadata, frames)) => {
+                let frames = frames.0.map_err(move |e| or_request_error(&request_error, e));
+                Ok((metadata, GrpcStreamWithTrailingMetadata::new(frames)))
+            }
+            Err(e) => Err(or_request_error(&request_error, e)),
+        }
+    }))
+}
+
+fn record_result(resp: StreamingResponse<Bytes>, balancer: Arc<Balancer>, call: Outstanding)
+    -> StreamingResponse<Bytes>
+{
+    StreamingResponse::new(resp.0.then(move |r| {
+        match r {
+            Ok((metadata, frames)) => {
+                let frames = RecordResult {
+                    stream: frames.0,
+                    balancer: balancer,
+                    call: call,
+                };
+                Ok((metadata, GrpcStreamWithTrailingMetadata::new(frames)))
+            }
+            Err(e) => {
+                balancer.record(&call.subchannel, Some(&e));
+                Err(call.subchannel.describe_error(e))
+            }
+        }
+    }))
+}
+
 fn _assert_types() {
     ::assert_types::assert_send::<Client>();
     ::assert_types::assert_sync::<Client>();
+    ::assert_types::assert_send::<Channel>();
+    ::assert_types::assert_sync::<Channel>();
+    ::assert_types::assert_send_static::<SingleResponse<Bytes>>();
+    ::assert_types::assert_send_static::<StreamingResponse<Bytes>>();
+    ::assert_types::assert_send_static::<StreamingRequest<Bytes>>();
+    ::assert_types::assert_send_static::<GrpcFuture<Bytes>>();
+    ::assert_types::assert_send_static::<GrpcStream<Bytes>>();
 }
@@ -0,0 +1,439 @@
+//! Message compression.
+//!
+//! Compression is negotiated per call with `grpc-encoding`
+//! and `grpc-accept-encoding` headers.
+
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::str;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use httpbis::Headers;
+
+use error::Error;
+use error::GrpcMessageError;
+use grpc::GrpcStatus;
+use grpc_frame::GRPC_HEADER_LEN;
+use grpc_frame::grpc_frame_header;
+use grpc_frame::write_grpc_frame_with_flag;
+use buffer_pool::BufferPool;
+use result;
+
+
+pub const HEADER_GRPC_ENCODING: &'static str = "grpc-encoding";
+pub const HEADER_GRPC_ACCEPT_ENCODING: &'static str = "grpc-accept-encoding";
+
+/// Name of encoding used for uncompressed messages.
+pub const IDENTITY: &'static str = "identity";
+
+
+/// Compression algorithm.
+pub trait Codec : fmt::Debug + Send + Sync + 'static {
+    /// Name used in `grpc-encoding` header.
+    fn name(&self) -> &str;
+
+    /// Compress message into `out`.
+    fn compress(&self, data: &[u8], out: &mut Write) -> io::Result<()>;
+
+    /// Decompress message into `out`.
+    fn decompress(&self, data: &[u8], out: &mut Write) -> io::Result<()>;
+}
+
+
+/// `gzip` codec.
+#[cfg(feature = "gzip")]
+#[derive(Debug)]
+pub struct GzipCodec;
+
+#[cfg(feature = "gzip")]
+impl Codec for GzipCodec {
+    fn name(&self) -> &str {
+        "gzip"
+    }
+
+    fn compress(&self, data: &[u8], out: &mut Write) -> io::Result<()> {
+        let mut encoder = ::flate2::write::GzEncoder::new(out, ::flate2::Compression::default());
+        encoder.write_all(data)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn decompress(&self, data: &[u8], out: &mut Write) -> io::Result<()> {
+        io::copy(&mut ::flate2::read::GzDecoder::new(data), out)?;
+        Ok(())
+    }
+}
+
+/// `snappy` codec.
+#[cfg(feature = "snappy")]
+#[derive(Debug)]
+pub struct SnappyCodec;
+
+#[cfg(feature = "snappy")]
+impl Codec for SnappyCodec {
+    fn name(&self) -> &str {
+        "snappy"
+    }
+
+    fn compress(&self, data: &[u8], out: &mut Write) -> io::Result<()> {
+        let mut writer = ::snap::Writer::new(out);
+        writer.write_all(data)?;
+        writer.flush()
+    }
+
+    fn decompress(&self, data: &[u8], out: &mut Write) -> io::Result<()> {
+        io::copy(&mut ::snap::Reader::new(data), out)?;
+        Ok(())
+    }
+}
+
+/// `zstd` codec.
+#[cfg(feature = "zstd")]
+#[derive(Debug)]
+pub struct ZstdCodec {
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Default for ZstdCodec {
+    fn default() -> ZstdCodec {
+        ZstdCodec { level: 0 }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Codec for ZstdCodec {
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn compress(&self, data: &[u8], out: &mut Write) -> io::Result<()> {
+        ::zstd::stream::copy_encode(data, out, self.level)
+    }
+
+    fn decompress(&self, data: &[u8], out: &mut Write) -> io::Result<()> {
+        ::zstd::stream::copy_decode(data, out)
+    }
+}
+
+/// Codecs enabled by crate features.
+fn builtin_codecs() -> Vec<Arc<Codec>> {
+    #[allow(unused_mut)]
+    let mut r: Vec<Arc<Codec>> = Vec::new();
+    #[cfg(feature = "gzip")]
+    r.push(Arc::new(GzipCodec));
+    #[cfg(feature = "snappy")]
+    r.push(Arc::new(SnappyCodec));
+    #[cfg(feature = "zstd")]
+    r.push(Arc::new(ZstdCodec::default()));
+    r
+}
+
+
+fn unimplemented_encoding(name: &str) -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::Unimplemented as i32,
+        grpc_message: format!("unsupported grpc-encoding: {}", name),
+    })
+}
+
+/// Codecs known to client or server: configured ones and built-in ones.
+#[derive(Debug)]
+pub(crate) struct CodecRegistry {
+    codecs: Vec<Arc<Codec>>,
+}
+
+impl CodecRegistry {
+    /// Codecs take precedence over built-in codecs with the same name.
+    pub fn new(codecs: &[Arc<Codec>]) -> CodecRegistry {
+        let mut all = codecs.to_vec();
+        for codec in builtin_codecs() {
+            if !all.iter().any(|c| c.name() == codec.name()) {
+                all.push(codec);
+            }
+        }
+        CodecRegistry { codecs: all }
+    }
+
+    pub fn find(&self, name: &str) -> Option<Arc<Codec>> {
+        self.codecs.iter().find(|c| c.name() == name).cloned()
+    }
+
+    /// Codec for configured compression name, `None` for identity.
+    pub fn find_configured(&self, name: &Option<String>) -> result::Result<Option<Arc<Codec>>> {
+        match *name {
+            None => Ok(None),
+            Some(ref name) if name == IDENTITY => Ok(None),
+            Some(ref name) => self.find(name).map(Some).ok_or_else(|| unimplemented_encoding(name)),
+        }
+    }
+
+    /// Value of `grpc-accept-encoding` header.
+    pub fn accept_encoding(&self) -> String {
+        let mut names = vec![IDENTITY];
+        names.extend(self.codecs.iter().map(|c| c.name()));
+        names.join(",")
+    }
+
+    /// Codec to decompress messages of stream with given headers.
+    pub fn decoder(&self, headers: &Headers) -> result::Result<Option<Arc<Codec>>> {
+        match headers.get_opt(HEADER_GRPC_ENCODING) {
+            None => Ok(None),
+            Some(name) if name == IDENTITY => Ok(None),
+            Some(name) => self.find(name).map(Some).ok_or_else(|| unimplemented_encoding(name)),
+        }
+    }
+}
+
+fn message_too_large(limit: usize) -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::ResourceExhausted as i32,
+        grpc_message: format!("received message larger than max ({} bytes)", limit),
+    })
+}
+
+/// Output of decompression which stops accepting data after limit.
+struct LimitedWriter {
+    buf: Vec<u8>,
+    limit: usize,
+    exceeded: bool,
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.limit {
+            self.exceeded = true;
+            return Err(io::Error::new(io::ErrorKind::Other, "message too large"));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decompresses received messages and checks their size.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MessageDecoder {
+    /// Codec from `grpc-encoding` header
+    pub codec: Option<Arc<Codec>>,
+    pub max_message_size: Option<usize>,
+    /// Pool of buffers frames split across DATA frames are joined in.
+    pub pool: Option<Arc<BufferPool>>,
+}
+
+impl MessageDecoder {
+    /// Check size of frame before it is completely received.
+    pub fn check_frame_len(&self, len: usize) -> result::Result<()> {
+        match self.max_message_size {
+            Some(limit) if len > limit => Err(message_too_large(limit)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn decode(&self, compressed: bool, message: Bytes) -> result::Result<Bytes> {
+        self.check_frame_len(message.len())?;
+        if !compressed {
+            return Ok(message);
+        }
+        let codec = match self.codec {
+            Some(ref codec) => codec,
+            None => return Err(Error::Protocol("compressed frame without grpc-encoding")),
+        };
+        // size is checked during decompression,
+        // so compression bomb is not expanded in memory
+        let limit = self.max_message_size.unwrap_or(usize::max_value());
+        let mut out = LimitedWriter { buf: Vec::new(), limit: limit, exceeded: false };
+        let r = codec.decompress(&message, &mut out);
+        if out.exceeded {
+            return Err(message_too_large(limit));
+        }
+        r?;
+        Ok(Bytes::from(out.buf))
+    }
+}
+
+/// Compresses sent messages.
+///
+/// Compressed flag is set per message: messages smaller than
+/// `min_message_size` are sent uncompressed even if the call uses compression.
+#[derive(Debug, Clone, Default)]
+/// Per-message compression decision on a compressed call,
+/// e. g. to compress large snapshots but not small deltas sent on the same stream.
+///
+/// Called with serialized message, overrides compression min message size.
+/// Has no effect on calls without compression.
+#[derive(Clone)]
+pub struct MessageCompression(Arc<Fn(&[u8]) -> bool + Send + Sync>);
+
+impl MessageCompression {
+    /// Compress messages for which `compress` returns `true`.
+    pub fn new<F>(compress: F) -> MessageCompression
+        where F : Fn(&[u8]) -> bool + Send + Sync + 'static
+    {
+        MessageCompression(Arc::new(compress))
+    }
+
+    /// Whether `message` should be compressed.
+    pub fn compress(&self, message: &[u8]) -> bool {
+        (self.0)(message)
+    }
+}
+
+impl fmt::Debug for MessageCompression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MessageCompression").finish()
+    }
+}
+
+
+pub(crate) struct MessageEncoder {
+    /// Codec from `grpc-encoding` header
+    pub codec: Option<Arc<Codec>>,
+    pub min_message_size: usize,
+    /// Pool of buffers frames are written into.
+    pub pool: Option<Arc<BufferPool>>,
+}
+
+impl MessageEncoder {
+    /// Encode message into frame.
+    ///
+    /// `compress` overrides decision based on message size,
+    /// message is never compressed if call has no codec.
+    pub fn encode(&self, message: &[u8], compress: Option<bool>) -> result::Result<Bytes> {
+        let compress = compress.unwrap_or(message.len() >= self.min_message_size);
+        let codec = match self.codec {
+            Some(ref codec) if compress => codec,
+            _ => return Ok(self.frame(message, false)),
+        };
+        let mut compressed = Vec::new();
+        codec.compress(message, &mut compressed)?;
+        Ok(self.frame(&compressed, true))
+    }
+
+    fn frame(&self, message: &[u8], compressed: bool) -> Bytes {
+        match self.pool {
+            Some(ref pool) => pool.frame(GRPC_HEADER_LEN + message.len(), |buf| {
+                buf.extend_from_slice(&grpc_frame_header(message.len(), compressed));
+                buf.extend_from_slice(message);
+            }),
+            None => Bytes::from(write_grpc_frame_with_flag(message, compressed)),
+        }
+    }
+}
+
+/// Check if peer accepts messages compressed with codec.
+pub(crate) fn accepts(headers: &Headers, codec: &Codec) -> bool {
+    accepted_by(&accept_encoding_of(headers), codec)
+}
+
+/// Values of all `grpc-accept-encoding` headers joined with `,`.
+pub(crate) fn accept_encoding_of(headers: &Headers) -> String {
+    let values: Vec<_> = headers.0.iter()
+        .filter(|h| h.name() == HEADER_GRPC_ACCEPT_ENCODING.as_bytes())
+        .filter_map(|h| str::from_utf8(&h.value).ok())
+        .collect();
+    values.join(",")
+}
+
+/// Check if codec is listed in `grpc-accept-encoding` header value.
+pub(crate) fn accepted_by(accept_encoding: &str, codec: &Codec) -> bool {
+    accept_encoding.split(',').any(|name| name.trim() == codec.name())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug)]
+    struct RepeatCodec;
+
+    // decompressed message is `data[0]` repeated `data[1] * 1000` times
+    impl Codec for RepeatCodec {
+        fn name(&self) -> &str {
+            "repeat"
+        }
+
+        fn compress(&self, _data: &[u8], _out: &mut Write) -> io::Result<()> {
+            unimplemented!()
+        }
+
+        fn decompress(&self, data: &[u8], out: &mut Write) -> io::Result<()> {
+            for _ in 0..data[1] {
+                out.write_all(&[data[0]; 1000])?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn decode_limit() {
+        let decoder = MessageDecoder {
+            codec: Some(Arc::new(RepeatCodec)),
+            max_message_size: Some(5000),
+            pool: None,
+        };
+
+        assert_eq!(5000, decoder.decode(true, Bytes::from(&b"a\x05"[..])).unwrap().len());
+        assert!(decoder.decode(true, Bytes::from(&b"a\x06"[..])).is_err());
+        assert!(decoder.decode(false, Bytes::from(vec![0; 5001])).is_err());
+        assert!(decoder.check_frame_len(5001).is_err());
+    }
+
+    #[derive(Debug)]
+    struct HalfCodec;
+
+    // compressed message is the first half of data
+    impl Codec for HalfCodec {
+        fn name(&self) -> &str {
+            "half"
+        }
+
+        fn compress(&self, data: &[u8], out: &mut Write) -> io::Result<()> {
+            out.write_all(&data[..(data.len() + 1) / 2])
+        }
+
+        fn decompress(&self, _data: &[u8], _out: &mut Write) -> io::Result<()> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn encode_per_message() {
+        let encoder = MessageEncoder {
+            codec: Some(Arc::new(HalfCodec)),
+            min_message_size: 4,
+            pool: None,
+        };
+
+        assert_eq!(b"\x01\x00\x00\x00\x02ab".to_vec(), encoder.encode(b"abcd", None).unwrap());
+        // too small
+        assert_eq!(b"\x00\x00\x00\x00\x03abc".to_vec(), encoder.encode(b"abc", None).unwrap());
+        // explicit override
+        assert_eq!(b"\x00\x00\x00\x00\x04abcd".to_vec(), encoder.encode(b"abcd", Some(false)).unwrap());
+        assert_eq!(b"\x01\x00\x00\x00\x01a".to_vec(), encoder.encode(b"ab", Some(true)).unwrap());
+
+        let encoder = MessageEncoder {
+            pool: Some(Arc::new(BufferPool::new(64, 1))),
+            ..encoder
+        };
+        assert_eq!(b"\x01\x00\x00\x00\x02ab".to_vec(), encoder.encode(b"abcd", None).unwrap());
+        assert_eq!(b"\x00\x00\x00\x00\x03abc".to_vec(), encoder.encode(b"abc", None).unwrap());
+    }
+
+    #[test]
+    fn encode_without_codec_ignores_override() {
+        let encoder = MessageEncoder {
+            codec: None,
+            min_message_size: 0,
+            pool: None,
+        };
+        assert_eq!(b"\x00\x00\x00\x00\x02ab".to_vec(), encoder.encode(b"ab", Some(true)).unwrap());
+    }
+}
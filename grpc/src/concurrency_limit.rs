@@ -0,0 +1,375 @@
+//! Server concurrency limit adapting to latency.
+//!
+//! Limit of calls in progress is adjusted after each call by the gradient
+//! of long-term average latency to the latency of the call: while latency
+//! is stable, limit grows, and when requests start queueing and latency
+//! grows, limit decreases and excess calls are rejected.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use futures::Async;
+use futures::Poll;
+use futures::future::Future;
+use futures::stream::Stream;
+
+use auth::pattern_matches;
+use error::Error;
+use error::GrpcMessageError;
+use grpc::GrpcStatus;
+use interceptor::*;
+use metadata::Metadata;
+use req::*;
+use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use stream_item::ItemOrMetadata;
+
+
+/// Limit is multiplied by this when call exceeds its deadline.
+const BACKOFF_RATIO: f64 = 0.9;
+
+/// How limit is adjusted.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimitConf {
+    pub initial_limit: u32,
+    pub min_limit: u32,
+    pub max_limit: u32,
+    /// Weight of new limit computed after a call, from 0 to 1
+    pub smoothing: f64,
+    /// Latency up to this times long-term average does not decrease limit
+    pub tolerance: f64,
+    /// Number of calls long-term average latency is computed over
+    pub long_window: u32,
+}
+
+impl Default for ConcurrencyLimitConf {
+    fn default() -> ConcurrencyLimitConf {
+        ConcurrencyLimitConf {
+            initial_limit: 20,
+            min_limit: 1,
+            max_limit: 1000,
+            smoothing: 0.2,
+            tolerance: 1.5,
+            long_window: 600,
+        }
+    }
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+struct Limiter {
+    conf: ConcurrencyLimitConf,
+    limit: f64,
+    /// Long-term average latency in seconds, zero before the first call
+    long_rtt: f64,
+    in_flight: usize,
+    /// Calls in progress and share of limit in percent of each partition
+    partitions: Vec<(usize, u32)>,
+}
+
+impl Limiter {
+    fn new(conf: ConcurrencyLimitConf) -> Limiter {
+        assert!(conf.min_limit > 0);
+        assert!(conf.min_limit <= conf.initial_limit && conf.initial_limit <= conf.max_limit);
+        Limiter {
+            limit: conf.initial_limit as f64,
+            conf,
+            long_rtt: 0.0,
+            in_flight: 0,
+            partitions: Vec::new(),
+        }
+    }
+
+    fn limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    /// Start a call, or return `false` if it should be rejected.
+    ///
+    /// Partition may exceed the limit while it uses less than its share.
+    fn start(&mut self, partition: Option<usize>) -> bool {
+        let limit = self.limit();
+        let accept = self.in_flight < limit || match partition {
+            Some(p) => {
+                let (in_flight, percent) = self.partitions[p];
+                in_flight < (limit * percent as usize / 100).max(1)
+            }
+            None => false,
+        };
+        if accept {
+            self.in_flight += 1;
+            if let Some(p) = partition {
+                self.partitions[p].0 += 1;
+            }
+        }
+        accept
+    }
+
+    /// Finish a call, with latency if it succeeded.
+    fn finish(&mut self, partition: Option<usize>, outcome: Outcome) {
+        let in_flight = self.in_flight;
+        self.in_flight -= 1;
+        if let Some(p) = partition {
+            self.partitions[p].0 -= 1;
+        }
+        match outcome {
+            Outcome::Success(rtt) => self.sample(secs(rtt), in_flight),
+            Outcome::DeadlineExceeded => {
+                self.limit = (self.limit * BACKOFF_RATIO).max(self.conf.min_limit as f64);
+            }
+            Outcome::Ignored => {}
+        }
+    }
+
+    fn sample(&mut self, rtt: f64, in_flight: usize) {
+        if self.long_rtt == 0.0 {
+            self.long_rtt = rtt;
+        } else {
+            self.long_rtt += (rtt - self.long_rtt) * 2.0 / (self.conf.long_window as f64 + 1.0);
+        }
+        if rtt <= 0.0 {
+            return;
+        }
+        // recover faster when load drops
+        if self.long_rtt / rtt > 2.0 {
+            self.long_rtt *= 0.95;
+        }
+        // limit is not reached, so latency tells nothing about it
+        if (in_flight as f64) < self.limit / 2.0 {
+            return;
+        }
+
+        let gradient = (self.conf.tolerance * self.long_rtt / rtt).max(0.5).min(1.0);
+        let new_limit = self.limit * gradient + self.limit.sqrt();
+        let limit = self.limit * (1.0 - self.conf.smoothing) + new_limit * self.conf.smoothing;
+        self.limit = limit.max(self.conf.min_limit as f64).min(self.conf.max_limit as f64);
+    }
+}
+
+enum Outcome {
+    Success(Duration),
+    DeadlineExceeded,
+    Ignored,
+}
+
+/// Call in progress, finished without latency sample if dropped.
+struct CallGuard {
+    limiter: Arc<Mutex<Limiter>>,
+    partition: Option<usize>,
+    start: Instant,
+    finished: bool,
+}
+
+impl CallGuard {
+    fn finish(&mut self, outcome: Outcome) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.limiter.lock().unwrap().finish(self.partition, outcome);
+    }
+
+    fn finish_with_error(&mut self, e: &Error) {
+        if e.grpc_status() == GrpcStatus::DeadlineExceeded as i32 {
+            self.finish(Outcome::DeadlineExceeded);
+        } else {
+            self.finish(Outcome::Ignored);
+        }
+    }
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        self.finish(Outcome::Ignored);
+    }
+}
+
+/// Response stream finishing call when it ends.
+struct GuardedStream<S> {
+    stream: S,
+    guard: CallGuard,
+}
+
+impl<S> Stream for GuardedStream<S>
+    where S : Stream<Item=ItemOrMetadata<Vec<u8>>, Error=Error>
+{
+    type Item = ItemOrMetadata<Vec<u8>>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ItemOrMetadata<Vec<u8>>>, Error> {
+        match self.stream.poll() {
+            Ok(Async::Ready(None)) => {
+                let rtt = self.guard.start.elapsed();
+                self.guard.finish(Outcome::Success(rtt));
+                Ok(Async::Ready(None))
+            }
+            Err(e) => {
+                self.guard.finish_with_error(&e);
+                Err(e)
+            }
+            r => r,
+        }
+    }
+}
+
+/// Server interceptor rejecting calls over adaptive concurrency limit
+/// with `UNAVAILABLE` status.
+///
+/// Methods may be partitioned to guarantee them a share of the limit,
+/// so that load of other methods does not starve them.
+pub struct ConcurrencyLimitInterceptor {
+    limiter: Arc<Mutex<Limiter>>,
+    partitions: Vec<String>,
+}
+
+impl ConcurrencyLimitInterceptor {
+    pub fn new(conf: ConcurrencyLimitConf) -> ConcurrencyLimitInterceptor {
+        ConcurrencyLimitInterceptor {
+            limiter: Arc::new(Mutex::new(Limiter::new(conf))),
+            partitions: Vec::new(),
+        }
+    }
+
+    /// Guarantee methods matching pattern `percent` of the limit.
+    /// Patterns are the same as in `AccessPolicy`, the first matching is used.
+    pub fn set_partition(&mut self, pattern: &str, percent: u32) {
+        assert!(percent <= 100);
+        self.partitions.push(pattern.to_owned());
+        self.limiter.lock().unwrap().partitions.push((0, percent));
+    }
+
+    /// Current limit of calls in progress.
+    pub fn limit(&self) -> usize {
+        self.limiter.lock().unwrap().limit()
+    }
+}
+
+impl ServerInterceptor for ConcurrencyLimitInterceptor {
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>
+    {
+        let partition = self.partitions.iter().position(|pattern| pattern_matches(pattern, method));
+        if !self.limiter.lock().unwrap().start(partition) {
+            debug!("rejecting call {} over concurrency limit", method);
+            return StreamingResponse::err(Error::GrpcMessage(GrpcMessageError {
+                grpc_status: GrpcStatus::Unavailable as i32,
+                grpc_message: "concurrency limit exceeded".to_owned(),
+            }));
+        }
+
+        let mut guard = CallGuard {
+            limiter: self.limiter.clone(),
+            partition,
+            start: Instant::now(),
+            finished: false,
+        };
+        let resp = next.call(o, req).0.then(move |r| -> Result<(Metadata, GrpcStreamWithTrailingMetadata<Vec<u8>>), Error> {
+            match r {
+                Ok((metadata, stream)) => {
+                    let stream = GuardedStream { stream: stream.0, guard };
+                    Ok((metadata, GrpcStreamWithTrailingMetadata::new(stream)))
+                }
+                Err(e) => {
+                    guard.finish_with_error(&e);
+                    Err(e)
+                }
+            }
+        });
+        StreamingResponse::new(resp)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limiter() -> Limiter {
+        Limiter::new(ConcurrencyLimitConf {
+            initial_limit: 10,
+            min_limit: 2,
+            max_limit: 100,
+            ..Default::default()
+        })
+    }
+
+    /// Run `n` concurrent calls with given latency.
+    fn run(limiter: &mut Limiter, n: usize, rtt: Duration) {
+        for _ in 0..n {
+            assert!(limiter.start(None));
+        }
+        for _ in 0..n {
+            limiter.finish(None, Outcome::Success(rtt));
+        }
+    }
+
+    #[test]
+    fn gradient() {
+        let mut limiter = limiter();
+
+        // limit grows while latency is stable
+        for _ in 0..10 {
+            let n = limiter.limit();
+            run(&mut limiter, n, Duration::from_millis(10));
+        }
+        assert!(limiter.limit() > 10);
+
+        // and decreases when latency grows
+        let limit = limiter.limit();
+        for _ in 0..10 {
+            let n = limiter.limit();
+            run(&mut limiter, n, Duration::from_millis(100));
+        }
+        assert!(limiter.limit() < limit);
+        assert!(limiter.limit() >= 2);
+
+        assert_eq!(0, limiter.in_flight);
+    }
+
+    #[test]
+    fn app_limited() {
+        let mut limiter = limiter();
+        for _ in 0..10 {
+            run(&mut limiter, 2, Duration::from_millis(10));
+        }
+        assert_eq!(10, limiter.limit());
+    }
+
+    #[test]
+    fn deadline_exceeded() {
+        let mut limiter = limiter();
+        assert!(limiter.start(None));
+        limiter.finish(None, Outcome::DeadlineExceeded);
+        assert_eq!(9, limiter.limit());
+    }
+
+    #[test]
+    fn partition() {
+        let mut limiter = limiter();
+        limiter.partitions.push((0, 20));
+
+        for _ in 0..10 {
+            assert!(limiter.start(None));
+        }
+        assert!(!limiter.start(None));
+
+        // partition has two calls guaranteed
+        assert!(limiter.start(Some(0)));
+        assert!(limiter.start(Some(0)));
+        assert!(!limiter.start(Some(0)));
+
+        limiter.finish(Some(0), Outcome::Ignored);
+        assert!(limiter.start(Some(0)));
+    }
+}
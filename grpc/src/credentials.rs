@@ -0,0 +1,140 @@
+//! Per-call credentials sent in request metadata.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use futures::future;
+use futures::future::Future;
+
+use futures_grpc::GrpcFuture;
+use interceptor::*;
+use metadata::Metadata;
+use metadata::MetadataKey;
+use req::*;
+use resp::*;
+
+
+/// Metadata key of credentials.
+pub const AUTHORIZATION: &'static str = "authorization";
+
+/// Token is refreshed this long before it expires.
+fn refresh_margin() -> Duration {
+    Duration::from_secs(60)
+}
+
+
+/// Metadata attached to each call, e. g. `authorization` header.
+pub trait CallCredentials : Send + Sync + 'static {
+    fn metadata(&self, method: &str) -> GrpcFuture<Metadata>;
+}
+
+fn bearer_metadata(token: &str) -> Metadata {
+    let mut metadata = Metadata::new();
+    metadata.add(MetadataKey::from(AUTHORIZATION), Bytes::from(format!("Bearer {}", token)));
+    metadata
+}
+
+
+/// Static bearer token.
+pub struct BearerTokenCredentials {
+    token: String,
+}
+
+impl BearerTokenCredentials {
+    pub fn new(token: &str) -> BearerTokenCredentials {
+        BearerTokenCredentials { token: token.to_owned() }
+    }
+}
+
+impl CallCredentials for BearerTokenCredentials {
+    fn metadata(&self, _method: &str) -> GrpcFuture<Metadata> {
+        Box::new(future::ok(bearer_metadata(&self.token)))
+    }
+}
+
+
+/// Access token with expiration time.
+#[derive(Debug, Clone)]
+pub struct AccessToken {
+    pub token: String,
+    /// `None` if token does not expire
+    pub expires: Option<Instant>,
+}
+
+/// Source of access tokens, e. g. OAuth2 token endpoint.
+pub trait TokenSource : Send + Sync + 'static {
+    fn fetch_token(&self) -> GrpcFuture<AccessToken>;
+}
+
+/// Bearer token credentials which obtain new token from `TokenSource`
+/// shortly before previous token expires.
+///
+/// Concurrent calls started when token needs refresh may fetch
+/// the token more than once.
+pub struct RefreshingTokenCredentials {
+    source: Arc<TokenSource>,
+    token: Arc<Mutex<Option<AccessToken>>>,
+}
+
+impl RefreshingTokenCredentials {
+    pub fn new(source: Arc<TokenSource>) -> RefreshingTokenCredentials {
+        RefreshingTokenCredentials {
+            source: source,
+            token: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl CallCredentials for RefreshingTokenCredentials {
+    fn metadata(&self, _method: &str) -> GrpcFuture<Metadata> {
+        if let Some(ref token) = *self.token.lock().unwrap() {
+            let fresh = match token.expires {
+                Some(expires) => Instant::now() + refresh_margin() < expires,
+                None => true,
+            };
+            if fresh {
+                return Box::new(future::ok(bearer_metadata(&token.token)));
+            }
+        }
+
+        debug!("fetching access token");
+        let cached = self.token.clone();
+        Box::new(self.source.fetch_token().map(move |token| {
+            let metadata = bearer_metadata(&token.token);
+            *cached.lock().unwrap() = Some(token);
+            metadata
+        }))
+    }
+}
+
+
+/// Client interceptor which adds credentials metadata to each call.
+pub struct CallCredentialsInterceptor {
+    credentials: Arc<CallCredentials>,
+}
+
+impl CallCredentialsInterceptor {
+    pub fn new(credentials: Arc<CallCredentials>) -> CallCredentialsInterceptor {
+        CallCredentialsInterceptor { credentials: credentials }
+    }
+}
+
+impl ClientInterceptor for CallCredentialsInterceptor {
+    fn intercept(
+        &self,
+        method: &str,
+        mut o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>
+    {
+        StreamingResponse::new(self.credentials.metadata(method).and_then(move |metadata| {
+            o.metadata.extend(metadata);
+            next.call(o, req).0
+        }))
+    }
+}
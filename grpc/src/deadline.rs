@@ -0,0 +1,344 @@
+//! Call deadlines.
+//!
+//! Client sends time remaining until deadline in `grpc-timeout` header,
+//! and both client and server fail the call with `DEADLINE_EXCEEDED`
+//! when deadline passes.
+//!
+//! Message timeout is local to each side: call fails with `DEADLINE_EXCEEDED`
+//! when no message is sent or received for the timeout,
+//! however long the call is.
+
+use std::cmp;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use futures::Async;
+use futures::Poll;
+use futures::future::Either;
+use futures::future::Future;
+use futures::stream::Stream;
+
+use error::Error;
+use error::GrpcMessageError;
+use futures_grpc::GrpcFuture;
+use futures_grpc::GrpcStream;
+use metadata::Metadata;
+use grpc::GrpcStatus;
+use interceptor::*;
+use req::*;
+use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use timer;
+
+
+pub const HEADER_GRPC_TIMEOUT: &'static str = "grpc-timeout";
+
+/// Timeout value is at most this number of digits.
+const MAX_TIMEOUT_DIGITS: usize = 8;
+
+/// `grpc-timeout` value, rounded up to the smallest unit which fits.
+pub fn encode_timeout(timeout: Duration) -> String {
+    let nanos = timeout.as_secs() as u128 * 1_000_000_000 + timeout.subsec_nanos() as u128;
+    let max = 10u128.pow(MAX_TIMEOUT_DIGITS as u32);
+    let units: [(u128, char); 6] = [
+        (1, 'n'),
+        (1_000, 'u'),
+        (1_000_000, 'm'),
+        (1_000_000_000, 'S'),
+        (60 * 1_000_000_000, 'M'),
+        (3600 * 1_000_000_000, 'H'),
+    ];
+    for &(unit_nanos, unit) in &units {
+        let value = (nanos + unit_nanos - 1) / unit_nanos;
+        if value < max {
+            return format!("{}{}", value, unit);
+        }
+    }
+    format!("{}H", max - 1)
+}
+
+/// Parse `grpc-timeout` value.
+pub fn decode_timeout(value: &[u8]) -> Option<Duration> {
+    let (unit, digits) = match value.split_last() {
+        Some((&unit, digits)) => (unit, digits),
+        None => return None,
+    };
+    if digits.is_empty() || digits.len() > MAX_TIMEOUT_DIGITS || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let value: u64 = ::std::str::from_utf8(digits).ok()?.parse().ok()?;
+    match unit {
+        b'n' => Some(Duration::from_nanos(value)),
+        b'u' => Some(Duration::from_micros(value)),
+        b'm' => Some(Duration::from_millis(value)),
+        b'S' => Some(Duration::from_secs(value)),
+        b'M' => Some(Duration::from_secs(value * 60)),
+        b'H' => Some(Duration::from_secs(value * 3600)),
+        _ => None,
+    }
+}
+
+/// Time remaining until deadline, zero if it passed.
+pub fn remaining(deadline: Instant) -> Duration {
+    let now = Instant::now();
+    if deadline > now { deadline - now } else { Duration::from_secs(0) }
+}
+
+pub fn deadline_exceeded() -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::DeadlineExceeded as i32,
+        grpc_message: "deadline exceeded".to_owned(),
+    })
+}
+
+/// Response failing with `DEADLINE_EXCEEDED` if it is not complete before deadline.
+pub fn with_deadline<T : Send + 'static>(resp: StreamingResponse<T>, deadline: Instant)
+    -> StreamingResponse<T>
+{
+    let timer = timer::sleep_until(deadline);
+    StreamingResponse::new(resp.0.select2(timer).then(|r| {
+        match r {
+            Ok(Either::A(((metadata, stream), timer))) => {
+                let stream = DeadlineStream { stream: stream.0, timer };
+                Ok((metadata, GrpcStreamWithTrailingMetadata::new(stream)))
+            }
+            Err(Either::A((e, _))) => Err(e),
+            Ok(Either::B(..)) | Err(Either::B(..)) => Err(deadline_exceeded()),
+        }
+    }))
+}
+
+struct DeadlineStream<S> {
+    stream: S,
+    timer: GrpcFuture<()>,
+}
+
+impl<S : Stream<Error=Error>> Stream for DeadlineStream<S> {
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, Error> {
+        match self.timer.poll() {
+            Ok(Async::NotReady) => self.stream.poll(),
+            Ok(Async::Ready(..)) | Err(..) => Err(deadline_exceeded()),
+        }
+    }
+}
+
+fn message_timeout_exceeded() -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::DeadlineExceeded as i32,
+        grpc_message: "message timeout exceeded".to_owned(),
+    })
+}
+
+/// Time of the last message sent or received by a call.
+#[derive(Clone)]
+struct Activity(Arc<Mutex<Instant>>);
+
+impl Activity {
+    fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Timer which expires when there's no activity for timeout.
+struct IdleTimer {
+    activity: Activity,
+    timeout: Duration,
+    timer: GrpcFuture<()>,
+}
+
+impl IdleTimer {
+    /// Fail if timer expired, otherwise make sure task is notified when it might.
+    fn poll(&mut self) -> Result<(), Error> {
+        loop {
+            if let Ok(Async::NotReady) = self.timer.poll() {
+                return Ok(());
+            }
+            let expires = self.activity.last() + self.timeout;
+            if expires <= Instant::now() {
+                return Err(message_timeout_exceeded());
+            }
+            self.timer = timer::sleep_until(expires);
+        }
+    }
+}
+
+/// Fails response with `DEADLINE_EXCEEDED` if no message of request
+/// or response (or initial metadata, before it is available)
+/// passes through for timeout.
+pub struct MessageTimeout {
+    activity: Activity,
+    timeout: Duration,
+}
+
+impl MessageTimeout {
+    /// Timeout starting now.
+    pub fn new(timeout: Duration) -> MessageTimeout {
+        MessageTimeout {
+            activity: Activity(Arc::new(Mutex::new(Instant::now()))),
+            timeout: timeout,
+        }
+    }
+
+    /// Request messages of the call.
+    pub fn request<T : Send + 'static>(&self, req: GrpcStream<T>) -> GrpcStream<T> {
+        let activity = self.activity.clone();
+        Box::new(req.inspect(move |_| activity.touch()))
+    }
+
+    /// Response of the call.
+    pub fn response<T : Send + 'static>(self, resp: StreamingResponse<T>) -> StreamingResponse<T> {
+        let idle = IdleTimer {
+            activity: self.activity,
+            timeout: self.timeout,
+            timer: timer::sleep(self.timeout),
+        };
+        StreamingResponse::new(MessageTimeoutFuture {
+            future: resp.0,
+            idle: Some(idle),
+        })
+    }
+}
+
+struct MessageTimeoutFuture<T : Send + 'static> {
+    future: GrpcFuture<(Metadata, GrpcStreamWithTrailingMetadata<T>)>,
+    idle: Option<IdleTimer>,
+}
+
+impl<T : Send + 'static> Future for MessageTimeoutFuture<T> {
+    type Item = (Metadata, GrpcStreamWithTrailingMetadata<T>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Error> {
+        let (metadata, stream) = match self.future.poll()? {
+            Async::Ready(r) => r,
+            Async::NotReady => {
+                self.idle.as_mut().expect("poll after completion").poll()?;
+                return Ok(Async::NotReady);
+            }
+        };
+        let idle = self.idle.take().expect("poll after completion");
+        idle.activity.touch();
+        let stream = MessageTimeoutStream { stream: stream.0, idle };
+        Ok(Async::Ready((metadata, GrpcStreamWithTrailingMetadata::new(stream))))
+    }
+}
+
+struct MessageTimeoutStream<S> {
+    stream: S,
+    idle: IdleTimer,
+}
+
+impl<S : Stream<Error=Error>> Stream for MessageTimeoutStream<S> {
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, Error> {
+        match self.stream.poll()? {
+            Async::Ready(item) => {
+                self.idle.activity.touch();
+                Ok(Async::Ready(item))
+            }
+            Async::NotReady => {
+                self.idle.poll()?;
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Client interceptor limiting calls by deadline of the parent call.
+pub struct PropagateDeadline {
+    pub deadline: Instant,
+}
+
+impl ClientInterceptor for PropagateDeadline {
+    fn intercept(
+        &self,
+        _method: &str,
+        mut o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>
+    {
+        o.deadline = Some(match o.deadline {
+            Some(deadline) => cmp::min(deadline, self.deadline),
+            None => self.deadline,
+        });
+        next.call(o, req)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+
+    use futures::stream;
+    use futures::sync::mpsc;
+
+    #[test]
+    fn message_timeout() {
+        let (tx, rx) = mpsc::unbounded::<u32>();
+        let resp = StreamingResponse::no_metadata(rx.map_err(|()| unreachable!()));
+        let message_timeout = MessageTimeout::new(Duration::from_millis(200));
+        let _req = message_timeout.request::<u32>(Box::new(stream::empty()));
+        let resp = message_timeout.response(resp);
+
+        let sender = thread::spawn(move || {
+            // total time is longer than timeout
+            for i in 0..5 {
+                thread::sleep(Duration::from_millis(50));
+                tx.unbounded_send(i).unwrap();
+            }
+            thread::sleep(Duration::from_millis(500));
+            drop(tx);
+        });
+
+        let mut items = resp.drop_metadata().wait();
+        for i in 0..5 {
+            assert_eq!(i, items.next().unwrap().unwrap());
+        }
+        match items.next() {
+            Some(Err(Error::GrpcMessage(ref e)))
+                if e.grpc_status == GrpcStatus::DeadlineExceeded as i32 => {}
+            _ => panic!("expecting message timeout"),
+        }
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!("0n", encode_timeout(Duration::from_secs(0)));
+        assert_eq!("1500000u", encode_timeout(Duration::from_millis(1500)));
+        assert_eq!("100000m", encode_timeout(Duration::from_secs(100)));
+        assert_eq!("100001m", encode_timeout(Duration::new(100, 1)));
+        assert_eq!("1000000S", encode_timeout(Duration::from_secs(1000000)));
+        assert_eq!("99999999H", encode_timeout(Duration::from_secs(u64::MAX)));
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(Some(Duration::from_millis(1500)), decode_timeout(b"1500000u"));
+        assert_eq!(Some(Duration::from_secs(120)), decode_timeout(b"2M"));
+        assert_eq!(Some(Duration::from_secs(7200)), decode_timeout(b"2H"));
+        assert_eq!(Some(Duration::from_nanos(7)), decode_timeout(b"7n"));
+        assert_eq!(None, decode_timeout(b""));
+        assert_eq!(None, decode_timeout(b"m"));
+        assert_eq!(None, decode_timeout(b"123456789m"));
+        assert_eq!(None, decode_timeout(b"-1S"));
+        assert_eq!(None, decode_timeout(b"1x"));
+    }
+}
@@ -0,0 +1,185 @@
+//! Built-in debug service reporting live state as JSON.
+//!
+//! State includes server calls in progress per method, connections
+//! of registered client channels with their active calls, and depths
+//! of registered call queues. HTTP/2 connections accepted by server
+//! and flow-control windows are managed by `httpbis` and not reported.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use futures::Poll;
+use futures::future::Future;
+use futures::stream::Stream;
+
+use serde_json;
+
+use client::Channel;
+use interceptor::*;
+use priority::PriorityInterceptor;
+use req::*;
+use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+
+
+/// Path of unary method returning state, request message is ignored.
+pub const DEBUG_STATE_PATH: &'static str = "/grpc.debug.Debug/State";
+
+type ActiveCalls = Arc<Mutex<HashMap<String, usize>>>;
+
+/// Server call in progress, unregistered on drop.
+struct ActiveCall {
+    calls: ActiveCalls,
+    method: String,
+}
+
+impl ActiveCall {
+    fn new(calls: ActiveCalls, method: &str) -> ActiveCall {
+        *calls.lock().unwrap().entry(method.to_owned()).or_insert(0) += 1;
+        ActiveCall {
+            calls: calls,
+            method: method.to_owned(),
+        }
+    }
+}
+
+impl Drop for ActiveCall {
+    fn drop(&mut self) {
+        let mut calls = self.calls.lock().unwrap();
+        let remove = match calls.get_mut(&self.method) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if remove {
+            calls.remove(&self.method);
+        }
+    }
+}
+
+/// Collects state reported by debug service.
+///
+/// Added to server with `ServerBuilder::add_debug_service`,
+/// which also registers it as interceptor counting server calls.
+#[derive(Default)]
+pub struct DebugService {
+    server_calls: ActiveCalls,
+    channels: Mutex<Vec<(String, Channel)>>,
+    queues: Mutex<Vec<(String, Arc<PriorityInterceptor>)>>,
+}
+
+impl DebugService {
+    pub fn new() -> DebugService {
+        Default::default()
+    }
+
+    /// Report connections of a client channel under given name.
+    pub fn add_channel(&self, name: &str, channel: Channel) {
+        self.channels.lock().unwrap().push((name.to_owned(), channel));
+    }
+
+    /// Report active and queued calls of a priority queue under given name.
+    pub fn add_queue(&self, name: &str, queue: Arc<PriorityInterceptor>) {
+        self.queues.lock().unwrap().push((name.to_owned(), queue));
+    }
+
+    /// Current state as JSON object.
+    pub fn state(&self) -> String {
+        let server_calls: serde_json::Map<String, serde_json::Value> = self.server_calls.lock().unwrap()
+            .iter()
+            .map(|(method, &count)| (method.clone(), serde_json::Value::from(count)))
+            .collect();
+
+        let mut channels = serde_json::Map::new();
+        for &(ref name, ref channel) in self.channels.lock().unwrap().iter() {
+            let connections = channel.connections().into_iter()
+                .map(|c| {
+                    let mut connection = serde_json::Map::new();
+                    connection.insert("addr".to_owned(), c.addr.to_string().into());
+                    connection.insert("state".to_owned(), c.state.into());
+                    connection.insert("active_calls".to_owned(), c.active_calls.into());
+                    connection.insert("ejected".to_owned(), c.ejected.into());
+                    connection.insert("last_error".to_owned(),
+                        c.last_error.map_or(serde_json::Value::Null, serde_json::Value::from));
+                    connection.insert("load_report".to_owned(),
+                        c.load_report.map_or(serde_json::Value::Null, |r| r.encode().into()));
+                    serde_json::Value::Object(connection)
+                })
+                .collect();
+            channels.insert(name.clone(), serde_json::Value::Array(connections));
+        }
+
+        let mut queues = serde_json::Map::new();
+        for &(ref name, ref queue) in self.queues.lock().unwrap().iter() {
+            let mut depth = serde_json::Map::new();
+            depth.insert("active_calls".to_owned(), queue.active_calls().into());
+            depth.insert("queued_calls".to_owned(), queue.queued_calls().into());
+            queues.insert(name.clone(), serde_json::Value::Object(depth));
+        }
+
+        let mut state = serde_json::Map::new();
+        state.insert("server_calls".to_owned(), serde_json::Value::Object(server_calls));
+        state.insert("channels".to_owned(), serde_json::Value::Object(channels));
+        state.insert("queues".to_owned(), serde_json::Value::Object(queues));
+        serde_json::Value::Object(state).to_string()
+    }
+}
+
+/// Response stream keeping call active until the stream is dropped.
+struct ActiveStream<S> {
+    stream: S,
+    _active: ActiveCall,
+}
+
+impl<S : Stream> Stream for ActiveStream<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        self.stream.poll()
+    }
+}
+
+impl ServerInterceptor for DebugService {
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>
+    {
+        let active = ActiveCall::new(self.server_calls.clone(), method);
+        StreamingResponse::new(next.call(o, req).0.map(move |(metadata, stream)| {
+            // call is active until response stream is dropped
+            let stream = ActiveStream { stream: stream.0, _active: active };
+            (metadata, GrpcStreamWithTrailingMetadata::new(stream))
+        }))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn active_calls() {
+        let debug = DebugService::new();
+        let a = ActiveCall::new(debug.server_calls.clone(), "/a/A");
+        let b = ActiveCall::new(debug.server_calls.clone(), "/a/A");
+        assert_eq!(
+            r#"{"channels":{},"queues":{},"server_calls":{"/a/A":2}}"#,
+            debug.state());
+        drop(a);
+        drop(b);
+        assert_eq!(
+            r#"{"channels":{},"queues":{},"server_calls":{}}"#,
+            debug.state());
+    }
+}
@@ -0,0 +1,264 @@
+//! Server-side deduplication of retried requests.
+//!
+//! Client marks a request with a unique key in metadata and sends the same
+//! key when it retries the request. Server executes the request once
+//! and returns the stored response to retries.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::mem;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use futures::Async;
+use futures::Poll;
+use futures::future;
+use futures::future::Future;
+use futures::stream;
+use futures::stream::Stream;
+
+use error::Error;
+use error::GrpcMessageError;
+use grpc::GrpcStatus;
+use interceptor::*;
+use metadata::Metadata;
+use req::*;
+use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use stream_item::ItemOrMetadata;
+
+
+/// Default metadata key of request id.
+pub const DEFAULT_IDEMPOTENCY_KEY: &'static str = "idempotency-key";
+
+#[derive(Clone)]
+struct StoredResponse {
+    /// Hash of request messages, to detect reuse of the key
+    request_hash: u64,
+    metadata: Metadata,
+    messages: Vec<Vec<u8>>,
+    trailing: Metadata,
+    expires: Instant,
+}
+
+enum Entry {
+    InProgress,
+    Done(StoredResponse),
+}
+
+#[derive(Default)]
+struct EntriesInner {
+    map: HashMap<(String, Bytes), Entry>,
+    /// Expired responses of keys which are not requested again
+    /// are removed at most once per ttl.
+    next_sweep: Option<Instant>,
+}
+
+type Entries = Arc<Mutex<EntriesInner>>;
+
+/// Hash of request messages accumulated as they are read by handler.
+#[derive(Clone, Default)]
+struct RequestHasher(Arc<Mutex<DefaultHasher>>);
+
+impl RequestHasher {
+    fn message(&self, message: &[u8]) {
+        let mut hasher = self.0.lock().unwrap();
+        hasher.write_usize(message.len());
+        hasher.write(message);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0.lock().unwrap().finish()
+    }
+}
+
+/// Forgets the request if it did not complete successfully,
+/// so it can be retried.
+struct InProgressGuard {
+    entries: Entries,
+    key: Option<(String, Bytes)>,
+}
+
+impl InProgressGuard {
+    fn complete(mut self, response: StoredResponse) {
+        let key = self.key.take().expect("key");
+        self.entries.lock().unwrap().map.insert(key, Entry::Done(response));
+    }
+}
+
+impl Drop for InProgressGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.entries.lock().unwrap().map.remove(&key);
+        }
+    }
+}
+
+/// Passes response through, storing a copy of it
+/// when it completes successfully.
+struct StoringStream<S> {
+    stream: S,
+    guard: Option<InProgressGuard>,
+    request_hash: RequestHasher,
+    metadata: Metadata,
+    messages: Vec<Vec<u8>>,
+    trailing: Metadata,
+    ttl: Duration,
+}
+
+impl<S> Stream for StoringStream<S>
+    where S : Stream<Item=ItemOrMetadata<Vec<u8>>, Error=Error>
+{
+    type Item = ItemOrMetadata<Vec<u8>>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ItemOrMetadata<Vec<u8>>>, Error> {
+        let item = try_ready!(self.stream.poll());
+        match item {
+            Some(ItemOrMetadata::Item(ref message)) => self.messages.push(message.clone()),
+            Some(ItemOrMetadata::TrailingMetadata(ref trailing)) => self.trailing.extend(trailing.clone()),
+            None => {
+                if let Some(guard) = self.guard.take() {
+                    guard.complete(StoredResponse {
+                        request_hash: self.request_hash.finish(),
+                        metadata: self.metadata.clone(),
+                        messages: mem::replace(&mut self.messages, Vec::new()),
+                        trailing: mem::replace(&mut self.trailing, Metadata::new()),
+                        expires: Instant::now() + self.ttl,
+                    });
+                }
+            }
+        }
+        Ok(Async::Ready(item))
+    }
+}
+
+fn key_reused() -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::Argument as i32,
+        grpc_message: "idempotency key is reused with a different request".to_owned(),
+    })
+}
+
+/// Server interceptor which returns stored response for requests
+/// with already seen idempotency key.
+///
+/// Only successful responses are stored, for `ttl` after completion.
+/// Response messages are passed through as they are produced,
+/// so streaming responses are not delayed.
+///
+/// Requests without the key are passed through. A duplicate of request
+/// which is still in progress is rejected with `ABORTED`. Duplicate
+/// is answered after its request stream ends, and is rejected with
+/// `INVALID_ARGUMENT` if its messages differ from the original request.
+pub struct DeduplicationInterceptor {
+    metadata_key: String,
+    ttl: Duration,
+    entries: Entries,
+}
+
+impl DeduplicationInterceptor {
+    /// Deduplicate by `idempotency-key` metadata.
+    pub fn new(ttl: Duration) -> DeduplicationInterceptor {
+        DeduplicationInterceptor::with_metadata_key(DEFAULT_IDEMPOTENCY_KEY, ttl)
+    }
+
+    pub fn with_metadata_key(metadata_key: &str, ttl: Duration) -> DeduplicationInterceptor {
+        DeduplicationInterceptor {
+            metadata_key: metadata_key.to_owned(),
+            ttl: ttl,
+            entries: Arc::new(Mutex::new(EntriesInner::default())),
+        }
+    }
+}
+
+impl ServerInterceptor for DeduplicationInterceptor {
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>
+    {
+        let key = match o.metadata.get(&self.metadata_key) {
+            Some(id) => (method.to_owned(), Bytes::from(id)),
+            None => return next.call(o, req),
+        };
+
+        {
+            let mut entries = self.entries.lock().unwrap();
+            let now = Instant::now();
+            if entries.next_sweep.map_or(true, |at| at <= now) {
+                entries.map.retain(|_, e| match *e {
+                    Entry::Done(ref r) => r.expires > now,
+                    Entry::InProgress => true,
+                });
+                entries.next_sweep = Some(now + self.ttl);
+            }
+
+            let stored = match entries.map.get(&key) {
+                Some(&Entry::Done(ref r)) if r.expires > now => Some(r.clone()),
+                Some(&Entry::Done(..)) => None,
+                Some(&Entry::InProgress) => {
+                    return StreamingResponse::err(Error::GrpcMessage(GrpcMessageError {
+                        grpc_status: GrpcStatus::Aborted as i32,
+                        grpc_message: "request with the same idempotency key is in progress".to_owned(),
+                    }));
+                }
+                None => None,
+            };
+            if let Some(r) = stored {
+                debug!("duplicate request to {}, returning stored response", method);
+                let request_hash = RequestHasher::default();
+                return StreamingResponse::new(req.0
+                    .for_each({
+                        let request_hash = request_hash.clone();
+                        move |message| {
+                            request_hash.message(&message);
+                            Ok(())
+                        }
+                    })
+                    .and_then(move |()| {
+                        if request_hash.finish() != r.request_hash {
+                            return Err(key_reused());
+                        }
+                        let stream = GrpcStreamWithTrailingMetadata::stream_with_trailing_metadata(
+                            stream::iter_ok(r.messages), future::ok(r.trailing));
+                        Ok((r.metadata, stream))
+                    }));
+            }
+            entries.map.insert(key.clone(), Entry::InProgress);
+        }
+
+        let guard = InProgressGuard {
+            entries: self.entries.clone(),
+            key: Some(key),
+        };
+        let ttl = self.ttl;
+
+        let request_hash = RequestHasher::default();
+        let req = {
+            let request_hash = request_hash.clone();
+            StreamingRequest::new(req.0.inspect(move |message| request_hash.message(message)))
+        };
+
+        StreamingResponse::new(next.call(o, req).0.map(move |(metadata, stream)| {
+            let stream = StoringStream {
+                stream: stream.0,
+                guard: Some(guard),
+                request_hash: request_hash,
+                metadata: metadata.clone(),
+                messages: Vec::new(),
+                trailing: Metadata::new(),
+                ttl: ttl,
+            };
+            (metadata, GrpcStreamWithTrailingMetadata::new(stream))
+        }))
+    }
+}
@@ -5,12 +5,16 @@ use std::fmt;
 use futures;
 
 use metadata;
+use grpc::GrpcStatus;
 
 use httpbis;
 
 use protobuf_lib::ProtobufError;
 
+/// Call finished with non-OK `grpc-status`, either received from peer
+/// or produced locally (e. g. by interceptor or balancer).
 #[derive(Debug)]
+#[cfg_attr(feature = "with-serde", derive(Serialize, Deserialize))]
 pub struct GrpcMessageError {
     pub grpc_status: i32,
 
@@ -18,17 +22,54 @@ pub struct GrpcMessageError {
     pub grpc_message: String,
 }
 
+impl fmt::Display for GrpcMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "grpc-status: {}, grpc-message: {:?}", self.grpc_status, self.grpc_message)
+    }
+}
+
+impl StdError for GrpcMessageError {
+    fn description(&self) -> &str {
+        &self.grpc_message
+    }
+}
+
+
+/// Broad category of `Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Connection failed or was closed: call may succeed if retried.
+    Transport,
+    /// Peer sent malformed data: bad frames, headers or messages.
+    Protocol,
+    /// Call finished with error status or handler panicked.
+    Application,
+    /// Call was canceled or failed locally.
+    Local,
+}
+
 
 #[derive(Debug)]
 pub enum Error {
+    /// I/O error (transport)
     Io(io::Error),
+    /// HTTP/2 error (transport)
     Http(httpbis::Error),
+    /// Peer violated gRPC protocol, e. g. sent partial frame
+    Protocol(&'static str),
+    /// Non-OK status of a call (application)
     GrpcMessage(GrpcMessageError),
     Canceled(futures::Canceled),
+    /// Invalid binary metadata value (protocol)
     MetadataDecode(metadata::MetadataDecodeError),
+    /// Failed to parse or serialize message (protocol)
     Protobuf(ProtobufError),
+    /// Server handler panicked (application)
     Panic(String),
     Other(&'static str),
+    /// Error with description where it happened, e. g. subchannel address.
+    /// Kind, status and source are those of the wrapped error.
+    WithContext(String, Box<Error>),
 }
 
 fn _assert_debug<D : ::std::fmt::Debug>(_: &D) {}
@@ -37,17 +78,60 @@ fn _assert_grpc_error_debug(e: &Error) {
     _assert_debug(e);
 }
 
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            Error::Io(..) | Error::Http(..) => ErrorKind::Transport,
+            Error::Protocol(..) | Error::MetadataDecode(..) | Error::Protobuf(..) => ErrorKind::Protocol,
+            Error::GrpcMessage(..) | Error::Panic(..) => ErrorKind::Application,
+            Error::Canceled(..) | Error::Other(..) => ErrorKind::Local,
+            Error::WithContext(_, ref e) => e.kind(),
+        }
+    }
+
+    /// Status of a call failed with this error.
+    ///
+    /// Malformed data is `INTERNAL`, transport errors are `UNAVAILABLE`.
+    pub fn grpc_status(&self) -> i32 {
+        match *self {
+            Error::GrpcMessage(ref e) => e.grpc_status,
+            Error::Io(..) | Error::Http(..) => GrpcStatus::Unavailable as i32,
+            Error::Canceled(..) => GrpcStatus::Cancelled as i32,
+            Error::Other(..) => GrpcStatus::Unknown as i32,
+            Error::Protocol(..) | Error::MetadataDecode(..) | Error::Protobuf(..) | Error::Panic(..) =>
+                GrpcStatus::Internal as i32,
+            Error::WithContext(_, ref e) => e.grpc_status(),
+        }
+    }
+}
+
 impl StdError for Error {
+    #[allow(deprecated)]
     fn description(&self) -> &str {
         match self {
             &Error::Io(ref err) => err.description(),
             &Error::Http(ref err) => err.description(),
+            &Error::Protocol(ref message) => message,
             &Error::GrpcMessage(ref err) => &err.grpc_message,
             &Error::MetadataDecode(..) => "metadata decode error",
             &Error::Protobuf(ref err) => err.description(),
             &Error::Canceled(..) => "canceled",
             &Error::Panic(ref message) => &message,
             &Error::Other(ref message) => message,
+            &Error::WithContext(_, ref err) => err.description(),
+        }
+    }
+
+    fn source(&self) -> Option<&(StdError + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            Error::Http(ref err) => Some(err),
+            Error::GrpcMessage(ref err) => Some(err),
+            Error::Canceled(ref err) => Some(err),
+            Error::MetadataDecode(ref err) => Some(err),
+            Error::Protobuf(ref err) => Some(err),
+            Error::WithContext(_, ref err) => Some(&**err),
+            Error::Protocol(..) | Error::Panic(..) | Error::Other(..) => None,
         }
     }
 }
@@ -55,14 +139,16 @@ impl StdError for Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &Error::Io(ref err) => write!(f, "io error: {}", err.description()),
-            &Error::Http(ref err) => write!(f, "http error: {}", err.description()),
+            &Error::Io(ref err) => write!(f, "io error: {}", err),
+            &Error::Http(ref err) => write!(f, "http error: {}", err),
+            &Error::Protocol(ref message) => write!(f, "protocol error: {}", message),
             &Error::GrpcMessage(ref err) => write!(f, "grpc message error: {}", err.grpc_message),
-            &Error::MetadataDecode(..) => write!(f, "metadata decode error"),
-            &Error::Protobuf(ref err) => write!(f, "protobuf error: {}", err.description()),
+            &Error::MetadataDecode(ref err) => write!(f, "metadata decode error: {}", err),
+            &Error::Protobuf(ref err) => write!(f, "protobuf error: {}", err),
             &Error::Canceled(..) => write!(f, "canceled"),
             &Error::Panic(ref message) => write!(f, "panic: {}", message),
             &Error::Other(ref message) => write!(f, "other error: {}", message),
+            &Error::WithContext(ref context, ref err) => write!(f, "{}; {}", err, context),
         }
     }
 }
@@ -108,7 +194,47 @@ impl From<metadata::MetadataDecodeError> for Error {
 }
 
 impl From<Error> for httpbis::Error {
-    fn from(_err: Error) -> httpbis::Error {
-        httpbis::Error::Other("grpc error") // TODO: preserve
+    fn from(err: Error) -> httpbis::Error {
+        match err {
+            Error::Http(e) => e,
+            // `io::Error` keeps original error as its source
+            err => httpbis::Error::from(io::Error::from(err)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn source_chain() {
+        let e = Error::from(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+        assert_eq!(ErrorKind::Transport, e.kind());
+        assert_eq!("reset", format!("{}", e.source().expect("source")));
+
+        let e = Error::GrpcMessage(GrpcMessageError {
+            grpc_status: 14,
+            grpc_message: "unavailable".to_owned(),
+        });
+        assert_eq!(ErrorKind::Application, e.kind());
+        let source = e.source().expect("source");
+        assert_eq!("unavailable", source.downcast_ref::<GrpcMessageError>().unwrap().grpc_message);
+
+        let e = Error::Protocol("partial frame");
+        assert_eq!(ErrorKind::Protocol, e.kind());
+        assert!(e.source().is_none());
+        assert_eq!("protocol error: partial frame", format!("{}", e));
+        assert_eq!(GrpcStatus::Internal as i32, e.grpc_status());
+
+        let e = Error::WithContext(
+            "subchannel: a:1".to_owned(),
+            Box::new(Error::from(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))));
+        assert_eq!(ErrorKind::Transport, e.kind());
+        assert_eq!(GrpcStatus::Unavailable as i32, e.grpc_status());
+        assert_eq!("io error: refused; subchannel: a:1", format!("{}", e));
+        let source = e.source().expect("source");
+        assert_eq!("refused", format!("{}", source.source().expect("io error")));
     }
 }
@@ -0,0 +1,103 @@
+//! Experimental features, which can ship disabled and be enabled progressively.
+//!
+//! Experiments are enabled for the whole process by `GRPC_EXPERIMENTS`
+//! environment variable, a comma-separated list of names, and for a single
+//! client or server by `experiments` field of its configuration.
+//! Name prefixed with `-` disables an experiment enabled by default.
+
+use std::env;
+
+
+/// Environment variable with comma-separated list of experiments.
+pub const EXPERIMENTS_ENV_VAR: &'static str = "GRPC_EXPERIMENTS";
+
+/// Known experiments, as name, description and whether enabled by default.
+pub const EXPERIMENTS: &'static [(&'static str, &'static str, bool)] = &[
+    (
+        "load_weighted_round_robin",
+        "`load_weighted_round_robin` balancing policy can be selected by service config",
+        false,
+    ),
+];
+
+/// Set of enabled experiments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Experiments {
+    enabled: Vec<&'static str>,
+}
+
+impl Default for Experiments {
+    fn default() -> Experiments {
+        Experiments {
+            enabled: EXPERIMENTS.iter()
+                .filter(|&&(_, _, default)| default)
+                .map(|&(name, _, _)| name)
+                .collect(),
+        }
+    }
+}
+
+impl Experiments {
+    /// Experiments enabled by default, updated by `GRPC_EXPERIMENTS`,
+    /// then by `overrides` from configuration.
+    pub fn resolve(overrides: &[String]) -> Experiments {
+        let mut experiments = Experiments::default();
+        if let Ok(list) = env::var(EXPERIMENTS_ENV_VAR) {
+            experiments.apply(list.split(','));
+        }
+        experiments.apply(overrides.iter().map(String::as_str));
+        experiments
+    }
+
+    /// Enable or disable (if prefixed with `-`) experiments by name.
+    /// Unknown names are ignored.
+    fn apply<'a, I : Iterator<Item=&'a str>>(&mut self, names: I) {
+        for name in names.map(str::trim).filter(|n| !n.is_empty()) {
+            let (name, enable) = if name.starts_with('-') {
+                (&name[1..], false)
+            } else {
+                (name, true)
+            };
+            let name = match EXPERIMENTS.iter().find(|&&(n, _, _)| n == name) {
+                Some(&(name, _, _)) => name,
+                None => {
+                    warn!("unknown experiment: {}", name);
+                    continue;
+                }
+            };
+            self.enabled.retain(|&n| n != name);
+            if enable {
+                self.enabled.push(name);
+            }
+        }
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.enabled.contains(&name)
+    }
+
+    /// Names of enabled experiments.
+    pub fn enabled(&self) -> &[&'static str] {
+        &self.enabled
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let mut experiments = Experiments::default();
+        assert!(!experiments.is_enabled("load_weighted_round_robin"));
+
+        experiments.apply(" load_weighted_round_robin, unknown,".split(','));
+        assert!(experiments.is_enabled("load_weighted_round_robin"));
+        assert!(!experiments.is_enabled("unknown"));
+        assert_eq!(&["load_weighted_round_robin"], experiments.enabled());
+
+        experiments.apply(vec!["-load_weighted_round_robin"].into_iter());
+        assert!(!experiments.is_enabled("load_weighted_round_robin"));
+    }
+}
@@ -0,0 +1,227 @@
+//! Resolver reading addresses from a file, for deployments
+//! where backends are managed by external tooling.
+
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use futures::future;
+use futures::Future;
+
+use serde_json;
+
+use balancer::AddressUpdates;
+use balancer::WeightedAddr;
+use futures_grpc::GrpcFuture;
+use resolver::Resolver;
+use result;
+
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn parse_address(s: &str, port: u16) -> io::Result<SocketAddr> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    match s.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, port)),
+        Err(_) => Err(invalid_data(format!("invalid address: {}", s))),
+    }
+}
+
+fn parse_weighted_address(v: &serde_json::Value, port: u16) -> io::Result<WeightedAddr> {
+    if let Some(s) = v.as_str() {
+        return Ok(WeightedAddr::new(parse_address(s, port)?));
+    }
+    let addr = v.get("address").and_then(|a| a.as_str());
+    let weight = match v.get("weight") {
+        None => Some(1),
+        Some(w) => w.as_u64().filter(|&w| w <= u32::max_value() as u64),
+    };
+    match (addr, weight) {
+        (Some(addr), Some(weight)) => Ok(WeightedAddr {
+            addr: parse_address(addr, port)?,
+            weight: weight as u32,
+        }),
+        _ => Err(invalid_data(format!("invalid address: {}", v))),
+    }
+}
+
+/// Find addresses of `host:port`, or `host` if there's no entry with port.
+fn parse_endpoints(json: &[u8], host: &str, port: u16) -> io::Result<Vec<WeightedAddr>> {
+    let endpoints: serde_json::Value = serde_json::from_slice(json)
+        .map_err(|e| invalid_data(format!("invalid endpoints file: {}", e)))?;
+    let endpoints = endpoints.as_object()
+        .ok_or_else(|| invalid_data("endpoints file must contain an object".to_owned()))?;
+
+    let addrs = endpoints.get(&format!("{}:{}", host, port))
+        .or_else(|| endpoints.get(host))
+        .ok_or_else(|| io::Error::new(
+            io::ErrorKind::NotFound, format!("{} is not found in endpoints file", host)))?;
+    let addrs = addrs.as_array()
+        .ok_or_else(|| invalid_data(format!("addresses of {} must be an array", host)))?;
+
+    addrs.iter().map(|a| parse_weighted_address(a, port)).collect()
+}
+
+fn read_endpoints(path: &Path, host: &str, port: u16) -> io::Result<Vec<WeightedAddr>> {
+    parse_endpoints(&fs::read(path)?, host, port)
+}
+
+
+/// Set when resolver is dropped, so watch threads exit
+/// without waiting for the next poll.
+#[derive(Debug, Default)]
+struct Stop {
+    stopped: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Stop {
+    /// Sleep for `timeout` or until stopped, return `true` if stopped.
+    fn wait(&self, timeout: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        if *stopped {
+            return true;
+        }
+        *self.condvar.wait_timeout(stopped, timeout).unwrap().0
+    }
+
+    fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+}
+
+
+/// Resolver which reads addresses from JSON file like
+///
+/// ```json
+/// {
+///     "greeter.example.com:50051": ["10.0.0.1:50051", "10.0.0.2:50051"],
+///     "store": ["10.0.0.3", {"address": "10.0.0.4", "weight": 3}]
+/// }
+/// ```
+///
+/// Keys are `host:port` or `host`, address port defaults to requested port.
+/// Weights are used by `WeightedRoundRobin` balancing policy.
+///
+/// File is re-read periodically, and changed addresses are sent to channels,
+/// so backends can be added or removed without restarting clients.
+///
+/// File is polled by a `grpc-file-resolver` thread per channel,
+/// which exits when the channel is closed or the resolver is dropped.
+/// Threads are not joined: the resolver may be released by a thread
+/// of the channel it serves, which would wait for itself.
+#[derive(Debug)]
+pub struct FileResolver {
+    path: PathBuf,
+    poll_interval: Duration,
+    stop: Arc<Stop>,
+}
+
+impl FileResolver {
+    /// File is checked for changes every second.
+    pub fn new<P : AsRef<Path>>(path: P) -> FileResolver {
+        FileResolver {
+            path: path.as_ref().to_owned(),
+            poll_interval: Duration::from_secs(1),
+            stop: Arc::new(Stop::default()),
+        }
+    }
+
+    pub fn set_poll_interval(&mut self, poll_interval: Duration) {
+        self.poll_interval = poll_interval;
+    }
+}
+
+impl Resolver for FileResolver {
+    fn resolve(&self, host: &str, port: u16) -> GrpcFuture<Vec<SocketAddr>> {
+        Box::new(self.resolve_weighted(host, port).map(|addrs| {
+            addrs.into_iter().map(|a| a.addr).collect()
+        }))
+    }
+
+    fn resolve_weighted(&self, host: &str, port: u16) -> GrpcFuture<Vec<WeightedAddr>> {
+        let r: result::Result<_> = read_endpoints(&self.path, host, port).map_err(From::from);
+        Box::new(future::result(r))
+    }
+
+    fn watch(&self, host: &str, port: u16, updates: AddressUpdates) {
+        let path = self.path.clone();
+        let host = host.to_owned();
+        let poll_interval = self.poll_interval;
+        let stop = self.stop.clone();
+
+        let spawned = thread::Builder::new()
+            .name("grpc-file-resolver".to_owned())
+            .spawn(move || {
+                let mut last = read_endpoints(&path, &host, port).ok();
+                while !updates.is_closed() {
+                    if stop.wait(poll_interval) {
+                        return;
+                    }
+                    match read_endpoints(&path, &host, port) {
+                        Ok(ref addrs) if Some(addrs) == last.as_ref() => {}
+                        Ok(addrs) => {
+                            debug!("addresses of {} changed: {:?}", host, addrs);
+                            if !updates.update_weighted(addrs.clone()) {
+                                return;
+                            }
+                            last = Some(addrs);
+                        }
+                        Err(e) => warn!("failed to read {}: {}", path.display(), e),
+                    }
+                }
+            });
+        if let Err(e) = spawned {
+            warn!("failed to start file resolver thread: {}", e);
+        }
+    }
+}
+
+impl Drop for FileResolver {
+    fn drop(&mut self) {
+        self.stop.stop();
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn endpoints() {
+        let json = br#"{
+            "a:10": ["1.1.1.1:11", {"address": "2.2.2.2", "weight": 3}],
+            "a": ["3.3.3.3"],
+            "b": []
+        }"#;
+
+        let a10 = parse_endpoints(json, "a", 10).unwrap();
+        assert_eq!(
+            vec![
+                WeightedAddr::new("1.1.1.1:11".parse().unwrap()),
+                WeightedAddr { addr: "2.2.2.2:10".parse().unwrap(), weight: 3 },
+            ],
+            a10);
+        assert_eq!(
+            vec![WeightedAddr::new("3.3.3.3:20".parse().unwrap())],
+            parse_endpoints(json, "a", 20).unwrap());
+        assert_eq!(Vec::<WeightedAddr>::new(), parse_endpoints(json, "b", 1).unwrap());
+        assert_eq!(io::ErrorKind::NotFound, parse_endpoints(json, "c", 1).unwrap_err().kind());
+        assert!(parse_endpoints(br#"{"a": ["x"]}"#, "a", 1).is_err());
+        assert!(parse_endpoints(br#"{"a": [{"address": "1.1.1.1", "weight": -1}]}"#, "a", 1).is_err());
+    }
+}
@@ -7,6 +7,9 @@ use result::Result;
 use error::Error;
 use marshall::Marshaller;
 
+pub use grpc_frame::write_grpc_frame;
+pub use grpc_frame::write_grpc_frame_to_vec;
+pub use grpc_frame::parse_grpc_frames_completely;
 
 
 pub struct MarshallerString;
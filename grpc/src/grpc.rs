@@ -1,6 +1,33 @@
 pub static HEADER_GRPC_STATUS: &'static str = "grpc-status";
 pub static HEADER_GRPC_MESSAGE: &'static str = "grpc-message";
 
+static CONTENT_TYPE_GRPC: &'static str = "application/grpc";
+
+/// `content-type` for given subtype, e. g. `application/grpc+json`.
+pub fn content_type(subtype: &Option<String>) -> String {
+    match *subtype {
+        Some(ref subtype) => format!("{}+{}", CONTENT_TYPE_GRPC, subtype),
+        None => CONTENT_TYPE_GRPC.to_owned(),
+    }
+}
+
+/// Subtype of gRPC `content-type`, `None` for `application/grpc`.
+pub fn content_subtype(content_type: &str) -> Option<String> {
+    if !content_type.starts_with(CONTENT_TYPE_GRPC) {
+        return None;
+    }
+    let rem = &content_type[CONTENT_TYPE_GRPC.len()..];
+    if !rem.starts_with('+') {
+        return None;
+    }
+    let subtype = rem[1..].split(';').next().unwrap().trim();
+    if subtype.is_empty() {
+        None
+    } else {
+        Some(subtype.to_owned())
+    }
+}
+
 // copied from https://github.com/grpc/grpc/blob/master/include/grpc/impl/codegen/status.h
 #[allow(dead_code)]
 pub enum GrpcStatus {
@@ -113,3 +140,18 @@ pub enum GrpcStatus {
     DataLoss = 15,
 }
 
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_content_subtype() {
+        assert_eq!(None, content_subtype("application/grpc"));
+        assert_eq!(Some("json".to_owned()), content_subtype("application/grpc+json"));
+        assert_eq!(Some("json".to_owned()), content_subtype("application/grpc+json; charset=utf-8"));
+        assert_eq!(None, content_subtype("application/grpcx"));
+        assert_eq!("application/grpc+json", content_type(&content_subtype("application/grpc+json")));
+    }
+}
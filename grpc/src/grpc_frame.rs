@@ -8,6 +8,8 @@ use futures::stream;
 use futures::stream::Stream;
 
 use error::*;
+use compression::MessageDecoder;
+use buffer_pool;
 use result;
 use httpbis::HttpStreamAfterHeaders;
 use httpbis::DataOrTrailers;
@@ -34,26 +36,42 @@ fn write_u32_be(v: u32) -> [u8; 4] {
 pub const GRPC_HEADER_LEN: usize = 5;
 
 
-/// Return frame len
-pub fn parse_grpc_frame_0(stream: &[u8]) -> result::Result<Option<usize>> {
+/// Return compressed flag and frame len
+fn parse_grpc_frame_header(stream: &[u8]) -> result::Result<Option<(bool, usize)>> {
     if stream.len() < GRPC_HEADER_LEN {
         return Ok(None);
     }
     let compressed = match stream[0] {
         0 => false,
         1 => true,
-        _ => return Err(Error::Other("unknown compression flag")),
+        // other bits of flags byte are reserved
+        _ => return Err(Error::Protocol("unknown compression flag")),
     };
-    if compressed {
-        return Err(Error::Other("compression is not implemented"));
-    }
     let len = read_u32_be(&stream[1..]) as usize;
     let end = len + GRPC_HEADER_LEN;
     if end > stream.len() {
         return Ok(None);
     }
 
-    Ok(Some(len))
+    Ok(Some((compressed, len)))
+}
+
+/// Error for data left unparsed when HTTP body ends.
+pub fn incomplete_frame_error(rem: &[u8]) -> Error {
+    if rem.len() < GRPC_HEADER_LEN {
+        Error::Protocol("trailing data after last message")
+    } else {
+        Error::Protocol("message length exceeds remaining body")
+    }
+}
+
+/// Return frame len
+pub fn parse_grpc_frame_0(stream: &[u8]) -> result::Result<Option<usize>> {
+    match parse_grpc_frame_header(stream)? {
+        Some((true, _)) => Err(Error::Protocol("compressed frame")),
+        Some((false, len)) => Ok(Some(len)),
+        None => Ok(None),
+    }
 }
 
 
@@ -67,20 +85,29 @@ pub fn parse_grpc_frame(stream: &[u8]) -> result::Result<Option<(&[u8], usize)>>
         })
 }
 
-pub fn parse_grpc_frame_from_bytes(stream: &mut Bytes) -> result::Result<Option<Bytes>> {
-    if let Some(len) = parse_grpc_frame_0(&stream)? {
+/// Parse frame, decompressing it with `decoder` if frame is compressed
+pub fn parse_grpc_frame_from_bytes(stream: &mut Bytes, decoder: &MessageDecoder)
+    -> result::Result<Option<Bytes>>
+{
+    if stream.len() >= GRPC_HEADER_LEN {
+        // fail before buffering too large frame
+        decoder.check_frame_len(read_u32_be(&stream[1..]) as usize)?;
+    }
+    if let Some((compressed, len)) = parse_grpc_frame_header(&stream)? {
         let r = stream.slice(GRPC_HEADER_LEN, len + GRPC_HEADER_LEN);
         stream.split_to(len + GRPC_HEADER_LEN);
-        Ok(Some(r))
+        Ok(Some(decoder.decode(compressed, r)?))
     } else {
         Ok(None)
     }
 }
 
-pub fn parse_grpc_frames_from_bytes(stream: &mut Bytes) -> result::Result<Vec<Bytes>> {
+pub fn parse_grpc_frames_from_bytes(stream: &mut Bytes, decoder: &MessageDecoder)
+    -> result::Result<Vec<Bytes>>
+{
     let mut r = Vec::new();
     loop {
-        match parse_grpc_frame_from_bytes(stream)? {
+        match parse_grpc_frame_from_bytes(stream, decoder)? {
             Some(bytes) => {
                 r.push(bytes);
             }
@@ -97,7 +124,7 @@ pub fn parse_grpc_frames_completely(stream: &[u8]) -> result::Result<Vec<&[u8]>>
     while pos < stream.len() {
         let frame_opt = parse_grpc_frame(&stream[pos..])?;
         match frame_opt {
-            None => return Err(Error::Other("not complete frames")),
+            None => return Err(incomplete_frame_error(&stream[pos..])),
             Some((frame, len)) => {
                 r.push(frame);
                 pos += len;
@@ -113,7 +140,7 @@ pub fn parse_grpc_frame_completely(stream: &[u8]) -> result::Result<&[u8]> {
     if frames.len() == 1 {
         Ok(frames[0])
     } else {
-        Err(Error::Other("expecting exactly one frame"))
+        Err(Error::Protocol("expecting exactly one frame"))
     }
 }
 
@@ -129,6 +156,20 @@ pub fn write_grpc_frame_to_vec(frame: &[u8]) -> Vec<u8> {
     r
 }
 
+/// Header of frame of given length with given compressed flag.
+pub fn grpc_frame_header(len: usize, compressed: bool) -> [u8; GRPC_HEADER_LEN] {
+    let len = write_u32_be(len as u32);
+    [compressed as u8, len[0], len[1], len[2], len[3]]
+}
+
+/// Write frame with given compressed flag.
+pub fn write_grpc_frame_with_flag(frame: &[u8], compressed: bool) -> Vec<u8> {
+    let mut r = Vec::with_capacity(GRPC_HEADER_LEN + frame.len());
+    r.extend(&grpc_frame_header(frame.len(), compressed));
+    r.extend(frame);
+    r
+}
+
 
 
 trait RequestOrResponse {
@@ -137,15 +178,17 @@ trait RequestOrResponse {
 
 pub struct GrpcFrameFromHttpFramesStreamRequest {
     http_stream_stream: HttpStreamAfterHeaders,
+    decoder: MessageDecoder,
     buf: Bytes,
     parsed_frames: VecDeque<Bytes>,
     error: Option<stream::Once<Bytes, Error>>,
 }
 
 impl GrpcFrameFromHttpFramesStreamRequest {
-    pub fn new(http_stream_stream: HttpStreamAfterHeaders) -> Self {
+    pub fn new(http_stream_stream: HttpStreamAfterHeaders, decoder: MessageDecoder) -> Self {
         GrpcFrameFromHttpFramesStreamRequest {
             http_stream_stream,
+            decoder,
             buf: Bytes::new(),
             parsed_frames: VecDeque::new(),
             error: None,
@@ -164,7 +207,7 @@ impl Stream for GrpcFrameFromHttpFramesStreamRequest {
                 return error.poll();
             }
 
-            self.parsed_frames.extend(match parse_grpc_frames_from_bytes(&mut self.buf) {
+            self.parsed_frames.extend(match parse_grpc_frames_from_bytes(&mut self.buf, &self.decoder) {
                 Ok(r) => r,
                 Err(e) => {
                     self.error = Some(stream::once(Err(e)));
@@ -185,7 +228,7 @@ impl Stream for GrpcFrameFromHttpFramesStreamRequest {
                     if self.buf.is_empty() {
                         return Ok(Async::Ready(None));
                     } else {
-                        self.error = Some(stream::once(Err(Error::Other("partial frame"))));
+                        self.error = Some(stream::once(Err(incomplete_frame_error(&self.buf))));
                         continue;
                     }
                 },
@@ -196,7 +239,7 @@ impl Stream for GrpcFrameFromHttpFramesStreamRequest {
                 // unexpected but OK
                 DataOrTrailers::Trailers(..) => (),
                 DataOrTrailers::Data(data, ..) => {
-                    self.buf.extend_from_slice(&data);
+                    buffer_pool::append(&self.decoder.pool, &mut self.buf, data);
                 },
             }
         }
@@ -231,7 +274,7 @@ mod test {
 
             let r: Vec<Bytes> = r.into_iter().map(|&s| Bytes::from(s)).collect();
 
-            let rr = parse_grpc_frames_from_bytes(&mut b).unwrap();
+            let rr = parse_grpc_frames_from_bytes(&mut b, &MessageDecoder::default()).unwrap();
             assert_eq!(r, rr);
             assert_eq!(trail, b.as_ref());
         }
@@ -244,4 +287,19 @@ mod test {
             &[&b"ab"[..], &b"cde"[..]],
             &b"\0\x00\x00\x00\x02ab\0\x00\x00\x00\x03cde"[..], &b"\x00"[..]);
     }
+
+    #[test]
+    fn invalid_frames() {
+        fn err(input: &[u8]) -> String {
+            match parse_grpc_frames_completely(input) {
+                Err(Error::Protocol(message)) => message.to_owned(),
+                r => panic!("{:?}", r),
+            }
+        }
+
+        assert_eq!("unknown compression flag", err(b"\x02\x00\x00\x00\x01a"));
+        assert_eq!("unknown compression flag", err(b"\x81\x00\x00\x00\x01a"));
+        assert_eq!("message length exceeds remaining body", err(b"\x00\x00\x00\x00\x03ab"));
+        assert_eq!("trailing data after last message", err(b"\x00\x00\x00\x00\x01a\x00"));
+    }
 }
@@ -1,6 +1,8 @@
 ///! Convert HTTP response stream to gRPC stream
 
 use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
 
 use futures::Async;
 use futures::Poll;
@@ -16,6 +18,10 @@ use httpbis;
 use httpbis::Headers;
 
 use grpc_frame::*;
+use compression::CodecRegistry;
+use compression::MessageDecoder;
+use buffer_pool;
+use buffer_pool::BufferPool;
 
 use bytes::Bytes;
 
@@ -31,9 +37,11 @@ use httpbis::HttpStreamAfterHeaders;
 use httpbis::DataOrTrailers;
 
 
-fn init_headers_to_metadata(headers: Headers) -> result::Result<Metadata> {
+fn init_headers_to_metadata(headers: Headers, duplicate_keys: DuplicateKeyPolicy)
+    -> result::Result<Metadata>
+{
     if headers.get_opt(":status") != Some("200") {
-        return Err(Error::Other("not 200"));
+        return Err(Error::Protocol("HTTP status is not 200"));
     }
 
     // Check gRPC status code and message
@@ -50,15 +58,30 @@ fn init_headers_to_metadata(headers: Headers) -> result::Result<Metadata> {
         }
     }
 
-    Ok(Metadata::from_headers(headers)?)
+    Ok(Metadata::from_headers_with(headers, duplicate_keys)?)
 }
 
 
-pub fn http_response_to_grpc_frames(response: httpbis::Response) -> StreamingResponse<Bytes> {
-    StreamingResponse::new(response.0.map_err(|e| Error::from(e)).and_then(|(headers, rem)| {
-        let metadata = init_headers_to_metadata(headers)?;
-        let frames: GrpcStreamWithTrailingMetadata<Bytes> =
-            GrpcStreamWithTrailingMetadata::new(GrpcFrameFromHttpFramesStreamResponse::new(rem));
+pub fn http_response_to_grpc_frames(
+    response: httpbis::Response,
+    codecs: Arc<CodecRegistry>,
+    max_message_size: Option<usize>,
+    pool: Option<Arc<BufferPool>>,
+    duplicate_keys: DuplicateKeyPolicy)
+    -> StreamingResponse<Bytes>
+{
+    StreamingResponse::new(response.0.map_err(|e| Error::from(e)).and_then(move |(headers, rem)| {
+        let decoder = MessageDecoder {
+            codec: codecs.decoder(&headers)?,
+            max_message_size: max_message_size,
+            pool: pool,
+        };
+        // trailers-only response, body must be empty
+        let trailers_only = headers.get_opt(HEADER_GRPC_STATUS).is_some();
+        let metadata = init_headers_to_metadata(headers, duplicate_keys)?;
+        let mut frames = GrpcFrameFromHttpFramesStreamResponse::new(rem, decoder, duplicate_keys);
+        frames.trailers_received = trailers_only;
+        let frames: GrpcStreamWithTrailingMetadata<Bytes> = GrpcStreamWithTrailingMetadata::new(frames);
         Ok((metadata, frames))
     }))
 }
@@ -66,17 +89,34 @@ pub fn http_response_to_grpc_frames(response: httpbis::Response) -> StreamingRes
 
 struct GrpcFrameFromHttpFramesStreamResponse {
     http_stream_stream: HttpStreamAfterHeaders,
+    decoder: MessageDecoder,
+    duplicate_keys: DuplicateKeyPolicy,
     buf: Bytes,
     parsed_frames: VecDeque<Bytes>,
+    /// Stream ending before trailers means peer closed connection or reset the stream
+    trailers_received: bool,
     error: Option<stream::Once<ItemOrMetadata<Bytes>, Error>>,
 }
 
+/// Response stream ended before trailers.
+fn closed_before_trailers_error() -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "stream closed before trailers"))
+}
+
 impl GrpcFrameFromHttpFramesStreamResponse {
-    pub fn new(http_stream_stream: HttpStreamAfterHeaders) -> Self {
+    pub fn new(
+        http_stream_stream: HttpStreamAfterHeaders,
+        decoder: MessageDecoder,
+        duplicate_keys: DuplicateKeyPolicy)
+        -> Self
+    {
         GrpcFrameFromHttpFramesStreamResponse {
             http_stream_stream,
+            decoder,
+            duplicate_keys,
             buf: Bytes::new(),
             parsed_frames: VecDeque::new(),
+            trailers_received: false,
             error: None,
         }
     }
@@ -92,7 +132,7 @@ impl Stream for GrpcFrameFromHttpFramesStreamResponse {
                 return error.poll();
             }
 
-            self.parsed_frames.extend(match parse_grpc_frames_from_bytes(&mut self.buf) {
+            self.parsed_frames.extend(match parse_grpc_frames_from_bytes(&mut self.buf, &self.decoder) {
                 Ok(r) => r,
                 Err(e) => {
                     self.error = Some(stream::once(Err(e)));
@@ -110,10 +150,13 @@ impl Stream for GrpcFrameFromHttpFramesStreamResponse {
             };
             let part = match part_opt {
                 None => {
-                    if self.buf.is_empty() {
+                    if !self.trailers_received {
+                        self.error = Some(stream::once(Err(closed_before_trailers_error())));
+                        continue;
+                    } else if self.buf.is_empty() {
                         return Ok(Async::Ready(None));
                     } else {
-                        self.error = Some(stream::once(Err(Error::Other("partial frame"))));
+                        self.error = Some(stream::once(Err(incomplete_frame_error(&self.buf))));
                         continue;
                     }
                 },
@@ -122,30 +165,80 @@ impl Stream for GrpcFrameFromHttpFramesStreamResponse {
 
             match part {
                 DataOrTrailers::Trailers(headers) => {
+                    self.trailers_received = true;
                     if !self.buf.is_empty() {
-                        self.error = Some(stream::once(Err(Error::Other("partial frame"))));
+                        self.error = Some(stream::once(Err(incomplete_frame_error(&self.buf))));
                     } else {
                         let grpc_status = headers.get_opt_parse(HEADER_GRPC_STATUS);
                         if grpc_status == Some(GrpcStatus::Ok as i32) {
                             return Ok(Async::Ready(Some(ItemOrMetadata::TrailingMetadata(
-                                Metadata::from_headers(headers)?))));
+                                Metadata::from_headers_with(headers, self.duplicate_keys)?))));
                         } else {
-                            self.error = Some(stream::once(Err(if let Some(message) = headers.get_opt(HEADER_GRPC_MESSAGE) {
-                                Error::GrpcMessage(GrpcMessageError {
+                            let message = headers.get_opt(HEADER_GRPC_MESSAGE);
+                            self.error = Some(stream::once(Err(match (grpc_status, message) {
+                                (None, None) => Error::Protocol("trailers without grpc-status"),
+                                (grpc_status, message) => Error::GrpcMessage(GrpcMessageError {
                                     grpc_status: grpc_status.unwrap_or(GrpcStatus::Unknown as i32),
-                                    grpc_message: message.to_owned(),
-                                })
-                            } else {
-                                Error::Other("not xxx")
+                                    grpc_message: message.unwrap_or("").to_owned(),
+                                }),
                             })));
                         }
                     }
                     continue;
                 },
                 DataOrTrailers::Data(data, ..) => {
-                    self.buf.extend_from_slice(&data);
+                    buffer_pool::append(&self.decoder.pool, &mut self.buf, data);
                 }
             }
         }
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use httpbis::Header;
+
+    fn response(headers: Vec<Header>, parts: Vec<DataOrTrailers>) -> StreamingResponse<Bytes> {
+        let response = httpbis::Response::headers_and_stream(
+            Headers(headers),
+            HttpStreamAfterHeaders::new(stream::iter_ok(parts)));
+        http_response_to_grpc_frames(
+            response, Arc::new(CodecRegistry::new(&[])), None, None, DuplicateKeyPolicy::default())
+    }
+
+    fn data(message: &[u8]) -> DataOrTrailers {
+        DataOrTrailers::intermediate_data(Bytes::from(write_grpc_frame_to_vec(message)))
+    }
+
+    #[test]
+    fn closed_before_trailers() {
+        let r = response(vec![Header::new(":status", "200")], vec![data(b"ab")]);
+        match r.collect().wait() {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            r => panic!("expecting EOF: {:?}", r.map(|(_, items, _)| items)),
+        }
+    }
+
+    #[test]
+    fn trailers() {
+        let r = response(vec![Header::new(":status", "200")], vec![
+            data(b"ab"),
+            DataOrTrailers::Trailers(Headers(vec![Header::new(HEADER_GRPC_STATUS, "0")])),
+        ]);
+        let (_, items, _) = r.collect().wait().expect("response");
+        assert_eq!(vec![Bytes::from_static(b"ab")], items);
+    }
+
+    #[test]
+    fn trailers_only() {
+        let r = response(vec![
+            Header::new(":status", "200"),
+            Header::new(HEADER_GRPC_STATUS, "0"),
+        ], vec![]);
+        let (_, items, _) = r.collect().wait().expect("response");
+        assert!(items.is_empty());
+    }
+}
@@ -0,0 +1,53 @@
+//! Observing serialized messages of calls with their methods,
+//! e. g. to check schema compatibility or to record traffic.
+
+use std::sync::Arc;
+
+use method::GrpcStreaming;
+use method::MethodDescriptor;
+use method::MethodOptions;
+
+
+/// Method of an inspected message.
+#[derive(Debug, Clone)]
+pub struct MethodInfo {
+    /// Full path like `/package.Service/Method`
+    pub name: String,
+    pub streaming: GrpcStreaming,
+    pub options: MethodOptions,
+}
+
+impl MethodInfo {
+    pub fn of<Req, Resp>(descriptor: &MethodDescriptor<Req, Resp>) -> MethodInfo {
+        MethodInfo {
+            name: descriptor.name.clone(),
+            streaming: descriptor.streaming,
+            options: descriptor.options.clone(),
+        }
+    }
+}
+
+/// Receives serialized messages of calls.
+///
+/// Messages are uncompressed, as they are passed to and from marshallers.
+/// Inspectors are invoked on the thread driving the call,
+/// so they should not block.
+pub trait MessageInspector : Send + Sync + 'static {
+    /// Message serialized by this side, before it is sent.
+    fn sent(&self, _method: &MethodInfo, _message: &[u8]) {}
+
+    /// Message received from peer, before it is parsed.
+    fn received(&self, _method: &MethodInfo, _message: &[u8]) {}
+}
+
+pub(crate) fn inspect_sent(inspectors: &[Arc<MessageInspector>], method: &MethodInfo, message: &[u8]) {
+    for inspector in inspectors {
+        inspector.sent(method, message);
+    }
+}
+
+pub(crate) fn inspect_received(inspectors: &[Arc<MessageInspector>], method: &MethodInfo, message: &[u8]) {
+    for inspector in inspectors {
+        inspector.received(method, message);
+    }
+}
@@ -0,0 +1,170 @@
+//! Hooks invoked around client and server calls.
+//!
+//! Interceptors see serialized messages, so the same interceptor
+//! works for all methods regardless of message types.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use futures::future::Future;
+
+use req::*;
+use resp::*;
+use cancel::cancelled;
+use cancel::with_cancellation;
+use client::ClientTransport;
+use deadline::deadline_exceeded;
+use deadline::with_deadline;
+use deadline::MessageTimeout;
+use method::MethodOptions;
+use server::ServerServiceDefinition;
+
+
+/// Client-side interceptor.
+pub trait ClientInterceptor : Send + Sync + 'static {
+    /// Called for each call before it is sent.
+    ///
+    /// Implementation should pass (possibly modified) request to `next`,
+    /// or return response without calling it.
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>;
+}
+
+/// Server-side interceptor.
+pub trait ServerInterceptor : Send + Sync + 'static {
+    /// Called for each call before it is dispatched to method handler.
+    ///
+    /// Implementation should pass (possibly modified) request to `next`,
+    /// or return response without calling it.
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>;
+}
+
+
+/// Remaining part of client interceptor chain.
+///
+/// Can be cloned to send the same call several times, e. g. for retries.
+#[derive(Clone)]
+pub struct ClientNext {
+    pub(crate) interceptors: Arc<Vec<Arc<ClientInterceptor>>>,
+    pub(crate) index: usize,
+    pub(crate) method: String,
+    pub(crate) method_options: MethodOptions,
+    pub(crate) transport: Arc<ClientTransport>,
+}
+
+impl ClientNext {
+    /// Name of the method being called.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Options of the method being called.
+    pub fn method_options(&self) -> &MethodOptions {
+        &self.method_options
+    }
+
+    /// Invoke next interceptor, or send request if this is the last one.
+    pub fn call(self, o: RequestOptions, req: StreamingRequest<Bytes>) -> StreamingResponse<Bytes> {
+        match self.interceptors.get(self.index).cloned() {
+            Some(interceptor) => {
+                let method = self.method.clone();
+                let next = ClientNext {
+                    index: self.index + 1,
+                    ..self
+                };
+                interceptor.intercept(&method, o, req, next)
+            }
+            None => self.send(o, req),
+        }
+    }
+
+    /// Send request, failing it when deadline passes, message timeout
+    /// is exceeded or call is cancelled.
+    fn send(self, o: RequestOptions, req: StreamingRequest<Bytes>) -> StreamingResponse<Bytes> {
+        if o.is_cancelled() {
+            return StreamingResponse::err(cancelled());
+        }
+        let cancellation = o.cancellation.clone();
+        let deadline = o.deadline;
+        if let Some(deadline) = deadline {
+            if deadline <= Instant::now() {
+                return StreamingResponse::err(deadline_exceeded());
+            }
+        }
+        let message_timeout = o.message_timeout.map(MessageTimeout::new);
+        let req = match message_timeout {
+            Some(ref message_timeout) => StreamingRequest(message_timeout.request(req.0)),
+            None => req,
+        };
+        let resp = if o.wait_for_ready {
+            let transport = self.transport.clone();
+            let method = self.method;
+            StreamingResponse::new(self.transport.wait_for_ready().and_then(move |()| {
+                transport.call(&method, o, req).0
+            }))
+        } else {
+            self.transport.call(&self.method, o, req)
+        };
+        let resp = match deadline {
+            Some(deadline) => with_deadline(resp, deadline),
+            None => resp,
+        };
+        let resp = match message_timeout {
+            Some(message_timeout) => message_timeout.response(resp),
+            None => resp,
+        };
+        match cancellation {
+            Some(ref cancellation) => with_cancellation(resp, cancellation),
+            None => resp,
+        }
+    }
+}
+
+
+/// Remaining part of server interceptor chain.
+pub struct ServerNext {
+    pub(crate) interceptors: Arc<Vec<Arc<ServerInterceptor>>>,
+    pub(crate) index: usize,
+    pub(crate) method: String,
+    pub(crate) service_definition: Arc<ServerServiceDefinition>,
+}
+
+impl ServerNext {
+    /// Name of the method being called.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Options of the method being called, `None` if there's no such method.
+    pub fn method_options(&self) -> Option<&MethodOptions> {
+        self.service_definition.find_method(&self.method).map(|m| &m.options)
+    }
+
+    /// Invoke next interceptor, or method handler if this is the last one.
+    pub fn call(self, o: RequestOptions, req: StreamingRequest<Bytes>) -> StreamingResponse<Vec<u8>> {
+        match self.interceptors.get(self.index).cloned() {
+            Some(interceptor) => {
+                let method = self.method.clone();
+                let next = ServerNext {
+                    index: self.index + 1,
+                    ..self
+                };
+                interceptor.intercept(&method, o, req, next)
+            }
+            None => self.service_definition.handle_method(&self.method, o, req),
+        }
+    }
+}
@@ -0,0 +1,420 @@
+//! JWT bearer tokens: OAuth2 service account credentials for clients
+//! and token verification for servers.
+
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use bytes::Bytes;
+
+use futures::future::Future;
+
+use jsonwebtoken;
+use jsonwebtoken::Algorithm;
+use jsonwebtoken::DecodingKey;
+use jsonwebtoken::EncodingKey;
+use jsonwebtoken::Validation;
+
+use serde_json;
+
+use auth::AuthorizationError;
+use auth::PeerIdentity;
+use credentials::AccessToken;
+use credentials::TokenSource;
+use credentials::AUTHORIZATION;
+use error::Error;
+use error::GrpcMessageError;
+use futures_grpc::GrpcFuture;
+use grpc::GrpcStatus;
+use interceptor::*;
+use req::*;
+use resp::*;
+use result;
+
+
+/// Google OAuth2 token endpoint.
+pub const GOOGLE_TOKEN_URI: &'static str = "https://oauth2.googleapis.com/token";
+
+/// HTTP POST of `application/x-www-form-urlencoded` body to URL, returning response body.
+///
+/// Token endpoints are usually HTTP/1.1 services, so the transport is provided by user.
+pub type HttpPost = Fn(&str, String) -> GrpcFuture<Vec<u8>> + Send + Sync;
+
+fn token_error(message: String) -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::Unavailable as i32,
+        grpc_message: message,
+    })
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+#[derive(Serialize)]
+struct AssertionClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+/// OAuth2 token source signing assertions with service account key
+/// (RFC 7523 JWT bearer grant).
+///
+/// Use with `RefreshingTokenCredentials`.
+pub struct ServiceAccountTokenSource {
+    client_email: String,
+    key: EncodingKey,
+    token_uri: String,
+    scope: String,
+    post: Arc<HttpPost>,
+}
+
+impl ServiceAccountTokenSource {
+    /// `private_key_pem` is RSA key in PEM format, as found in service account JSON file.
+    /// `scope` is space-separated list of requested scopes.
+    pub fn new(
+        client_email: &str,
+        private_key_pem: &[u8],
+        token_uri: &str,
+        scope: &str,
+        post: Arc<HttpPost>)
+        -> result::Result<ServiceAccountTokenSource>
+    {
+        let key = EncodingKey::from_rsa_pem(private_key_pem)
+            .map_err(|e| token_error(format!("invalid service account key: {}", e)))?;
+        Ok(ServiceAccountTokenSource {
+            client_email: client_email.to_owned(),
+            key: key,
+            token_uri: token_uri.to_owned(),
+            scope: scope.to_owned(),
+            post: post,
+        })
+    }
+
+    fn assertion(&self) -> result::Result<String> {
+        let iat = unix_now();
+        let claims = AssertionClaims {
+            iss: &self.client_email,
+            scope: &self.scope,
+            aud: &self.token_uri,
+            iat: iat,
+            exp: iat + 3600,
+        };
+        jsonwebtoken::encode(&jsonwebtoken::Header::new(Algorithm::RS256), &claims, &self.key)
+            .map_err(|e| token_error(format!("failed to sign assertion: {}", e)))
+    }
+}
+
+impl TokenSource for ServiceAccountTokenSource {
+    fn fetch_token(&self) -> GrpcFuture<AccessToken> {
+        let assertion = match self.assertion() {
+            Ok(assertion) => assertion,
+            Err(e) => return Box::new(::futures::future::err(e)),
+        };
+        // assertion is base64url, no escaping needed
+        let body = format!(
+            "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer&assertion={}",
+            assertion);
+
+        let requested = Instant::now();
+        Box::new((self.post)(&self.token_uri, body).and_then(move |response| {
+            let response: TokenResponse = serde_json::from_slice(&response)
+                .map_err(|e| token_error(format!("invalid token response: {}", e)))?;
+            Ok(AccessToken {
+                token: response.access_token,
+                // expiration too far to represent is no expiration
+                expires: response.expires_in.and_then(|s| requested.checked_add(Duration::from_secs(s))),
+            })
+        }))
+    }
+}
+
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audiences {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    #[serde(default)]
+    sub: String,
+    aud: Option<Audiences>,
+}
+
+fn parse_jwks(jwks: &[u8]) -> result::Result<Vec<Jwk>> {
+    let jwks: Jwks = serde_json::from_slice(jwks)
+        .map_err(|e| token_error(format!("invalid JWKS: {}", e)))?;
+    Ok(jwks.keys.into_iter().filter(|k| k.kty == "RSA").collect())
+}
+
+/// Server interceptor verifying RS256 `authorization: Bearer` tokens
+/// against JSON Web Key Set.
+///
+/// Calls with valid token get `peer_identity` from token claims,
+/// calls with invalid token are rejected with `UNAUTHENTICATED`.
+/// Calls without token are passed through, so anonymous access
+/// can be restricted by `AuthorizationInterceptor` added after this one.
+pub struct JwtVerifier {
+    keys: RwLock<Vec<Jwk>>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl JwtVerifier {
+    /// Create verifier from JWKS document, e. g. downloaded from `jwks_uri`.
+    pub fn from_jwks(jwks: &[u8]) -> result::Result<JwtVerifier> {
+        Ok(JwtVerifier {
+            keys: RwLock::new(parse_jwks(jwks)?),
+            issuer: None,
+            audience: None,
+        })
+    }
+
+    /// Replace keys, e. g. after key rotation.
+    pub fn update_jwks(&self, jwks: &[u8]) -> result::Result<()> {
+        *self.keys.write().unwrap() = parse_jwks(jwks)?;
+        Ok(())
+    }
+
+    /// Require `iss` claim.
+    pub fn set_issuer(&mut self, issuer: &str) {
+        self.issuer = Some(issuer.to_owned());
+    }
+
+    /// Require `aud` claim to contain `audience`.
+    pub fn set_audience(&mut self, audience: &str) {
+        self.audience = Some(audience.to_owned());
+    }
+
+    fn verify(&self, token: &str) -> Result<PeerIdentity, AuthorizationError> {
+        let invalid = |e: jsonwebtoken::errors::Error| {
+            AuthorizationError::Unauthenticated(format!("invalid token: {}", e))
+        };
+
+        let header = jsonwebtoken::decode_header(token).map_err(&invalid)?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.iss = self.issuer.clone();
+        if let Some(ref audience) = self.audience {
+            validation.set_audience(&[audience]);
+        }
+
+        let keys = self.keys.read().unwrap();
+        // token without `kid` can be signed by any key of the set
+        let candidates: Vec<&Jwk> = keys.iter()
+            .filter(|k| header.kid.is_none() || k.kid == header.kid)
+            .collect();
+        if candidates.is_empty() {
+            return Err(AuthorizationError::Unauthenticated("unknown token key".to_owned()));
+        }
+
+        let mut last_error = None;
+        for key in candidates {
+            let (n, e) = match (&key.n, &key.e) {
+                (&Some(ref n), &Some(ref e)) => (n, e),
+                _ => continue,
+            };
+
+            match jsonwebtoken::decode::<Claims>(token, &DecodingKey::from_rsa_components(n, e), &validation) {
+                Ok(data) => return Ok(peer_identity(data.claims)),
+                Err(err) => {
+                    // signature is checked before claims, so other errors are final
+                    let wrong_key = match err.kind() {
+                        &jsonwebtoken::errors::ErrorKind::InvalidSignature => true,
+                        _ => false,
+                    };
+                    if !wrong_key {
+                        return Err(invalid(err));
+                    }
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        Err(match last_error {
+            Some(e) => invalid(e),
+            None => AuthorizationError::Unauthenticated("invalid token key".to_owned()),
+        })
+    }
+}
+
+fn peer_identity(claims: Claims) -> PeerIdentity {
+    PeerIdentity {
+        subject: claims.sub,
+        audiences: match claims.aud {
+            None => Vec::new(),
+            Some(Audiences::One(a)) => vec![a],
+            Some(Audiences::Many(a)) => a,
+        },
+        ..Default::default()
+    }
+}
+
+impl ServerInterceptor for JwtVerifier {
+    fn intercept(
+        &self,
+        method: &str,
+        mut o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>
+    {
+        let identity = match o.metadata.get(AUTHORIZATION) {
+            None => None,
+            Some(value) => {
+                let token = match ::std::str::from_utf8(value) {
+                    Ok(value) if value.starts_with("Bearer ") => &value["Bearer ".len()..],
+                    _ => {
+                        return StreamingResponse::err(AuthorizationError::Unauthenticated(
+                            "expecting bearer token".to_owned()).into());
+                    }
+                };
+                match self.verify(token) {
+                    Ok(identity) => Some(identity),
+                    Err(e) => {
+                        debug!("call to {} rejected: {:?}", method, e);
+                        return StreamingResponse::err(e.into());
+                    }
+                }
+            }
+        };
+
+        // calls without token keep identity set by other interceptors
+        if identity.is_some() {
+            o.peer_identity = identity;
+        }
+        next.call(o, req)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use jsonwebtoken::Header;
+
+    const KEY_A: &[u8] = include_bytes!("../tests/keys/jwt-a.pem");
+    const KEY_B: &[u8] = include_bytes!("../tests/keys/jwt-b.pem");
+
+    /// Public parts of `KEY_A` and `KEY_B`
+    const JWKS: &str = r#"{"keys": [
+        {"kty": "RSA", "kid": "a", "e": "AQAB",
+         "n": "oFkBqyx_DIMwKH7yTombb66fM54JD_hKQK97ILcplBQYAgHmgNromnv0yOknZh0_LDyNOar-5RKYFn6nMhzMWoaCReNLUSDhneq_fbXDTklMiY0Pfs8eRioi1xpjFy4P9HBhpjlZY-WGCTE57zakDH1tH20z6Wm9gyO4LsHPpwTTKhygmwMib61-lIXikCgvL_WRzpQA_Ml6PukeFhwKoPvims2RN-ZiS6YdwSZQfBH2berWvoaZ0mwpV4qAG_-T3SDcETON1gHoOJjRP_5F5QSUtCsC31Kut9-Mp47GLqSLj7foBVDZWk8j7SeAa07Xf5kD3Onbusn88UjL2WVlDQ"},
+        {"kty": "RSA", "kid": "b", "e": "AQAB",
+         "n": "k5TmM4iWrW17njc4SImhhxgQ6T_z2HyDzaU5eI_CwyKdEs5nL6lGc2vs23cKYiX35VeDFeaKAegMbRbK2TFo1RZS8cxPRYpJo_8zzt4p3zUWuKaA1pO7XLbgVOfkdsi5VIYl9Q10RkmFxy9J4PyvjCKWiXZNom1NQhMMe1jEH5E8W29tpaIdq6ht249dS6Yy1p6uPWt3CUUXlRp5edla5HKmgQ-xIWxEAiwPxR6rNbs9HXASm4k5VLbtg3uxeus4wukgxDpishbQFq9NXTPSM5F545AdbSasuJfYNTkrIkgxc1x4PQnqL7nb609dbHoqjWi_LthgKfMUbD1p6vYHZw"}
+    ]}"#;
+
+    #[derive(Serialize)]
+    struct TestClaims {
+        sub: &'static str,
+        iss: &'static str,
+        aud: &'static str,
+        exp: u64,
+    }
+
+    fn claims() -> TestClaims {
+        TestClaims {
+            sub: "alice",
+            iss: "issuer",
+            aud: "service",
+            exp: unix_now() + 3600,
+        }
+    }
+
+    fn sign(key: &[u8], kid: Option<&str>, claims: &TestClaims) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = kid.map(str::to_owned);
+        jsonwebtoken::encode(&header, claims, &EncodingKey::from_rsa_pem(key).unwrap()).unwrap()
+    }
+
+    fn verifier() -> JwtVerifier {
+        let mut verifier = JwtVerifier::from_jwks(JWKS.as_bytes()).unwrap();
+        verifier.set_issuer("issuer");
+        verifier.set_audience("service");
+        verifier
+    }
+
+    fn assert_rejected(token: &str) {
+        match verifier().verify(token) {
+            Err(AuthorizationError::Unauthenticated(_)) => {}
+            r => panic!("expecting unauthenticated: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let identity = verifier().verify(&sign(KEY_A, Some("a"), &claims())).unwrap();
+        assert_eq!("alice", identity.subject);
+        assert_eq!(vec!["service".to_owned()], identity.audiences);
+    }
+
+    #[test]
+    fn valid_without_kid() {
+        // signed with second key of the set
+        let identity = verifier().verify(&sign(KEY_B, None, &claims())).unwrap();
+        assert_eq!("alice", identity.subject);
+    }
+
+    #[test]
+    fn bad_signature() {
+        assert_rejected(&sign(KEY_B, Some("a"), &claims()));
+
+        let token = sign(KEY_A, Some("a"), &claims());
+        let (payload, signature) = token.split_at(token.rfind('.').unwrap() + 1);
+        let first = if signature.starts_with('A') { "B" } else { "A" };
+        assert_rejected(&format!("{}{}{}", payload, first, &signature[1..]));
+    }
+
+    #[test]
+    fn wrong_audience() {
+        assert_rejected(&sign(KEY_A, Some("a"), &TestClaims { aud: "other", ..claims() }));
+    }
+
+    #[test]
+    fn wrong_issuer() {
+        assert_rejected(&sign(KEY_A, Some("a"), &TestClaims { iss: "other", ..claims() }));
+    }
+
+    #[test]
+    fn expired() {
+        assert_rejected(&sign(KEY_A, Some("a"), &TestClaims { exp: unix_now() - 3600, ..claims() }));
+    }
+
+    #[test]
+    fn unknown_kid() {
+        match verifier().verify(&sign(KEY_A, Some("c"), &claims())) {
+            Err(AuthorizationError::Unauthenticated(ref m)) if m == "unknown token key" => {}
+            r => panic!("{:?}", r),
+        }
+    }
+}
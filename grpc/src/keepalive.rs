@@ -0,0 +1,95 @@
+//! Keepalive messages of long-lived response streams.
+//!
+//! HTTP/2 pings are per connection and sent by `httpbis`,
+//! so quiet streams are kept alive by application-level messages,
+//! e. g. empty messages the client knows to skip.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::Async;
+use futures::Poll;
+use futures::future::Future;
+use futures::stream::Stream;
+
+use error::Error;
+use futures_grpc::GrpcFuture;
+use resp::StreamingResponse;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use stream_item::ItemOrMetadata;
+use timer;
+
+
+/// Response sending message made by `keepalive` whenever
+/// no message is sent for `interval`, so intermediaries
+/// with idle timeouts don't close the stream.
+///
+/// Keepalive messages are sent only after initial metadata
+/// and before trailing metadata.
+pub fn with_keepalive<T, F>(resp: StreamingResponse<T>, interval: Duration, keepalive: F)
+    -> StreamingResponse<T>
+    where
+        T : Send + 'static,
+        F : FnMut() -> T + Send + 'static,
+{
+    StreamingResponse::new(resp.0.map(move |(metadata, stream)| {
+        let stream = KeepaliveStream {
+            stream: stream.0,
+            interval: interval,
+            last: Instant::now(),
+            timer: timer::sleep(interval),
+            keepalive: keepalive,
+            trailing: false,
+        };
+        (metadata, GrpcStreamWithTrailingMetadata::new(stream))
+    }))
+}
+
+struct KeepaliveStream<S, F> {
+    stream: S,
+    interval: Duration,
+    /// When the last message was sent
+    last: Instant,
+    /// Re-armed only when it fires, not on every message
+    timer: GrpcFuture<()>,
+    keepalive: F,
+    /// Trailing metadata is sent, only end of stream may follow
+    trailing: bool,
+}
+
+impl<T, S, F> Stream for KeepaliveStream<S, F>
+    where
+        T : Send + 'static,
+        S : Stream<Item=ItemOrMetadata<T>, Error=Error>,
+        F : FnMut() -> T,
+{
+    type Item = ItemOrMetadata<T>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ItemOrMetadata<T>>, Error> {
+        if let Async::Ready(item) = self.stream.poll()? {
+            if let Some(ItemOrMetadata::TrailingMetadata(..)) = item {
+                self.trailing = true;
+            }
+            self.last = Instant::now();
+            return Ok(Async::Ready(item));
+        }
+        if self.trailing {
+            return Ok(Async::NotReady);
+        }
+        loop {
+            if let Ok(Async::NotReady) = self.timer.poll() {
+                return Ok(Async::NotReady);
+            }
+            let due = self.last + self.interval;
+            if due <= Instant::now() {
+                break;
+            }
+            self.timer = timer::sleep_until(due);
+        }
+        self.last = Instant::now();
+        // new timer is polled when the stream is polled after sending the message
+        self.timer = timer::sleep_until(self.last + self.interval);
+        Ok(Async::Ready(Some(ItemOrMetadata::Item((self.keepalive)()))))
+    }
+}
@@ -5,10 +5,32 @@ extern crate futures;
 extern crate bytes;
 extern crate futures_cpupool;
 extern crate tokio_core;
+extern crate tokio_io;
 extern crate tls_api;
 extern crate tls_api_stub;
+#[cfg(feature = "rustls")]
+extern crate tls_api_rustls;
+#[cfg(feature = "native-tls")]
+extern crate tls_api_native_tls;
 extern crate tokio_tls_api;
 extern crate base64;
+extern crate rand;
+extern crate serde_json;
+#[cfg(feature = "gzip")]
+extern crate flate2;
+#[cfg(feature = "snappy")]
+extern crate snap;
+#[cfg(feature = "zstd")]
+extern crate zstd_lib as zstd;
+#[cfg(feature = "jwt")]
+extern crate jsonwebtoken;
+#[cfg(feature = "tracing")]
+extern crate tracing_lib as tracing;
+#[cfg(any(feature = "jwt", feature = "with-serde"))]
+extern crate serde;
+#[cfg(any(feature = "jwt", feature = "with-serde"))]
+#[macro_use]
+extern crate serde_derive;
 
 // renamed to avoid name conflict with local protobuf library
 extern crate protobuf as protobuf_lib;
@@ -19,6 +41,7 @@ mod futures_misc;
 mod misc;
 
 mod client;
+mod channel_pool;
 mod server;
 mod server_method;
 
@@ -39,31 +62,121 @@ mod futures_grpc;
 mod error;
 mod iter;
 mod metadata;
+mod target;
+mod resolver;
+mod file_resolver;
+mod xds;
+mod balancer;
+mod call_stats;
+mod experiments;
+mod timer;
+mod deadline;
+mod cancel;
+mod interceptor;
+mod inspect;
+mod chaos;
+mod compression;
+mod write_batch;
+mod buffer_pool;
+mod cache;
+mod dedup;
+mod retry;
+mod pagination;
+mod pipe;
+mod keepalive;
+mod operations;
+mod reflect;
+mod protobuf_json;
+mod priority;
+mod debug;
+mod logging;
+mod rate_limit;
+mod request_id;
+mod circuit_breaker;
+mod concurrency_limit;
+mod load_report;
+mod auth;
+mod peer_cert;
+mod credentials;
+mod replay;
+#[cfg(feature = "jwt")]
+mod jwt;
+#[cfg(feature = "tracing")]
+mod span;
 
 pub mod rt;
 pub mod protobuf;
+pub mod well_known_types;
+pub mod transfer;
+pub mod tls;
 
 pub mod for_test;
 
 
 pub use error::Error;
 pub use error::GrpcMessageError;
+pub use error::ErrorKind;
 pub use grpc::GrpcStatus;
 pub use result::Result;
 
 pub use stream_item::ItemOrMetadata;
 
+pub use client::Channel;
 pub use client::Client;
 pub use client::ClientConf;
+pub use client::ClientTransport;
+pub use channel_pool::ChannelPool;
+pub use replay::RecordingTransport;
+pub use replay::ReplayTransport;
+
+pub use target::ClientTarget;
+
+pub use resolver::Resolver;
+pub use resolver::DefaultResolver;
+pub use file_resolver::FileResolver;
+pub use xds::XdsResolver;
+
+pub use balancer::BalancingPolicy;
+pub use balancer::OutlierDetectionConf;
+pub use balancer::AddressUpdates;
+pub use balancer::WeightedAddr;
+pub use balancer::ConnectionState;
+
+pub use load_report::LoadReport;
+pub use load_report::LoadRecorder;
+pub use load_report::LoadReportInterceptor;
+pub use load_report::LOAD_REPORT_METADATA_KEY;
 
 pub use server::Server;
 pub use server::ServerBuilder;
 pub use server::ServerConf;
 
+pub use experiments::Experiments;
+pub use experiments::EXPERIMENTS;
+pub use experiments::EXPERIMENTS_ENV_VAR;
+
 pub use resp::SingleResponse;
 pub use resp::StreamingResponse;
+pub use resp::Response;
 
 pub use req::RequestOptions;
+pub use cancel::Cancellation;
+
+pub use method::Method;
+pub use method::MethodOptions;
+pub use method::IdempotencyLevel;
+
+pub use call_stats::CallStats;
+pub use call_stats::CallStatsCollector;
+pub use call_stats::CallEvent;
+pub use call_stats::StatsHandler;
+
+pub use write_batch::WriteStrategy;
+pub use write_batch::Cork;
+
+pub use buffer_pool::BufferPool;
+pub use buffer_pool::BufferPoolStats;
+
 pub use req::StreamingRequest;
 
 pub use futures_grpc::GrpcStream;
@@ -71,3 +184,95 @@ pub use futures_grpc::GrpcFuture;
 
 pub use metadata::Metadata;
 pub use metadata::MetadataKey;
+pub use metadata::DuplicateKeyPolicy;
+
+pub use interceptor::ClientInterceptor;
+pub use interceptor::ClientNext;
+pub use interceptor::ServerInterceptor;
+pub use interceptor::ServerNext;
+
+pub use inspect::MessageInspector;
+pub use inspect::MethodInfo;
+
+pub use chaos::ChaosConf;
+pub use chaos::ChaosInterceptor;
+
+pub use cache::CacheKey;
+pub use cache::ResponseCache;
+pub use cache::LruResponseCache;
+pub use cache::CachingInterceptor;
+
+pub use dedup::DeduplicationInterceptor;
+pub use dedup::DEFAULT_IDEMPOTENCY_KEY;
+
+pub use retry::RetryPolicy;
+pub use retry::RetryThrottlingConf;
+pub use retry::RetryInterceptor;
+
+pub use pagination::Paginator;
+
+pub use pipe::pipe;
+
+pub use keepalive::with_keepalive;
+
+pub use operations::OperationsClient;
+pub use operations::PollPolicy;
+pub use operations::GET_OPERATION_PATH;
+
+pub use priority::CallPriority;
+pub use priority::PriorityInterceptor;
+pub use priority::PRIORITY_METADATA_KEY;
+
+pub use debug::DebugService;
+pub use debug::DEBUG_STATE_PATH;
+
+pub use logging::LoggingConf;
+pub use logging::LoggingInterceptor;
+
+pub use rate_limit::RateLimitConf;
+pub use rate_limit::RateLimitInterceptor;
+pub use rate_limit::RETRY_AFTER_METADATA_KEY;
+
+pub use request_id::RequestIdConf;
+pub use request_id::RequestIdFormat;
+pub use request_id::RequestIdInterceptor;
+pub use request_id::REQUEST_ID_METADATA_KEY;
+
+pub use circuit_breaker::CircuitBreakerConf;
+pub use circuit_breaker::CircuitBreakerInterceptor;
+
+pub use concurrency_limit::ConcurrencyLimitConf;
+pub use concurrency_limit::ConcurrencyLimitInterceptor;
+
+pub use auth::PeerIdentity;
+pub use auth::Authorizer;
+pub use auth::AuthorizationError;
+pub use auth::AccessPolicy;
+pub use auth::AuthorizationInterceptor;
+
+pub use credentials::CallCredentials;
+pub use credentials::CallCredentialsInterceptor;
+pub use credentials::BearerTokenCredentials;
+pub use credentials::AccessToken;
+pub use credentials::TokenSource;
+pub use credentials::RefreshingTokenCredentials;
+#[cfg(feature = "jwt")]
+pub use jwt::ServiceAccountTokenSource;
+#[cfg(feature = "jwt")]
+pub use jwt::HttpPost;
+#[cfg(feature = "jwt")]
+pub use jwt::GOOGLE_TOKEN_URI;
+#[cfg(feature = "jwt")]
+pub use jwt::JwtVerifier;
+
+#[cfg(feature = "tracing")]
+pub use span::TracingInterceptor;
+
+pub use compression::Codec;
+pub use compression::MessageCompression;
+#[cfg(feature = "gzip")]
+pub use compression::GzipCodec;
+#[cfg(feature = "snappy")]
+pub use compression::SnappyCodec;
+#[cfg(feature = "zstd")]
+pub use compression::ZstdCodec;
@@ -0,0 +1,271 @@
+//! Backend load reports sent in trailing metadata (ORCA).
+//!
+//! Server reports its load after each call in `endpoint-load-metrics`
+//! trailer, and client balancer uses reports to weight addresses
+//! by `LoadWeightedRoundRobin` policy.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use futures::Async;
+use futures::Poll;
+use futures::future::Future;
+use futures::stream::Stream;
+
+use error::Error;
+use interceptor::*;
+use metadata::Metadata;
+use metadata::MetadataKey;
+use req::*;
+use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use stream_item::ItemOrMetadata;
+
+
+/// Trailing metadata key of load report in text format.
+pub const LOAD_REPORT_METADATA_KEY: &'static str = "endpoint-load-metrics";
+
+const TEXT_PREFIX: &'static str = "TEXT ";
+
+/// Load of a backend, as reported after a call.
+///
+/// Zero values are not reported.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoadReport {
+    /// CPU utilization, usually from 0.0 to 1.0
+    pub cpu_utilization: f64,
+    pub mem_utilization: f64,
+    /// Application-specific utilization, used for weighting instead of CPU if set
+    pub application_utilization: f64,
+    /// Queries per second served by backend
+    pub rps_fractional: f64,
+    /// Errors per second
+    pub eps: f64,
+    /// Calls waiting to be processed
+    pub queue_depth: u64,
+    /// Costs of this call, e. g. number of database queries
+    pub request_cost: BTreeMap<String, f64>,
+    /// Other backend metrics
+    pub named_metrics: BTreeMap<String, f64>,
+}
+
+impl LoadReport {
+    pub fn is_empty(&self) -> bool {
+        *self == LoadReport::default()
+    }
+
+    /// Text format, e. g. `TEXT cpu_utilization=0.5, request_cost.db=2`.
+    pub fn encode(&self) -> String {
+        let mut fields = Vec::new();
+        {
+            let mut field = |key: &str, value: f64| {
+                if value != 0.0 {
+                    fields.push(format!("{}={}", key, value));
+                }
+            };
+            field("cpu_utilization", self.cpu_utilization);
+            field("mem_utilization", self.mem_utilization);
+            field("application_utilization", self.application_utilization);
+            field("rps_fractional", self.rps_fractional);
+            field("eps", self.eps);
+            field("queue_depth", self.queue_depth as f64);
+            for (name, &value) in &self.request_cost {
+                field(&format!("request_cost.{}", name), value);
+            }
+            for (name, &value) in &self.named_metrics {
+                field(&format!("named_metrics.{}", name), value);
+            }
+        }
+        format!("{}{}", TEXT_PREFIX, fields.join(", "))
+    }
+
+    /// Parse text format, ignoring unknown and malformed fields.
+    pub fn decode(value: &str) -> Option<LoadReport> {
+        if !value.starts_with(TEXT_PREFIX) {
+            return None;
+        }
+        let mut report = LoadReport::default();
+        for field in value[TEXT_PREFIX.len()..].split(',') {
+            let mut parts = field.trim().splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value: f64 = match parts.next().and_then(|v| v.parse().ok()) {
+                Some(value) => value,
+                None => continue,
+            };
+            match key {
+                "cpu_utilization" => report.cpu_utilization = value,
+                "mem_utilization" => report.mem_utilization = value,
+                "application_utilization" => report.application_utilization = value,
+                "rps_fractional" => report.rps_fractional = value,
+                "eps" => report.eps = value,
+                "queue_depth" => report.queue_depth = value as u64,
+                key if key.starts_with("request_cost.") => {
+                    report.request_cost.insert(key["request_cost.".len()..].to_owned(), value);
+                }
+                key if key.starts_with("named_metrics.") => {
+                    report.named_metrics.insert(key["named_metrics.".len()..].to_owned(), value);
+                }
+                _ => {}
+            }
+        }
+        Some(report)
+    }
+
+    /// Load report from trailing metadata of a call.
+    pub fn from_metadata(metadata: &Metadata) -> Option<LoadReport> {
+        metadata.get(LOAD_REPORT_METADATA_KEY)
+            .and_then(|v| ::std::str::from_utf8(v).ok())
+            .and_then(LoadReport::decode)
+    }
+}
+
+/// Load report of a server call, filled by handler.
+#[derive(Debug, Clone, Default)]
+pub struct LoadRecorder {
+    report: Arc<Mutex<LoadReport>>,
+}
+
+impl LoadRecorder {
+    pub fn new() -> LoadRecorder {
+        Default::default()
+    }
+
+    /// Modify report, e. g. add request cost.
+    pub fn update<F : FnOnce(&mut LoadReport)>(&self, f: F) {
+        f(&mut self.report.lock().unwrap());
+    }
+
+    pub fn report(&self) -> LoadReport {
+        self.report.lock().unwrap().clone()
+    }
+}
+
+/// Fills backend-wide metrics, e. g. CPU utilization, into report of a call.
+pub type ServerMetrics = Fn(&mut LoadReport) + Send + Sync;
+
+/// Server interceptor sending load report in trailing metadata of successful calls.
+///
+/// Handlers add per-call metrics with `RequestOptions::load_report`,
+/// backend-wide metrics are added by function set with `with_server_metrics`.
+/// Report should include `rps_fractional` and utilization
+/// to be used by `LoadWeightedRoundRobin` balancing policy.
+pub struct LoadReportInterceptor {
+    server_metrics: Option<Arc<ServerMetrics>>,
+}
+
+impl LoadReportInterceptor {
+    pub fn new() -> LoadReportInterceptor {
+        LoadReportInterceptor {
+            server_metrics: None,
+        }
+    }
+
+    /// Call `f` to fill backend-wide metrics when call completes.
+    pub fn with_server_metrics<F>(f: F) -> LoadReportInterceptor
+        where F : Fn(&mut LoadReport) + Send + Sync + 'static
+    {
+        LoadReportInterceptor {
+            server_metrics: Some(Arc::new(f)),
+        }
+    }
+}
+
+impl ServerInterceptor for LoadReportInterceptor {
+    fn intercept(
+        &self,
+        _method: &str,
+        mut o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>
+    {
+        let recorder = LoadRecorder::new();
+        o.load_report = Some(recorder.clone());
+        let server_metrics = self.server_metrics.clone();
+        StreamingResponse::new(next.call(o, req).0.map(move |(metadata, stream)| {
+            let stream = ReportingStream {
+                stream: stream.0,
+                recorder,
+                server_metrics,
+                done: false,
+            };
+            (metadata, GrpcStreamWithTrailingMetadata::new(stream))
+        }))
+    }
+}
+
+/// Response stream adding load report to trailing metadata.
+struct ReportingStream<S> {
+    stream: S,
+    recorder: LoadRecorder,
+    server_metrics: Option<Arc<ServerMetrics>>,
+    done: bool,
+}
+
+impl<S> ReportingStream<S> {
+    fn add_report(&self, metadata: &mut Metadata) {
+        let mut report = self.recorder.report();
+        if let Some(ref server_metrics) = self.server_metrics {
+            server_metrics(&mut report);
+        }
+        if !report.is_empty() {
+            metadata.add(MetadataKey::from(LOAD_REPORT_METADATA_KEY), Bytes::from(report.encode()));
+        }
+    }
+}
+
+impl<S : Stream<Item=ItemOrMetadata<Vec<u8>>, Error=Error>> Stream for ReportingStream<S> {
+    type Item = ItemOrMetadata<Vec<u8>>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ItemOrMetadata<Vec<u8>>>, Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        match self.stream.poll() {
+            Ok(Async::Ready(Some(ItemOrMetadata::TrailingMetadata(mut metadata)))) => {
+                self.done = true;
+                self.add_report(&mut metadata);
+                Ok(Async::Ready(Some(ItemOrMetadata::TrailingMetadata(metadata))))
+            }
+            Ok(Async::Ready(None)) => {
+                self.done = true;
+                let mut metadata = Metadata::new();
+                self.add_report(&mut metadata);
+                Ok(Async::Ready(Some(ItemOrMetadata::TrailingMetadata(metadata))))
+            }
+            r => r,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let mut report = LoadReport::default();
+        assert_eq!("TEXT ", report.encode());
+        report.cpu_utilization = 0.5;
+        report.rps_fractional = 100.0;
+        report.queue_depth = 3;
+        report.request_cost.insert("db".to_owned(), 2.0);
+        let text = report.encode();
+        assert_eq!("TEXT cpu_utilization=0.5, rps_fractional=100, queue_depth=3, request_cost.db=2", text);
+        assert_eq!(Some(report), LoadReport::decode(&text));
+    }
+
+    #[test]
+    fn decode_lenient() {
+        let report = LoadReport::decode("TEXT eps=1,mem_utilization=x, unknown=2, named_metrics.foo=1.5").unwrap();
+        assert_eq!(1.0, report.eps);
+        assert_eq!(0.0, report.mem_utilization);
+        assert_eq!(Some(&1.5), report.named_metrics.get("foo"));
+        assert_eq!(None, LoadReport::decode("cpu_utilization=0.5"));
+    }
+}
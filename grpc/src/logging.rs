@@ -0,0 +1,341 @@
+//! Server interceptor logging calls.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use base64;
+
+use bytes::Bytes;
+
+use futures::Async;
+use futures::Poll;
+use futures::future::Future;
+use futures::stream::Stream;
+
+use log::Level;
+
+use rand;
+use rand::Rng;
+
+use error::Error;
+use grpc::GrpcStatus;
+use interceptor::*;
+use metadata::Metadata;
+use req::*;
+use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use stream_item::ItemOrMetadata;
+
+
+/// What calls are logged and how.
+#[derive(Debug, Clone)]
+pub struct LoggingConf {
+    /// Percent of calls logged
+    pub sample_percent: u32,
+    /// Log calls finished with non-OK status even if not sampled
+    pub log_all_errors: bool,
+    /// Include request metadata in log record
+    pub log_metadata: bool,
+    /// Metadata keys which values are replaced with `<redacted>`,
+    /// compared ignoring case
+    pub redacted_metadata: Vec<String>,
+    pub level: Level,
+}
+
+impl Default for LoggingConf {
+    fn default() -> LoggingConf {
+        LoggingConf {
+            sample_percent: 100,
+            log_all_errors: true,
+            log_metadata: false,
+            redacted_metadata: vec![
+                "authorization".to_owned(),
+                "cookie".to_owned(),
+                "x-api-key".to_owned(),
+            ],
+            level: Level::Info,
+        }
+    }
+}
+
+impl LoggingConf {
+    /// Decide if a new call is sampled.
+    fn sample(&self) -> bool {
+        rand::thread_rng().gen_range(0, 100) < self.sample_percent
+    }
+
+    /// Whether call finished with `status` is logged.
+    fn is_logged(&self, sampled: bool, status: i32) -> bool {
+        sampled || (self.log_all_errors && status != GrpcStatus::Ok as i32)
+    }
+
+    fn is_redacted(&self, key: &str) -> bool {
+        self.redacted_metadata.iter().any(|k| k.eq_ignore_ascii_case(key))
+    }
+
+    /// Metadata as `key=value` pairs, binary values in base64.
+    fn format_metadata(&self, metadata: &Metadata) -> String {
+        let entries: Vec<String> = metadata.entries.iter()
+            .map(|e| {
+                let value = if self.is_redacted(e.key.as_str()) {
+                    "<redacted>".to_owned()
+                } else if e.key.is_bin() {
+                    base64::encode(&e.value)
+                } else {
+                    String::from_utf8_lossy(&e.value).into_owned()
+                };
+                format!("{}={:?}", e.key.as_str(), value)
+            })
+            .collect();
+        entries.join(" ")
+    }
+}
+
+/// Call being logged, record is written once when call finishes.
+struct CallLog {
+    conf: Arc<LoggingConf>,
+    sampled: bool,
+    method: String,
+    peer: String,
+    request_id: Option<String>,
+    metadata: Option<String>,
+    start: Instant,
+    request_bytes: AtomicUsize,
+    response_bytes: AtomicUsize,
+    finished: AtomicBool,
+}
+
+impl CallLog {
+    fn new(conf: Arc<LoggingConf>, sampled: bool, method: &str, o: &RequestOptions) -> CallLog {
+        CallLog {
+            sampled,
+            method: method.to_owned(),
+            peer: o.peer_identity.as_ref().map_or("-".to_owned(), |p| p.subject.clone()),
+            request_id: o.request_id.clone(),
+            metadata: if conf.log_metadata {
+                Some(conf.format_metadata(&o.metadata))
+            } else {
+                None
+            },
+            start: Instant::now(),
+            request_bytes: AtomicUsize::new(0),
+            response_bytes: AtomicUsize::new(0),
+            finished: AtomicBool::new(false),
+            conf,
+        }
+    }
+
+    /// Write log record unless already finished or not logged,
+    /// return `true` if record is written.
+    fn finish(&self, status: i32) -> bool {
+        if self.finished.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        if !self.conf.is_logged(self.sampled, status) {
+            return false;
+        }
+        let latency = self.start.elapsed();
+        let latency_ms = latency.as_secs() as f64 * 1e3 + latency.subsec_nanos() as f64 / 1e6;
+        log!(self.conf.level,
+            "grpc call {} peer={} request_id={} status={} latency={:.3}ms request_bytes={} response_bytes={}{}{}",
+            self.method,
+            self.peer,
+            self.request_id.as_ref().map_or("-", |id| id.as_str()),
+            status,
+            latency_ms,
+            self.request_bytes.load(Ordering::SeqCst),
+            self.response_bytes.load(Ordering::SeqCst),
+            if self.metadata.is_some() { " " } else { "" },
+            self.metadata.as_ref().map_or("", |m| m.as_str()));
+        true
+    }
+}
+
+/// Response stream finishing call log when it ends or is dropped.
+struct LoggedStream<S> {
+    stream: S,
+    log: Arc<CallLog>,
+}
+
+impl<S> Stream for LoggedStream<S>
+    where S : Stream<Item=ItemOrMetadata<Vec<u8>>, Error=Error>
+{
+    type Item = ItemOrMetadata<Vec<u8>>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ItemOrMetadata<Vec<u8>>>, Error> {
+        match self.stream.poll() {
+            Ok(Async::Ready(Some(item))) => {
+                if let ItemOrMetadata::Item(ref message) = item {
+                    self.log.response_bytes.fetch_add(message.len(), Ordering::SeqCst);
+                }
+                Ok(Async::Ready(Some(item)))
+            }
+            Ok(Async::Ready(None)) => {
+                self.log.finish(GrpcStatus::Ok as i32);
+                Ok(Async::Ready(None))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => {
+                self.log.finish(e.grpc_status());
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<S> Drop for LoggedStream<S> {
+    fn drop(&mut self) {
+        // no-op if stream is finished
+        self.log.finish(GrpcStatus::Cancelled as i32);
+    }
+}
+
+/// Response future wrapping response stream, finishing call log
+/// if call fails or is dropped before response headers.
+struct LoggedResponse<F> {
+    resp: F,
+    log: Option<Arc<CallLog>>,
+}
+
+impl<F> Future for LoggedResponse<F>
+    where F : Future<Item=(Metadata, GrpcStreamWithTrailingMetadata<Vec<u8>>), Error=Error>
+{
+    type Item = (Metadata, GrpcStreamWithTrailingMetadata<Vec<u8>>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(Metadata, GrpcStreamWithTrailingMetadata<Vec<u8>>), Error> {
+        match self.resp.poll() {
+            Ok(Async::Ready((metadata, stream))) => {
+                let log = self.log.take().expect("polled after completion");
+                let stream = LoggedStream { stream: stream.0, log };
+                Ok(Async::Ready((metadata, GrpcStreamWithTrailingMetadata::new(stream))))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => {
+                if let Some(log) = self.log.take() {
+                    log.finish(e.grpc_status());
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<F> Drop for LoggedResponse<F> {
+    fn drop(&mut self) {
+        if let Some(ref log) = self.log {
+            log.finish(GrpcStatus::Cancelled as i32);
+        }
+    }
+}
+
+/// Server interceptor logging method, peer, status, latency and
+/// request and response message sizes of calls.
+///
+/// Peer is the subject of `RequestOptions::peer_identity`
+/// set by authentication interceptor or `-` if not authenticated,
+/// and request ID is set by `RequestIdInterceptor`, so it should be
+/// added after these interceptors.
+pub struct LoggingInterceptor {
+    conf: Arc<LoggingConf>,
+}
+
+impl LoggingInterceptor {
+    pub fn new(conf: LoggingConf) -> LoggingInterceptor {
+        LoggingInterceptor { conf: Arc::new(conf) }
+    }
+}
+
+impl ServerInterceptor for LoggingInterceptor {
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>
+    {
+        let log = Arc::new(CallLog::new(self.conf.clone(), self.conf.sample(), method, &o));
+
+        let request_log = log.clone();
+        let req = StreamingRequest::new(req.0.inspect(move |message| {
+            request_log.request_bytes.fetch_add(message.len(), Ordering::SeqCst);
+        }));
+
+        StreamingResponse::new(LoggedResponse {
+            resp: next.call(o, req).0,
+            log: Some(log),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::stream;
+
+    use metadata::MetadataKey;
+
+    fn call_log(conf: &LoggingConf, sampled: bool) -> Arc<CallLog> {
+        Arc::new(CallLog::new(Arc::new(conf.clone()), sampled, "/test/Method", &RequestOptions::new()))
+    }
+
+    #[test]
+    fn sampling() {
+        let never = LoggingConf { sample_percent: 0, ..LoggingConf::default() };
+        assert!((0..1000).all(|_| !never.sample()));
+
+        let always = LoggingConf { sample_percent: 100, ..LoggingConf::default() };
+        assert!((0..1000).all(|_| always.sample()));
+
+        let half = LoggingConf { sample_percent: 50, ..LoggingConf::default() };
+        let sampled = (0..1000).filter(|_| half.sample()).count();
+        assert!(sampled > 300 && sampled < 700, "{}", sampled);
+    }
+
+    #[test]
+    fn log_all_errors() {
+        let conf = LoggingConf { sample_percent: 0, log_all_errors: true, ..LoggingConf::default() };
+        assert!(call_log(&conf, false).finish(GrpcStatus::Internal as i32));
+        assert!(!call_log(&conf, false).finish(GrpcStatus::Ok as i32));
+        assert!(call_log(&conf, true).finish(GrpcStatus::Ok as i32));
+
+        let conf = LoggingConf { log_all_errors: false, ..conf };
+        assert!(!call_log(&conf, false).finish(GrpcStatus::Internal as i32));
+        assert!(call_log(&conf, true).finish(GrpcStatus::Internal as i32));
+    }
+
+    #[test]
+    fn failed_stream_finishes_once() {
+        let conf = LoggingConf { sample_percent: 0, ..LoggingConf::default() };
+        let log = call_log(&conf, false);
+        let mut stream = LoggedStream {
+            stream: stream::once::<ItemOrMetadata<Vec<u8>>, _>(Err(Error::Other("test"))),
+            log: log.clone(),
+        };
+        assert!(stream.poll().is_err());
+        drop(stream);
+        // error was logged, neither drop nor another status logs it again
+        assert!(log.finished.load(Ordering::SeqCst));
+        assert!(!log.finish(GrpcStatus::Cancelled as i32));
+    }
+
+    #[test]
+    fn format_metadata() {
+        let mut metadata = Metadata::new();
+        metadata.add(MetadataKey::from("Authorization"), Bytes::from("Bearer secret"));
+        metadata.add(MetadataKey::from("user-agent"), Bytes::from("test"));
+        metadata.add(MetadataKey::from("trace-bin"), Bytes::from(&b"\x01\x02"[..]));
+
+        let conf = LoggingConf::default();
+        assert_eq!(
+            r#"Authorization="<redacted>" user-agent="test" trace-bin="AQI=""#,
+            conf.format_metadata(&metadata));
+    }
+}
@@ -6,4 +6,24 @@ use result::Result;
 pub trait Marshaller<M> {
     fn write(&self, m: &M) -> Result<Vec<u8>>;
     fn read(&self, bytes: Bytes) -> Result<M>;
+
+    /// Client sends requests with `content-type: application/grpc+<subtype>`,
+    /// `None` for `application/grpc`.
+    fn content_subtype(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+
+/// Marshaller which passes messages as is.
+pub(crate) struct MarshallerBytes;
+
+impl Marshaller<Bytes> for MarshallerBytes {
+    fn write(&self, m: &Bytes) -> Result<Vec<u8>> {
+        Ok(m.to_vec())
+    }
+
+    fn read(&self, bytes: Bytes) -> Result<Bytes> {
+        Ok(bytes)
+    }
 }
@@ -1,3 +1,6 @@
+use std::error::Error as StdError;
+use std::fmt;
+
 use base64;
 
 use bytes::Bytes;
@@ -6,7 +9,7 @@ use chars::Chars;
 use httpbis::Header;
 use httpbis::Headers;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MetadataKey {
     pub name: Chars,
 }
@@ -34,9 +37,34 @@ impl MetadataKey {
     pub fn as_str(&self) -> &str {
         &self.name
     }
+
+    /// Policy applied to repeated entries of this key when `configured` policy is used:
+    /// values of binary keys cannot be joined, so they are always preserved.
+    pub fn duplicate_key_policy(&self, configured: DuplicateKeyPolicy) -> DuplicateKeyPolicy {
+        match self.is_bin() {
+            true => DuplicateKeyPolicy::Preserve,
+            false => configured,
+        }
+    }
 }
 
-#[derive(Debug)]
+/// How repeated keys of received metadata are represented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateKeyPolicy {
+    /// Each header is a separate entry.
+    Preserve,
+    /// Values of repeated ASCII keys are joined with `,` into the first entry,
+    /// like HTTP combines repeated header fields.
+    Concatenate,
+}
+
+impl Default for DuplicateKeyPolicy {
+    fn default() -> DuplicateKeyPolicy {
+        DuplicateKeyPolicy::Preserve
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct MetadataEntry {
     pub key: MetadataKey,
     pub value: Bytes,
@@ -47,6 +75,22 @@ pub enum MetadataDecodeError {
     Base64(base64::DecodeError),
 }
 
+impl fmt::Display for MetadataDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MetadataDecodeError::Base64(ref e) => write!(f, "invalid base64: {}", e),
+        }
+    }
+}
+
+impl StdError for MetadataDecodeError {
+    fn source(&self) -> Option<&(StdError + 'static)> {
+        match *self {
+            MetadataDecodeError::Base64(ref e) => Some(e),
+        }
+    }
+}
+
 impl From<base64::DecodeError> for MetadataDecodeError {
     fn from(decode_error: base64::DecodeError) -> Self {
         MetadataDecodeError::Base64(decode_error)
@@ -65,28 +109,52 @@ impl MetadataEntry {
         Header::new(self.key.name.into_inner(), value)
     }
 
-    fn from_header(header: Header) -> Result<Option<MetadataEntry>, MetadataDecodeError> {
+    /// Entries of header, none for reserved headers.
+    ///
+    /// Value of binary header may be several comma-separated base64 values
+    /// if an intermediary joined repeated headers, each is a separate entry.
+    fn from_header(header: Header) -> Result<Vec<MetadataEntry>, MetadataDecodeError> {
         if header.name().starts_with(b":") {
-            return Ok(None);
+            return Ok(Vec::new());
         }
         if header.name().starts_with(b"grpc-") {
-            return Ok(None);
+            return Ok(Vec::new());
+        }
+        // HTTP/2 transport header, the only one allowed by HTTP/2 is `te: trailers`
+        if header.name() == b"te" {
+            return Ok(Vec::new());
         }
         let key = MetadataKey {
             name: Chars::try_from(header.name).expect("utf-8")
         };
-        let value = match key.is_bin() {
-            true => Bytes::from(base64::decode(&header.value)?),
-            false => header.value,
-        };
-        Ok(Some(MetadataEntry {
-            key: key,
-            value: value,
-        }))
+        if !key.is_bin() {
+            return Ok(vec![MetadataEntry {
+                key: key,
+                value: header.value,
+            }]);
+        }
+        let mut entries = Vec::new();
+        for value in header.value.split(|&b| b == b',') {
+            entries.push(MetadataEntry {
+                key: key.clone(),
+                value: Bytes::from(base64::decode(trim_ascii(value))?),
+            });
+        }
+        Ok(entries)
     }
 }
 
-#[derive(Default, Debug)]
+fn trim_ascii(mut s: &[u8]) -> &[u8] {
+    while let Some((&b' ', rem)) = s.split_first() {
+        s = rem;
+    }
+    while let Some((&b' ', rem)) = s.split_last() {
+        s = rem;
+    }
+    s
+}
+
+#[derive(Default, Debug, Clone)]
 pub struct Metadata {
     pub entries: Vec<MetadataEntry>,
 }
@@ -97,10 +165,17 @@ impl Metadata {
     }
 
     pub fn from_headers(headers: Headers) -> Result<Metadata, MetadataDecodeError> {
+        Metadata::from_headers_with(headers, DuplicateKeyPolicy::Preserve)
+    }
+
+    /// Decode headers, representing repeated keys according to `policy`.
+    pub fn from_headers_with(headers: Headers, policy: DuplicateKeyPolicy)
+        -> Result<Metadata, MetadataDecodeError>
+    {
         let mut r = Metadata::new();
         for h in headers.0 {
-            if let Some(e) = MetadataEntry::from_header(h)? {
-                r.entries.push(e);
+            for e in MetadataEntry::from_header(h)? {
+                r.add_with(e.key, e.value, policy);
             }
         }
         Ok(r)
@@ -120,6 +195,14 @@ impl Metadata {
         None
     }
 
+    /// Values of all entries with given key.
+    pub fn get_all<'a>(&'a self, name: &str) -> Vec<&'a [u8]> {
+        self.entries.iter()
+            .filter(|e| e.key.as_str() == name)
+            .map(|e| &e.value[..])
+            .collect()
+    }
+
     pub fn extend(&mut self, extend: Metadata) {
         self.entries.extend(extend.entries);
     }
@@ -130,4 +213,135 @@ impl Metadata {
             value: value,
         });
     }
+
+    /// Add entry, joining value with existing entry of the same key
+    /// if `policy` applied to the key is `Concatenate`.
+    pub fn add_with(&mut self, key: MetadataKey, value: Bytes, policy: DuplicateKeyPolicy) {
+        if key.duplicate_key_policy(policy) == DuplicateKeyPolicy::Concatenate {
+            if let Some(e) = self.entries.iter_mut().find(|e| e.key.as_str() == key.as_str()) {
+                let mut joined = Vec::with_capacity(e.value.len() + 1 + value.len());
+                joined.extend_from_slice(&e.value);
+                joined.push(b',');
+                joined.extend_from_slice(&value);
+                e.value = Bytes::from(joined);
+                return;
+            }
+        }
+        self.add(key, value);
+    }
+
+    /// Remove all entries with given key, returning value of the first one.
+    pub fn remove(&mut self, name: &str) -> Option<Bytes> {
+        let first = self.entries.iter().position(|e| e.key.as_str() == name)
+            .map(|i| self.entries[i].value.clone());
+        self.entries.retain(|e| e.key.as_str() != name);
+        first
+    }
+}
+
+/// Serialized as a list of key and value pairs, values of `-bin` keys are base64-encoded.
+#[cfg(feature = "with-serde")]
+mod serde_impl {
+    use serde::de::Error;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serialize;
+    use serde::Serializer;
+
+    use base64;
+    use bytes::Bytes;
+
+    use super::Metadata;
+    use super::MetadataKey;
+
+    impl Serialize for Metadata {
+        fn serialize<S : Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let entries: Vec<(&str, String)> = self.entries.iter()
+                .map(|e| {
+                    let value = match e.key.is_bin() {
+                        true => base64::encode(&e.value),
+                        false => String::from_utf8_lossy(&e.value).into_owned(),
+                    };
+                    (e.key.as_str(), value)
+                })
+                .collect();
+            entries.serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Metadata {
+        fn deserialize<D : Deserializer<'de>>(deserializer: D) -> Result<Metadata, D::Error> {
+            let entries: Vec<(String, String)> = Vec::deserialize(deserializer)?;
+            let mut metadata = Metadata::new();
+            for (key, value) in entries {
+                if key.is_empty() {
+                    return Err(D::Error::custom("empty metadata key"));
+                }
+                let key = MetadataKey::from(key);
+                let value = match key.is_bin() {
+                    true => Bytes::from(base64::decode(&value).map_err(D::Error::custom)?),
+                    false => Bytes::from(value),
+                };
+                metadata.add(key, value);
+            }
+            Ok(metadata)
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use serde_json;
+
+        use super::super::*;
+
+        #[test]
+        fn json() {
+            let mut metadata = Metadata::new();
+            metadata.add(MetadataKey::from("a"), Bytes::from("x"));
+            metadata.add(MetadataKey::from("b-bin"), Bytes::from(&[1, 2][..]));
+            let json = serde_json::to_string(&metadata).unwrap();
+            assert_eq!(r#"[["a","x"],["b-bin","AQI="]]"#, json);
+
+            let metadata: Metadata = serde_json::from_str(&json).unwrap();
+            assert_eq!(Some(&[1, 2][..]), metadata.get("b-bin"));
+            assert!(serde_json::from_str::<Metadata>(r#"[["a-bin","!"]]"#).is_err());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers() -> Headers {
+        Headers(vec![
+            Header::new("te", "trailers"),
+            Header::new("grpc-accept-encoding", "gzip"),
+            Header::new("a", "x"),
+            Header::new("b-bin", "AQI="),
+            Header::new("a", "y"),
+            Header::new("b-bin", "Aw==, BA=="),
+        ])
+    }
+
+    #[test]
+    fn duplicate_keys_preserved() {
+        let metadata = Metadata::from_headers(headers()).unwrap();
+        assert_eq!(5, metadata.entries.len());
+        assert_eq!(vec![&b"x"[..], &b"y"[..]], metadata.get_all("a"));
+        assert_eq!(vec![&[1, 2][..], &[3][..], &[4][..]], metadata.get_all("b-bin"));
+        assert_eq!(None, metadata.get("te"));
+    }
+
+    #[test]
+    fn duplicate_keys_concatenated() {
+        let metadata = Metadata::from_headers_with(headers(), DuplicateKeyPolicy::Concatenate).unwrap();
+        assert_eq!(4, metadata.entries.len());
+        assert_eq!(vec![&b"x,y"[..]], metadata.get_all("a"));
+        assert_eq!(vec![&[1, 2][..], &[3][..], &[4][..]], metadata.get_all("b-bin"));
+        assert_eq!(
+            DuplicateKeyPolicy::Preserve,
+            MetadataKey::from("b-bin").duplicate_key_policy(DuplicateKeyPolicy::Concatenate));
+    }
 }
@@ -1,6 +1,7 @@
 use marshall::*;
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GrpcStreaming {
     Unary,
     ClientStreaming,
@@ -8,6 +9,16 @@ pub enum GrpcStreaming {
     Bidi,
 }
 
+impl GrpcStreaming {
+    pub fn client_streaming(&self) -> bool {
+        *self == GrpcStreaming::ClientStreaming || *self == GrpcStreaming::Bidi
+    }
+
+    pub fn server_streaming(&self) -> bool {
+        *self == GrpcStreaming::ServerStreaming || *self == GrpcStreaming::Bidi
+    }
+}
+
 pub trait GrpcStreamingFlavor {
     type Flavor;
 
@@ -20,9 +31,52 @@ pub struct GrpcStreamingServerStreaming;
 pub struct GrpcStreamingBidi;
 
 
+/// Idempotency level of a method declared with `idempotency_level` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdempotencyLevel {
+    IdempotencyUnknown,
+    /// Method has no side effects, e. g. HTTP GET
+    NoSideEffects,
+    /// Method may have side effects, but repeated calls have the same effect
+    Idempotent,
+}
+
+impl Default for IdempotencyLevel {
+    fn default() -> IdempotencyLevel {
+        IdempotencyLevel::IdempotencyUnknown
+    }
+}
+
+impl IdempotencyLevel {
+    /// Whether call can be safely retried after a failure
+    /// when request may have reached the server.
+    pub fn is_safe_to_retry(&self) -> bool {
+        *self != IdempotencyLevel::IdempotencyUnknown
+    }
+}
+
+/// Method options declared in proto file.
+#[derive(Debug, Clone, Default)]
+pub struct MethodOptions {
+    pub idempotency_level: IdempotencyLevel,
+    /// Serialized `google.protobuf.MethodOptions`, including custom options,
+    /// which can be read with extensions generated by rust-protobuf.
+    pub serialized: &'static [u8],
+}
+
+/// Name and kind of a method, generated as constant per method,
+/// e. g. to match methods in interceptors without string literals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Method {
+    /// Full path like `/package.Service/Method`
+    pub name: &'static str,
+    pub streaming: GrpcStreaming,
+}
+
 pub struct MethodDescriptor<Req, Resp> {
     pub name: String,
     pub streaming: GrpcStreaming,
+    pub options: MethodOptions,
     pub req_marshaller: Box<Marshaller<Req> + Sync + Send>,
     pub resp_marshaller: Box<Marshaller<Resp> + Sync + Send>,
 }
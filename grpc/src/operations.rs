@@ -0,0 +1,294 @@
+//! Client for long-running operations following `google.longrunning.Operations`.
+//!
+//! `Operation` and `GetOperationRequest` messages are generated by user
+//! from `google/longrunning/operations.proto`, fields are found by reflection.
+
+use std::cmp;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future;
+use futures::future::Future;
+
+use protobuf_lib::Message;
+use protobuf_lib::descriptor::FieldDescriptorProto_Type;
+use protobuf_lib::reflect::FieldDescriptor;
+use protobuf_lib::reflect::ReflectFieldRef;
+use protobuf_lib::reflect::ReflectValueRef;
+
+use client::Client;
+use error::Error;
+use error::GrpcMessageError;
+use futures_grpc::GrpcFuture;
+use grpc::GrpcStatus;
+use method::GrpcStreaming;
+use method::MethodDescriptor;
+use protobuf::MarshallerProtobuf;
+use reflect;
+use req::RequestOptions;
+use resp::SingleResponse;
+use result;
+use timer;
+use well_known_types::Any;
+use well_known_types::unpack_any;
+
+
+/// Path of `GetOperation` method of `Operations` service.
+pub const GET_OPERATION_PATH: &'static str = "/google.longrunning.Operations/GetOperation";
+
+/// How often operation is polled until done.
+#[derive(Debug, Clone)]
+pub struct PollPolicy {
+    /// Delay before the first poll, multiplied by `delay_multiplier`
+    /// after each poll up to `max_delay`
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub delay_multiplier: f64,
+    /// Fail with `DEADLINE_EXCEEDED` if operation is not done in this time
+    pub timeout: Option<Duration>,
+}
+
+impl Default for PollPolicy {
+    fn default() -> PollPolicy {
+        PollPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            delay_multiplier: 1.5,
+            timeout: None,
+        }
+    }
+}
+
+impl PollPolicy {
+    fn next_delay(&self, delay: Duration) -> Duration {
+        let nanos = (delay.as_secs() as f64 * 1e9 + delay.subsec_nanos() as f64) * self.delay_multiplier;
+        let delay = Duration::new((nanos / 1e9) as u64, (nanos % 1e9) as u32);
+        cmp::min(delay, self.max_delay)
+    }
+}
+
+/// Fields of `Operation` and `GetOperationRequest` messages.
+#[derive(Clone, Copy)]
+struct OperationFields {
+    name: &'static FieldDescriptor,
+    done: &'static FieldDescriptor,
+    error: &'static FieldDescriptor,
+    response: &'static FieldDescriptor,
+    request_name: &'static FieldDescriptor,
+}
+
+impl OperationFields {
+    fn find<Op : Message, Req : Message>() -> result::Result<OperationFields> {
+        let operation = Op::descriptor_static();
+        let field = |name, field_type| reflect::find_field(operation, name, field_type)
+            .ok_or(Error::Other("message is not google.longrunning.Operation"));
+        Ok(OperationFields {
+            name: field("name", FieldDescriptorProto_Type::TYPE_STRING)?,
+            done: field("done", FieldDescriptorProto_Type::TYPE_BOOL)?,
+            error: field("error", FieldDescriptorProto_Type::TYPE_MESSAGE)?,
+            response: field("response", FieldDescriptorProto_Type::TYPE_MESSAGE)?,
+            request_name: reflect::find_string_field(Req::descriptor_static(), "name")
+                .ok_or(Error::Other("message is not google.longrunning.GetOperationRequest"))?,
+        })
+    }
+
+    /// Result of done operation: response or error status.
+    fn result<Op : Message>(&self, op: &Op) -> result::Result<Option<Any>> {
+        if self.error.has_field(op) {
+            let status = self.error.get_message(op);
+            let descriptor = status.descriptor();
+            let code = reflect::find_field(descriptor, "code", FieldDescriptorProto_Type::TYPE_INT32)
+                .map_or(GrpcStatus::Unknown as i32, |f| f.get_i32(status));
+            let message = reflect::find_string_field(descriptor, "message")
+                .map_or("", |f| f.get_str(status));
+            return Err(Error::GrpcMessage(GrpcMessageError {
+                grpc_status: code,
+                grpc_message: message.to_owned(),
+            }));
+        }
+        if self.response.has_field(op) {
+            return match self.response.get_message(op).as_any().downcast_ref::<Any>() {
+                Some(any) => Ok(Some(any.clone())),
+                None => Err(Error::Other("operation response is not google.protobuf.Any")),
+            };
+        }
+        Ok(None)
+    }
+
+    /// `details` of operation error status.
+    fn error_details<Op : Message>(&self, op: &Op) -> Vec<Any> {
+        if !self.error.has_field(op) {
+            return Vec::new();
+        }
+        let status = self.error.get_message(op);
+        let details = status.descriptor().fields().iter()
+            .find(|f| f.name() == "details" && f.is_repeated());
+        let details = match details {
+            Some(details) => details,
+            None => return Vec::new(),
+        };
+        match details.get_reflect(status) {
+            ReflectFieldRef::Repeated(r) => r.reflect_iter()
+                .filter_map(|v| match v.as_ref() {
+                    ReflectValueRef::Message(m) => m.as_any().downcast_ref::<Any>().cloned(),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn deadline_exceeded() -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::DeadlineExceeded as i32,
+        grpc_message: "operation is not done before poll timeout".to_owned(),
+    })
+}
+
+/// Client polling long-running operations until they are done.
+///
+/// `Op` is `google.longrunning.Operation` and `Req` is
+/// `google.longrunning.GetOperationRequest` generated by user.
+pub struct OperationsClient<Op, Req> {
+    get_operation: Arc<Fn(RequestOptions, Req) -> SingleResponse<Op> + Send + Sync>,
+    /// Polling backoff and timeout
+    pub poll_policy: PollPolicy,
+}
+
+impl<Op, Req> OperationsClient<Op, Req>
+    where
+        Op : Message + Clone,
+        Req : Message + Clone,
+{
+    /// Client getting operation with given function, e. g. method of generated client.
+    pub fn new<F>(get_operation: F) -> OperationsClient<Op, Req>
+        where F : Fn(RequestOptions, Req) -> SingleResponse<Op> + Send + Sync + 'static
+    {
+        OperationsClient {
+            get_operation: Arc::new(get_operation),
+            poll_policy: PollPolicy::default(),
+        }
+    }
+
+    /// Client calling `Operations` service on the same server as given client.
+    pub fn for_client(client: Client) -> OperationsClient<Op, Req> {
+        let method = Arc::new(MethodDescriptor {
+            name: GET_OPERATION_PATH.to_owned(),
+            streaming: GrpcStreaming::Unary,
+            options: Default::default(),
+            req_marshaller: Box::new(MarshallerProtobuf),
+            resp_marshaller: Box::new(MarshallerProtobuf),
+        });
+        OperationsClient::new(move |o, req| client.call_unary(o, req, method.clone()))
+    }
+
+    /// Latest state of operation.
+    pub fn get(&self, o: RequestOptions, name: &str) -> GrpcFuture<Op> {
+        let fields = match OperationFields::find::<Op, Req>() {
+            Ok(fields) => fields,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        match reflect::with_string_field(&Req::new(), fields.request_name, name) {
+            Ok(req) => Box::new((self.get_operation)(o, req).drop_metadata()),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Operation started by `started` call, polled until done.
+    pub fn wait(&self, o: RequestOptions, started: SingleResponse<Op>) -> GrpcFuture<Op> {
+        let fields = match OperationFields::find::<Op, Req>() {
+            Ok(fields) => fields,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let polling = Polling {
+            get_operation: self.get_operation.clone(),
+            policy: self.poll_policy.clone(),
+            fields,
+            options: o,
+            deadline: self.poll_policy.timeout.map(|t| Instant::now() + t),
+        };
+        let delay = self.poll_policy.initial_delay;
+        Box::new(started.drop_metadata().and_then(move |op| polling.until_done(op, delay)))
+    }
+
+    /// Response of operation started by `started` call, after it is done.
+    ///
+    /// Operation error is returned as `Error::GrpcMessage` with its code and message.
+    pub fn wait_response<T : Message>(&self, o: RequestOptions, started: SingleResponse<Op>) -> GrpcFuture<T> {
+        let fields = match OperationFields::find::<Op, Req>() {
+            Ok(fields) => fields,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        Box::new(self.wait(o, started).and_then(move |op| -> result::Result<T> {
+            let any = fields.result(&op)?
+                .ok_or(Error::Other("operation is done without response or error"))?;
+            unpack_any(&any)?
+                .ok_or(Error::Other("operation response is of unexpected type"))
+        }))
+    }
+
+    /// Details of operation error of type `D`, e. g. `google.rpc.RetryInfo`,
+    /// empty if operation has no error or no details of this type.
+    pub fn error_details<D : Message>(&self, op: &Op) -> result::Result<Vec<D>> {
+        let fields = OperationFields::find::<Op, Req>()?;
+        let mut details = Vec::new();
+        for any in fields.error_details(op) {
+            if let Some(d) = unpack_any(&any)? {
+                details.push(d);
+            }
+        }
+        Ok(details)
+    }
+}
+
+/// Polling of a single operation.
+struct Polling<Op, Req> {
+    get_operation: Arc<Fn(RequestOptions, Req) -> SingleResponse<Op> + Send + Sync>,
+    policy: PollPolicy,
+    fields: OperationFields,
+    options: RequestOptions,
+    deadline: Option<Instant>,
+}
+
+impl<Op, Req> Polling<Op, Req>
+    where
+        Op : Message + Clone,
+        Req : Message + Clone,
+{
+    fn until_done(self, op: Op, delay: Duration) -> GrpcFuture<Op> {
+        if self.fields.done.get_bool(&op) {
+            return Box::new(future::ok(op));
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() + delay > deadline {
+                return Box::new(future::err(deadline_exceeded()));
+            }
+        }
+        let req = match reflect::with_string_field(&Req::new(), self.fields.request_name, self.fields.name.get_str(&op)) {
+            Ok(req) => req,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        Box::new(timer::sleep(delay).and_then(move |()| {
+            (self.get_operation)(self.options.clone(), req).drop_metadata()
+                .and_then(move |op| {
+                    let delay = self.policy.next_delay(delay);
+                    self.until_done(op, delay)
+                })
+        }))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn next_delay() {
+        let policy = PollPolicy::default();
+        assert_eq!(Duration::from_millis(750), policy.next_delay(policy.initial_delay));
+        assert_eq!(policy.max_delay, policy.next_delay(Duration::from_secs(9)));
+    }
+}
@@ -0,0 +1,188 @@
+//! Listing all items of a paginated method following
+//! [AIP-158](https://google.aip.dev/158).
+//!
+//! Request message must have `string page_token` field and response
+//! message `string next_page_token` field, items are taken from the first
+//! repeated field of the response. Fields are found by reflection.
+
+use std::sync::Arc;
+
+use futures::future;
+use futures::future::Future;
+use futures::stream;
+use futures::stream::Stream;
+
+use protobuf_lib::Message;
+use protobuf_lib::reflect::FieldDescriptor;
+use protobuf_lib::reflect::MessageDescriptor;
+use protobuf_lib::reflect::ReflectFieldRef;
+use protobuf_lib::reflect::ReflectValueRef;
+
+use client::Client;
+use error::Error;
+use futures_grpc::GrpcFuture;
+use futures_grpc::GrpcStream;
+use method::MethodDescriptor;
+use reflect;
+use req::RequestOptions;
+use resp::SingleResponse;
+use result;
+use retry::RetryPolicy;
+use timer;
+
+
+/// Pagination fields of request and response messages.
+#[derive(Clone, Copy)]
+struct PageFields {
+    page_token: &'static FieldDescriptor,
+    next_page_token: &'static FieldDescriptor,
+    items: &'static FieldDescriptor,
+}
+
+impl PageFields {
+    fn find(request: &'static MessageDescriptor, response: &'static MessageDescriptor)
+        -> result::Result<PageFields>
+    {
+        let page_token = reflect::find_string_field(request, "page_token")
+            .ok_or(Error::Other("request has no string page_token field"))?;
+        let next_page_token = reflect::find_string_field(response, "next_page_token")
+            .ok_or(Error::Other("response has no string next_page_token field"))?;
+        let items = response.fields().iter()
+            .find(|f| f.is_repeated())
+            .ok_or(Error::Other("response has no repeated field"))?;
+        Ok(PageFields {
+            page_token,
+            next_page_token,
+            items,
+        })
+    }
+
+    fn items<M : Message, T : Message + Clone>(&self, resp: &M) -> result::Result<Vec<T>> {
+        let repeated = match self.items.get_reflect(resp) {
+            ReflectFieldRef::Repeated(repeated) => repeated,
+            _ => return Err(Error::Other("page items field is not repeated")),
+        };
+        repeated.reflect_iter()
+            .map(|item| match item.as_ref() {
+                ReflectValueRef::Message(m) => m.as_any().downcast_ref::<T>().cloned(),
+                _ => None,
+            }.ok_or(Error::Other("page items are not messages of expected type")))
+            .collect()
+    }
+}
+
+/// Fetch a page, retrying failures allowed by retry policy.
+fn fetch_page<Req, Resp>(
+    call: Arc<Fn(RequestOptions, Req) -> SingleResponse<Resp> + Send + Sync>,
+    policy: RetryPolicy,
+    o: RequestOptions,
+    req: Req,
+    attempts: u32)
+    -> GrpcFuture<Resp>
+    where
+        Req : Clone + Send + 'static,
+        Resp : Send + 'static,
+{
+    let resp = call(o.clone(), req.clone()).drop_metadata();
+    Box::new(resp.or_else(move |e| -> GrpcFuture<Resp> {
+        if attempts >= policy.max_attempts() || !policy.retryable_status_codes.contains(&e.grpc_status()) {
+            return Box::new(future::err(e));
+        }
+        let backoff = policy.backoff(attempts);
+        debug!("retrying page request in {:?} after error: {:?}", backoff, e);
+        Box::new(timer::sleep(backoff).and_then(move |()| {
+            fetch_page(call, policy, o, req, attempts + 1)
+        }))
+    }))
+}
+
+/// Lists all items of a paginated method, requesting pages one by one
+/// as items are consumed.
+pub struct Paginator<Req, Resp> {
+    call: Arc<Fn(RequestOptions, Req) -> SingleResponse<Resp> + Send + Sync>,
+    /// Failed page requests are retried according to this policy
+    pub retry_policy: RetryPolicy,
+}
+
+impl<Req, Resp> Paginator<Req, Resp>
+    where
+        Req : Message + Clone,
+        Resp : Message,
+{
+    /// Paginator calling method with given function, e. g. method of generated client.
+    pub fn new<F>(call: F) -> Paginator<Req, Resp>
+        where F : Fn(RequestOptions, Req) -> SingleResponse<Resp> + Send + Sync + 'static
+    {
+        Paginator {
+            call: Arc::new(call),
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Paginator calling unary method with client.
+    pub fn for_method(client: Client, method: Arc<MethodDescriptor<Req, Resp>>) -> Paginator<Req, Resp> {
+        Paginator::new(move |o, req| client.call_unary(o, req, method.clone()))
+    }
+
+    /// Items of all pages starting with the page requested by `req`.
+    ///
+    /// Stream fails if messages don't follow pagination convention
+    /// or items are not of type `T`.
+    pub fn list<T : Message + Clone>(&self, o: RequestOptions, req: Req) -> GrpcStream<T> {
+        let fields = match PageFields::find(Req::descriptor_static(), Resp::descriptor_static()) {
+            Ok(fields) => fields,
+            Err(e) => return Box::new(stream::once(Err(e))),
+        };
+        let call = self.call.clone();
+        let policy = self.retry_policy.clone();
+
+        let pages = stream::unfold(Some(req), move |req| {
+            let req = req?;
+            let resp = fetch_page(call.clone(), policy.clone(), o.clone(), req.clone(), 1);
+            Some(resp.and_then(move |resp| -> result::Result<_> {
+                let items = fields.items::<Resp, T>(&resp)?;
+                let token = fields.next_page_token.get_str(&resp);
+                let next = if token.is_empty() {
+                    None
+                } else {
+                    Some(reflect::with_string_field(&req, fields.page_token, token)?)
+                };
+                Ok((items, next))
+            }))
+        });
+        Box::new(pages.map(stream::iter_ok::<_, Error>).flatten())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use protobuf_lib::well_known_types::Int32Value;
+    use protobuf_lib::well_known_types::ListValue;
+    use protobuf_lib::well_known_types::Value;
+
+    #[test]
+    fn find_fields() {
+        assert!(PageFields::find(Int32Value::descriptor_static(), ListValue::descriptor_static()).is_err());
+    }
+
+    #[test]
+    fn items() {
+        let value_field = Value::descriptor_static().field_by_name("string_value");
+        let fields = PageFields {
+            page_token: value_field,
+            next_page_token: value_field,
+            items: ListValue::descriptor_static().field_by_name("values"),
+        };
+
+        let mut value = Value::new();
+        value.set_string_value("a".to_owned());
+        let mut list = ListValue::new();
+        list.mut_values().push(value.clone());
+        list.mut_values().push(value.clone());
+        assert_eq!(vec![value.clone(), value], fields.items::<_, Value>(&list).unwrap());
+        assert!(fields.items::<_, ListValue>(&list).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! Identity of a peer from its X.509 certificate.
+//!
+//! Only names used for authorization are extracted, the certificate
+//! must already be verified by the TLS library. TLS streams don't expose
+//! the peer certificate to this crate, so it is obtained from the TLS library
+//! (or from a proxy terminating TLS) by the application.
+
+use auth::PeerIdentity;
+use error::Error;
+use result;
+
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_OID: u8 = 0x06;
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_UTF8_STRING: u8 = 0x0c;
+const TAG_PRINTABLE_STRING: u8 = 0x13;
+const TAG_IA5_STRING: u8 = 0x16;
+/// Explicit `[0]` version of `TBSCertificate`
+const TAG_VERSION: u8 = 0xa0;
+/// Explicit `[3]` extensions of `TBSCertificate`
+const TAG_EXTENSIONS: u8 = 0xa3;
+/// `dNSName` choice of `GeneralName`
+const TAG_DNS_NAME: u8 = 0x82;
+/// `uniformResourceIdentifier` choice of `GeneralName`
+const TAG_URI: u8 = 0x86;
+
+/// 2.5.4.3
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+/// 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+fn malformed() -> Error {
+    Error::Protocol("malformed certificate")
+}
+
+/// Read DER element with single byte tag, return tag and contents.
+fn read<'a>(data: &mut &'a [u8]) -> result::Result<(u8, &'a [u8])> {
+    if data.len() < 2 {
+        return Err(malformed());
+    }
+    let tag = data[0];
+    let (len, header) = match data[1] {
+        len @ 0..=0x7f => (len as usize, 2),
+        0x81..=0x84 => {
+            let n = (data[1] & 0x7f) as usize;
+            if data.len() < 2 + n {
+                return Err(malformed());
+            }
+            let len = data[2..2 + n].iter().fold(0, |len, &b| (len << 8) | b as usize);
+            (len, 2 + n)
+        }
+        _ => return Err(malformed()),
+    };
+    if data.len() - header < len {
+        return Err(malformed());
+    }
+    let contents = &data[header..header + len];
+    *data = &data[header + len..];
+    Ok((tag, contents))
+}
+
+/// Read element which must have given tag.
+fn expect<'a>(data: &mut &'a [u8], tag: u8) -> result::Result<&'a [u8]> {
+    match read(data)? {
+        (t, contents) if t == tag => Ok(contents),
+        _ => Err(malformed()),
+    }
+}
+
+fn string(tag: u8, contents: &[u8]) -> Option<String> {
+    match tag {
+        TAG_UTF8_STRING | TAG_PRINTABLE_STRING | TAG_IA5_STRING => {
+            String::from_utf8(contents.to_vec()).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Last common name of distinguished name.
+fn common_name(mut name: &[u8]) -> result::Result<Option<String>> {
+    let mut cn = None;
+    while !name.is_empty() {
+        let mut rdn = expect(&mut name, TAG_SET)?;
+        while !rdn.is_empty() {
+            let mut attr = expect(&mut rdn, TAG_SEQUENCE)?;
+            let oid = expect(&mut attr, TAG_OID)?;
+            let (tag, value) = read(&mut attr)?;
+            if oid == OID_COMMON_NAME {
+                cn = string(tag, value).or(cn);
+            }
+        }
+    }
+    Ok(cn)
+}
+
+/// DNS names and URIs of subject alternative name extension.
+fn alt_names(mut names: &[u8], identity: &mut PeerIdentity) -> result::Result<()> {
+    while !names.is_empty() {
+        let (tag, value) = read(&mut names)?;
+        let list = match tag {
+            TAG_DNS_NAME => &mut identity.dns_names,
+            TAG_URI => &mut identity.uris,
+            _ => continue,
+        };
+        list.push(String::from_utf8(value.to_vec()).map_err(|_| malformed())?);
+    }
+    Ok(())
+}
+
+impl PeerIdentity {
+    /// Identity of the owner of DER-encoded X.509 certificate:
+    /// subject is the common name, `dns_names` and `uris`
+    /// (including SPIFFE ID) are subject alternative names.
+    pub fn from_certificate(der: &[u8]) -> result::Result<PeerIdentity> {
+        let mut der = der;
+        let mut cert = expect(&mut der, TAG_SEQUENCE)?;
+        let mut tbs = expect(&mut cert, TAG_SEQUENCE)?;
+
+        if tbs.first() == Some(&TAG_VERSION) {
+            read(&mut tbs)?;
+        }
+        // serial number, signature algorithm, issuer, validity
+        for _ in 0..4 {
+            read(&mut tbs)?;
+        }
+        let subject = expect(&mut tbs, TAG_SEQUENCE)?;
+
+        let mut identity = PeerIdentity {
+            subject: common_name(subject)?.unwrap_or_default(),
+            ..Default::default()
+        };
+
+        // public key and optional unique ids precede extensions
+        while !tbs.is_empty() {
+            let (tag, mut extensions) = read(&mut tbs)?;
+            if tag != TAG_EXTENSIONS {
+                continue;
+            }
+            let mut extensions = expect(&mut extensions, TAG_SEQUENCE)?;
+            while !extensions.is_empty() {
+                let mut extension = expect(&mut extensions, TAG_SEQUENCE)?;
+                let oid = expect(&mut extension, TAG_OID)?;
+                if extension.first() == Some(&TAG_BOOLEAN) {
+                    read(&mut extension)?;
+                }
+                let mut value = expect(&mut extension, TAG_OCTET_STRING)?;
+                if oid == OID_SUBJECT_ALT_NAME {
+                    alt_names(expect(&mut value, TAG_SEQUENCE)?, &mut identity)?;
+                }
+            }
+        }
+
+        Ok(identity)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use base64;
+
+    // CN=backend.example.com, SANs: two DNS names, SPIFFE ID and IP address
+    const CERT: &str = "\
+        MIICGTCCAcCgAwIBAgIUUV3MYDDOFaqBP/m4EkULrgwo2N8wCgYIKoZIzj0EAwIw\
+        LTENMAsGA1UECgwEVGVzdDEcMBoGA1UEAwwTYmFja2VuZC5leGFtcGxlLmNvbTAe\
+        Fw0yNjEwMTYxMTA3MzRaFw0zNjEwMTMxMTA3MzRaMC0xDTALBgNVBAoMBFRlc3Qx\
+        HDAaBgNVBAMME2JhY2tlbmQuZXhhbXBsZS5jb20wWTATBgcqhkjOPQIBBggqhkjO\
+        PQMBBwNCAASGV0N349/vcvf8B+NI2rVrCf9kzzF1OhulgrfJKS75qsl17/Mh2afE\
+        MJA/bFsSlGh0IXW28g7MFkcmvsAsm1MKo4G9MIG6MB0GA1UdDgQWBBT1fuKNJy5H\
+        czwFpeDT59+qbuJKozAfBgNVHSMEGDAWgBT1fuKNJy5HczwFpeDT59+qbuJKozAP\
+        BgNVHRMBAf8EBTADAQH/MGcGA1UdEQRgMF6CE2JhY2tlbmQuZXhhbXBsZS5jb22C\
+        FSouYmFja2VuZC5leGFtcGxlLmNvbYYqc3BpZmZlOi8vZXhhbXBsZS5vcmcvbnMv\
+        ZGVmYXVsdC9zYS9iYWNrZW5khwR/AAABMAoGCCqGSM49BAMCA0cAMEQCIFqXSaGb\
+        L8xg/VCXq9aN8/aHcQeMjMHLmbyRDSIX53TMAiBQJewLf1OQrcaiew32MiMFPAqB\
+        FNBO0cCfA1vk3EMFJA==";
+
+    #[test]
+    fn from_certificate() {
+        let der = base64::decode(CERT).unwrap();
+        let identity = PeerIdentity::from_certificate(&der).unwrap();
+        assert_eq!("backend.example.com", identity.subject);
+        assert_eq!(vec!["backend.example.com", "*.backend.example.com"], identity.dns_names);
+        assert_eq!(vec!["spiffe://example.org/ns/default/sa/backend"], identity.uris);
+        assert_eq!(Some("spiffe://example.org/ns/default/sa/backend"), identity.spiffe_id());
+        assert!(identity.audiences.is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        let der = base64::decode(CERT).unwrap();
+        assert!(PeerIdentity::from_certificate(&der[..der.len() - 1]).is_err());
+        assert!(PeerIdentity::from_certificate(&der[..100]).is_err());
+        assert!(PeerIdentity::from_certificate(&[]).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+//! Forwarding messages from a stream into a sink, e. g. in proxies.
+
+use futures::Async;
+use futures::AsyncSink;
+use futures::Canceled;
+use futures::Poll;
+use futures::future::Future;
+use futures::sink::Sink;
+use futures::stream::Stream;
+
+use error::Error;
+use futures_grpc::GrpcFuture;
+
+
+/// Forward messages from `input` to `output`, then close `output`.
+///
+/// Next message is read only after `output` accepted the previous one,
+/// so a slow receiver slows down the sender through flow control
+/// of both calls.
+///
+/// Future fails with the error of `input`, or with `Canceled`
+/// when `output` is closed by receiver. Either way the other side
+/// is dropped, and `output` is not closed, so a call receiving from it
+/// should be dropped as well to be cancelled rather than completed.
+pub fn pipe<T, S, K>(input: S, output: K) -> GrpcFuture<()>
+    where
+        T : Send + 'static,
+        S : Stream<Item=T, Error=Error> + Send + 'static,
+        K : Sink<SinkItem=T> + Send + 'static,
+{
+    Box::new(Pipe {
+        input: input,
+        output: output,
+        buffered: None,
+    })
+}
+
+struct Pipe<S : Stream, K> {
+    input: S,
+    output: K,
+    /// Message not yet accepted by `output`
+    buffered: Option<S::Item>,
+}
+
+fn output_closed<E>(_: E) -> Error {
+    Error::Canceled(Canceled)
+}
+
+impl<S, K> Pipe<S, K>
+    where
+        S : Stream<Error=Error>,
+        K : Sink<SinkItem=S::Item>,
+{
+    fn start_send(&mut self, item: S::Item) -> Poll<(), Error> {
+        if let AsyncSink::NotReady(item) = self.output.start_send(item).map_err(output_closed)? {
+            self.buffered = Some(item);
+            return Ok(Async::NotReady);
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<S, K> Future for Pipe<S, K>
+    where
+        S : Stream<Error=Error>,
+        K : Sink<SinkItem=S::Item>,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        if let Some(item) = self.buffered.take() {
+            try_ready!(self.start_send(item));
+        }
+
+        loop {
+            match self.input.poll()? {
+                Async::Ready(Some(item)) => try_ready!(self.start_send(item)),
+                Async::Ready(None) => {
+                    try_ready!(self.output.close().map_err(output_closed));
+                    return Ok(Async::Ready(()));
+                }
+                Async::NotReady => {
+                    try_ready!(self.output.poll_complete().map_err(output_closed));
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! Limit of concurrent calls with priority classes.
+//!
+//! Calls over the limit wait in a queue, calls of higher priority
+//! are started first, calls of the same priority in order of arrival.
+//! This keeps e. g. health checks from being starved by bulk transfers.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::str;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use futures::Async;
+use futures::Poll;
+use futures::future::Future;
+use futures::stream::Stream;
+use futures::sync::oneshot;
+
+use interceptor::*;
+use metadata::MetadataKey;
+use req::*;
+use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+
+
+/// Metadata key used to pass call priority from client to server.
+pub const PRIORITY_METADATA_KEY: &'static str = "call-priority";
+
+/// Priority class of a call, set in `RequestOptions::priority`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CallPriority {
+    /// Bulk transfers and background jobs
+    Low,
+    Normal,
+    /// Health checks and control-plane calls
+    High,
+}
+
+impl Default for CallPriority {
+    fn default() -> CallPriority {
+        CallPriority::Normal
+    }
+}
+
+impl CallPriority {
+    /// Name used in metadata.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CallPriority::Low => "low",
+            CallPriority::Normal => "normal",
+            CallPriority::High => "high",
+        }
+    }
+
+    /// Priority by name used in metadata.
+    pub fn from_name(name: &str) -> Option<CallPriority> {
+        match name {
+            "low" => Some(CallPriority::Low),
+            "normal" => Some(CallPriority::Normal),
+            "high" => Some(CallPriority::High),
+            _ => None,
+        }
+    }
+}
+
+struct Waiter {
+    priority: CallPriority,
+    seq: u64,
+    tx: oneshot::Sender<()>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Waiter) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Waiter) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    // max-heap: higher priority first, then lower sequence number
+    fn cmp(&self, other: &Waiter) -> Ordering {
+        self.priority.cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct State {
+    active: usize,
+    next_seq: u64,
+    waiting: BinaryHeap<Waiter>,
+}
+
+struct Shared {
+    max_concurrent_calls: usize,
+    state: Mutex<State>,
+}
+
+impl Shared {
+    /// Future resolved when call may start, with a permit to hold while it runs.
+    fn acquire(shared: &Arc<Shared>, priority: CallPriority) -> Acquire {
+        let mut state = shared.state.lock().unwrap();
+        if state.active < shared.max_concurrent_calls {
+            state.active += 1;
+            return Acquire { rx: None, shared: Some(shared.clone()) };
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.waiting.push(Waiter { priority, seq, tx });
+        Acquire { rx: Some(rx), shared: Some(shared.clone()) }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        // pass the slot to the next waiter which is still interested
+        while let Some(waiter) = state.waiting.pop() {
+            if waiter.tx.send(()).is_ok() {
+                return;
+            }
+        }
+        state.active -= 1;
+    }
+}
+
+/// Waiting for a slot, `rx` is `None` if slot is already taken.
+struct Acquire {
+    rx: Option<oneshot::Receiver<()>>,
+    shared: Option<Arc<Shared>>,
+}
+
+impl Future for Acquire {
+    type Item = Permit;
+    type Error = oneshot::Canceled;
+
+    fn poll(&mut self) -> Poll<Permit, oneshot::Canceled> {
+        if let Some(ref mut rx) = self.rx {
+            try_ready!(rx.poll());
+        }
+        let shared = self.shared.take().expect("polled after completion");
+        Ok(Async::Ready(Permit { shared }))
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        let shared = match self.shared.take() {
+            Some(shared) => shared,
+            None => return,
+        };
+        match self.rx {
+            Some(ref mut rx) => {
+                // slot may be passed to this call after it was canceled
+                rx.close();
+                if let Ok(Some(())) = rx.try_recv() {
+                    shared.release();
+                }
+            }
+            None => shared.release(),
+        }
+    }
+}
+
+/// Slot of a running call, released on drop.
+struct Permit {
+    shared: Arc<Shared>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.shared.release();
+    }
+}
+
+/// Response stream holding a permit until the stream is dropped.
+struct PermitStream<S> {
+    stream: S,
+    _permit: Permit,
+}
+
+impl<S : Stream> Stream for PermitStream<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        self.stream.poll()
+    }
+}
+
+/// Interceptor limiting number of concurrent calls, queueing calls over the limit
+/// by their priority.
+///
+/// Can be used both as client and server interceptor. Client interceptor
+/// passes `RequestOptions::priority` to server in `call-priority` metadata,
+/// server interceptor reads priority from it.
+pub struct PriorityInterceptor {
+    shared: Arc<Shared>,
+}
+
+impl PriorityInterceptor {
+    pub fn new(max_concurrent_calls: usize) -> PriorityInterceptor {
+        assert!(max_concurrent_calls > 0);
+        PriorityInterceptor {
+            shared: Arc::new(Shared {
+                max_concurrent_calls,
+                state: Mutex::new(State::default()),
+            }),
+        }
+    }
+
+    /// Number of calls in progress.
+    pub fn active_calls(&self) -> usize {
+        self.shared.state.lock().unwrap().active
+    }
+
+    /// Number of calls waiting for a slot.
+    pub fn queued_calls(&self) -> usize {
+        self.shared.state.lock().unwrap().waiting.len()
+    }
+
+    fn call<T, F>(&self, priority: CallPriority, call: F) -> StreamingResponse<T>
+        where
+            T : Send + 'static,
+            F : FnOnce() -> StreamingResponse<T> + Send + 'static,
+    {
+        let resp = Shared::acquire(&self.shared, priority)
+            .map_err(From::from)
+            .and_then(move |permit| {
+                call().0.map(move |(metadata, stream)| {
+                    // slot is released when response stream is dropped
+                    let stream = PermitStream { stream: stream.0, _permit: permit };
+                    (metadata, GrpcStreamWithTrailingMetadata::new(stream))
+                })
+            });
+        StreamingResponse::new(resp)
+    }
+}
+
+impl ClientInterceptor for PriorityInterceptor {
+    fn intercept(
+        &self,
+        _method: &str,
+        mut o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>
+    {
+        let priority = o.priority;
+        if priority != CallPriority::Normal {
+            o.metadata.add(MetadataKey::from(PRIORITY_METADATA_KEY), Bytes::from(priority.name()));
+        }
+        self.call(priority, move || next.call(o, req))
+    }
+}
+
+impl ServerInterceptor for PriorityInterceptor {
+    fn intercept(
+        &self,
+        _method: &str,
+        mut o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>
+    {
+        if let Some(priority) = o.metadata.get(PRIORITY_METADATA_KEY)
+            .and_then(|p| str::from_utf8(p).ok())
+            .and_then(CallPriority::from_name)
+        {
+            o.priority = priority;
+        }
+        let priority = o.priority;
+        self.call(priority, move || next.call(o, req))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn higher_priority_first() {
+        let shared = Arc::new(Shared {
+            max_concurrent_calls: 1,
+            state: Mutex::new(State::default()),
+        });
+
+        let running = Shared::acquire(&shared, CallPriority::Low).wait().unwrap();
+        let low = Shared::acquire(&shared, CallPriority::Low);
+        let normal = Shared::acquire(&shared, CallPriority::Normal);
+        let high = Shared::acquire(&shared, CallPriority::High);
+
+        drop(running);
+        let high = high.wait().unwrap();
+        drop(high);
+        let normal = normal.wait().unwrap();
+        drop(normal);
+        let low = low.wait().unwrap();
+        drop(low);
+
+        assert_eq!(0, shared.state.lock().unwrap().active);
+    }
+
+    #[test]
+    fn canceled_waiter_skipped() {
+        let shared = Arc::new(Shared {
+            max_concurrent_calls: 1,
+            state: Mutex::new(State::default()),
+        });
+
+        let running = Shared::acquire(&shared, CallPriority::Normal).wait().unwrap();
+        drop(Shared::acquire(&shared, CallPriority::High));
+        let normal = Shared::acquire(&shared, CallPriority::Normal);
+
+        drop(running);
+        drop(normal.wait().unwrap());
+        assert_eq!(0, shared.state.lock().unwrap().active);
+    }
+}
@@ -7,7 +7,11 @@ use marshall::Marshaller;
 use protobuf_lib::Message;
 use protobuf_lib::CodedInputStream;
 
+use protobuf_json;
 use result;
+use error::Error;
+use error::GrpcMessageError;
+use grpc::GrpcStatus;
 
 
 pub struct MarshallerProtobuf;
@@ -26,3 +30,86 @@ impl<M : Message> Marshaller<M> for MarshallerProtobuf {
         Ok(r)
     }
 }
+
+
+/// Message validation rules, implemented by hand or by a validator generator.
+pub trait Validate {
+    /// Return error description if message is not valid.
+    fn validate(&self) -> Result<(), String>;
+}
+
+fn validate<M : Validate>(m: &M) -> result::Result<()> {
+    m.validate().map_err(|message| Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::Argument as i32,
+        grpc_message: message,
+    }))
+}
+
+/// Protobuf marshaller which validates messages before sending
+/// and after receiving.
+///
+/// Generated code uses it for requests when `validate` codegen option is set,
+/// so invalid requests are rejected by client before sending,
+/// and by server before invoking handler, with `INVALID_ARGUMENT` status.
+pub struct MarshallerProtobufValidate;
+
+impl<M : Message + Validate> Marshaller<M> for MarshallerProtobufValidate {
+    fn write(&self, m: &M) -> result::Result<Vec<u8>> {
+        validate(m)?;
+        MarshallerProtobuf.write(m)
+    }
+
+    fn read(&self, buf: Bytes) -> result::Result<M> {
+        let m: M = MarshallerProtobuf.read(buf)?;
+        validate(&m)?;
+        Ok(m)
+    }
+}
+
+
+/// Marshaller encoding messages with protobuf JSON mapping,
+/// sent with `content-type: application/grpc+json`.
+///
+/// Messages are printed and parsed with rust-protobuf reflection,
+/// well-known types have their special representations,
+/// except `google.protobuf.Any`, which is rejected.
+pub struct MarshallerJson;
+
+impl<M : Message> Marshaller<M> for MarshallerJson {
+    fn write(&self, m: &M) -> result::Result<Vec<u8>> {
+        Ok(protobuf_json::print(m)?.into_bytes())
+    }
+
+    fn read(&self, buf: Bytes) -> result::Result<M> {
+        let encoded = protobuf_json::parse(M::descriptor_static(), &buf)?;
+        MarshallerProtobuf.read(Bytes::from(encoded))
+    }
+
+    fn content_subtype(&self) -> Option<&'static str> {
+        Some("json")
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use protobuf_lib::well_known_types::Duration;
+
+    #[test]
+    fn json() {
+        let mut d = Duration::new();
+        d.set_seconds(3);
+        let json = MarshallerJson.write(&d).unwrap();
+        assert_eq!(&b"\"3s\""[..], &json[..]);
+        assert_eq!(d, MarshallerJson.read(Bytes::from(json)).unwrap());
+
+        let err = Marshaller::<Duration>::read(&MarshallerJson, Bytes::from_static(b"{")).unwrap_err();
+        assert_eq!(GrpcStatus::Internal as i32, err.grpc_status());
+        match err {
+            Error::GrpcMessage(ref e) => assert!(e.grpc_message.starts_with("invalid JSON"), "{}", e.grpc_message),
+            ref e => panic!("{:?}", e),
+        }
+    }
+}
@@ -0,0 +1,998 @@
+//! [Protobuf JSON mapping](https://developers.google.com/protocol-buffers/docs/proto3#json)
+//! implemented with rust-protobuf reflection.
+//!
+//! rust-protobuf 2 has no JSON support, and its reflection is read-only,
+//! so messages are printed from reflection, and JSON is parsed into
+//! protobuf binary encoding guided by message descriptors.
+//!
+//! Limitations:
+//! * `google.protobuf.Any` is not supported, because there's no type registry
+//! * enum values are parsed by name only if their numbers are in `ENUM_PROBE_RANGE`,
+//!   because enum values cannot be listed with reflection; numbers are always accepted
+//! * groups are not supported
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::sync::ONCE_INIT;
+
+use base64;
+use serde_json;
+use serde_json::Value;
+
+use protobuf_lib::CodedOutputStream;
+use protobuf_lib::Message;
+use protobuf_lib::ProtobufResult;
+use protobuf_lib::descriptor::DescriptorProto;
+use protobuf_lib::descriptor::FieldDescriptorProto;
+use protobuf_lib::descriptor::FieldDescriptorProto_Type;
+use protobuf_lib::reflect::FieldDescriptor;
+use protobuf_lib::reflect::MessageDescriptor;
+use protobuf_lib::reflect::ReflectFieldRef;
+use protobuf_lib::reflect::ReflectValueRef;
+
+use result;
+use error::Error;
+use error::GrpcMessageError;
+use grpc::GrpcStatus;
+
+
+/// Enum numbers probed to find enum value by name.
+const ENUM_PROBE_RANGE: (i32, i32) = (-128, 1024);
+
+const WRAPPERS: &[&str] = &[
+    "google.protobuf.DoubleValue",
+    "google.protobuf.FloatValue",
+    "google.protobuf.Int64Value",
+    "google.protobuf.UInt64Value",
+    "google.protobuf.Int32Value",
+    "google.protobuf.UInt32Value",
+    "google.protobuf.BoolValue",
+    "google.protobuf.StringValue",
+    "google.protobuf.BytesValue",
+];
+
+/// Timestamp range is 0001-01-01T00:00:00Z to 9999-12-31T23:59:59Z
+const TIMESTAMP_SECONDS_MIN: i64 = -62135596800;
+const TIMESTAMP_SECONDS_MAX: i64 = 253402300799;
+/// Duration range is approximately +-10000 years
+const DURATION_SECONDS_MAX: i64 = 315576000000;
+const NANOS_PER_SEC: i32 = 1_000_000_000;
+const SECONDS_PER_DAY: i64 = 86400;
+
+
+fn invalid(message: String) -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::Internal as i32,
+        grpc_message: message,
+    })
+}
+
+
+/// Print message as JSON.
+pub fn print(m: &Message) -> result::Result<String> {
+    let mut out = String::new();
+    print_message(m, &mut out)?;
+    Ok(out)
+}
+
+fn print_message(m: &Message, out: &mut String) -> result::Result<()> {
+    let descriptor = m.descriptor();
+    let name = descriptor.full_name();
+    match name {
+        "google.protobuf.Timestamp" => {
+            let seconds = descriptor.field_by_number(1).get_i64(m);
+            let nanos = descriptor.field_by_number(2).get_i32(m);
+            print_str(&format_timestamp(seconds, nanos)?, out);
+        }
+        "google.protobuf.Duration" => {
+            let seconds = descriptor.field_by_number(1).get_i64(m);
+            let nanos = descriptor.field_by_number(2).get_i32(m);
+            print_str(&format_duration(seconds, nanos)?, out);
+        }
+        "google.protobuf.FieldMask" => {
+            let mut paths = Vec::new();
+            if let ReflectFieldRef::Repeated(r) = descriptor.field_by_number(1).get_reflect(m) {
+                for path in r.reflect_iter() {
+                    if let ReflectValueRef::String(path) = path.as_ref() {
+                        paths.push(snake_to_camel(path));
+                    }
+                }
+            }
+            print_str(&paths.join(","), out);
+        }
+        "google.protobuf.Struct" | "google.protobuf.ListValue" => {
+            print_field(descriptor.field_by_number(1).get_reflect(m), out)?;
+        }
+        "google.protobuf.Value" => {
+            let field = descriptor.fields().iter().find(|f| f.has_field(m))
+                .ok_or_else(|| invalid(format!("{} has no value", name)))?;
+            match field.name() {
+                "null_value" => out.push_str("null"),
+                "number_value" => print_float(field.get_f64(m), &field.get_f64(m).to_string(), out),
+                "string_value" => print_str(field.get_str(m), out),
+                "bool_value" => out.push_str(if field.get_bool(m) { "true" } else { "false" }),
+                _ => print_message(field.get_message(m), out)?,
+            }
+        }
+        name if WRAPPERS.contains(&name) => {
+            let value = descriptor.field_by_number(1);
+            match value.get_reflect(m) {
+                ReflectFieldRef::Optional(Some(v)) => print_value(v, out)?,
+                _ => print_default(value.proto().get_field_type(), out),
+            }
+        }
+        "google.protobuf.Any" => {
+            return Err(invalid(format!("{} is not supported in JSON", name)));
+        }
+        _ => {
+            out.push('{');
+            let mut first = true;
+            for field in descriptor.fields() {
+                let value = field.get_reflect(m);
+                let empty = match value {
+                    ReflectFieldRef::Optional(ref v) => v.is_none(),
+                    ReflectFieldRef::Repeated(r) => r.len() == 0,
+                    ReflectFieldRef::Map(map) => map.len() == 0,
+                };
+                if empty {
+                    continue;
+                }
+                if !first {
+                    out.push(',');
+                }
+                first = false;
+                print_str(field.json_name(), out);
+                out.push(':');
+                print_field(value, out)?;
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+fn print_field(value: ReflectFieldRef, out: &mut String) -> result::Result<()> {
+    match value {
+        ReflectFieldRef::Optional(Some(v)) => print_value(v, out)?,
+        ReflectFieldRef::Optional(None) => out.push_str("null"),
+        ReflectFieldRef::Repeated(r) => {
+            out.push('[');
+            for (i, v) in r.reflect_iter().enumerate() {
+                if i != 0 {
+                    out.push(',');
+                }
+                print_value(v.as_ref(), out)?;
+            }
+            out.push(']');
+        }
+        ReflectFieldRef::Map(map) => {
+            let mut entries = Vec::new();
+            for (k, v) in map.reflect_iter() {
+                entries.push((map_key(k.as_ref())?, v));
+            }
+            // stable output
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            out.push('{');
+            for (i, &(ref k, v)) in entries.iter().enumerate() {
+                if i != 0 {
+                    out.push(',');
+                }
+                print_str(k, out);
+                out.push(':');
+                print_value(v.as_ref(), out)?;
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+fn print_value(value: ReflectValueRef, out: &mut String) -> result::Result<()> {
+    match value {
+        ReflectValueRef::U32(v) => out.push_str(&v.to_string()),
+        ReflectValueRef::I32(v) => out.push_str(&v.to_string()),
+        // 64-bit integers are strings, because JavaScript numbers are doubles
+        ReflectValueRef::U64(v) => print_str(&v.to_string(), out),
+        ReflectValueRef::I64(v) => print_str(&v.to_string(), out),
+        ReflectValueRef::F32(v) => print_float(v as f64, &v.to_string(), out),
+        ReflectValueRef::F64(v) => print_float(v, &v.to_string(), out),
+        ReflectValueRef::Bool(v) => out.push_str(if v { "true" } else { "false" }),
+        ReflectValueRef::String(v) => print_str(v, out),
+        ReflectValueRef::Bytes(v) => print_str(&base64::encode(v), out),
+        ReflectValueRef::Enum(v) => print_str(v.name(), out),
+        ReflectValueRef::Message(v) => print_message(v, out)?,
+    }
+    Ok(())
+}
+
+/// Value of wrapper with default value, which is not returned by reflection.
+fn print_default(field_type: FieldDescriptorProto_Type, out: &mut String) {
+    out.push_str(match field_type {
+        FieldDescriptorProto_Type::TYPE_INT64 | FieldDescriptorProto_Type::TYPE_UINT64 => "\"0\"",
+        FieldDescriptorProto_Type::TYPE_BOOL => "false",
+        FieldDescriptorProto_Type::TYPE_STRING | FieldDescriptorProto_Type::TYPE_BYTES => "\"\"",
+        _ => "0",
+    });
+}
+
+/// `finite` is `v` formatted with its original precision.
+fn print_float(v: f64, finite: &str, out: &mut String) {
+    if v.is_nan() {
+        out.push_str("\"NaN\"");
+    } else if v == f64::INFINITY {
+        out.push_str("\"Infinity\"");
+    } else if v == f64::NEG_INFINITY {
+        out.push_str("\"-Infinity\"");
+    } else {
+        out.push_str(finite);
+    }
+}
+
+fn print_str(s: &str, out: &mut String) {
+    out.push_str(&Value::String(s.to_owned()).to_string());
+}
+
+fn map_key(key: ReflectValueRef) -> result::Result<String> {
+    Ok(match key {
+        ReflectValueRef::String(k) => k.to_owned(),
+        ReflectValueRef::Bool(k) => k.to_string(),
+        ReflectValueRef::U32(k) => k.to_string(),
+        ReflectValueRef::I32(k) => k.to_string(),
+        ReflectValueRef::U64(k) => k.to_string(),
+        ReflectValueRef::I64(k) => k.to_string(),
+        _ => return Err(invalid("unsupported map key type".to_owned())),
+    })
+}
+
+
+/// Parse JSON into protobuf binary encoding of message described by `descriptor`.
+pub fn parse(descriptor: &'static MessageDescriptor, json: &[u8]) -> result::Result<Vec<u8>> {
+    let json: Value = serde_json::from_slice(json)
+        .map_err(|e| invalid(format!("invalid JSON: {}", e)))?;
+    encode_message(descriptor, &json)
+}
+
+fn to_vec<F>(write: F) -> result::Result<Vec<u8>>
+    where F : FnOnce(&mut CodedOutputStream) -> result::Result<()>
+{
+    let mut r = Vec::new();
+    {
+        let mut os = CodedOutputStream::vec(&mut r);
+        write(&mut os)?;
+        os.flush()?;
+    }
+    Ok(r)
+}
+
+fn encode_message(descriptor: &'static MessageDescriptor, json: &Value) -> result::Result<Vec<u8>> {
+    to_vec(|os| write_message(descriptor, json, os))
+}
+
+fn write_message(descriptor: &'static MessageDescriptor, json: &Value, os: &mut CodedOutputStream)
+    -> result::Result<()>
+{
+    let name = descriptor.full_name();
+    match name {
+        "google.protobuf.Timestamp" => {
+            let (seconds, nanos) = parse_timestamp(json_str(json)?)?;
+            os.write_int64(1, seconds)?;
+            os.write_int32(2, nanos)?;
+        }
+        "google.protobuf.Duration" => {
+            let (seconds, nanos) = parse_duration(json_str(json)?)?;
+            os.write_int64(1, seconds)?;
+            os.write_int32(2, nanos)?;
+        }
+        "google.protobuf.FieldMask" => {
+            for path in json_str(json)?.split(',').filter(|p| !p.is_empty()) {
+                os.write_string(1, &camel_to_snake(path))?;
+            }
+        }
+        "google.protobuf.Struct" => write_struct(json, os)?,
+        "google.protobuf.ListValue" => write_list_value(json, os)?,
+        "google.protobuf.Value" => write_value(json, os)?,
+        name if WRAPPERS.contains(&name) => {
+            let field = descriptor.field_by_number(1);
+            Target { descriptor, field, map: false }.write(field.proto(), json, os)?;
+        }
+        "google.protobuf.Any" => {
+            return Err(invalid(format!("{} is not supported in JSON", name)));
+        }
+        _ => {
+            let fields = match *json {
+                Value::Object(ref fields) => fields,
+                _ => return Err(invalid(format!("expecting JSON object for {}, got {}", name, json))),
+            };
+            for (key, value) in fields {
+                let field = descriptor.get_field_by_name_or_json_name(key)
+                    .ok_or_else(|| invalid(format!("unknown field {} in {}", key, name)))?;
+                write_field(descriptor, field, value, os)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_field(
+    descriptor: &'static MessageDescriptor,
+    field: &'static FieldDescriptor,
+    json: &Value,
+    os: &mut CodedOutputStream)
+    -> result::Result<()>
+{
+    // null is the default value, except for `google.protobuf.Value`
+    if json.is_null() && (field.is_repeated() || field.proto().get_type_name() != ".google.protobuf.Value") {
+        return Ok(());
+    }
+
+    if let Some(entry) = map_entry(descriptor, field) {
+        let target = Target { descriptor, field, map: true };
+        let key_field = entry.get_field().iter().find(|f| f.get_number() == 1);
+        let value_field = entry.get_field().iter().find(|f| f.get_number() == 2);
+        let (key_field, value_field) = match (key_field, value_field) {
+            (Some(k), Some(v)) => (k, v),
+            _ => return Err(invalid(format!("malformed map entry of {}", field.name()))),
+        };
+        for (key, value) in json_object(json)? {
+            let key = match key_field.get_field_type() {
+                FieldDescriptorProto_Type::TYPE_BOOL => match &key[..] {
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    _ => return Err(invalid(format!("expecting bool map key, got {}", key))),
+                },
+                _ => Value::String(key.clone()),
+            };
+            let entry = to_vec(|os| {
+                target.write(key_field, &key, os)?;
+                target.write(value_field, value, os)
+            })?;
+            os.write_bytes(field.proto().get_number() as u32, &entry)?;
+        }
+    } else {
+        let target = Target { descriptor, field, map: false };
+        if field.is_repeated() {
+            // repeated scalars are written unpacked, parser accepts both encodings
+            for value in json_array(json)? {
+                target.write(field.proto(), value, os)?;
+            }
+        } else {
+            target.write(field.proto(), json, os)?;
+        }
+    }
+    Ok(())
+}
+
+/// Map entry message if field is map.
+fn map_entry(descriptor: &'static MessageDescriptor, field: &FieldDescriptor) -> Option<&'static DescriptorProto> {
+    let proto = field.proto();
+    if !field.is_repeated() || proto.get_field_type() != FieldDescriptorProto_Type::TYPE_MESSAGE {
+        return None;
+    }
+    // map entry is nested in message, named like `.pkg.Message.FieldEntry`
+    let entry_name = proto.get_type_name().rsplit('.').next().unwrap_or("");
+    let nested: &'static [DescriptorProto] = descriptor.get_proto().get_nested_type();
+    nested.iter()
+        .find(|n| n.get_name() == entry_name && n.get_options().get_map_entry())
+}
+
+/// Field which value is written.
+///
+/// Descriptors of message and enum types of field are not available with reflection,
+/// so they are discovered by parsing `descriptor` message with the field set.
+struct Target {
+    descriptor: &'static MessageDescriptor,
+    field: &'static FieldDescriptor,
+    /// Value is map entry value
+    map: bool,
+}
+
+impl Target {
+    /// Write value of `proto` field, which is `field` or map entry key or value.
+    fn write(&self, proto: &FieldDescriptorProto, json: &Value, os: &mut CodedOutputStream)
+        -> result::Result<()>
+    {
+        let number = proto.get_number() as u32;
+        match proto.get_field_type() {
+            FieldDescriptorProto_Type::TYPE_DOUBLE => os.write_double(number, json_f64(json)?)?,
+            FieldDescriptorProto_Type::TYPE_FLOAT => os.write_float(number, json_f32(json)?)?,
+            FieldDescriptorProto_Type::TYPE_INT64 => os.write_int64(number, json_i64(json)?)?,
+            FieldDescriptorProto_Type::TYPE_SINT64 => os.write_sint64(number, json_i64(json)?)?,
+            FieldDescriptorProto_Type::TYPE_SFIXED64 => os.write_sfixed64(number, json_i64(json)?)?,
+            FieldDescriptorProto_Type::TYPE_UINT64 => os.write_uint64(number, json_u64(json)?)?,
+            FieldDescriptorProto_Type::TYPE_FIXED64 => os.write_fixed64(number, json_u64(json)?)?,
+            FieldDescriptorProto_Type::TYPE_INT32 => os.write_int32(number, json_i32(json)?)?,
+            FieldDescriptorProto_Type::TYPE_SINT32 => os.write_sint32(number, json_i32(json)?)?,
+            FieldDescriptorProto_Type::TYPE_SFIXED32 => os.write_sfixed32(number, json_i32(json)?)?,
+            FieldDescriptorProto_Type::TYPE_UINT32 => os.write_uint32(number, json_u32(json)?)?,
+            FieldDescriptorProto_Type::TYPE_FIXED32 => os.write_fixed32(number, json_u32(json)?)?,
+            FieldDescriptorProto_Type::TYPE_BOOL => match *json {
+                Value::Bool(v) => os.write_bool(number, v)?,
+                _ => return Err(invalid(format!("expecting bool, got {}", json))),
+            },
+            FieldDescriptorProto_Type::TYPE_STRING => os.write_string(number, json_str(json)?)?,
+            FieldDescriptorProto_Type::TYPE_BYTES => os.write_bytes(number, &json_bytes(json)?)?,
+            FieldDescriptorProto_Type::TYPE_ENUM => os.write_enum(number, self.enum_number(json)?)?,
+            FieldDescriptorProto_Type::TYPE_MESSAGE => {
+                let message = encode_message(self.message()?, json)?;
+                os.write_bytes(number, &message)?;
+            }
+            FieldDescriptorProto_Type::TYPE_GROUP => {
+                return Err(invalid(format!("group field {} is not supported in JSON", self.field.name())));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse `descriptor` message with field value written by `write_value`.
+    fn probe<F>(&self, write_value: F) -> Option<Box<Message>>
+        where F : Fn(&mut CodedOutputStream, u32) -> ProtobufResult<()>
+    {
+        let number = self.field.proto().get_number() as u32;
+        let encoded = to_vec(|os| {
+            if self.map {
+                let entry = to_vec(|os| Ok(write_value(os, 2)?))?;
+                os.write_bytes(number, &entry)?;
+            } else {
+                write_value(os, number)?;
+            }
+            Ok(())
+        }).ok()?;
+        let mut m = self.descriptor.new_instance();
+        m.merge_from_bytes(&encoded).ok()?;
+        Some(m)
+    }
+
+    /// Value of field in probe message.
+    fn probe_value<'a>(&self, m: &'a Message) -> Option<ReflectValueRef<'a>> {
+        match self.field.get_reflect(m) {
+            ReflectFieldRef::Optional(v) => v,
+            ReflectFieldRef::Repeated(r) => if r.len() != 0 { Some(r.get(0).as_ref()) } else { None },
+            ReflectFieldRef::Map(map) => map.reflect_iter().next().map(|(_, v)| v.as_ref()),
+        }
+    }
+
+    fn message(&self) -> result::Result<&'static MessageDescriptor> {
+        let probe = self.probe(|os, number| os.write_bytes(number, &[]));
+        let value = match probe {
+            Some(ref m) => self.probe_value(&**m),
+            None => None,
+        };
+        match value {
+            Some(ReflectValueRef::Message(m)) => Ok(m.descriptor()),
+            _ => Err(invalid(format!("cannot find message type of field {}", self.field.name()))),
+        }
+    }
+
+    fn enum_number(&self, json: &Value) -> result::Result<i32> {
+        match *json {
+            Value::String(ref name) => {
+                self.enum_values().get(name).cloned()
+                    .or_else(|| name.parse().ok())
+                    .ok_or_else(|| invalid(format!("unknown value {} of enum field {}", name, self.field.name())))
+            }
+            _ => json_i32(json),
+        }
+    }
+
+    /// Names of enum values of field, cached.
+    fn enum_values(&self) -> Arc<HashMap<String, i32>> {
+        let key = (self.descriptor.full_name().to_owned(), self.field.proto().get_number());
+        if let Some(values) = enum_values_cache().lock().unwrap().get(&key) {
+            return values.clone();
+        }
+        let values = Arc::new(self.probe_enum_values());
+        enum_values_cache().lock().unwrap().insert(key, values.clone());
+        values
+    }
+
+    fn probe_enum_values(&self) -> HashMap<String, i32> {
+        let mut values = HashMap::new();
+        for number in ENUM_PROBE_RANGE.0..ENUM_PROBE_RANGE.1 {
+            let probe = match self.probe(|os, field_number| os.write_enum(field_number, number)) {
+                Some(m) => m,
+                None => continue,
+            };
+            let value = match self.probe_value(&*probe) {
+                Some(ReflectValueRef::Enum(v)) => v,
+                // proto3 zero value is not returned by reflection,
+                // while unknown values are preserved in unknown fields
+                None if number == 0 && !self.field.is_repeated()
+                    && probe.get_unknown_fields().iter().next().is_none() =>
+                {
+                    self.field.get_enum(&*probe)
+                }
+                _ => continue,
+            };
+            // unknown values are skipped or replaced with default by parser
+            if value.value() == number {
+                values.insert(value.name().to_owned(), number);
+            }
+        }
+        values
+    }
+}
+
+/// Enum values by message name and field number
+type EnumValuesCache = Mutex<HashMap<(String, i32), Arc<HashMap<String, i32>>>>;
+
+fn enum_values_cache() -> &'static EnumValuesCache {
+    static INIT: Once = ONCE_INIT;
+    static mut CACHE: *const EnumValuesCache = 0 as *const EnumValuesCache;
+
+    unsafe {
+        INIT.call_once(|| {
+            CACHE = Box::leak(Box::new(Mutex::new(HashMap::new())));
+        });
+        &*CACHE
+    }
+}
+
+fn write_struct(json: &Value, os: &mut CodedOutputStream) -> result::Result<()> {
+    for (key, value) in json_object(json)? {
+        let value = to_vec(|os| write_value(value, os))?;
+        let entry = to_vec(|os| {
+            os.write_string(1, key)?;
+            os.write_bytes(2, &value)?;
+            Ok(())
+        })?;
+        os.write_bytes(1, &entry)?;
+    }
+    Ok(())
+}
+
+fn write_list_value(json: &Value, os: &mut CodedOutputStream) -> result::Result<()> {
+    for value in json_array(json)? {
+        let value = to_vec(|os| write_value(value, os))?;
+        os.write_bytes(1, &value)?;
+    }
+    Ok(())
+}
+
+fn write_value(json: &Value, os: &mut CodedOutputStream) -> result::Result<()> {
+    match *json {
+        // NULL_VALUE
+        Value::Null => os.write_enum(1, 0)?,
+        Value::Number(..) => os.write_double(2, json_f64(json)?)?,
+        Value::String(ref s) => os.write_string(3, s)?,
+        Value::Bool(b) => os.write_bool(4, b)?,
+        Value::Object(..) => {
+            let s = to_vec(|os| write_struct(json, os))?;
+            os.write_bytes(5, &s)?;
+        }
+        Value::Array(..) => {
+            let l = to_vec(|os| write_list_value(json, os))?;
+            os.write_bytes(6, &l)?;
+        }
+    }
+    Ok(())
+}
+
+
+fn json_str(json: &Value) -> result::Result<&str> {
+    match *json {
+        Value::String(ref s) => Ok(s),
+        _ => Err(invalid(format!("expecting string, got {}", json))),
+    }
+}
+
+fn json_object(json: &Value) -> result::Result<&serde_json::Map<String, Value>> {
+    match *json {
+        Value::Object(ref o) => Ok(o),
+        _ => Err(invalid(format!("expecting object, got {}", json))),
+    }
+}
+
+fn json_array(json: &Value) -> result::Result<&Vec<Value>> {
+    match *json {
+        Value::Array(ref a) => Ok(a),
+        _ => Err(invalid(format!("expecting array, got {}", json))),
+    }
+}
+
+fn json_bytes(json: &Value) -> result::Result<Vec<u8>> {
+    let s = json_str(json)?;
+    // both standard and URL-safe alphabets are accepted, with or without padding
+    let config = if s.contains(&['-', '_'][..]) { base64::URL_SAFE } else { base64::STANDARD };
+    base64::decode_config(s, config).map_err(|e| invalid(format!("invalid base64: {}", e)))
+}
+
+fn json_f64(json: &Value) -> result::Result<f64> {
+    let v = match *json {
+        Value::Number(ref n) => n.as_f64(),
+        Value::String(ref s) => match &s[..] {
+            "NaN" => Some(f64::NAN),
+            "Infinity" => Some(f64::INFINITY),
+            "-Infinity" => Some(f64::NEG_INFINITY),
+            s => s.parse().ok(),
+        },
+        _ => None,
+    };
+    v.ok_or_else(|| invalid(format!("expecting number, got {}", json)))
+}
+
+fn json_f32(json: &Value) -> result::Result<f32> {
+    let v = json_f64(json)?;
+    if v.is_finite() && v.abs() > f32::MAX as f64 {
+        return Err(invalid(format!("float out of range: {}", json)));
+    }
+    Ok(v as f32)
+}
+
+/// Integers are numbers or strings, possibly in exponent notation.
+fn json_integer<T, F>(json: &Value, from_f64: F) -> Option<T>
+    where T : ::std::str::FromStr, F : Fn(f64) -> Option<T>
+{
+    match *json {
+        Value::Number(ref n) => n.to_string().parse().ok()
+            .or_else(|| n.as_f64().and_then(|v| if v.fract() == 0.0 { from_f64(v) } else { None })),
+        Value::String(ref s) => s.parse().ok()
+            .or_else(|| s.parse::<f64>().ok().and_then(|v| if v.fract() == 0.0 { from_f64(v) } else { None })),
+        _ => None,
+    }
+}
+
+fn json_i64(json: &Value) -> result::Result<i64> {
+    json_integer(json, |v| if v >= -9223372036854775808.0 && v < 9223372036854775808.0 { Some(v as i64) } else { None })
+        .ok_or_else(|| invalid(format!("expecting int64, got {}", json)))
+}
+
+fn json_u64(json: &Value) -> result::Result<u64> {
+    json_integer(json, |v| if v >= 0.0 && v < 18446744073709551616.0 { Some(v as u64) } else { None })
+        .ok_or_else(|| invalid(format!("expecting uint64, got {}", json)))
+}
+
+fn json_i32(json: &Value) -> result::Result<i32> {
+    json_integer(json, |v| if v >= i32::MIN as f64 && v <= i32::MAX as f64 { Some(v as i32) } else { None })
+        .ok_or_else(|| invalid(format!("expecting int32, got {}", json)))
+}
+
+fn json_u32(json: &Value) -> result::Result<u32> {
+    json_integer(json, |v| if v >= 0.0 && v <= u32::MAX as f64 { Some(v as u32) } else { None })
+        .ok_or_else(|| invalid(format!("expecting uint32, got {}", json)))
+}
+
+
+fn snake_to_camel(path: &str) -> String {
+    let mut r = String::new();
+    let mut upper = false;
+    for c in path.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            r.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            r.push(c);
+        }
+    }
+    r
+}
+
+fn camel_to_snake(path: &str) -> String {
+    let mut r = String::new();
+    for c in path.chars() {
+        if c.is_ascii_uppercase() {
+            r.push('_');
+            r.push(c.to_ascii_lowercase());
+        } else {
+            r.push(c);
+        }
+    }
+    r
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 => if is_leap_year(year) { 29 } else { 28 },
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Proleptic Gregorian date of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// 0, 3, 6 or 9 fractional digits.
+fn format_nanos(nanos: i32) -> String {
+    if nanos == 0 {
+        String::new()
+    } else if nanos % 1_000_000 == 0 {
+        format!(".{:03}", nanos / 1_000_000)
+    } else if nanos % 1_000 == 0 {
+        format!(".{:06}", nanos / 1_000)
+    } else {
+        format!(".{:09}", nanos)
+    }
+}
+
+fn format_timestamp(seconds: i64, nanos: i32) -> result::Result<String> {
+    if seconds < TIMESTAMP_SECONDS_MIN || seconds > TIMESTAMP_SECONDS_MAX || nanos < 0 || nanos >= NANOS_PER_SEC {
+        return Err(invalid(format!("timestamp out of range: {}s {}ns", seconds, nanos)));
+    }
+    let mut days = seconds / SECONDS_PER_DAY;
+    let mut time = seconds % SECONDS_PER_DAY;
+    if time < 0 {
+        time += SECONDS_PER_DAY;
+        days -= 1;
+    }
+    let (year, month, day) = civil_from_days(days);
+    Ok(format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}Z",
+        year, month, day, time / 3600, time / 60 % 60, time % 60, format_nanos(nanos)))
+}
+
+fn format_duration(seconds: i64, nanos: i32) -> result::Result<String> {
+    if seconds < -DURATION_SECONDS_MAX || seconds > DURATION_SECONDS_MAX
+        || nanos <= -NANOS_PER_SEC || nanos >= NANOS_PER_SEC
+        || (seconds < 0 && nanos > 0) || (seconds > 0 && nanos < 0)
+    {
+        return Err(invalid(format!("duration out of range: {}s {}ns", seconds, nanos)));
+    }
+    let sign = if seconds < 0 || nanos < 0 { "-" } else { "" };
+    Ok(format!("{}{}{}s", sign, seconds.abs(), format_nanos(nanos.abs())))
+}
+
+/// Non-empty string of decimal digits.
+fn parse_digits(s: &str) -> Option<i64> {
+    if s.is_empty() || s.len() > 18 || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Fraction of second with 1 to 9 digits.
+fn parse_nanos(s: &str) -> Option<i32> {
+    if s.len() > 9 {
+        return None;
+    }
+    let mut nanos = parse_digits(s)? as i32;
+    for _ in s.len()..9 {
+        nanos *= 10;
+    }
+    Some(nanos)
+}
+
+/// Parse RFC 3339 timestamp like `1972-01-01T10:00:20.021+01:00`.
+fn parse_timestamp(s: &str) -> result::Result<(i64, i32)> {
+    parse_timestamp_opt(s).ok_or_else(|| invalid(format!("invalid timestamp: {}", s)))
+}
+
+fn parse_timestamp_opt(s: &str) -> Option<(i64, i32)> {
+    let b = s.as_bytes();
+    if !s.is_ascii() || b.len() < 20
+        || b[4] != b'-' || b[7] != b'-' || (b[10] != b'T' && b[10] != b't')
+        || b[13] != b':' || b[16] != b':'
+    {
+        return None;
+    }
+    let year = parse_digits(&s[0..4])?;
+    let month = parse_digits(&s[5..7])?;
+    let day = parse_digits(&s[8..10])?;
+    let hour = parse_digits(&s[11..13])?;
+    let minute = parse_digits(&s[14..16])?;
+    let second = parse_digits(&s[17..19])?;
+    if year < 1 || month < 1 || month > 12 || day < 1 || day > days_in_month(year, month)
+        || hour > 23 || minute > 59 || second > 59
+    {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    let mut nanos = 0;
+    if rest.starts_with('.') {
+        let end = rest[1..].find(|c: char| !c.is_ascii_digit()).map_or(rest.len(), |i| i + 1);
+        nanos = parse_nanos(&rest[1..end])?;
+        rest = &rest[end..];
+    }
+
+    let offset = if rest == "Z" || rest == "z" {
+        0
+    } else if rest.len() == 6 && (rest.starts_with('+') || rest.starts_with('-')) && &rest[3..4] == ":" {
+        let offset = parse_digits(&rest[1..3])? * 3600 + parse_digits(&rest[4..6])? * 60;
+        if rest.starts_with('-') { -offset } else { offset }
+    } else {
+        return None;
+    };
+
+    let seconds = days_from_civil(year, month, day) * SECONDS_PER_DAY
+        + hour * 3600 + minute * 60 + second - offset;
+    if seconds < TIMESTAMP_SECONDS_MIN || seconds > TIMESTAMP_SECONDS_MAX {
+        return None;
+    }
+    Some((seconds, nanos))
+}
+
+/// Parse duration like `-1.5s`.
+fn parse_duration(s: &str) -> result::Result<(i64, i32)> {
+    parse_duration_opt(s).ok_or_else(|| invalid(format!("invalid duration: {}", s)))
+}
+
+fn parse_duration_opt(s: &str) -> Option<(i64, i32)> {
+    if !s.is_ascii() || !s.ends_with('s') {
+        return None;
+    }
+    let s = &s[..s.len() - 1];
+    let (negative, s) = if s.starts_with('-') { (true, &s[1..]) } else { (false, s) };
+    let (seconds, nanos) = match s.find('.') {
+        Some(i) => (parse_digits(&s[..i])?, parse_nanos(&s[i + 1..])?),
+        None => (parse_digits(s)?, 0),
+    };
+    if seconds > DURATION_SECONDS_MAX {
+        return None;
+    }
+    if negative {
+        Some((-seconds, -nanos))
+    } else {
+        Some((seconds, nanos))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use protobuf_lib::descriptor::FieldDescriptorProto_Label;
+    use protobuf_lib::descriptor::UninterpretedOption;
+    use protobuf_lib::descriptor::UninterpretedOption_NamePart;
+    use protobuf_lib::well_known_types::Duration;
+    use protobuf_lib::well_known_types::FieldMask;
+    use protobuf_lib::well_known_types::Int64Value;
+    use protobuf_lib::well_known_types::Struct;
+    use protobuf_lib::well_known_types::Timestamp;
+
+    fn round_trip<M : Message + PartialEq + ::std::fmt::Debug>(m: &M, json: &str) {
+        assert_eq!(json, print(m).unwrap());
+        let encoded = parse(M::descriptor_static(), json.as_bytes()).unwrap();
+        let mut parsed = M::new();
+        parsed.merge_from_bytes(&encoded).unwrap();
+        assert_eq!(*m, parsed);
+    }
+
+    fn parse_as<M : Message>(json: &str) -> result::Result<M> {
+        let encoded = parse(M::descriptor_static(), json.as_bytes())?;
+        let mut m = M::new();
+        m.merge_from_bytes(&encoded)?;
+        Ok(m)
+    }
+
+    #[test]
+    fn scalars() {
+        let mut option = UninterpretedOption::new();
+        option.set_identifier_value("x\"y".to_owned());
+        option.set_positive_int_value(18446744073709551615);
+        option.set_negative_int_value(-5);
+        option.set_double_value(1.5);
+        option.set_string_value(b"\x00\xff".to_vec());
+        round_trip(&option, concat!(
+            r#"{"identifierValue":"x\"y","positiveIntValue":"18446744073709551615","#,
+            r#""negativeIntValue":"-5","doubleValue":1.5,"stringValue":"AP8="}"#));
+
+        // numbers for 64-bit integers, proto field names, URL-safe base64 without padding
+        let parsed: UninterpretedOption = parse_as(
+            r#"{"negative_int_value":-5,"stringValue":"AP8","doubleValue":"NaN"}"#).unwrap();
+        assert_eq!(-5, parsed.get_negative_int_value());
+        assert_eq!(b"\x00\xff", parsed.get_string_value());
+        assert!(parsed.get_double_value().is_nan());
+        let parsed: UninterpretedOption = parse_as(r#"{"stringValue":"AP-_"}"#).unwrap();
+        assert_eq!(b"\x00\xff\xbf", parsed.get_string_value());
+    }
+
+    #[test]
+    fn repeated_messages() {
+        let mut option = UninterpretedOption::new();
+        for &(name, is_extension) in &[("a", false), ("b", true)] {
+            let mut part = UninterpretedOption_NamePart::new();
+            part.set_name_part(name.to_owned());
+            part.set_is_extension(is_extension);
+            option.mut_name().push(part);
+        }
+        round_trip(&option, r#"{"name":[{"namePart":"a","isExtension":false},{"namePart":"b","isExtension":true}]}"#);
+    }
+
+    #[test]
+    fn enums() {
+        let mut field = FieldDescriptorProto::new();
+        field.set_label(FieldDescriptorProto_Label::LABEL_REPEATED);
+        field.set_field_type(FieldDescriptorProto_Type::TYPE_STRING);
+        round_trip(&field, r#"{"label":"LABEL_REPEATED","type":"TYPE_STRING"}"#);
+
+        let parsed: FieldDescriptorProto = parse_as(r#"{"label":3,"type":"9"}"#).unwrap();
+        assert_eq!(field, parsed);
+        assert!(parse_as::<FieldDescriptorProto>(r#"{"label":"LABEL_UNKNOWN"}"#).is_err());
+    }
+
+    #[test]
+    fn timestamp() {
+        let mut t = Timestamp::new();
+        t.set_seconds(1_500_000_000);
+        t.set_nanos(10_000_000);
+        round_trip(&t, r#""2017-07-14T02:40:00.010Z""#);
+        t.set_seconds(-1);
+        t.set_nanos(0);
+        round_trip(&t, r#""1969-12-31T23:59:59Z""#);
+        t.set_seconds(TIMESTAMP_SECONDS_MIN);
+        t.set_nanos(1);
+        round_trip(&t, r#""0001-01-01T00:00:00.000000001Z""#);
+
+        let parsed: Timestamp = parse_as(r#""2017-07-14T04:40:00.01+02:00""#).unwrap();
+        assert_eq!((1_500_000_000, 10_000_000), (parsed.get_seconds(), parsed.get_nanos()));
+        assert!(parse_as::<Timestamp>(r#""2017-02-29T00:00:00Z""#).is_err());
+        assert!(parse_as::<Timestamp>(r#""2017-07-14 02:40:00Z""#).is_err());
+    }
+
+    #[test]
+    fn duration() {
+        let mut d = Duration::new();
+        d.set_seconds(1);
+        d.set_nanos(500_000_000);
+        round_trip(&d, r#""1.500s""#);
+        d.set_seconds(0);
+        d.set_nanos(-1_000);
+        round_trip(&d, r#""-0.000001s""#);
+        d.set_seconds(-3);
+        d.set_nanos(0);
+        round_trip(&d, r#""-3s""#);
+        assert!(parse_as::<Duration>(r#""3""#).is_err());
+        assert!(parse_as::<Duration>(r#""1.0000000001s""#).is_err());
+    }
+
+    #[test]
+    fn struct_value() {
+        let json = r#"{"a":[1,"x",true,null],"b":{"c":{}}}"#;
+        let s: Struct = parse_as(json).unwrap();
+        round_trip(&s, json);
+    }
+
+    #[test]
+    fn wrapper() {
+        let mut v = Int64Value::new();
+        round_trip(&v, r#""0""#);
+        v.set_value(-7);
+        round_trip(&v, r#""-7""#);
+    }
+
+    #[test]
+    fn field_mask() {
+        let mut mask = FieldMask::new();
+        mask.mut_paths().push("foo_bar".to_owned());
+        mask.mut_paths().push("baz.qux_quux".to_owned());
+        round_trip(&mask, r#""fooBar,baz.quxQuux""#);
+    }
+
+    #[test]
+    fn errors() {
+        let unknown = parse_as::<UninterpretedOption>(r#"{"unknown":1}"#).unwrap_err();
+        assert_eq!(GrpcStatus::Internal as i32, unknown.grpc_status());
+        assert!(parse_as::<UninterpretedOption>(r#"{"doubleValue":"x"}"#).is_err());
+        assert!(parse_as::<UninterpretedOption>(r#"{"negativeIntValue":1.5}"#).is_err());
+        assert!(parse_as::<UninterpretedOption>(r#"["#).is_err());
+    }
+}
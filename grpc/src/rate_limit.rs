@@ -0,0 +1,191 @@
+//! Server call rate limiting with token buckets.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use futures::stream;
+
+use error::Error;
+use error::GrpcMessageError;
+use grpc::GrpcStatus;
+use interceptor::*;
+use metadata::Metadata;
+use metadata::MetadataKey;
+use req::*;
+use resp::*;
+
+
+/// Metadata key of response to rate limited call,
+/// number of seconds after which call may be retried.
+pub const RETRY_AFTER_METADATA_KEY: &'static str = "retry-after";
+
+/// Full buckets are removed when there are more buckets than this.
+const MAX_BUCKETS: usize = 10000;
+
+/// Rate limit and how calls are grouped into buckets.
+#[derive(Debug, Clone)]
+pub struct RateLimitConf {
+    /// Calls per second allowed on average
+    pub rate: f64,
+    /// Calls allowed at once after a period of no calls
+    pub burst: u32,
+    /// Separate bucket for each method
+    pub per_method: bool,
+    /// Separate bucket for each value of this metadata key, e. g. `x-api-key`.
+    /// Calls without this key share a bucket.
+    pub metadata_key: Option<String>,
+}
+
+impl Default for RateLimitConf {
+    fn default() -> RateLimitConf {
+        RateLimitConf {
+            rate: 100.0,
+            burst: 100,
+            per_method: false,
+            metadata_key: None,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, conf: &RateLimitConf, now: Instant) {
+        if now > self.updated {
+            let elapsed = now - self.updated;
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            self.tokens = (self.tokens + elapsed * conf.rate).min(conf.burst as f64);
+            self.updated = now;
+        }
+    }
+}
+
+/// Server interceptor rejecting calls over the rate limit with `RESOURCE_EXHAUSTED`
+/// status and `retry-after` metadata.
+pub struct RateLimitInterceptor {
+    conf: RateLimitConf,
+    buckets: Mutex<HashMap<(String, Vec<u8>), Bucket>>,
+}
+
+impl RateLimitInterceptor {
+    pub fn new(conf: RateLimitConf) -> RateLimitInterceptor {
+        assert!(conf.rate > 0.0);
+        assert!(conf.burst > 0);
+        RateLimitInterceptor {
+            conf,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn bucket_key(&self, method: &str, metadata: &Metadata) -> (String, Vec<u8>) {
+        let method = if self.conf.per_method { method.to_owned() } else { String::new() };
+        let value = self.conf.metadata_key.as_ref()
+            .and_then(|key| metadata.get(key))
+            .map_or(Vec::new(), |value| value.to_vec());
+        (method, value)
+    }
+
+    /// Take a token from the bucket, or return time until a token is available.
+    fn acquire(&self, key: (String, Vec<u8>), now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            // full buckets are the same as new ones
+            let conf = &self.conf;
+            buckets.retain(|_, bucket| {
+                bucket.refill(conf, now);
+                bucket.tokens < conf.burst as f64
+            });
+        }
+
+        let burst = self.conf.burst as f64;
+        let bucket = buckets.entry(key).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.refill(&self.conf, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let secs = (1.0 - bucket.tokens) / self.conf.rate;
+            Err(Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32))
+        }
+    }
+}
+
+/// Response to rate limited call, retry delay is rounded up to seconds.
+fn rate_limited(retry_after: Duration) -> StreamingResponse<Vec<u8>> {
+    let secs = retry_after.as_secs() + if retry_after.subsec_nanos() > 0 { 1 } else { 0 };
+    let mut metadata = Metadata::new();
+    metadata.add(MetadataKey::from(RETRY_AFTER_METADATA_KEY), Bytes::from(secs.to_string()));
+    let error = Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::ResourceExhausted as i32,
+        grpc_message: "rate limit exceeded".to_owned(),
+    });
+    StreamingResponse::metadata_and_stream(metadata, stream::once(Err(error)))
+}
+
+impl ServerInterceptor for RateLimitInterceptor {
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>
+    {
+        let key = self.bucket_key(method, &o.metadata);
+        match self.acquire(key, Instant::now()) {
+            Ok(()) => next.call(o, req),
+            Err(retry_after) => {
+                debug!("rate limiting call {}, retry after {:?}", method, retry_after);
+                rate_limited(retry_after)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let limit = RateLimitInterceptor::new(RateLimitConf {
+            rate: 2.0,
+            burst: 2,
+            per_method: true,
+            metadata_key: Some("x-api-key".to_owned()),
+        });
+        let key = |method: &str| (method.to_owned(), b"k".to_vec());
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), limit.acquire(key("/a/A"), now));
+        assert_eq!(Ok(()), limit.acquire(key("/a/A"), now));
+        assert_eq!(Err(Duration::from_millis(500)), limit.acquire(key("/a/A"), now));
+        assert_eq!(Ok(()), limit.acquire(key("/a/B"), now));
+
+        let later = now + Duration::from_millis(250);
+        assert_eq!(Err(Duration::from_millis(250)), limit.acquire(key("/a/A"), later));
+        let later = now + Duration::from_millis(500);
+        assert_eq!(Ok(()), limit.acquire(key("/a/A"), later));
+    }
+
+    #[test]
+    fn bucket_key() {
+        let limit = RateLimitInterceptor::new(RateLimitConf {
+            metadata_key: Some("x-api-key".to_owned()),
+            ..Default::default()
+        });
+        let mut metadata = Metadata::new();
+        assert_eq!((String::new(), Vec::new()), limit.bucket_key("/a/A", &metadata));
+        metadata.add(MetadataKey::from("x-api-key"), Bytes::from("k"));
+        assert_eq!((String::new(), b"k".to_vec()), limit.bucket_key("/a/A", &metadata));
+    }
+}
@@ -0,0 +1,56 @@
+//! Access to fields of messages of unknown types by field name.
+
+use protobuf_lib::CodedOutputStream;
+use protobuf_lib::Message;
+use protobuf_lib::descriptor::FieldDescriptorProto_Type;
+use protobuf_lib::reflect::FieldDescriptor;
+use protobuf_lib::reflect::MessageDescriptor;
+
+use result;
+
+
+/// Singular field with given name and type.
+pub fn find_field(descriptor: &'static MessageDescriptor, name: &str, field_type: FieldDescriptorProto_Type)
+    -> Option<&'static FieldDescriptor>
+{
+    descriptor.fields().iter()
+        .find(|f| f.name() == name && !f.is_repeated() && f.proto().get_field_type() == field_type)
+}
+
+/// Singular string field with given name.
+pub fn find_string_field(descriptor: &'static MessageDescriptor, name: &str) -> Option<&'static FieldDescriptor> {
+    find_field(descriptor, name, FieldDescriptorProto_Type::TYPE_STRING)
+}
+
+/// Copy of message with string field set.
+pub fn with_string_field<M : Message + Clone>(m: &M, field: &FieldDescriptor, value: &str) -> result::Result<M> {
+    // reflection is read-only, so the field is merged from its encoding
+    let mut encoded = Vec::new();
+    {
+        let mut os = CodedOutputStream::vec(&mut encoded);
+        os.write_string(field.proto().get_number() as u32, value)?;
+        os.flush()?;
+    }
+    let mut m = m.clone();
+    m.merge_from_bytes(&encoded)?;
+    Ok(m)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use protobuf_lib::well_known_types::Value;
+
+    #[test]
+    fn string_field() {
+        let field = find_string_field(Value::descriptor_static(), "string_value").unwrap();
+        assert!(find_string_field(Value::descriptor_static(), "number_value").is_none());
+
+        let value = with_string_field(&Value::new(), field, "x").unwrap();
+        assert_eq!("x", field.get_str(&value));
+        let value = with_string_field(&value, field, "y").unwrap();
+        assert_eq!("y", value.get_string_value());
+    }
+}
@@ -0,0 +1,398 @@
+//! Recording of client calls to a file and their replay,
+//! so interactions with real servers can be turned into deterministic tests.
+//!
+//! Session file contains a JSON object per line for each call:
+//! method, request and response messages, metadata and status.
+//! Messages and metadata values are base64-encoded.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use base64;
+
+use bytes::Bytes;
+
+use futures::Async;
+use futures::Poll;
+use futures::future;
+use futures::future::Future;
+use futures::stream;
+use futures::stream::Stream;
+
+use serde_json;
+use serde_json::Value;
+
+use client::ClientTransport;
+use error::Error;
+use error::GrpcMessageError;
+use futures_grpc::GrpcFuture;
+use futures_grpc::GrpcStream;
+use grpc::GrpcStatus;
+use metadata::Metadata;
+use metadata::MetadataKey;
+use req::RequestOptions;
+use req::StreamingRequest;
+use resp::StreamingResponse;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use stream_item::ItemOrMetadata;
+
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn bytes_to_json(bytes: &[u8]) -> Value {
+    Value::String(base64::encode(bytes))
+}
+
+fn bytes_from_json(v: &Value) -> io::Result<Bytes> {
+    let s = v.as_str().ok_or_else(|| invalid_data(format!("expecting string: {}", v)))?;
+    base64::decode(s)
+        .map(Bytes::from)
+        .map_err(|e| invalid_data(format!("invalid base64: {}", e)))
+}
+
+fn messages_to_json(messages: &[Bytes]) -> Value {
+    Value::Array(messages.iter().map(|m| bytes_to_json(m)).collect())
+}
+
+fn messages_from_json(v: Option<&Value>) -> io::Result<Vec<Bytes>> {
+    match v {
+        None => Ok(Vec::new()),
+        Some(v) => {
+            let messages = v.as_array()
+                .ok_or_else(|| invalid_data(format!("expecting array: {}", v)))?;
+            messages.iter().map(bytes_from_json).collect()
+        }
+    }
+}
+
+fn metadata_to_json(metadata: &Option<Metadata>) -> Value {
+    match *metadata {
+        None => Value::Null,
+        Some(ref metadata) => Value::Array(metadata.entries.iter()
+            .map(|e| Value::Array(vec![
+                Value::String(e.key.as_str().to_owned()),
+                bytes_to_json(&e.value),
+            ]))
+            .collect()),
+    }
+}
+
+fn metadata_from_json(v: Option<&Value>) -> io::Result<Option<Metadata>> {
+    let entries = match v {
+        None | Some(&Value::Null) => return Ok(None),
+        Some(v) => v.as_array().ok_or_else(|| invalid_data(format!("expecting array: {}", v)))?,
+    };
+    let mut metadata = Metadata::new();
+    for e in entries {
+        match e.as_array().map(|e| &e[..]) {
+            Some(&[Value::String(ref key), ref value]) if !key.is_empty() => {
+                metadata.add(MetadataKey::from(key.clone()), bytes_from_json(value)?);
+            }
+            _ => return Err(invalid_data(format!("invalid metadata entry: {}", e))),
+        }
+    }
+    Ok(Some(metadata))
+}
+
+
+/// Call as seen by client.
+#[derive(Debug, Default)]
+struct CallRecord {
+    method: String,
+    requests: Vec<Bytes>,
+    /// `None` if call failed before response headers
+    metadata: Option<Metadata>,
+    responses: Vec<Bytes>,
+    trailing_metadata: Option<Metadata>,
+    grpc_status: i32,
+    grpc_message: String,
+}
+
+impl CallRecord {
+    fn set_error(&mut self, e: &Error) {
+        match *e {
+            Error::GrpcMessage(ref e) => {
+                self.grpc_status = e.grpc_status;
+                self.grpc_message = e.grpc_message.clone();
+            }
+            ref e => {
+                self.grpc_status = e.grpc_status();
+                self.grpc_message = format!("{}", e);
+            }
+        }
+    }
+
+    fn error(&self) -> Error {
+        Error::GrpcMessage(GrpcMessageError {
+            grpc_status: self.grpc_status,
+            grpc_message: self.grpc_message.clone(),
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        let mut json = serde_json::Map::new();
+        json.insert("method".to_owned(), Value::String(self.method.clone()));
+        json.insert("request".to_owned(), messages_to_json(&self.requests));
+        json.insert("metadata".to_owned(), metadata_to_json(&self.metadata));
+        json.insert("response".to_owned(), messages_to_json(&self.responses));
+        json.insert("trailing_metadata".to_owned(), metadata_to_json(&self.trailing_metadata));
+        json.insert("grpc_status".to_owned(), Value::from(self.grpc_status));
+        json.insert("grpc_message".to_owned(), Value::String(self.grpc_message.clone()));
+        Value::Object(json)
+    }
+
+    fn from_json(json: &Value) -> io::Result<CallRecord> {
+        let method = json.get("method").and_then(|m| m.as_str())
+            .ok_or_else(|| invalid_data(format!("call record without method: {}", json)))?;
+        let grpc_status = match json.get("grpc_status") {
+            None => GrpcStatus::Ok as i32,
+            Some(s) => s.as_i64().ok_or_else(|| invalid_data(format!("invalid grpc_status: {}", s)))? as i32,
+        };
+        Ok(CallRecord {
+            method: method.to_owned(),
+            requests: messages_from_json(json.get("request"))?,
+            metadata: metadata_from_json(json.get("metadata"))?,
+            responses: messages_from_json(json.get("response"))?,
+            trailing_metadata: metadata_from_json(json.get("trailing_metadata"))?,
+            grpc_status: grpc_status,
+            grpc_message: json.get("grpc_message").and_then(|m| m.as_str()).unwrap_or("").to_owned(),
+        })
+    }
+}
+
+fn write_record(out: &Mutex<File>, record: &CallRecord) {
+    let mut line = record.to_json().to_string();
+    line.push('\n');
+    if let Err(e) = out.lock().unwrap().write_all(line.as_bytes()) {
+        warn!("failed to write call record: {}", e);
+    }
+}
+
+
+/// Transport which records calls made through another transport to a file.
+///
+/// Call is written when its response ends or fails;
+/// calls dropped before that are not recorded.
+pub struct RecordingTransport {
+    inner: Arc<ClientTransport>,
+    out: Arc<Mutex<File>>,
+}
+
+impl RecordingTransport {
+    /// Record calls of `inner` to a new file at `path`.
+    pub fn create<P : AsRef<Path>>(inner: Arc<ClientTransport>, path: P) -> io::Result<RecordingTransport> {
+        Ok(RecordingTransport {
+            inner: inner,
+            out: Arc::new(Mutex::new(File::create(path)?)),
+        })
+    }
+}
+
+impl ClientTransport for RecordingTransport {
+    fn call(&self, method: &str, options: RequestOptions, req: StreamingRequest<Bytes>)
+        -> StreamingResponse<Bytes>
+    {
+        let record = Arc::new(Mutex::new(CallRecord {
+            method: method.to_owned(),
+            ..Default::default()
+        }));
+
+        let req = {
+            let record = record.clone();
+            StreamingRequest::new(req.0.inspect(move |message| {
+                record.lock().unwrap().requests.push(message.clone());
+            }))
+        };
+
+        let out = self.out.clone();
+        StreamingResponse::new(self.inner.call(method, options, req).0.then(move |r| {
+            match r {
+                Ok((metadata, stream)) => {
+                    record.lock().unwrap().metadata = Some(metadata.clone());
+                    let stream = RecordResponse {
+                        stream: stream.0,
+                        record: record,
+                        out: out,
+                    };
+                    Ok((metadata, GrpcStreamWithTrailingMetadata::new(stream)))
+                }
+                Err(e) => {
+                    let mut record = record.lock().unwrap();
+                    record.set_error(&e);
+                    write_record(&out, &record);
+                    Err(e)
+                }
+            }
+        }))
+    }
+
+    fn warm_up(&self) -> GrpcFuture<()> {
+        self.inner.warm_up()
+    }
+
+    fn shutdown(&self) {
+        self.inner.shutdown()
+    }
+}
+
+struct RecordResponse {
+    stream: GrpcStream<ItemOrMetadata<Bytes>>,
+    record: Arc<Mutex<CallRecord>>,
+    out: Arc<Mutex<File>>,
+}
+
+impl Stream for RecordResponse {
+    type Item = ItemOrMetadata<Bytes>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ItemOrMetadata<Bytes>>, Error> {
+        let r = self.stream.poll();
+        {
+            let mut record = self.record.lock().unwrap();
+            match r {
+                Ok(Async::Ready(Some(ItemOrMetadata::Item(ref message)))) => {
+                    record.responses.push(message.clone());
+                }
+                Ok(Async::Ready(Some(ItemOrMetadata::TrailingMetadata(ref metadata)))) => {
+                    record.trailing_metadata = Some(metadata.clone());
+                }
+                Ok(Async::Ready(None)) => write_record(&self.out, &record),
+                Err(ref e) => {
+                    record.set_error(e);
+                    write_record(&self.out, &record);
+                }
+                Ok(Async::NotReady) => {}
+            }
+        }
+        r
+    }
+}
+
+
+/// Transport which answers calls with responses from a file
+/// written by `RecordingTransport`.
+///
+/// Calls of each method get responses of recorded calls of that method
+/// in recorded order. Request messages are consumed but not compared
+/// with recorded ones.
+pub struct ReplayTransport {
+    calls: Mutex<HashMap<String, VecDeque<CallRecord>>>,
+}
+
+impl ReplayTransport {
+    /// Load calls recorded to `path`.
+    pub fn open<P : AsRef<Path>>(path: P) -> io::Result<ReplayTransport> {
+        let content = fs::read_to_string(path)?;
+        let mut calls: HashMap<String, VecDeque<CallRecord>> = HashMap::new();
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            let json: Value = serde_json::from_str(line)
+                .map_err(|e| invalid_data(format!("invalid call record: {}", e)))?;
+            let record = CallRecord::from_json(&json)?;
+            calls.entry(record.method.clone()).or_insert_with(VecDeque::new).push_back(record);
+        }
+        Ok(ReplayTransport {
+            calls: Mutex::new(calls),
+        })
+    }
+}
+
+impl ClientTransport for ReplayTransport {
+    fn call(&self, method: &str, _options: RequestOptions, req: StreamingRequest<Bytes>)
+        -> StreamingResponse<Bytes>
+    {
+        let record = self.calls.lock().unwrap().get_mut(method).and_then(|calls| calls.pop_front());
+        let record = match record {
+            Some(record) => record,
+            None => return StreamingResponse::err(Error::GrpcMessage(GrpcMessageError {
+                grpc_status: GrpcStatus::Unimplemented as i32,
+                grpc_message: format!("no more recorded calls of {}", method),
+            })),
+        };
+
+        let error = record.error();
+        let metadata = match record.metadata {
+            Some(metadata) => metadata,
+            None => return StreamingResponse::err(error),
+        };
+
+        let mut items: Vec<Result<ItemOrMetadata<Bytes>, Error>> = record.responses.into_iter()
+            .map(|message| Ok(ItemOrMetadata::Item(message)))
+            .collect();
+        if record.grpc_status != GrpcStatus::Ok as i32 {
+            items.push(Err(error));
+        } else if let Some(trailing_metadata) = record.trailing_metadata {
+            items.push(Ok(ItemOrMetadata::TrailingMetadata(trailing_metadata)));
+        }
+
+        let stream = DrainRequest {
+            request: Some(req.0),
+            response: stream::iter_result(items),
+        };
+        StreamingResponse::new(future::ok((metadata, GrpcStreamWithTrailingMetadata::new(stream))))
+    }
+}
+
+/// Consumes request while response is read,
+/// so senders of request messages are not blocked.
+struct DrainRequest<S> {
+    request: Option<GrpcStream<Bytes>>,
+    response: S,
+}
+
+impl<S : Stream<Error=Error>> Stream for DrainRequest<S> {
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, Error> {
+        let done = match self.request {
+            Some(ref mut request) => loop {
+                match request.poll() {
+                    Ok(Async::Ready(Some(_))) => continue,
+                    Ok(Async::NotReady) => break false,
+                    Ok(Async::Ready(None)) | Err(_) => break true,
+                }
+            },
+            None => false,
+        };
+        if done {
+            self.request = None;
+        }
+        self.response.poll()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn record_json() {
+        let mut metadata = Metadata::new();
+        metadata.add(MetadataKey::from("x-key"), Bytes::from_static(b"value"));
+        let mut record = CallRecord {
+            method: "/test/Method".to_owned(),
+            requests: vec![Bytes::from_static(b"req")],
+            metadata: Some(metadata),
+            responses: vec![Bytes::new(), Bytes::from_static(b"\x00\xff")],
+            ..Default::default()
+        };
+        record.set_error(&Error::Protocol("partial frame"));
+
+        let json = record.to_json();
+        let parsed = CallRecord::from_json(&json).unwrap();
+        assert_eq!(json, parsed.to_json());
+        assert_eq!(GrpcStatus::Internal as i32, parsed.grpc_status);
+        assert_eq!(Some(&b"value"[..]), parsed.metadata.unwrap().get("x-key"));
+        assert!(parsed.trailing_metadata.is_none());
+    }
+}
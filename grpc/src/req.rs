@@ -1,20 +1,129 @@
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future;
 use futures::stream;
 use futures::stream::Stream;
 
 use metadata::Metadata;
+use call_stats::CallStatsCollector;
+use auth::PeerIdentity;
+use write_batch::Cork;
+use compression::MessageCompression;
+use priority::CallPriority;
+use cancel::Cancellation;
+use load_report::LoadRecorder;
+use method::IdempotencyLevel;
 
+use futures_grpc::GrpcFuture;
 use futures_grpc::GrpcStream;
 use error::Error;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct RequestOptions {
     pub metadata: Metadata,
+    /// Client only: collect call statistics into this collector.
+    pub call_stats: Option<CallStatsCollector>,
+    /// Client only: hold back request messages while corked.
+    pub cork: Option<Cork>,
+    /// Subtype of `content-type: application/grpc+<subtype>`, e. g. `json`.
+    /// Client sets it from request marshaller unless already set.
+    pub content_subtype: Option<String>,
+    /// Priority class used by `PriorityInterceptor`.
+    pub priority: CallPriority,
+    /// Call fails with `DEADLINE_EXCEEDED` if it is not complete by this instant.
+    /// Server: deadline sent by client.
+    pub deadline: Option<Instant>,
+    /// Client only: call fails with `DEADLINE_EXCEEDED` if no message
+    /// is sent or received for this long, e. g. to detect stuck
+    /// long-lived streams which have no deadline.
+    pub message_timeout: Option<Duration>,
+    /// Client: call fails with `CANCELLED` when this is cancelled.
+    /// Server: cancelled when client cancels the call, connection is closed
+    /// or deadline passes.
+    pub cancellation: Option<Cancellation>,
+    /// Client only: compression of request messages, e. g. `"gzip"` or `"identity"`,
+    /// overrides `ClientConf::compression`.
+    pub compression: Option<String>,
+    /// Client only: decide per request message whether it is compressed,
+    /// overrides `ClientConf::compression_min_message_size`.
+    pub message_compression: Option<MessageCompression>,
+    /// Client only: wait until a connection is established instead of
+    /// failing with `UNAVAILABLE` when server cannot be connected.
+    /// Waiting is limited by deadline.
+    pub wait_for_ready: bool,
+    /// Client only: idempotency level of this call, overrides level
+    /// declared for the method, used by `CachingInterceptor`.
+    pub idempotency_level: Option<IdempotencyLevel>,
+    /// Request ID sent (on client) or received (on server) by `RequestIdInterceptor`.
+    pub request_id: Option<String>,
+    /// Server only: load report of the call sent by `LoadReportInterceptor`.
+    pub load_report: Option<LoadRecorder>,
+    /// Server only: caller identity established by authentication interceptor.
+    pub peer_identity: Option<PeerIdentity>,
+    /// Server only: span of the call created by `TracingInterceptor`.
+    #[cfg(feature = "tracing")]
+    pub span: Option<::tracing::Span>,
 }
 
 impl RequestOptions {
     pub fn new() -> RequestOptions {
         Default::default()
     }
+
+    pub fn with_metadata(self, metadata: Metadata) -> RequestOptions {
+        RequestOptions { metadata, ..self }
+    }
+
+    pub fn with_deadline(self, deadline: Instant) -> RequestOptions {
+        RequestOptions { deadline: Some(deadline), ..self }
+    }
+
+    /// Set deadline `timeout` from now.
+    pub fn with_timeout(self, timeout: Duration) -> RequestOptions {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    pub fn with_message_timeout(self, message_timeout: Duration) -> RequestOptions {
+        RequestOptions { message_timeout: Some(message_timeout), ..self }
+    }
+
+    pub fn with_cancellation(self, cancellation: Cancellation) -> RequestOptions {
+        RequestOptions { cancellation: Some(cancellation), ..self }
+    }
+
+    pub fn with_compression(self, compression: &str) -> RequestOptions {
+        RequestOptions { compression: Some(compression.to_owned()), ..self }
+    }
+
+    pub fn with_message_compression(self, message_compression: MessageCompression) -> RequestOptions {
+        RequestOptions { message_compression: Some(message_compression), ..self }
+    }
+
+    pub fn with_wait_for_ready(self, wait_for_ready: bool) -> RequestOptions {
+        RequestOptions { wait_for_ready, ..self }
+    }
+
+    pub fn with_priority(self, priority: CallPriority) -> RequestOptions {
+        RequestOptions { priority, ..self }
+    }
+
+    pub fn with_idempotency_level(self, idempotency_level: IdempotencyLevel) -> RequestOptions {
+        RequestOptions { idempotency_level: Some(idempotency_level), ..self }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.as_ref().map_or(false, Cancellation::is_cancelled)
+    }
+
+    /// Future which resolves when call is cancelled,
+    /// never resolves if there's no cancellation.
+    pub fn cancelled(&self) -> GrpcFuture<()> {
+        match self.cancellation {
+            Some(ref cancellation) => cancellation.cancelled(),
+            None => Box::new(future::empty()),
+        }
+    }
 }
 
 /// Excluding initial metadata which is passed separately
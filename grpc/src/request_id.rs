@@ -0,0 +1,198 @@
+//! Request ID generation and propagation for correlating calls across services.
+
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use bytes::Bytes;
+
+use futures::future::Future;
+
+use rand;
+use rand::Rng;
+
+use interceptor::*;
+use metadata::MetadataKey;
+use req::*;
+use resp::*;
+
+
+/// Default metadata key of request ID.
+pub const REQUEST_ID_METADATA_KEY: &'static str = "x-request-id";
+
+/// Format of generated request IDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestIdFormat {
+    /// Random UUID (version 4)
+    Uuid,
+    /// ULID, sortable by generation time
+    Ulid,
+}
+
+/// How request IDs are sent and generated.
+#[derive(Debug, Clone)]
+pub struct RequestIdConf {
+    pub metadata_key: String,
+    /// Generate request ID for calls without one
+    pub generate: bool,
+    pub format: RequestIdFormat,
+}
+
+impl Default for RequestIdConf {
+    fn default() -> RequestIdConf {
+        RequestIdConf {
+            metadata_key: REQUEST_ID_METADATA_KEY.to_owned(),
+            generate: true,
+            format: RequestIdFormat::Uuid,
+        }
+    }
+}
+
+fn uuid() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}",
+        hex[..4].concat(), hex[4..6].concat(), hex[6..8].concat(), hex[8..10].concat(), hex[10..].concat())
+}
+
+fn ulid(millis: u64, random: u128) -> String {
+    const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+    let value = ((millis as u128 & 0xffff_ffff_ffff) << 80) | (random & ((1 << 80) - 1));
+    (0..26)
+        .map(|i| ALPHABET[(value >> (125 - 5 * i) & 0x1f) as usize] as char)
+        .collect()
+}
+
+impl RequestIdFormat {
+    fn generate(&self) -> String {
+        match *self {
+            RequestIdFormat::Uuid => uuid(),
+            RequestIdFormat::Ulid => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                let millis = now.as_secs() * 1000 + now.subsec_millis() as u64;
+                let mut rng = rand::thread_rng();
+                ulid(millis, (rng.gen::<u64>() as u128) << 64 | rng.gen::<u64>() as u128)
+            }
+        }
+    }
+}
+
+/// Interceptor attaching request ID to calls.
+///
+/// On client, request ID is taken from `RequestOptions::request_id`,
+/// from metadata, or generated, and sent in metadata. Calls made
+/// with client from `Client::for_request` have request ID of the parent call.
+///
+/// On server, request ID is taken from metadata or generated, stored in
+/// `RequestOptions::request_id` for handlers and logging and sent back in
+/// response metadata. Interceptor should be added before interceptors which
+/// log request ID.
+pub struct RequestIdInterceptor {
+    conf: RequestIdConf,
+}
+
+impl RequestIdInterceptor {
+    pub fn new(conf: RequestIdConf) -> RequestIdInterceptor {
+        RequestIdInterceptor { conf }
+    }
+
+    /// Set `o.request_id` and metadata, return request ID.
+    fn attach(&self, o: &mut RequestOptions) -> Option<String> {
+        let sent = o.metadata.get(&self.conf.metadata_key)
+            .map(|id| String::from_utf8_lossy(id).into_owned());
+        let request_id = match o.request_id.clone().or_else(|| sent.clone()) {
+            Some(request_id) => request_id,
+            None if self.conf.generate => self.conf.format.generate(),
+            None => return None,
+        };
+        if sent.is_none() {
+            o.metadata.add(MetadataKey::from(&self.conf.metadata_key[..]), Bytes::from(request_id.clone()));
+        }
+        o.request_id = Some(request_id.clone());
+        Some(request_id)
+    }
+}
+
+impl ClientInterceptor for RequestIdInterceptor {
+    fn intercept(
+        &self,
+        _method: &str,
+        mut o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>
+    {
+        self.attach(&mut o);
+        next.call(o, req)
+    }
+}
+
+impl ServerInterceptor for RequestIdInterceptor {
+    fn intercept(
+        &self,
+        _method: &str,
+        mut o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>
+    {
+        // request ID set by client is not trusted on server
+        o.request_id = None;
+        let request_id = match self.attach(&mut o) {
+            Some(request_id) => request_id,
+            None => return next.call(o, req),
+        };
+        let key = MetadataKey::from(&self.conf.metadata_key[..]);
+        StreamingResponse::new(next.call(o, req).0.map(move |(mut metadata, stream)| {
+            metadata.add(key, Bytes::from(request_id));
+            (metadata, stream)
+        }))
+    }
+}
+
+/// Client interceptor setting request ID of the parent call.
+pub struct PropagateRequestId {
+    pub request_id: String,
+}
+
+impl ClientInterceptor for PropagateRequestId {
+    fn intercept(
+        &self,
+        _method: &str,
+        mut o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>
+    {
+        if o.request_id.is_none() {
+            o.request_id = Some(self.request_id.clone());
+        }
+        next.call(o, req)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uuid_format() {
+        let id = uuid();
+        assert_eq!(36, id.len());
+        assert_eq!(vec![8, 4, 4, 4, 12], id.split('-').map(str::len).collect::<Vec<_>>());
+        assert_eq!(Some('4'), id.chars().nth(14));
+        assert!("89ab".contains(id.chars().nth(19).unwrap()));
+        assert!(uuid() != id);
+    }
+
+    #[test]
+    fn ulid_format() {
+        assert_eq!("00000000000000000000000000", ulid(0, 0));
+        assert_eq!("01ARYZ6S410000000000000001", ulid(1469918176385, 1));
+        assert_eq!("7ZZZZZZZZZZZZZZZZZZZZZZZZZ", ulid(u64::MAX, u128::MAX));
+        assert_eq!(26, RequestIdFormat::Ulid.generate().len());
+    }
+}
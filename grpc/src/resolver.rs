@@ -0,0 +1,83 @@
+//! Host name resolution.
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+
+use futures::future;
+use futures::Future;
+use futures_cpupool;
+use futures_cpupool::CpuPool;
+
+use balancer::AddressUpdates;
+use balancer::WeightedAddr;
+use error::Error;
+use futures_grpc::GrpcFuture;
+
+
+/// Resolves host names to addresses.
+///
+/// Can be overridden in `ClientConf` e. g. to implement service discovery.
+pub trait Resolver : fmt::Debug + Send + Sync + 'static {
+    /// Resolve host and port to a list of addresses.
+    ///
+    /// Must not block.
+    fn resolve(&self, host: &str, port: u16) -> GrpcFuture<Vec<SocketAddr>>;
+
+    /// Resolve host and port to a list of addresses with weights.
+    ///
+    /// Default implementation assigns weight 1 to all addresses.
+    fn resolve_weighted(&self, host: &str, port: u16) -> GrpcFuture<Vec<WeightedAddr>> {
+        Box::new(self.resolve(host, port).map(|addrs| {
+            addrs.into_iter().map(WeightedAddr::new).collect()
+        }))
+    }
+
+    /// Start sending new addresses of host and port to `updates`
+    /// when they change, until `updates.update` returns `false`.
+    ///
+    /// Called once after channel is created. Default implementation
+    /// never updates addresses.
+    fn watch(&self, _host: &str, _port: u16, _updates: AddressUpdates) {}
+}
+
+/// Resolver using system resolver on a thread pool.
+#[derive(Debug)]
+pub struct DefaultResolver {
+    pool: CpuPool,
+}
+
+impl DefaultResolver {
+    pub fn new() -> DefaultResolver {
+        DefaultResolver::with_threads(1)
+    }
+
+    /// Resolver with threads named `grpc-resolver-N`.
+    ///
+    /// Threads exit when resolver and all channels using it are dropped
+    /// or shut down.
+    pub fn with_threads(threads: usize) -> DefaultResolver {
+        DefaultResolver {
+            pool: futures_cpupool::Builder::new()
+                .name_prefix("grpc-resolver-")
+                .pool_size(threads)
+                .create(),
+        }
+    }
+}
+
+impl Resolver for DefaultResolver {
+    fn resolve(&self, host: &str, port: u16) -> GrpcFuture<Vec<SocketAddr>> {
+        // no need to go to resolver thread for literal addresses
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Box::new(future::ok(vec![SocketAddr::new(ip, port)]));
+        }
+
+        let host = host.to_owned();
+        Box::new(self.pool.spawn_fn(move || -> io::Result<Vec<SocketAddr>> {
+            Ok((&host[..], port).to_socket_addrs()?.collect())
+        }).map_err(Error::from))
+    }
+}
@@ -1,9 +1,15 @@
+use std::sync::Mutex;
+
 use futures::future;
 use futures::future::Future;
 use futures::stream;
 use futures::stream::Stream;
 
+use bytes::Bytes;
+
+use compression::HEADER_GRPC_ENCODING;
 use error;
+use error::GrpcMessageError;
 use result;
 use futures_grpc::*;
 use iter::*;
@@ -101,6 +107,52 @@ impl<T : Send + 'static> SingleResponse<T> {
 }
 
 
+/// Single message response with metadata and compression set by handler,
+/// converted into `SingleResponse` with `into()`.
+#[derive(Debug)]
+pub struct Response<T> {
+    message: T,
+    metadata: Metadata,
+    trailers: Metadata,
+}
+
+impl<T : Send + 'static> Response<T> {
+    pub fn new(message: T) -> Response<T> {
+        Response {
+            message: message,
+            metadata: Metadata::new(),
+            trailers: Metadata::new(),
+        }
+    }
+
+    /// Initial metadata.
+    pub fn with_metadata(mut self, metadata: Metadata) -> Response<T> {
+        self.metadata.extend(metadata);
+        self
+    }
+
+    /// Trailing metadata.
+    pub fn with_trailers(self, trailers: Metadata) -> Response<T> {
+        Response { trailers, ..self }
+    }
+
+    /// Compress the response with codec registered in `ServerConf`, e. g. `"gzip"`,
+    /// or send it uncompressed with `"identity"`, instead of `ServerConf::compression`.
+    /// Ignored if client doesn't accept the codec.
+    pub fn with_compression(mut self, compression: &str) -> Response<T> {
+        self.metadata.remove(HEADER_GRPC_ENCODING);
+        self.metadata.add(MetadataKey::from(HEADER_GRPC_ENCODING), Bytes::from(compression));
+        self
+    }
+}
+
+impl<T : Send + 'static> From<Response<T>> for SingleResponse<T> {
+    fn from(response: Response<T>) -> SingleResponse<T> {
+        SingleResponse::completed_with_metadata_and_trailing_metadata(
+            response.metadata, response.message, response.trailers)
+    }
+}
+
 /// Streaming response
 pub struct StreamingResponse<T : Send + 'static>(
     /// Initial metadata, stream of items followed by trailing metadata
@@ -220,6 +272,38 @@ impl<T : Send + 'static> StreamingResponse<T> {
         Box::new(self.0.map(|(_metadata, stream)| stream.drop_metadata()).flatten_stream())
     }
 
+    /// Initial metadata, resolved as soon as response headers arrive
+    /// before any message, and items followed by trailing metadata.
+    ///
+    /// Polling either of them drives the response for both.
+    /// If the call fails before headers, the stream fails with the error,
+    /// and the metadata future with its status and message.
+    pub fn split_metadata(self) -> (GrpcFuture<Metadata>, GrpcStreamWithTrailingMetadata<T>) {
+        let shared = self.0
+            .map(|(metadata, stream)| (metadata, Mutex::new(Some(stream))))
+            .map_err(|e| ((e.grpc_status(), e.to_string()), Mutex::new(Some(e))))
+            .shared();
+
+        let metadata = shared.clone()
+            .map(|r| r.0.clone())
+            .map_err(|e| {
+                let (grpc_status, ref grpc_message) = e.0;
+                error::Error::GrpcMessage(GrpcMessageError {
+                    grpc_status: grpc_status,
+                    grpc_message: grpc_message.clone(),
+                })
+            });
+
+        let stream = shared
+            .then(|r| match r {
+                Ok(r) => Ok(r.1.lock().unwrap().take().expect("stream is taken once").0),
+                Err(e) => Err(e.1.lock().unwrap().take().expect("error is taken once")),
+            })
+            .flatten_stream();
+
+        (Box::new(metadata), GrpcStreamWithTrailingMetadata::new(stream))
+    }
+
     pub fn into_future(self) -> SingleResponse<Vec<T>> {
         SingleResponse::new(self.0.map(|(initial, stream)| {
             let future: GrpcFuture<(Vec<T>, Metadata)> = stream.collect_with_metadata();
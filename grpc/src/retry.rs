@@ -0,0 +1,439 @@
+//! Client call retries with retry throttling, as described in
+//! [gRPC retry design](https://github.com/grpc/proposal/blob/master/A6-client-retries.md).
+//!
+//! A call is retried only if it fails before response headers are received,
+//! after that response is committed. Request messages are buffered until
+//! request stream ends, so retries are not suitable for bidirectional calls
+//! which send requests in reply to responses.
+
+use std::cmp;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use futures::future;
+use futures::future::Future;
+use futures::stream::Stream;
+
+use rand;
+use rand::Rng;
+
+use serde_json;
+
+use error::Error;
+use futures_grpc::GrpcFuture;
+use grpc::GrpcStatus;
+use interceptor::*;
+use metadata::Metadata;
+use req::*;
+use resp::*;
+use result;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use timer;
+
+
+/// Attempts above this limit are ignored, as required by retry design.
+const MAX_ATTEMPTS_LIMIT: u32 = 5;
+
+/// When and how often calls are retried, `retryPolicy` in service config.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts including the original one, at most 5
+    pub max_attempts: u32,
+    /// Delay before retry is random between zero and current backoff,
+    /// which starts with `initial_backoff` and is multiplied by
+    /// `backoff_multiplier` after each attempt up to `max_backoff`
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub backoff_multiplier: f64,
+    /// Only calls failed with these statuses are retried
+    pub retryable_status_codes: Vec<i32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            backoff_multiplier: 2.0,
+            retryable_status_codes: vec![GrpcStatus::Unavailable as i32],
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn from_config(config: &serde_json::Value) -> result::Result<RetryPolicy> {
+        let duration = |name: &str| config.get(name)
+            .and_then(|d| d.as_str())
+            .and_then(parse_duration)
+            .ok_or(Error::Other("invalid backoff in retryPolicy"));
+
+        let max_attempts = config.get("maxAttempts")
+            .and_then(|a| a.as_u64())
+            .filter(|&a| a >= 2)
+            .ok_or(Error::Other("invalid maxAttempts in retryPolicy"))?;
+        let backoff_multiplier = config.get("backoffMultiplier")
+            .and_then(|m| m.as_f64())
+            .filter(|&m| m > 0.0)
+            .ok_or(Error::Other("invalid backoffMultiplier in retryPolicy"))?;
+        let retryable_status_codes = config.get("retryableStatusCodes")
+            .and_then(|c| c.as_array())
+            .filter(|c| !c.is_empty())
+            .ok_or(Error::Other("invalid retryableStatusCodes in retryPolicy"))?
+            .iter()
+            .map(|c| match *c {
+                serde_json::Value::String(ref name) => status_from_name(name),
+                ref c => c.as_u64().filter(|&c| c <= 16).map(|c| c as i32),
+            })
+            .collect::<Option<Vec<i32>>>()
+            .ok_or(Error::Other("invalid retryableStatusCodes in retryPolicy"))?;
+
+        Ok(RetryPolicy {
+            max_attempts: cmp::min(max_attempts, MAX_ATTEMPTS_LIMIT as u64) as u32,
+            initial_backoff: duration("initialBackoff")?,
+            max_backoff: duration("maxBackoff")?,
+            backoff_multiplier,
+            retryable_status_codes,
+        })
+    }
+
+    pub(crate) fn max_attempts(&self) -> u32 {
+        cmp::min(self.max_attempts, MAX_ATTEMPTS_LIMIT)
+    }
+
+    /// Random delay before the next attempt after `attempts` attempts.
+    pub(crate) fn backoff(&self, attempts: u32) -> Duration {
+        let current = duration_secs(self.initial_backoff)
+            * self.backoff_multiplier.powi(attempts as i32 - 1);
+        let current = current.min(duration_secs(self.max_backoff));
+        if current <= 0.0 {
+            return Duration::from_millis(0);
+        }
+        secs_duration(rand::thread_rng().gen_range(0.0, current))
+    }
+}
+
+/// Token bucket limiting retries when server is failing,
+/// `retryThrottling` in service config.
+///
+/// Each failed call takes a token, each successful call returns
+/// `token_ratio` tokens. Calls are retried only while more than
+/// half of `max_tokens` are left.
+#[derive(Debug, Clone)]
+pub struct RetryThrottlingConf {
+    pub max_tokens: u32,
+    pub token_ratio: f64,
+}
+
+impl RetryThrottlingConf {
+    fn from_config(config: &serde_json::Value) -> result::Result<RetryThrottlingConf> {
+        let max_tokens = config.get("maxTokens")
+            .and_then(|t| t.as_u64())
+            .filter(|&t| t > 0 && t <= 1000)
+            .ok_or(Error::Other("invalid maxTokens in retryThrottling"))?;
+        let token_ratio = config.get("tokenRatio")
+            .and_then(|r| r.as_f64())
+            .filter(|&r| r > 0.0)
+            .ok_or(Error::Other("invalid tokenRatio in retryThrottling"))?;
+        Ok(RetryThrottlingConf {
+            max_tokens: max_tokens as u32,
+            token_ratio,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct RetryThrottle {
+    conf: RetryThrottlingConf,
+    tokens: Mutex<f64>,
+}
+
+impl RetryThrottle {
+    fn new(conf: RetryThrottlingConf) -> RetryThrottle {
+        RetryThrottle {
+            tokens: Mutex::new(conf.max_tokens as f64),
+            conf,
+        }
+    }
+
+    fn success(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.conf.token_ratio).min(self.conf.max_tokens as f64);
+    }
+
+    /// Record failure, return whether call may be retried.
+    fn failure(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens - 1.0).max(0.0);
+        *tokens > self.conf.max_tokens as f64 / 2.0
+    }
+}
+
+/// Method name in service config, missing fields match any name.
+#[derive(Debug, Clone)]
+struct MethodName {
+    service: Option<String>,
+    method: Option<String>,
+}
+
+impl MethodName {
+    fn matches(&self, path: &str) -> bool {
+        let mut parts = path.trim_start_matches('/').splitn(2, '/');
+        let (service, method) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        self.service.as_ref().map_or(true, |s| s == service)
+            && self.method.as_ref().map_or(true, |m| m == method)
+    }
+}
+
+/// Client interceptor retrying failed calls.
+///
+/// Throttling state is shared by all calls made through this interceptor,
+/// so the same interceptor should be added to all clients of a channel.
+pub struct RetryInterceptor {
+    policies: Vec<(Vec<MethodName>, RetryPolicy)>,
+    throttle: Option<Arc<RetryThrottle>>,
+}
+
+impl RetryInterceptor {
+    /// Retry calls of all methods with given policy.
+    pub fn new(policy: RetryPolicy, throttling: Option<RetryThrottlingConf>) -> RetryInterceptor {
+        let all = MethodName { service: None, method: None };
+        RetryInterceptor {
+            policies: vec![(vec![all], policy)],
+            throttle: throttling.map(|t| Arc::new(RetryThrottle::new(t))),
+        }
+    }
+
+    /// Interceptor configured by `retryPolicy` of `methodConfig` entries and
+    /// `retryThrottling` of JSON service config, `None` if no method has retry policy.
+    pub fn from_service_config(json: &str) -> result::Result<Option<RetryInterceptor>> {
+        let config: serde_json::Value = serde_json::from_str(json)
+            .map_err(|_| Error::Other("invalid service config"))?;
+
+        let mut policies = Vec::new();
+        let method_configs = config.get("methodConfig").and_then(|c| c.as_array());
+        for c in method_configs.into_iter().flat_map(|c| c) {
+            let policy = match c.get("retryPolicy") {
+                Some(policy) => RetryPolicy::from_config(policy)?,
+                None => continue,
+            };
+            let names = c.get("name")
+                .and_then(|n| n.as_array())
+                .ok_or(Error::Other("methodConfig without name"))?
+                .iter()
+                .map(|n| MethodName {
+                    service: n.get("service").and_then(|s| s.as_str())
+                        .filter(|s| !s.is_empty()).map(|s| s.to_owned()),
+                    method: n.get("method").and_then(|m| m.as_str())
+                        .filter(|m| !m.is_empty()).map(|m| m.to_owned()),
+                })
+                .collect();
+            policies.push((names, policy));
+        }
+
+        if policies.is_empty() {
+            return Ok(None);
+        }
+
+        let throttling = match config.get("retryThrottling") {
+            Some(t) => Some(RetryThrottlingConf::from_config(t)?),
+            None => None,
+        };
+
+        Ok(Some(RetryInterceptor {
+            policies,
+            throttle: throttling.map(|t| Arc::new(RetryThrottle::new(t))),
+        }))
+    }
+
+    fn policy(&self, method: &str) -> Option<&RetryPolicy> {
+        // config with method name takes precedence over config with service name only
+        let find = |with_method: bool| self.policies.iter()
+            .find(|&&(ref names, _)| names.iter()
+                .any(|n| n.method.is_some() == with_method && n.matches(method)))
+            .map(|&(_, ref policy)| policy);
+        find(true).or_else(|| find(false))
+    }
+}
+
+impl ClientInterceptor for RetryInterceptor {
+    fn intercept(
+        &self,
+        method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>
+    {
+        let policy = match self.policy(method) {
+            Some(policy) => policy.clone(),
+            None => return next.call(o, req),
+        };
+        let throttle = self.throttle.clone();
+
+        StreamingResponse::new(req.0.collect().and_then(move |messages| {
+            Attempt {
+                next,
+                options: o,
+                messages,
+                policy,
+                throttle,
+                attempts: 1,
+            }.call()
+        }))
+    }
+}
+
+struct Attempt {
+    next: ClientNext,
+    options: RequestOptions,
+    messages: Vec<Bytes>,
+    policy: RetryPolicy,
+    throttle: Option<Arc<RetryThrottle>>,
+    attempts: u32,
+}
+
+impl Attempt {
+    fn call(self) -> GrpcFuture<(Metadata, GrpcStreamWithTrailingMetadata<Bytes>)> {
+        let req = StreamingRequest::iter(self.messages.clone());
+        let resp = self.next.clone().call(self.options.clone(), req);
+        Box::new(resp.0.then(move |r| -> GrpcFuture<_> {
+            let e = match r {
+                Ok(r) => {
+                    if let Some(ref throttle) = self.throttle {
+                        throttle.success();
+                    }
+                    return Box::new(future::ok(r));
+                }
+                Err(e) => e,
+            };
+
+            if !self.policy.retryable_status_codes.contains(&e.grpc_status()) {
+                return Box::new(future::err(e));
+            }
+            let throttled = match self.throttle {
+                Some(ref throttle) => !throttle.failure(),
+                None => false,
+            };
+            if throttled || self.attempts >= self.policy.max_attempts() {
+                return Box::new(future::err(e));
+            }
+
+            let backoff = self.policy.backoff(self.attempts);
+            debug!("retrying call {} in {:?} after error: {:?}", self.next.method(), backoff, e);
+            Box::new(timer::sleep(backoff).and_then(move |()| {
+                Attempt {
+                    attempts: self.attempts + 1,
+                    ..self
+                }.call()
+            }))
+        }))
+    }
+}
+
+/// Duration like `0.5s` as used in service config.
+fn parse_duration(s: &str) -> Option<Duration> {
+    if !s.ends_with('s') {
+        return None;
+    }
+    s[..s.len() - 1].parse::<f64>().ok()
+        .filter(|&secs| secs >= 0.0)
+        .map(secs_duration)
+}
+
+fn duration_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+fn secs_duration(secs: f64) -> Duration {
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1e9).round() as u32)
+}
+
+/// Status code by canonical name like `UNAVAILABLE`.
+fn status_from_name(name: &str) -> Option<i32> {
+    let status = match name {
+        "OK" => GrpcStatus::Ok,
+        "CANCELLED" => GrpcStatus::Cancelled,
+        "UNKNOWN" => GrpcStatus::Unknown,
+        "INVALID_ARGUMENT" => GrpcStatus::Argument,
+        "DEADLINE_EXCEEDED" => GrpcStatus::DeadlineExceeded,
+        "NOT_FOUND" => GrpcStatus::NotFound,
+        "ALREADY_EXISTS" => GrpcStatus::AlreadyExists,
+        "PERMISSION_DENIED" => GrpcStatus::PermissionDenied,
+        "RESOURCE_EXHAUSTED" => GrpcStatus::ResourceExhausted,
+        "FAILED_PRECONDITION" => GrpcStatus::FailedPrecondition,
+        "ABORTED" => GrpcStatus::Aborted,
+        "OUT_OF_RANGE" => GrpcStatus::OutOfRange,
+        "UNIMPLEMENTED" => GrpcStatus::Unimplemented,
+        "INTERNAL" => GrpcStatus::Internal,
+        "UNAVAILABLE" => GrpcStatus::Unavailable,
+        "DATA_LOSS" => GrpcStatus::DataLoss,
+        "UNAUTHENTICATED" => GrpcStatus::Unauthenticated,
+        _ => return None,
+    };
+    Some(status as i32)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn service_config() {
+        let json = r#"{
+            "methodConfig": [{
+                "name": [{"service": "test.Echo"}],
+                "retryPolicy": {
+                    "maxAttempts": 10,
+                    "initialBackoff": "0.1s",
+                    "maxBackoff": "1.5s",
+                    "backoffMultiplier": 2,
+                    "retryableStatusCodes": ["UNAVAILABLE", 8]
+                }
+            }, {
+                "name": [{"service": "test.Echo", "method": "Once"}],
+                "timeout": "1s"
+            }],
+            "retryThrottling": {"maxTokens": 10, "tokenRatio": 0.1}
+        }"#;
+        let retry = RetryInterceptor::from_service_config(json).unwrap().unwrap();
+        let policy = retry.policy("/test.Echo/Echo").unwrap();
+        assert_eq!(5, policy.max_attempts);
+        assert_eq!(Duration::from_millis(100), policy.initial_backoff);
+        assert_eq!(Duration::from_millis(1500), policy.max_backoff);
+        assert_eq!(vec![GrpcStatus::Unavailable as i32, 8], policy.retryable_status_codes);
+        assert!(retry.policy("/test.Other/Echo").is_none());
+        assert!(retry.throttle.is_some());
+
+        assert!(RetryInterceptor::from_service_config(r#"{"loadBalancingPolicy": "round_robin"}"#)
+            .unwrap().is_none());
+        assert!(RetryInterceptor::from_service_config(
+            r#"{"methodConfig": [{"name": [{}], "retryPolicy": {"maxAttempts": 2}}]}"#).is_err());
+    }
+
+    #[test]
+    fn throttle() {
+        let throttle = RetryThrottle::new(RetryThrottlingConf { max_tokens: 4, token_ratio: 0.5 });
+        assert!(throttle.failure());
+        // 2 tokens left, not more than half
+        assert!(!throttle.failure());
+        throttle.success();
+        throttle.success();
+        throttle.success();
+        assert!(throttle.failure());
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy::default();
+        for attempts in 1..10 {
+            assert!(policy.backoff(attempts) <= policy.max_backoff);
+        }
+        assert_eq!(Some(Duration::from_millis(1500)), parse_duration("1.5s"));
+        assert_eq!(None, parse_duration("1.5"));
+    }
+}
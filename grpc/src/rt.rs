@@ -12,3 +12,15 @@ pub use method::GrpcStreamingFlavor;
 pub use method::MethodDescriptor;
 
 pub use server::ServerServiceDefinition;
+
+use error::Error;
+use error::GrpcMessageError;
+use grpc::GrpcStatus;
+
+/// Error returned by default implementations of generated service methods.
+pub fn unimplemented_error(method: &str) -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::Unimplemented as i32,
+        grpc_message: format!("Method {} is not implemented", method),
+    })
+}
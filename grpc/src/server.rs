@@ -1,4 +1,6 @@
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
 use bytes::Bytes;
 
@@ -13,7 +15,9 @@ use result::Result;
 use tls_api;
 use tls_api_stub;
 
+use futures::Async;
 use futures::Future;
+use futures::Poll;
 use futures::stream;
 use futures::stream::Stream;
 
@@ -23,10 +27,39 @@ use grpc_frame::*;
 use req::*;
 use resp::*;
 use metadata::Metadata;
+use metadata::DuplicateKeyPolicy;
 use server_method::*;
+use method::GrpcStreaming;
+use method::MethodDescriptor;
+use marshall::MarshallerBytes;
+use interceptor::ServerInterceptor;
+use debug::DebugService;
+use debug::DEBUG_STATE_PATH;
+use compression;
+use compression::Codec;
+use compression::CodecRegistry;
+use compression::MessageDecoder;
+use compression::MessageEncoder;
+use compression::MessageCompression;
+use compression::HEADER_GRPC_ENCODING;
+use compression::HEADER_GRPC_ACCEPT_ENCODING;
+use interceptor::ServerNext;
 use httpbis::DataOrTrailers;
 use httpbis::HttpStreamAfterHeaders;
 use httpbis::AnySocketAddr;
+use cancel::Cancellation;
+use experiments::Experiments;
+use buffer_pool::BufferPool;
+use futures_grpc::GrpcStream;
+use inspect::MessageInspector;
+use inspect::MethodInfo;
+use inspect::inspect_received;
+use inspect::inspect_sent;
+use cancel::cancel_on_drop;
+use deadline::HEADER_GRPC_TIMEOUT;
+use deadline::decode_timeout;
+use deadline::with_deadline;
+use deadline::MessageTimeout;
 
 
 pub struct ServerServiceDefinition {
@@ -69,6 +102,31 @@ impl ServerServiceDefinition {
 
 #[derive(Default, Debug, Clone)]
 pub struct ServerConf {
+    /// Compression of responses, e. g. `"gzip"`.
+    /// Responses are sent uncompressed to clients which don't accept it.
+    pub compression: Option<String>,
+    /// Response messages smaller than this are sent uncompressed
+    /// even if `compression` is set, default is 0.
+    pub compression_min_message_size: Option<usize>,
+    /// Decide per response message whether it is compressed,
+    /// overrides `compression_min_message_size`.
+    pub message_compression: Option<MessageCompression>,
+    /// Compression codecs in addition to built-in ones.
+    pub codecs: Vec<Arc<Codec>>,
+    /// Max size of received message after decompression, unlimited by default.
+    /// Calls sending larger messages fail with `RESOURCE_EXHAUSTED`.
+    pub max_receive_message_size: Option<usize>,
+    /// Calls fail with `DEADLINE_EXCEEDED` if no message is received
+    /// or sent for this long, unlimited by default.
+    pub message_timeout: Option<Duration>,
+    /// Experiments to enable, or disable if prefixed with `-`,
+    /// in addition to ones listed in `GRPC_EXPERIMENTS`.
+    pub experiments: Vec<String>,
+    /// Pool of buffers sent and received frames are written into,
+    /// frames are allocated individually if unset.
+    pub buffer_pool: Option<Arc<BufferPool>>,
+    /// Representation of repeated keys of request metadata.
+    pub metadata_duplicate_keys: DuplicateKeyPolicy,
 }
 
 impl ServerConf {
@@ -78,8 +136,18 @@ impl ServerConf {
 }
 
 pub struct ServerBuilder<A : tls_api::TlsAcceptor = tls_api_stub::TlsAcceptor> {
+    /// HTTP/2 server address and configuration.
+    ///
+    /// Set `http.conf.reuse_port` to bind listen socket with `SO_REUSEPORT`,
+    /// so several servers, in one or several processes, can accept
+    /// connections on the same port. It has no effect on platforms
+    /// without `SO_REUSEPORT`.
     pub http: httpbis::ServerBuilder<A>,
     pub conf: ServerConf,
+    services: Vec<ServerServiceDefinition>,
+    interceptors: Vec<Arc<ServerInterceptor>>,
+    inspectors: Vec<Arc<MessageInspector>>,
+    listeners: Vec<Box<Listener>>,
 }
 
 impl ServerBuilder<tls_api_stub::TlsAcceptor> {
@@ -93,43 +161,200 @@ impl<A : tls_api::TlsAcceptor> ServerBuilder<A> {
         ServerBuilder {
             http: httpbis::ServerBuilder::new(),
             conf: ServerConf::new(),
+            services: Vec::new(),
+            interceptors: Vec::new(),
+            inspectors: Vec::new(),
+            listeners: Vec::new(),
         }
     }
 
+    /// Add a service, usually created by generated `FooServer::new_service_def`.
+    ///
+    /// Any number of services can be added. Methods of services with
+    /// the same prefix are merged.
     pub fn add_service(&mut self, def: ServerServiceDefinition) {
-        self.http.service.set_service(&def.prefix.clone(), Arc::new(GrpcHttpService {
-            service_definition: Arc::new(def),
-        }));
+        match self.services.iter().position(|s| s.prefix == def.prefix) {
+            Some(i) => self.services[i].methods.extend(def.methods),
+            None => self.services.push(def),
+        }
+    }
+
+    /// Add a single method handler.
+    ///
+    /// Method name must be a full path like `/package.Service/Method`.
+    pub fn add_method(&mut self, method: ServerMethod) {
+        let prefix = match method.name.rfind('/') {
+            Some(pos) => method.name[..pos].to_owned(),
+            None => String::new(),
+        };
+        self.add_service(ServerServiceDefinition::new(&prefix, vec![method]));
+    }
+
+    /// Add a unary method handler operating on serialized messages.
+    ///
+    /// Useful for methods without generated code, e. g. proxies.
+    pub fn add_unary_handler<F>(&mut self, path: &str, f: F)
+        where F : Fn(RequestOptions, Bytes) -> SingleResponse<Bytes> + Send + Sync + 'static
+    {
+        let descriptor = Arc::new(MethodDescriptor {
+            name: path.to_owned(),
+            streaming: GrpcStreaming::Unary,
+            options: Default::default(),
+            req_marshaller: Box::new(MarshallerBytes),
+            resp_marshaller: Box::new(MarshallerBytes),
+        });
+        self.add_method(ServerMethod::new(descriptor, MethodHandlerUnary::new(f)));
+    }
+
+    /// Serve state of `debug` at `DEBUG_STATE_PATH` and count calls of this server in it.
+    pub fn add_debug_service(&mut self, debug: Arc<DebugService>) {
+        self.add_interceptor(debug.clone());
+        self.add_unary_handler(DEBUG_STATE_PATH, move |_o, _req| {
+            SingleResponse::completed(Bytes::from(debug.state()))
+        });
+    }
+
+    /// Add an interceptor invoked for each call to any service of this server.
+    ///
+    /// Interceptors are invoked in order they are added.
+    pub fn add_interceptor(&mut self, interceptor: Arc<ServerInterceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Add an inspector of serialized messages of calls to this server.
+    pub fn add_message_inspector(&mut self, inspector: Arc<MessageInspector>) {
+        self.inspectors.push(inspector);
+    }
+
+    /// Also listen on `port` without TLS, e. g. for sidecars,
+    /// in addition to the address configured in `http`.
+    ///
+    /// All listeners serve the same services with the same configuration.
+    pub fn add_plain_port(&mut self, port: u16) {
+        let mut http = httpbis::ServerBuilder::<tls_api_stub::TlsAcceptor>::new();
+        http.set_port(port);
+        self.listeners.push(Box::new(http));
+    }
+
+    /// Also listen on `port` with TLS, in addition to the address configured in `http`.
+    pub fn add_tls_port<B : tls_api::TlsAcceptor + 'static>(&mut self, port: u16, acceptor: B) {
+        let mut http = httpbis::ServerBuilder::<B>::new();
+        http.set_port(port);
+        http.set_tls(acceptor);
+        self.listeners.push(Box::new(http));
     }
 
     pub fn build(mut self) -> Result<Server> {
         self.http.conf.thread_name =
             Some(self.http.conf.thread_name.unwrap_or_else(|| "grpc-server-loop".to_owned()));
 
+        let codecs = Arc::new(CodecRegistry::new(&self.conf.codecs));
+        let compression = codecs.find_configured(&self.conf.compression)?;
+
+        let experiments = Experiments::resolve(&self.conf.experiments);
+        if !experiments.enabled().is_empty() {
+            info!("enabled experiments: {}", experiments.enabled().join(", "));
+        }
+
+        let interceptors = Arc::new(self.interceptors);
+        let inspectors = Arc::new(self.inspectors);
+        let mut services = Vec::new();
+        for def in self.services {
+            services.push((def.prefix.clone(), Arc::new(GrpcHttpService {
+                service_definition: Arc::new(def),
+                interceptors: interceptors.clone(),
+                inspectors: inspectors.clone(),
+                codecs: codecs.clone(),
+                compression: compression.clone(),
+                compression_min_message_size: self.conf.compression_min_message_size.unwrap_or(0),
+                message_compression: self.conf.message_compression.clone(),
+                max_receive_message_size: self.conf.max_receive_message_size,
+                message_timeout: self.conf.message_timeout,
+                buffer_pool: self.conf.buffer_pool.clone(),
+                metadata_duplicate_keys: self.conf.metadata_duplicate_keys,
+            })));
+        }
+
+        let mut listeners = Vec::new();
+        for listener in self.listeners {
+            listeners.push(listener.build(self.http.conf.clone(), &services)?);
+        }
+
+        for (prefix, service) in services {
+            self.http.service.set_service(&prefix, service);
+        }
+
         Ok(Server {
             server: self.http.build()?,
+            listeners: listeners,
+            experiments: experiments,
         })
     }
 }
 
+/// Additional listen socket of a server.
+trait Listener {
+    fn build(self: Box<Self>, conf: httpbis::ServerConf, services: &[(String, Arc<GrpcHttpService>)])
+        -> Result<httpbis::Server>;
+}
+
+impl<A : tls_api::TlsAcceptor> Listener for httpbis::ServerBuilder<A> {
+    fn build(self: Box<Self>, conf: httpbis::ServerConf, services: &[(String, Arc<GrpcHttpService>)])
+        -> Result<httpbis::Server>
+    {
+        let mut http = *self;
+        http.conf = conf;
+        for &(ref prefix, ref service) in services {
+            http.service.set_service(prefix, service.clone());
+        }
+        Ok(http.build()?)
+    }
+}
+
 
 pub struct Server {
     server: httpbis::Server,
+    /// Servers of ports added with `add_plain_port` and `add_tls_port`
+    listeners: Vec<httpbis::Server>,
+    experiments: Experiments,
 }
 
 impl Server {
+    /// Experiments enabled for this server.
+    pub fn experiments(&self) -> &Experiments {
+        &self.experiments
+    }
+
     pub fn local_addr(&self) -> &AnySocketAddr {
         self.server.local_addr()
     }
 
+    /// Addresses of all listeners, `local_addr` first,
+    /// followed by added ports in order they were added.
+    pub fn local_addrs(&self) -> Vec<&AnySocketAddr> {
+        let mut addrs = vec![self.server.local_addr()];
+        addrs.extend(self.listeners.iter().map(|l| l.local_addr()));
+        addrs
+    }
+
     pub fn is_alive(&self) -> bool {
-        self.server.is_alive()
+        self.server.is_alive() && self.listeners.iter().all(|l| l.is_alive())
     }
 }
 
 /// Implementation of gRPC over http2 HttpService
 struct GrpcHttpService {
     service_definition: Arc<ServerServiceDefinition>,
+    interceptors: Arc<Vec<Arc<ServerInterceptor>>>,
+    inspectors: Arc<Vec<Arc<MessageInspector>>>,
+    codecs: Arc<CodecRegistry>,
+    compression: Option<Arc<Codec>>,
+    compression_min_message_size: usize,
+    message_compression: Option<MessageCompression>,
+    max_receive_message_size: Option<usize>,
+    message_timeout: Option<Duration>,
+    buffer_pool: Option<Arc<BufferPool>>,
+    metadata_duplicate_keys: DuplicateKeyPolicy,
 }
 
 
@@ -143,6 +368,59 @@ fn http_response_500(message: &str) -> httpbis::Response {
     httpbis::Response::headers_and_stream(headers, httpbis::HttpStreamAfterHeaders::empty())
 }
 
+/// Trailers with status and metadata.
+///
+/// Pseudo-headers are not allowed in trailers, and status
+/// from metadata would duplicate the call status, so these are dropped.
+fn trailers(grpc_status: i32, grpc_message: Option<String>, metadata: Metadata) -> Headers {
+    let mut trailers = Headers(vec![
+        Header::new(HEADER_GRPC_STATUS, format!("{}", grpc_status)),
+    ]);
+    if let Some(grpc_message) = grpc_message {
+        trailers.0.push(Header::new(HEADER_GRPC_MESSAGE, grpc_message));
+    }
+    trailers.0.extend(metadata.into_headers().0.into_iter().filter(|h| {
+        let name = h.name();
+        !name.starts_with(b":")
+            && name != HEADER_GRPC_STATUS.as_bytes()
+            && name != HEADER_GRPC_MESSAGE.as_bytes()
+    }));
+    trailers
+}
+
+/// Ends response with exactly one trailers part, which httpbis
+/// sends as HEADERS with END_STREAM.
+///
+/// `grpc-status: 0` is sent if handler stream ends without trailing metadata,
+/// and parts after trailers are dropped.
+struct TrailersLast<S> {
+    stream: S,
+    done: bool,
+}
+
+impl<S : Stream<Item=DataOrTrailers, Error=httpbis::Error>> Stream for TrailersLast<S> {
+    type Item = DataOrTrailers;
+    type Error = httpbis::Error;
+
+    fn poll(&mut self) -> Poll<Option<DataOrTrailers>, httpbis::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        match try_ready!(self.stream.poll()) {
+            Some(DataOrTrailers::Trailers(trailers)) => {
+                self.done = true;
+                Ok(Async::Ready(Some(DataOrTrailers::Trailers(trailers))))
+            }
+            Some(data) => Ok(Async::Ready(Some(data))),
+            None => {
+                self.done = true;
+                Ok(Async::Ready(Some(DataOrTrailers::Trailers(
+                    trailers(GrpcStatus::Ok as i32, None, Metadata::new())))))
+            }
+        }
+    }
+}
+
 impl httpbis::Service for GrpcHttpService {
     fn start_request(&self, headers: Headers, req: HttpStreamAfterHeaders) -> httpbis::Response {
 
@@ -151,28 +429,133 @@ impl httpbis::Service for GrpcHttpService {
             None => return http_response_500("no :path header"),
         };
 
-        let grpc_request = GrpcFrameFromHttpFramesStreamRequest::new(req);
+        let decoder = self.codecs.decoder(&headers);
+
+        let encoder = MessageEncoder {
+            codec: match self.compression {
+                Some(ref codec) if compression::accepts(&headers, &**codec) => Some(codec.clone()),
+                _ => None,
+            },
+            min_message_size: self.compression_min_message_size,
+            pool: self.buffer_pool.clone(),
+        };
+        let message_compression = self.message_compression.clone();
+        let accept_encoding = self.codecs.accept_encoding();
+        let peer_accept_encoding = compression::accept_encoding_of(&headers);
+        let codecs = self.codecs.clone();
+
+        // response has the same content type as request
+        let subtype = headers.get_opt("content-type").and_then(content_subtype);
+
+        let deadline = headers.get_opt(HEADER_GRPC_TIMEOUT)
+            .and_then(|timeout| decode_timeout(timeout.as_bytes()))
+            .map(|timeout| Instant::now() + timeout);
 
-        let metadata = match Metadata::from_headers(headers) {
+        let metadata = match Metadata::from_headers_with(headers, self.metadata_duplicate_keys) {
             Ok(metadata) => metadata,
             Err(_) => return http_response_500("decode metadata error"),
         };
 
-        let request_options = RequestOptions { metadata: metadata };
-        // TODO: catch unwind
-        let grpc_response = self.service_definition.handle_method(
-            &path, request_options, StreamingRequest::new(grpc_request));
+        let cancellation = Cancellation::new();
+
+        // unknown methods are not inspected
+        let inspected = match self.service_definition.find_method(&path) {
+            Some(method) if !self.inspectors.is_empty() => Some((self.inspectors.clone(), Arc::new(MethodInfo {
+                name: method.name.clone(),
+                streaming: method.streaming,
+                options: method.options.clone(),
+            }))),
+            _ => None,
+        };
+        let inspected_response = inspected.clone();
+
+        let message_timeout = self.message_timeout.map(MessageTimeout::new);
+
+        let grpc_response = match decoder {
+            Ok(codec) => {
+                let decoder = MessageDecoder {
+                    codec: codec,
+                    max_message_size: self.max_receive_message_size,
+                    pool: self.buffer_pool.clone(),
+                };
+                let grpc_request = GrpcFrameFromHttpFramesStreamRequest::new(req, decoder);
+                let grpc_request: GrpcStream<Bytes> = match inspected {
+                    Some((inspectors, info)) => Box::new(grpc_request.inspect(move |m| {
+                        inspect_received(&inspectors, &info, m)
+                    })),
+                    None => Box::new(grpc_request),
+                };
+                let grpc_request = match message_timeout {
+                    Some(ref message_timeout) => message_timeout.request(grpc_request),
+                    None => grpc_request,
+                };
+
+                let request_options = RequestOptions {
+                    metadata: metadata,
+                    content_subtype: subtype.clone(),
+                    deadline: deadline,
+                    cancellation: Some(cancellation.clone()),
+                    ..Default::default()
+                };
+                let next = ServerNext {
+                    interceptors: self.interceptors.clone(),
+                    index: 0,
+                    method: path,
+                    service_definition: self.service_definition.clone(),
+                };
+
+                // TODO: catch unwind
+                next.call(request_options, StreamingRequest::new(grpc_request))
+            }
+            Err(e) => StreamingResponse::err(e),
+        };
+        // response is dropped when client resets the stream or connection is closed
+        let grpc_response = cancel_on_drop(grpc_response, cancellation);
+        let grpc_response = match deadline {
+            Some(deadline) => with_deadline(grpc_response, deadline),
+            None => grpc_response,
+        };
+        let grpc_response = match message_timeout {
+            Some(message_timeout) => message_timeout.response(grpc_response),
+            None => grpc_response,
+        };
+
+        httpbis::Response::new(grpc_response.0.map_err(httpbis::Error::from).map(move |(mut metadata, grpc_frames)| {
+            // compression chosen by handler with `Response::with_compression`
+            let mut encoder = encoder;
+            if let Some(name) = metadata.remove(HEADER_GRPC_ENCODING) {
+                let name = String::from_utf8_lossy(&name).into_owned();
+                match codecs.find_configured(&Some(name.clone())) {
+                    Ok(None) => encoder.codec = None,
+                    Ok(Some(ref codec))
+                        if compression::accepted_by(&peer_accept_encoding, &**codec) =>
+                    {
+                        encoder.codec = Some(codec.clone());
+                    }
+                    _ => debug!("not compressing response with {}", name),
+                }
+            }
 
-        httpbis::Response::new(grpc_response.0.map_err(httpbis::Error::from).map(|(metadata, grpc_frames)| {
             let mut init_headers = Headers(vec![
                 Header::new(":status", "200"),
-                Header::new("content-type", "application/grpc"),
+                Header::new("content-type", content_type(&subtype)),
+                Header::new(HEADER_GRPC_ACCEPT_ENCODING, accept_encoding),
             ]);
+            if let Some(ref codec) = encoder.codec {
+                init_headers.0.push(Header::new(HEADER_GRPC_ENCODING, codec.name().to_owned()));
+            }
 
             init_headers.extend(metadata.into_headers());
 
             let s2 = grpc_frames
-                .map_items(|frame| DataOrTrailers::intermediate_data(Bytes::from(write_grpc_frame_to_vec(&frame))))
+                .and_then_items(move |frame| {
+                    if let Some((ref inspectors, ref info)) = inspected_response {
+                        inspect_sent(inspectors, info, &frame);
+                    }
+                    let compress = message_compression.as_ref().map(|c| c.compress(&frame));
+                    let frame = encoder.encode(&frame, compress)?;
+                    Ok(DataOrTrailers::intermediate_data(frame))
+                })
                 .then_items(|result| {
                     match result {
                         Ok(part) => {
@@ -189,11 +572,7 @@ impl httpbis::Service for GrpcHttpService {
                                 ),
                             };
                             Ok(DataOrTrailers::Trailers(
-                                Headers(vec![
-                                    Header::new(HEADER_GRPC_STATUS, format!("{}", grpc_status)),
-                                    Header::new(HEADER_GRPC_MESSAGE, grpc_message),
-                                ])
-                            ))
+                                trailers(grpc_status, Some(grpc_message), Metadata::new())))
                         }
                     }
                 })
@@ -201,25 +580,14 @@ impl httpbis::Service for GrpcHttpService {
                     match item {
                         ItemOrMetadata::Item(part) => part,
                         ItemOrMetadata::TrailingMetadata(trailing_metadata) => {
-                            DataOrTrailers::Trailers( {
-                                let mut trailing = Headers(vec![
-                                    Header::new(HEADER_GRPC_STATUS, "0")
-                                ]);
-                                trailing.extend(trailing_metadata.into_headers());
-                                trailing
-                            })
+                            DataOrTrailers::Trailers(
+                                trailers(GrpcStatus::Ok as i32, None, trailing_metadata))
                         },
                     }
                 })
                 .map_err(httpbis::Error::from);
 
-            // If stream contains trailing metadata, it is converted to grpc status 0,
-            // here we add trailing headers after trailing headers are ignored by HTTP server.
-            let s3 = stream::once(Ok(DataOrTrailers::Trailers(Headers(vec![
-                Header::new(HEADER_GRPC_STATUS, "0"),
-            ]))));
-
-            let http_parts = HttpStreamAfterHeaders::new(s2.chain(s3));
+            let http_parts = HttpStreamAfterHeaders::new(TrailersLast { stream: s2, done: false });
 
             (init_headers, http_parts)
         }))
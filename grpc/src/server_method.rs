@@ -216,6 +216,8 @@ impl<Req, Resp> MethodHandlerDispatch for MethodHandlerDispatchImpl<Req, Resp>
 
 pub struct ServerMethod {
     pub(crate) name: String,
+    pub(crate) streaming: GrpcStreaming,
+    pub(crate) options: MethodOptions,
     pub(crate) dispatch: Box<MethodHandlerDispatch + Sync + Send>,
 }
 
@@ -228,6 +230,8 @@ impl ServerMethod {
     {
         ServerMethod {
             name: method.name.clone(),
+            streaming: method.streaming,
+            options: method.options.clone(),
             dispatch: Box::new(MethodHandlerDispatchImpl {
                 desc: method,
                 method_handler: Box::new(handler),
@@ -0,0 +1,339 @@
+//! Tracing span per server call.
+//!
+//! Span is entered whenever the handler or its response is polled,
+//! so events and spans created by the handler are nested in it.
+
+use std::borrow::Cow;
+
+use bytes::Bytes;
+
+use futures::Async;
+use futures::Poll;
+use futures::future::Future;
+use futures::stream::Stream;
+
+use tracing;
+use tracing::Span;
+
+use error::Error;
+use grpc::GrpcStatus;
+use interceptor::*;
+use metadata::Metadata;
+use req::*;
+use request_id::REQUEST_ID_METADATA_KEY;
+use resp::*;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use stream_item::ItemOrMetadata;
+
+
+/// Future or stream polled in span.
+struct InSpan<T> {
+    inner: T,
+    span: Span,
+}
+
+impl<F : Future> Future for InSpan<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let _enter = self.span.enter();
+        self.inner.poll()
+    }
+}
+
+impl<S : Stream> Stream for InSpan<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let _enter = self.span.enter();
+        self.inner.poll()
+    }
+}
+
+/// Response stream recording messages and status of the call in span.
+struct TracedStream<S> {
+    stream: S,
+    span: Span,
+}
+
+impl<S> Stream for TracedStream<S>
+    where S : Stream<Item=ItemOrMetadata<Vec<u8>>, Error=Error>
+{
+    type Item = ItemOrMetadata<Vec<u8>>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ItemOrMetadata<Vec<u8>>>, Error> {
+        let _enter = self.span.enter();
+        match self.stream.poll() {
+            Ok(Async::Ready(Some(ItemOrMetadata::Item(message)))) => {
+                tracing::trace!(bytes = message.len(), "sent message");
+                Ok(Async::Ready(Some(ItemOrMetadata::Item(message))))
+            }
+            Ok(Async::Ready(None)) => {
+                self.span.record("status", GrpcStatus::Ok as i32);
+                tracing::debug!("call finished");
+                Ok(Async::Ready(None))
+            }
+            Err(e) => {
+                self.span.record("status", e.grpc_status());
+                tracing::debug!(error = ?e, "call failed");
+                Err(e)
+            }
+            r => r,
+        }
+    }
+}
+
+/// Server interceptor creating a span with method, peer and request ID
+/// for each call, available to handlers as `RequestOptions::span`.
+///
+/// Span is at `INFO` level, named `grpc_call`, with fields `method`,
+/// `peer`, `request_id` and `status` recorded when call finishes.
+/// Request ID is set by `RequestIdInterceptor` if it is added before this one,
+/// or taken from metadata.
+/// Handlers running work on other threads can enter it there,
+/// or create child spans with their own attributes.
+///
+/// Interceptor should be added first (but after `RequestIdInterceptor`),
+/// so that other interceptors run in the span too.
+pub struct TracingInterceptor {
+    request_id_key: String,
+}
+
+impl TracingInterceptor {
+    /// Interceptor taking request ID from `x-request-id` metadata.
+    pub fn new() -> TracingInterceptor {
+        TracingInterceptor::with_request_id_key(REQUEST_ID_METADATA_KEY)
+    }
+
+    pub fn with_request_id_key(key: &str) -> TracingInterceptor {
+        TracingInterceptor {
+            request_id_key: key.to_owned(),
+        }
+    }
+}
+
+impl ServerInterceptor for TracingInterceptor {
+    fn intercept(
+        &self,
+        method: &str,
+        mut o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ServerNext)
+        -> StreamingResponse<Vec<u8>>
+    {
+        let peer = o.peer_identity.as_ref().map_or("-", |p| p.subject.as_str());
+        let request_id = match o.request_id {
+            Some(ref request_id) => Cow::from(request_id.as_str()),
+            None => o.metadata.get(&self.request_id_key).map_or("-".into(), String::from_utf8_lossy),
+        };
+        let span = tracing::info_span!(
+            "grpc_call",
+            method = method,
+            peer = peer,
+            request_id = &*request_id,
+            status = tracing::field::Empty);
+        o.span = Some(span.clone());
+
+        let req = StreamingRequest::new(InSpan {
+            inner: req.0.inspect(|message| tracing::trace!(bytes = message.len(), "received message")),
+            span: span.clone(),
+        });
+
+        let resp = span.in_scope(|| next.call(o, req));
+
+        let stream_span = span.clone();
+        let resp = resp.0.then(move |r| -> Result<(Metadata, GrpcStreamWithTrailingMetadata<Vec<u8>>), Error> {
+            match r {
+                Ok((metadata, stream)) => {
+                    let stream = TracedStream { stream: stream.0, span: stream_span };
+                    Ok((metadata, GrpcStreamWithTrailingMetadata::new(stream)))
+                }
+                Err(e) => {
+                    stream_span.record("status", e.grpc_status());
+                    tracing::debug!(error = ?e, "call failed");
+                    Err(e)
+                }
+            }
+        });
+        StreamingResponse::new(InSpan { inner: resp, span })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fmt;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use futures::stream;
+
+    use tracing::Event;
+    use tracing::Subscriber;
+    use tracing::field::Field;
+    use tracing::field::Visit;
+    use tracing::span;
+
+    use auth::PeerIdentity;
+    use error::GrpcMessageError;
+    use for_test::MarshallerString;
+    use metadata::MetadataKey;
+    use method::GrpcStreaming;
+    use method::MethodDescriptor;
+    use server::ServerServiceDefinition;
+    use server_method::MethodHandlerUnary;
+    use server_method::ServerMethod;
+
+    /// Field values as strings
+    struct Fields<'a>(&'a mut Vec<(String, String)>);
+
+    impl<'a> Visit for Fields<'a> {
+        fn record_debug(&mut self, field: &Field, value: &fmt::Debug) {
+            self.0.push((field.name().to_owned(), format!("{:?}", value)));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push((field.name().to_owned(), value.to_owned()));
+        }
+    }
+
+    #[derive(Default)]
+    struct Captured {
+        /// Names of created spans, index is span ID - 1
+        spans: Vec<String>,
+        /// Fields of `grpc_call` span
+        call_fields: Vec<(String, String)>,
+        /// Event message and name of span it was emitted in
+        events: Vec<(String, Option<String>)>,
+        entered: Vec<u64>,
+    }
+
+    /// Subscriber recording spans and events for checks
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Captured>>);
+
+    impl Capture {
+        fn field(&self, name: &str) -> Option<String> {
+            let captured = self.0.lock().unwrap();
+            captured.call_fields.iter().rev().find(|f| f.0 == name).map(|f| f.1.clone())
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _metadata: &tracing::Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &span::Attributes) -> span::Id {
+            let mut captured = self.0.lock().unwrap();
+            let name = attrs.metadata().name();
+            if name == "grpc_call" {
+                attrs.record(&mut Fields(&mut captured.call_fields));
+            }
+            captured.spans.push(name.to_owned());
+            span::Id::from_u64(captured.spans.len() as u64)
+        }
+
+        fn record(&self, span: &span::Id, values: &span::Record) {
+            let mut captured = self.0.lock().unwrap();
+            if captured.spans[span.into_u64() as usize - 1] == "grpc_call" {
+                values.record(&mut Fields(&mut captured.call_fields));
+            }
+        }
+
+        fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut captured = self.0.lock().unwrap();
+            let mut fields = Vec::new();
+            event.record(&mut Fields(&mut fields));
+            let message = fields.into_iter().find(|f| f.0 == "message").map_or(String::new(), |f| f.1);
+            let span = captured.entered.last().map(|id| captured.spans[*id as usize - 1].clone());
+            captured.events.push((message, span));
+        }
+
+        fn enter(&self, span: &span::Id) {
+            self.0.lock().unwrap().entered.push(span.into_u64());
+        }
+
+        fn exit(&self, _span: &span::Id) {
+            self.0.lock().unwrap().entered.pop();
+        }
+    }
+
+    /// Call `/test/Echo` handler failing on `"fail"` through interceptor
+    fn call(message: &str) -> Capture {
+        let method = Arc::new(MethodDescriptor {
+            name: "/test/Echo".to_owned(),
+            streaming: GrpcStreaming::Unary,
+            options: Default::default(),
+            req_marshaller: Box::new(MarshallerString),
+            resp_marshaller: Box::new(MarshallerString),
+        });
+        let service_definition = ServerServiceDefinition::new("/test", vec![
+            ServerMethod::new(method, MethodHandlerUnary::new(|_o, s: String| {
+                tracing::info!("handling request");
+                if s == "fail" {
+                    SingleResponse::err(Error::GrpcMessage(GrpcMessageError {
+                        grpc_status: GrpcStatus::NotFound as i32,
+                        grpc_message: "not found".to_owned(),
+                    }))
+                } else {
+                    SingleResponse::completed(s)
+                }
+            })),
+        ]);
+        let next = ServerNext {
+            interceptors: Arc::new(Vec::new()),
+            index: 0,
+            method: "/test/Echo".to_owned(),
+            service_definition: Arc::new(service_definition),
+        };
+
+        let mut o = RequestOptions::new();
+        o.metadata.add(MetadataKey::from(REQUEST_ID_METADATA_KEY), Bytes::from_static(b"req-1"));
+        o.peer_identity = Some(PeerIdentity { subject: "alice".to_owned(), ..Default::default() });
+        let req = StreamingRequest::new(stream::once(Ok(Bytes::from(message.as_bytes()))));
+
+        let capture = Capture::default();
+        let result = tracing::subscriber::with_default(capture.clone(), || {
+            TracingInterceptor::new().intercept("/test/Echo", o, req, next).collect().wait()
+        });
+        assert_eq!(message == "fail", result.is_err(), "{:?}", result.map(|r| r.1));
+        capture
+    }
+
+    #[test]
+    fn ok() {
+        let capture = call("abc");
+        assert_eq!(Some("/test/Echo".to_owned()), capture.field("method"));
+        assert_eq!(Some("alice".to_owned()), capture.field("peer"));
+        assert_eq!(Some("req-1".to_owned()), capture.field("request_id"));
+        assert_eq!(Some((GrpcStatus::Ok as i32).to_string()), capture.field("status"));
+
+        let captured = capture.0.lock().unwrap();
+        assert!(captured.events.contains(
+            &("handling request".to_owned(), Some("grpc_call".to_owned()))),
+            "{:?}", captured.events);
+        assert!(captured.events.iter().all(|e| e.1 == Some("grpc_call".to_owned())), "{:?}", captured.events);
+    }
+
+    #[test]
+    fn error() {
+        let capture = call("fail");
+        assert_eq!(Some((GrpcStatus::NotFound as i32).to_string()), capture.field("status"));
+
+        let captured = capture.0.lock().unwrap();
+        assert!(captured.events.contains(
+            &("handling request".to_owned(), Some("grpc_call".to_owned()))),
+            "{:?}", captured.events);
+        assert!(captured.events.contains(
+            &("call failed".to_owned(), Some("grpc_call".to_owned()))),
+            "{:?}", captured.events);
+    }
+}
@@ -0,0 +1,118 @@
+//! Parsing of client target strings like `https://example.com` or `localhost:50051`.
+
+use error::Error;
+use result;
+
+
+/// Host, port and transport security a client connects to.
+///
+/// Fields are public so inferred values can be overridden after parsing.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientTarget {
+    pub host: String,
+    pub port: u16,
+    /// Use TLS (`https`) or plaintext HTTP/2 (`h2c`)
+    pub tls: bool,
+}
+
+const HTTP_DEFAULT_PORT: u16 = 80;
+const HTTPS_DEFAULT_PORT: u16 = 443;
+
+impl ClientTarget {
+    /// Parse target string.
+    ///
+    /// * `https://host[:port]` selects TLS, port defaults to 443
+    /// * `http://host[:port]` selects plaintext, port defaults to 80
+    /// * `host:port` selects TLS if port is 443 and plaintext otherwise
+    pub fn parse(target: &str) -> result::Result<ClientTarget> {
+        let (tls, rem) = if target.starts_with("https://") {
+            (Some(true), &target["https://".len()..])
+        } else if target.starts_with("http://") {
+            (Some(false), &target["http://".len()..])
+        } else {
+            (None, target)
+        };
+
+        // path is meaningless for gRPC target, but tolerate trailing slash
+        let rem = if rem.ends_with("/") { &rem[..rem.len() - 1] } else { rem };
+
+        let (host, port) = split_host_port(rem)?;
+
+        if host.is_empty() {
+            return Err(Error::Other("empty host in target"));
+        }
+
+        let port = match (port, tls) {
+            (Some(port), _) => port,
+            (None, Some(true)) => HTTPS_DEFAULT_PORT,
+            (None, Some(false)) => HTTP_DEFAULT_PORT,
+            (None, None) => return Err(Error::Other("target must have either scheme or port")),
+        };
+
+        Ok(ClientTarget {
+            host: host.to_owned(),
+            port: port,
+            tls: tls.unwrap_or(port == HTTPS_DEFAULT_PORT),
+        })
+    }
+}
+
+fn split_host_port(s: &str) -> result::Result<(&str, Option<u16>)> {
+    let (host, port) = if s.starts_with("[") {
+        // IPv6 literal
+        let close = match s.find(']') {
+            Some(close) => close,
+            None => return Err(Error::Other("unclosed '[' in target")),
+        };
+        let host = &s[1..close];
+        let rem = &s[close + 1..];
+        if rem.is_empty() {
+            (host, None)
+        } else if rem.starts_with(":") {
+            (host, Some(&rem[1..]))
+        } else {
+            return Err(Error::Other("unexpected characters after ']' in target"));
+        }
+    } else {
+        match s.rfind(':') {
+            Some(colon) => (&s[..colon], Some(&s[colon + 1..])),
+            None => (s, None),
+        }
+    };
+
+    let port = match port {
+        Some(port) => Some(port.parse().map_err(|_| Error::Other("failed to parse port"))?),
+        None => None,
+    };
+
+    Ok((host, port))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn t(host: &str, port: u16, tls: bool) -> ClientTarget {
+        ClientTarget { host: host.to_owned(), port, tls }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(t("example.com", 443, true), ClientTarget::parse("https://example.com").unwrap());
+        assert_eq!(t("example.com", 8443, true), ClientTarget::parse("https://example.com:8443/").unwrap());
+        assert_eq!(t("example.com", 80, false), ClientTarget::parse("http://example.com").unwrap());
+        assert_eq!(t("localhost", 50051, false), ClientTarget::parse("localhost:50051").unwrap());
+        assert_eq!(t("localhost", 443, true), ClientTarget::parse("localhost:443").unwrap());
+        assert_eq!(t("::1", 50051, false), ClientTarget::parse("[::1]:50051").unwrap());
+        assert_eq!(t("::1", 443, true), ClientTarget::parse("https://[::1]").unwrap());
+    }
+
+    #[test]
+    fn parse_error() {
+        assert!(ClientTarget::parse("localhost").is_err());
+        assert!(ClientTarget::parse("localhost:port").is_err());
+        assert!(ClientTarget::parse("https://:443").is_err());
+        assert!(ClientTarget::parse("[::1:50051").is_err());
+    }
+}
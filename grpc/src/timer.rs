@@ -0,0 +1,122 @@
+//! Timer for code which has no access to event loop.
+//!
+//! All timeouts are served by single lazily started thread.
+//! It is shared by all clients and servers of the process,
+//! so it is never joined: it lives until the process exits,
+//! sleeping on a condition variable when there are no timers.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::sync::ONCE_INIT;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::Future;
+use futures::sync::oneshot;
+
+use error::Error;
+use futures_grpc::GrpcFuture;
+
+
+struct Entry {
+    at: Instant,
+    seq: u64,
+    tx: oneshot::Sender<()>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.at == other.at && self.seq == other.seq
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    // reversed to make `BinaryHeap` a min-heap
+    fn cmp(&self, other: &Entry) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    entries: BinaryHeap<Entry>,
+    next_seq: u64,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    condvar: Condvar,
+}
+
+fn run(shared: &Shared) {
+    let mut queue = shared.queue.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        let wait = match queue.entries.peek() {
+            Some(entry) if entry.at <= now => None,
+            Some(entry) => Some(entry.at - now),
+            None => Some(Duration::from_secs(3600)),
+        };
+        match wait {
+            None => {
+                let entry = queue.entries.pop().unwrap();
+                // receiver may be already dropped
+                drop(entry.tx.send(()));
+            }
+            Some(wait) => {
+                queue = shared.condvar.wait_timeout(queue, wait).unwrap().0;
+            }
+        }
+    }
+}
+
+fn shared() -> &'static Shared {
+    static INIT: Once = ONCE_INIT;
+    static mut SHARED: *const Shared = 0 as *const Shared;
+
+    unsafe {
+        INIT.call_once(|| {
+            let shared: &'static Shared = Box::leak(Box::new(Shared {
+                queue: Mutex::new(Queue::default()),
+                condvar: Condvar::new(),
+            }));
+            thread::Builder::new()
+                .name("grpc-timer".to_owned())
+                .spawn(move || run(shared))
+                .expect("spawn timer thread");
+            SHARED = shared;
+        });
+        &*SHARED
+    }
+}
+
+/// Future which resolves at given instant.
+pub fn sleep_until(at: Instant) -> GrpcFuture<()> {
+    let (tx, rx) = oneshot::channel();
+    let shared = shared();
+    {
+        let mut queue = shared.queue.lock().unwrap();
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.entries.push(Entry { at, seq, tx });
+    }
+    shared.condvar.notify_one();
+    Box::new(rx.map_err(Error::from))
+}
+
+/// Future which resolves after given duration.
+pub fn sleep(duration: Duration) -> GrpcFuture<()> {
+    sleep_until(Instant::now() + duration)
+}
@@ -0,0 +1,27 @@
+//! TLS implementations enabled by cargo features.
+//!
+//! Clients and servers are generic over `tls_api` traits, so any
+//! implementation can be used. Features `rustls` (pure Rust) and
+//! `native-tls` (platform library and trust store) export implementations
+//! from this module, and `DefaultTlsConnector` and `DefaultTlsAcceptor`
+//! select one of them, preferring `rustls` if both are enabled:
+//!
+//! ```ignore
+//! let client = grpc::Client::new_url::<grpc::tls::DefaultTlsConnector>(
+//!     "https://example.com", Default::default())?;
+//! ```
+
+#[cfg(feature = "rustls")]
+pub use tls_api_rustls as rustls;
+#[cfg(feature = "native-tls")]
+pub use tls_api_native_tls as native_tls;
+
+#[cfg(feature = "rustls")]
+pub type DefaultTlsConnector = ::tls_api_rustls::TlsConnector;
+#[cfg(feature = "rustls")]
+pub type DefaultTlsAcceptor = ::tls_api_rustls::TlsAcceptor;
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub type DefaultTlsConnector = ::tls_api_native_tls::TlsConnector;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub type DefaultTlsAcceptor = ::tls_api_native_tls::TlsAcceptor;
@@ -0,0 +1,614 @@
+//! Helpers for transferring files over streaming calls.
+//!
+//! Data is read in chunks, each sent as a message built by caller.
+//! CRC-32 of all data is sent in the last message of an upload
+//! and in `checksum-crc32` trailing metadata of a download;
+//! receiving side verifies it when present.
+//!
+//! Resumable uploads are bidi calls: client sends `upload-id` (except
+//! when starting a new upload) and `upload-offset` request metadata,
+//! server responds with `upload-id` initial metadata and messages
+//! acknowledging offsets of persisted data. When a call fails,
+//! client calls again with data from the last acknowledged offset.
+
+use std::cmp;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::str;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use futures::Async;
+use futures::Poll;
+use futures::future;
+use futures::future::Future;
+use futures::future::IntoFuture;
+use futures::stream::Stream;
+
+use tokio_io::AsyncRead;
+use tokio_io::AsyncWrite;
+use tokio_io::io as async_io;
+
+use error::Error;
+use error::GrpcMessageError;
+use futures_grpc::GrpcFuture;
+use grpc::GrpcStatus;
+use metadata::Metadata;
+use metadata::MetadataKey;
+use req::RequestOptions;
+use req::StreamingRequest;
+use resp::StreamingResponse;
+use result;
+use retry::RetryPolicy;
+use stream_item::GrpcStreamWithTrailingMetadata;
+use stream_item::ItemOrMetadata;
+use timer;
+
+
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Trailing metadata key of download checksum, CRC-32 as 8 hex digits.
+pub const CHECKSUM_METADATA_KEY: &'static str = "checksum-crc32";
+
+/// Request metadata key of resumable upload ID, absent for a new upload,
+/// and initial metadata key of upload ID in response.
+pub const UPLOAD_ID_METADATA_KEY: &'static str = "upload-id";
+
+/// Request metadata key of resumable upload offset, where data of the call starts.
+pub const UPLOAD_OFFSET_METADATA_KEY: &'static str = "upload-offset";
+
+/// Piece of transferred data.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Chunk {
+    pub data: Bytes,
+    /// CRC-32 of all data up to and including this chunk.
+    /// Set by `upload_request` in the last chunk, which has no data.
+    pub crc32: Option<u32>,
+}
+
+/// Blocking `io::Read` or `io::Write` used as `AsyncRead` or `AsyncWrite`.
+///
+/// I/O blocks the thread polling the transfer, so files should not
+/// be read or written this way on an event loop thread.
+pub struct BlockingIo<T>(pub T);
+
+impl<T : Read> Read for BlockingIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<T : Write> Write for BlockingIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<T : Seek> Seek for BlockingIo<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl<T : Read> AsyncRead for BlockingIo<T> {}
+
+impl<T : Write> AsyncWrite for BlockingIo<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.0.flush()?;
+        Ok(Async::Ready(()))
+    }
+}
+
+/// CRC-32 (IEEE 802.3), same as zlib and gzip.
+#[derive(Debug, Clone, Copy)]
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Crc32 {
+        Crc32(0xffff_ffff)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        let mut crc = self.0;
+        for &b in data {
+            crc ^= b as u32;
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+            }
+        }
+        self.0 = crc;
+    }
+
+    fn value(&self) -> u32 {
+        !self.0
+    }
+}
+
+fn checksum_mismatch() -> Error {
+    Error::GrpcMessage(GrpcMessageError {
+        grpc_status: GrpcStatus::DataLoss as i32,
+        grpc_message: "checksum mismatch".to_owned(),
+    })
+}
+
+/// Chunks of `chunk_size` bytes followed by a chunk with checksum only.
+struct ChunkReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    len: usize,
+    eof: bool,
+    crc: Option<Crc32>,
+}
+
+impl<R : AsyncRead> ChunkReader<R> {
+    fn new(reader: R, chunk_size: usize) -> ChunkReader<R> {
+        assert!(chunk_size > 0);
+        ChunkReader {
+            reader,
+            buf: vec![0; chunk_size],
+            len: 0,
+            eof: false,
+            crc: Some(Crc32::new()),
+        }
+    }
+}
+
+impl<R : AsyncRead> Stream for ChunkReader<R> {
+    type Item = Chunk;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, Error> {
+        // buffer is kept between polls if reader is not ready
+        while !self.eof && self.len < self.buf.len() {
+            let n = try_ready!(self.reader.poll_read(&mut self.buf[self.len..]));
+            if n == 0 {
+                self.eof = true;
+            }
+            self.len += n;
+        }
+
+        let crc = match self.crc {
+            Some(ref mut crc) => crc,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        if self.len == 0 {
+            let crc32 = crc.value();
+            self.crc = None;
+            return Ok(Async::Ready(Some(Chunk { data: Bytes::new(), crc32: Some(crc32) })));
+        }
+
+        let data = Bytes::from(&self.buf[..self.len]);
+        self.len = 0;
+        crc.update(&data);
+        Ok(Async::Ready(Some(Chunk { data, crc32: None })))
+    }
+}
+
+/// Client-streaming request of chunks read from `reader`.
+///
+/// Last message is built from a chunk with no data and checksum.
+pub fn upload_request<R, M, F>(reader: R, chunk_size: usize, to_message: F) -> StreamingRequest<M>
+    where
+        R : AsyncRead + Send + 'static,
+        M : Send + 'static,
+        F : FnMut(Chunk) -> M + Send + 'static,
+{
+    StreamingRequest::new(ChunkReader::new(reader, chunk_size).map(to_message))
+}
+
+/// Server-streaming response of chunks read from `reader`,
+/// with checksum in trailing metadata.
+pub fn download_response<R, M, F>(reader: R, chunk_size: usize, mut to_message: F) -> StreamingResponse<M>
+    where
+        R : AsyncRead + Send + 'static,
+        M : Send + 'static,
+        F : FnMut(Chunk) -> M + Send + 'static,
+{
+    let stream = ChunkReader::new(reader, chunk_size).map(move |chunk| match chunk.crc32 {
+        Some(crc32) => {
+            let mut trailing = Metadata::new();
+            trailing.add(
+                MetadataKey::from(CHECKSUM_METADATA_KEY),
+                Bytes::from(format!("{:08x}", crc32)));
+            ItemOrMetadata::TrailingMetadata(trailing)
+        }
+        None => ItemOrMetadata::Item(to_message(chunk)),
+    });
+    StreamingResponse::new(future::ok((Metadata::new(), GrpcStreamWithTrailingMetadata::new(stream))))
+}
+
+/// Write chunks to `writer`, resolves to writer and number of bytes written.
+fn write_chunks<S, W>(chunks: S, writer: W) -> GrpcFuture<(W, u64)>
+    where
+        S : Stream<Item=Chunk, Error=Error> + Send + 'static,
+        W : AsyncWrite + Send + 'static,
+{
+    let written = chunks.fold((writer, Crc32::new(), 0), |(writer, mut crc, len), chunk| {
+        crc.update(&chunk.data);
+        if chunk.crc32.is_some() && chunk.crc32 != Some(crc.value()) {
+            return future::Either::A(future::err(checksum_mismatch()));
+        }
+        let n = chunk.data.len() as u64;
+        future::Either::B(async_io::write_all(writer, chunk.data)
+            .map(move |(writer, _)| (writer, crc, len + n))
+            .map_err(Error::from))
+    });
+    Box::new(written.and_then(|(writer, _, len)| {
+        async_io::flush(writer).map(move |writer| (writer, len)).map_err(Error::from)
+    }))
+}
+
+/// Write uploaded chunks to `writer` verifying checksum if present,
+/// resolves to writer and number of bytes written.
+pub fn write_request<W, M, F>(req: StreamingRequest<M>, writer: W, from_message: F) -> GrpcFuture<(W, u64)>
+    where
+        W : AsyncWrite + Send + 'static,
+        M : Send + 'static,
+        F : FnMut(M) -> Chunk + Send + 'static,
+{
+    write_chunks(req.0.map(from_message), writer)
+}
+
+/// Write downloaded chunks to `writer` verifying checksum trailing metadata
+/// if present, resolves to writer and number of bytes written.
+pub fn write_response<W, M, F>(resp: StreamingResponse<M>, writer: W, mut from_message: F) -> GrpcFuture<(W, u64)>
+    where
+        W : AsyncWrite + Send + 'static,
+        M : Send + 'static,
+        F : FnMut(M) -> Chunk + Send + 'static,
+{
+    Box::new(resp.0.and_then(move |(_metadata, stream)| {
+        let chunks = stream.0.and_then(move |item| match item {
+            ItemOrMetadata::Item(message) => Ok(from_message(message)),
+            ItemOrMetadata::TrailingMetadata(trailing) => {
+                let crc32 = match trailing.get(CHECKSUM_METADATA_KEY) {
+                    Some(value) => Some(str::from_utf8(value).ok()
+                        .and_then(|v| u32::from_str_radix(v, 16).ok())
+                        .ok_or(Error::Protocol("invalid checksum-crc32 metadata"))?),
+                    None => None,
+                };
+                Ok(Chunk { data: Bytes::new(), crc32 })
+            }
+        });
+        write_chunks(chunks, writer)
+    }))
+}
+
+/// ID and offset of resumable upload request, ID is `None` for a new upload.
+pub fn upload_position(metadata: &Metadata) -> result::Result<(Option<String>, u64)> {
+    let upload_id = match metadata.get(UPLOAD_ID_METADATA_KEY) {
+        Some(value) => Some(str::from_utf8(value)
+            .map_err(|_| Error::Protocol("invalid upload-id metadata"))?
+            .to_owned()),
+        None => None,
+    };
+    let offset = match metadata.get(UPLOAD_OFFSET_METADATA_KEY) {
+        Some(value) => str::from_utf8(value).ok()
+            .and_then(|v| v.parse().ok())
+            .ok_or(Error::Protocol("invalid upload-offset metadata"))?,
+        None => 0,
+    };
+    Ok((upload_id, offset))
+}
+
+/// Response of resumable upload writing chunks of request
+/// to `writer` positioned at `offset`.
+///
+/// After every `ack_interval` bytes and at the end of request,
+/// writer is flushed and message made by `to_ack` from offset
+/// of written data is sent. Handler should fail calls with unknown
+/// upload ID or offset past persisted data rather than call this.
+pub fn resumable_upload_response<W, M, A, F, G>(
+    upload_id: &str,
+    offset: u64,
+    req: StreamingRequest<M>,
+    writer: W,
+    ack_interval: u64,
+    from_message: F,
+    to_ack: G)
+    -> StreamingResponse<A>
+    where
+        W : AsyncWrite + Send + 'static,
+        M : Send + 'static,
+        A : Send + 'static,
+        F : FnMut(M) -> Chunk + Send + 'static,
+        G : FnMut(u64) -> A + Send + 'static,
+{
+    let mut metadata = Metadata::new();
+    metadata.add(MetadataKey::from(UPLOAD_ID_METADATA_KEY), Bytes::from(upload_id.to_owned()));
+    let acks = AckingWriter {
+        chunks: req.0.map(from_message),
+        writer: writer,
+        pending: Bytes::new(),
+        written: offset,
+        acked: offset,
+        ack_interval: ack_interval,
+        eof: false,
+    };
+    StreamingResponse::metadata_and_stream(metadata, acks.map(to_ack))
+}
+
+/// Writes chunks, yielding offset of written data after it is flushed.
+struct AckingWriter<S, W> {
+    chunks: S,
+    writer: W,
+    /// Data of chunk not yet written
+    pending: Bytes,
+    written: u64,
+    acked: u64,
+    ack_interval: u64,
+    eof: bool,
+}
+
+impl<S, W> Stream for AckingWriter<S, W>
+    where
+        S : Stream<Item=Chunk, Error=Error>,
+        W : AsyncWrite,
+{
+    type Item = u64;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<u64>, Error> {
+        loop {
+            if !self.pending.is_empty() {
+                let n = try_ready!(self.writer.poll_write(&self.pending));
+                if n == 0 {
+                    return Err(Error::from(io::Error::from(io::ErrorKind::WriteZero)));
+                }
+                self.pending.advance(n);
+                self.written += n as u64;
+                continue;
+            }
+
+            let unacked = self.written - self.acked;
+            if unacked > 0 && (unacked >= self.ack_interval || self.eof) {
+                try_ready!(self.writer.poll_flush());
+                self.acked = self.written;
+                return Ok(Async::Ready(Some(self.acked)));
+            }
+
+            if self.eof {
+                return Ok(Async::Ready(None));
+            }
+
+            match try_ready!(self.chunks.poll()) {
+                Some(chunk) => self.pending = chunk.data,
+                None => self.eof = true,
+            }
+        }
+    }
+}
+
+/// Reader shared by attempts of resumable upload.
+struct SharedReader<R>(Arc<Mutex<R>>);
+
+impl<R : Read> Read for SharedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl<R : Read> AsyncRead for SharedReader<R> {}
+
+/// Progress of resumable upload.
+#[derive(Default)]
+struct UploadState {
+    upload_id: Option<String>,
+    acked: u64,
+}
+
+/// Client of resumable upload method, calling it again
+/// from the last acknowledged offset when a call fails.
+pub struct ResumableUpload<M, A> {
+    call: Arc<Fn(RequestOptions, StreamingRequest<M>) -> StreamingResponse<A> + Send + Sync>,
+    to_message: Arc<Fn(Chunk) -> M + Send + Sync>,
+    ack_offset: Arc<Fn(A) -> u64 + Send + Sync>,
+    /// Size of data sent in each message
+    pub chunk_size: usize,
+    /// Failed calls are retried according to this policy,
+    /// attempts are counted since the last call which acknowledged data
+    pub retry_policy: RetryPolicy,
+}
+
+impl<M, A> Clone for ResumableUpload<M, A> {
+    fn clone(&self) -> Self {
+        ResumableUpload {
+            call: self.call.clone(),
+            to_message: self.to_message.clone(),
+            ack_offset: self.ack_offset.clone(),
+            chunk_size: self.chunk_size,
+            retry_policy: self.retry_policy.clone(),
+        }
+    }
+}
+
+impl<M, A> ResumableUpload<M, A>
+    where
+        M : Send + 'static,
+        A : Send + 'static,
+{
+    /// Upload calling bidi method with `call`, e. g. method of generated client,
+    /// sending messages made by `to_message` and reading offsets
+    /// from acknowledgements by `ack_offset`.
+    pub fn new<C, F, G>(call: C, to_message: F, ack_offset: G) -> ResumableUpload<M, A>
+        where
+            C : Fn(RequestOptions, StreamingRequest<M>) -> StreamingResponse<A> + Send + Sync + 'static,
+            F : Fn(Chunk) -> M + Send + Sync + 'static,
+            G : Fn(A) -> u64 + Send + Sync + 'static,
+    {
+        ResumableUpload {
+            call: Arc::new(call),
+            to_message: Arc::new(to_message),
+            ack_offset: Arc::new(ack_offset),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Upload data of `reader`, resolves to upload ID and size of uploaded data.
+    ///
+    /// Chunks have no checksums, as each call sends only part of data.
+    pub fn upload<R>(&self, o: RequestOptions, reader: R) -> GrpcFuture<(String, u64)>
+        where R : AsyncRead + Seek + Send + 'static
+    {
+        let reader = Arc::new(Mutex::new(reader));
+        let state = Arc::new(Mutex::new(UploadState::default()));
+        self.attempt(o, reader, state, 1)
+    }
+
+    fn attempt<R>(
+        &self,
+        o: RequestOptions,
+        reader: Arc<Mutex<R>>,
+        state: Arc<Mutex<UploadState>>,
+        attempts: u32)
+        -> GrpcFuture<(String, u64)>
+        where R : AsyncRead + Seek + Send + 'static
+    {
+        let (upload_id, offset) = {
+            let state = state.lock().unwrap();
+            (state.upload_id.clone(), state.acked)
+        };
+        if let Err(e) = reader.lock().unwrap().seek(SeekFrom::Start(offset)) {
+            return Box::new(future::err(Error::from(e)));
+        }
+
+        let mut call_options = o.clone();
+        if let Some(ref upload_id) = upload_id {
+            call_options.metadata.add(
+                MetadataKey::from(UPLOAD_ID_METADATA_KEY), Bytes::from(upload_id.clone()));
+        }
+        call_options.metadata.add(
+            MetadataKey::from(UPLOAD_OFFSET_METADATA_KEY), Bytes::from(offset.to_string()));
+
+        let to_message = self.to_message.clone();
+        let req = ChunkReader::new(SharedReader(reader.clone()), self.chunk_size)
+            .filter(|chunk| !chunk.data.is_empty())
+            .map(move |chunk| to_message(chunk));
+        let resp = (self.call)(call_options, StreamingRequest::new(req));
+
+        let ack_offset = self.ack_offset.clone();
+        let call_state = state.clone();
+        let done = resp.0.and_then(move |(metadata, stream)| {
+            if let Some(value) = metadata.get(UPLOAD_ID_METADATA_KEY) {
+                let received = str::from_utf8(value)
+                    .map_err(|_| Error::Protocol("invalid upload-id metadata"))?;
+                let mut state = call_state.lock().unwrap();
+                if state.upload_id.as_ref().map_or(false, |id| id != received) {
+                    return Err(Error::Protocol("upload-id changed on resumption"));
+                }
+                state.upload_id = Some(received.to_owned());
+            }
+            Ok(stream.drop_metadata().for_each(move |ack| {
+                let mut state = call_state.lock().unwrap();
+                state.acked = cmp::max(state.acked, ack_offset(ack));
+                Ok(())
+            }))
+        }).flatten();
+
+        let upload = self.clone();
+        Box::new(done.then(move |r| -> GrpcFuture<(String, u64)> {
+            let (upload_id, acked) = {
+                let state = state.lock().unwrap();
+                (state.upload_id.clone(), state.acked)
+            };
+            let e = match r {
+                Ok(()) => return Box::new(upload_id
+                    .map(|upload_id| (upload_id, acked))
+                    .ok_or(Error::Protocol("missing upload-id metadata"))
+                    .into_future()),
+                Err(e) => e,
+            };
+            let attempts = if acked > offset { 1 } else { attempts };
+            let policy = &upload.retry_policy;
+            if attempts >= policy.max_attempts() || !policy.retryable_status_codes.contains(&e.grpc_status()) {
+                return Box::new(future::err(e));
+            }
+            let backoff = policy.backoff(attempts);
+            debug!("resuming upload from {} in {:?} after error: {:?}", acked, backoff, e);
+            Box::new(timer::sleep(backoff).and_then(move |()| {
+                upload.attempt(o, reader, state, attempts + 1)
+            }))
+        }))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn crc32() {
+        let mut crc = Crc32::new();
+        assert_eq!(0, crc.value());
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(0xcbf4_3926, crc.value());
+    }
+
+    #[test]
+    fn chunks() {
+        let reader = BlockingIo(Cursor::new(b"abcdefgh".to_vec()));
+        let chunks = upload_request(reader, 3, |c| c).0.collect().wait().unwrap();
+        let data: Vec<&[u8]> = chunks.iter().map(|c| &c.data[..]).collect();
+        assert_eq!(vec![&b"abc"[..], b"def", b"gh", b""], data);
+        assert_eq!(None, chunks[2].crc32);
+
+        let (writer, len) = write_request(StreamingRequest::iter(chunks), BlockingIo(Vec::new()), |c| c)
+            .wait().unwrap();
+        assert_eq!(8, len);
+        assert_eq!(b"abcdefgh", &writer.0[..]);
+    }
+
+    #[test]
+    fn mismatch() {
+        let chunks = vec![
+            Chunk { data: Bytes::from("ab"), crc32: None },
+            Chunk { data: Bytes::new(), crc32: Some(1) },
+        ];
+        let r = write_request(StreamingRequest::iter(chunks), BlockingIo(Vec::new()), |c| c).wait();
+        match r {
+            Err(Error::GrpcMessage(ref e)) if e.grpc_status == GrpcStatus::DataLoss as i32 => {}
+            _ => panic!("expecting checksum mismatch"),
+        }
+    }
+
+    #[test]
+    fn position() {
+        assert_eq!((None, 0), upload_position(&Metadata::new()).unwrap());
+
+        let mut metadata = Metadata::new();
+        metadata.add(MetadataKey::from(UPLOAD_ID_METADATA_KEY), Bytes::from("u1"));
+        metadata.add(MetadataKey::from(UPLOAD_OFFSET_METADATA_KEY), Bytes::from("12"));
+        assert_eq!((Some("u1".to_owned()), 12), upload_position(&metadata).unwrap());
+
+        let mut metadata = Metadata::new();
+        metadata.add(MetadataKey::from(UPLOAD_OFFSET_METADATA_KEY), Bytes::from("-1"));
+        assert!(upload_position(&metadata).is_err());
+    }
+
+    #[test]
+    fn acks() {
+        let chunks = vec!["abc", "def", "g"].into_iter()
+            .map(|d| Chunk { data: Bytes::from(d), crc32: None })
+            .collect::<Vec<_>>();
+        let resp = resumable_upload_response(
+            "u1", 10, StreamingRequest::iter(chunks), BlockingIo(Vec::new()), 4, |c| c, |offset| offset);
+        let (metadata, acks, _) = resp.collect().wait().unwrap();
+        assert_eq!(Some(&b"u1"[..]), metadata.get(UPLOAD_ID_METADATA_KEY));
+        assert_eq!(vec![16, 17], acks);
+    }
+}
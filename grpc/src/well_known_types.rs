@@ -0,0 +1,148 @@
+//! Protobuf well-known types and conversions to std types.
+//!
+//! Generated code references these types as `::protobuf::well_known_types`,
+//! so services using `google/protobuf/*.proto` imports work without
+//! generating these messages again.
+
+use std::time;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use protobuf_lib::Message;
+
+pub use protobuf_lib::well_known_types::Any;
+pub use protobuf_lib::well_known_types::Duration;
+pub use protobuf_lib::well_known_types::Empty;
+pub use protobuf_lib::well_known_types::Timestamp;
+
+use result;
+
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// Prefix of `type_url` of `Any` created by `pack_any`.
+pub const TYPE_URL_PREFIX: &'static str = "type.googleapis.com/";
+
+
+/// Convert std duration to protobuf duration.
+pub fn duration_from_std(d: time::Duration) -> Duration {
+    let mut r = Duration::new();
+    r.set_seconds(d.as_secs() as i64);
+    r.set_nanos(d.subsec_nanos() as i32);
+    r
+}
+
+/// Convert protobuf duration to std duration.
+///
+/// Returns `None` if duration is negative.
+pub fn duration_to_std(d: &Duration) -> Option<time::Duration> {
+    // seconds and nanos have the same sign
+    if d.get_seconds() < 0 || d.get_nanos() < 0 {
+        return None;
+    }
+    Some(time::Duration::new(d.get_seconds() as u64, d.get_nanos() as u32))
+}
+
+/// Convert system time to protobuf timestamp.
+pub fn timestamp_from_system_time(t: SystemTime) -> Timestamp {
+    let mut r = Timestamp::new();
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => {
+            r.set_seconds(d.as_secs() as i64);
+            r.set_nanos(d.subsec_nanos() as i32);
+        }
+        Err(e) => {
+            // before epoch; timestamp nanos are always non-negative
+            let d = e.duration();
+            let nanos = d.subsec_nanos() as i64;
+            if nanos == 0 {
+                r.set_seconds(-(d.as_secs() as i64));
+            } else {
+                r.set_seconds(-(d.as_secs() as i64) - 1);
+                r.set_nanos((NANOS_PER_SEC - nanos) as i32);
+            }
+        }
+    }
+    r
+}
+
+/// Convert protobuf timestamp to system time.
+pub fn timestamp_to_system_time(t: &Timestamp) -> SystemTime {
+    let seconds = t.get_seconds();
+    let nanos = time::Duration::new(0, t.get_nanos() as u32);
+    if seconds >= 0 {
+        UNIX_EPOCH + time::Duration::from_secs(seconds as u64) + nanos
+    } else {
+        UNIX_EPOCH - time::Duration::from_secs(seconds.wrapping_neg() as u64) + nanos
+    }
+}
+
+fn type_name_of_url(type_url: &str) -> &str {
+    match type_url.rfind('/') {
+        Some(pos) => &type_url[pos + 1..],
+        None => type_url,
+    }
+}
+
+/// Pack message into `Any`, e. g. to send it in error details.
+pub fn pack_any<M : Message>(m: &M) -> result::Result<Any> {
+    let mut any = Any::new();
+    any.set_type_url(format!("{}{}", TYPE_URL_PREFIX, m.descriptor().full_name()));
+    any.set_value(m.write_to_bytes()?);
+    Ok(any)
+}
+
+/// Check if `Any` contains message of given type.
+pub fn any_is<M : Message>(any: &Any) -> bool {
+    type_name_of_url(any.get_type_url()) == M::descriptor_static().full_name()
+}
+
+/// Unpack message from `Any`.
+///
+/// Returns `None` if `Any` contains message of different type.
+pub fn unpack_any<M : Message>(any: &Any) -> result::Result<Option<M>> {
+    if !any_is::<M>(any) {
+        return Ok(None);
+    }
+    let mut m = M::new();
+    m.merge_from_bytes(any.get_value())?;
+    m.check_initialized()?;
+    Ok(Some(m))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn duration() {
+        let d = time::Duration::new(3, 5);
+        assert_eq!(Some(d), duration_to_std(&duration_from_std(d)));
+
+        let mut negative = Duration::new();
+        negative.set_seconds(-1);
+        assert_eq!(None, duration_to_std(&negative));
+    }
+
+    #[test]
+    fn timestamp() {
+        let after = UNIX_EPOCH + time::Duration::new(10, 20);
+        assert_eq!(after, timestamp_to_system_time(&timestamp_from_system_time(after)));
+
+        let before = UNIX_EPOCH - time::Duration::new(10, 20);
+        let t = timestamp_from_system_time(before);
+        assert_eq!(-11, t.get_seconds());
+        assert_eq!(999_999_980, t.get_nanos());
+        assert_eq!(before, timestamp_to_system_time(&t));
+    }
+
+    #[test]
+    fn any() {
+        let d = duration_from_std(time::Duration::from_secs(7));
+        let any = pack_any(&d).unwrap();
+        assert_eq!("type.googleapis.com/google.protobuf.Duration", any.get_type_url());
+        assert_eq!(Some(d), unpack_any::<Duration>(&any).unwrap());
+        assert_eq!(None, unpack_any::<Timestamp>(&any).unwrap());
+    }
+}
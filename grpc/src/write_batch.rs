@@ -0,0 +1,270 @@
+//! Coalescing of sent messages into larger HTTP/2 DATA frames.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use futures::Async;
+use futures::Future;
+use futures::Poll;
+use futures::stream::Stream;
+use futures::task::AtomicTask;
+
+use error::Error;
+use futures_grpc::GrpcFuture;
+use futures_grpc::GrpcStream;
+use timer;
+
+
+/// Max size of HTTP/2 frame unless peer allows larger.
+fn default_max_batch_bytes() -> usize {
+    16384
+}
+
+/// How request messages are written to HTTP/2 stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteStrategy {
+    /// Each message is written as soon as it is available:
+    /// the lowest latency.
+    Immediate,
+    /// Messages sent within `window` after the first one are written
+    /// together, up to `max_bytes`: fewer frames for many small messages
+    /// at the cost of up to `window` added latency.
+    Batch {
+        window: Duration,
+        max_bytes: usize,
+    },
+}
+
+impl Default for WriteStrategy {
+    fn default() -> WriteStrategy {
+        WriteStrategy::Immediate
+    }
+}
+
+impl WriteStrategy {
+    /// Batch messages sent within `window` into frames of default max size.
+    pub fn batch(window: Duration) -> WriteStrategy {
+        WriteStrategy::Batch {
+            window: window,
+            max_bytes: default_max_batch_bytes(),
+        }
+    }
+}
+
+
+struct CorkShared {
+    corked: AtomicBool,
+    task: AtomicTask,
+}
+
+/// Holds back messages of a streaming call while corked,
+/// so bulk uploads are written in large frames regardless of `WriteStrategy`.
+///
+/// Messages are still written when they fill a frame of max batch size.
+#[derive(Clone)]
+pub struct Cork {
+    shared: Arc<CorkShared>,
+}
+
+impl fmt::Debug for Cork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cork")
+            .field("corked", &self.is_corked())
+            .finish()
+    }
+}
+
+impl Cork {
+    /// New cork, initially not corked.
+    pub fn new() -> Cork {
+        Cork {
+            shared: Arc::new(CorkShared {
+                corked: AtomicBool::new(false),
+                task: AtomicTask::new(),
+            }),
+        }
+    }
+
+    pub fn cork(&self) {
+        self.shared.corked.store(true, Ordering::SeqCst);
+    }
+
+    /// Write held back messages.
+    pub fn uncork(&self) {
+        self.shared.corked.store(false, Ordering::SeqCst);
+        self.shared.task.notify();
+    }
+
+    pub fn is_corked(&self) -> bool {
+        self.shared.corked.load(Ordering::SeqCst)
+    }
+}
+
+
+/// Concatenates frames of `stream` according to strategy and cork.
+struct BatchFrames<S> {
+    stream: S,
+    window: Option<Duration>,
+    max_bytes: usize,
+    cork: Option<Cork>,
+    buf: Vec<u8>,
+    timer: Option<GrpcFuture<()>>,
+    done: bool,
+    error: Option<Error>,
+}
+
+/// Apply write strategy and cork to stream of serialized frames.
+pub(crate) fn batch_frames(stream: GrpcStream<Bytes>, strategy: &WriteStrategy, cork: Option<Cork>)
+    -> GrpcStream<Bytes>
+{
+    let (window, max_bytes) = match *strategy {
+        WriteStrategy::Immediate if cork.is_none() => return stream,
+        WriteStrategy::Immediate => (None, default_max_batch_bytes()),
+        WriteStrategy::Batch { window, max_bytes } => (Some(window), max_bytes),
+    };
+    Box::new(BatchFrames {
+        stream: stream,
+        window: window,
+        max_bytes: max_bytes,
+        cork: cork,
+        buf: Vec::new(),
+        timer: None,
+        done: false,
+        error: None,
+    })
+}
+
+impl<S : Stream<Item=Bytes, Error=Error>> BatchFrames<S> {
+    fn is_corked(&self) -> bool {
+        match self.cork {
+            Some(ref cork) => {
+                // register before checking to not miss `uncork`
+                cork.shared.task.register();
+                cork.is_corked()
+            }
+            None => false,
+        }
+    }
+
+    /// Whether batch window is over, registering for wakeup if not.
+    fn window_elapsed(&mut self) -> bool {
+        match self.timer {
+            Some(ref mut timer) => match timer.poll() {
+                Ok(Async::NotReady) => false,
+                Ok(Async::Ready(())) | Err(_) => true,
+            },
+            None => true,
+        }
+    }
+}
+
+impl<S : Stream<Item=Bytes, Error=Error>> Stream for BatchFrames<S> {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        while !self.done && self.error.is_none() && self.buf.len() < self.max_bytes {
+            match self.stream.poll() {
+                Ok(Async::Ready(Some(frame))) => {
+                    if self.buf.is_empty() {
+                        self.timer = self.window.map(timer::sleep);
+                    }
+                    self.buf.extend_from_slice(&frame);
+                }
+                Ok(Async::Ready(None)) => self.done = true,
+                Ok(Async::NotReady) => break,
+                Err(e) => self.error = Some(e),
+            }
+        }
+
+        if !self.buf.is_empty() {
+            let flush = self.done
+                || self.error.is_some()
+                || self.buf.len() >= self.max_bytes
+                || (!self.is_corked() && self.window_elapsed());
+            if !flush {
+                return Ok(Async::NotReady);
+            }
+            self.timer = None;
+            let buf = ::std::mem::replace(&mut self.buf, Vec::new());
+            return Ok(Async::Ready(Some(Bytes::from(buf))));
+        }
+
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::stream;
+    use futures::sync::mpsc;
+
+    fn frames(items: &[&'static [u8]]) -> GrpcStream<Bytes> {
+        let items: Vec<Bytes> = items.iter().map(|&b| Bytes::from_static(b)).collect();
+        Box::new(stream::iter_ok(items))
+    }
+
+    fn channel() -> (mpsc::UnboundedSender<Bytes>, GrpcStream<Bytes>) {
+        let (tx, rx) = mpsc::unbounded();
+        (tx, Box::new(rx.map_err(|()| Error::Other("unreachable"))))
+    }
+
+    #[test]
+    fn batch_max_bytes() {
+        let strategy = WriteStrategy::Batch { window: Duration::from_secs(3600), max_bytes: 4 };
+        let batched = batch_frames(frames(&[b"ab", b"c", b"de", b"f"]), &strategy, None);
+        let batched: Vec<Bytes> = batched.wait().map(|b| b.unwrap()).collect();
+        // the rest is written when stream ends, not after window
+        assert_eq!(vec![Bytes::from_static(b"abcde"), Bytes::from_static(b"f")], batched);
+    }
+
+    #[test]
+    fn batch_window() {
+        let (tx, rx) = channel();
+        let strategy = WriteStrategy::batch(Duration::from_millis(50));
+        let mut batched = batch_frames(rx, &strategy, None).wait();
+
+        tx.unbounded_send(Bytes::from_static(b"a")).unwrap();
+        tx.unbounded_send(Bytes::from_static(b"b")).unwrap();
+        assert_eq!(Bytes::from_static(b"ab"), batched.next().unwrap().unwrap());
+
+        drop(tx);
+        assert!(batched.next().is_none());
+    }
+
+    #[test]
+    fn corked() {
+        let (tx, rx) = channel();
+        let cork = Cork::new();
+        cork.cork();
+        let batched = batch_frames(rx, &WriteStrategy::Immediate, Some(cork.clone()));
+
+        tx.unbounded_send(Bytes::from_static(b"a")).unwrap();
+        tx.unbounded_send(Bytes::from_static(b"b")).unwrap();
+
+        let uncork = ::std::thread::spawn(move || {
+            ::std::thread::sleep(Duration::from_millis(50));
+            tx.unbounded_send(Bytes::from_static(b"c")).unwrap();
+            cork.uncork();
+        });
+
+        let mut batched = batched.wait();
+        assert_eq!(Bytes::from_static(b"abc"), batched.next().unwrap().unwrap());
+        uncork.join().unwrap();
+        assert!(batched.next().is_none());
+    }
+}
@@ -0,0 +1,752 @@
+//! Minimal xDS client: cluster and endpoint discovery over aggregated
+//! discovery service.
+//!
+//! Host name of a channel is used as a cluster name. `Cluster` resource
+//! (CDS) is requested first to find EDS service name of the cluster,
+//! then its `ClusterLoadAssignment` (EDS), whose endpoints become channel
+//! addresses. Only clusters of `EDS` type are supported. Listener and
+//! route resources are not requested.
+//!
+//! Messages are encoded by hand to avoid depending on Envoy protos;
+//! only fields used here are encoded and decoded.
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use futures::Future;
+use futures::Stream;
+use futures::sync::mpsc;
+use futures_cpupool;
+use futures_cpupool::CpuPool;
+
+use protobuf_lib::CodedInputStream;
+use protobuf_lib::CodedOutputStream;
+use protobuf_lib::ProtobufError;
+use protobuf_lib::ProtobufResult;
+use protobuf_lib::wire_format::WireType;
+
+use balancer::AddressUpdates;
+use balancer::WeightedAddr;
+use client::Channel;
+use client::Client;
+use error::Error;
+use futures_grpc::GrpcFuture;
+use req::*;
+use resolver::Resolver;
+use result;
+
+
+const ADS_METHOD: &'static str =
+    "/envoy.service.discovery.v3.AggregatedDiscoveryService/StreamAggregatedResources";
+
+const CDS_TYPE_URL: &'static str =
+    "type.googleapis.com/envoy.config.cluster.v3.Cluster";
+
+const EDS_TYPE_URL: &'static str =
+    "type.googleapis.com/envoy.config.endpoint.v3.ClusterLoadAssignment";
+
+// `envoy.config.cluster.v3.Cluster.DiscoveryType`
+const DISCOVERY_TYPE_EDS: u32 = 3;
+
+// `envoy.config.core.v3.HealthStatus`
+const HEALTH_UNKNOWN: u32 = 0;
+const HEALTH_HEALTHY: u32 = 1;
+const HEALTH_DEGRADED: u32 = 5;
+
+// `google.rpc.Code.INVALID_ARGUMENT`
+const CODE_INVALID_ARGUMENT: u32 = 3;
+
+
+/// `envoy.service.discovery.v3.DiscoveryRequest`
+struct DiscoveryRequest<'a> {
+    version_info: &'a str,
+    node_id: &'a str,
+    resource_names: &'a [String],
+    type_url: &'a str,
+    response_nonce: &'a str,
+    /// Message of `google.rpc.Status` sent with NACK
+    error_detail: Option<String>,
+}
+
+impl<'a> DiscoveryRequest<'a> {
+    fn write_to_bytes(&self) -> ProtobufResult<Vec<u8>> {
+        let mut node = Vec::new();
+        {
+            let mut os = CodedOutputStream::vec(&mut node);
+            os.write_string(1, self.node_id)?;
+            os.flush()?;
+        }
+
+        let mut r = Vec::new();
+        {
+            let mut os = CodedOutputStream::vec(&mut r);
+            os.write_string(1, self.version_info)?;
+            os.write_bytes(2, &node)?;
+            for name in self.resource_names {
+                os.write_string(3, name)?;
+            }
+            os.write_string(4, self.type_url)?;
+            os.write_string(5, self.response_nonce)?;
+            if let Some(ref message) = self.error_detail {
+                let mut status = Vec::new();
+                {
+                    let mut os = CodedOutputStream::vec(&mut status);
+                    os.write_int32(1, CODE_INVALID_ARGUMENT as i32)?;
+                    os.write_string(2, message)?;
+                    os.flush()?;
+                }
+                os.write_bytes(6, &status)?;
+            }
+            os.flush()?;
+        }
+        Ok(r)
+    }
+}
+
+/// `envoy.service.discovery.v3.DiscoveryResponse`
+#[derive(Default)]
+struct DiscoveryResponse {
+    version_info: String,
+    /// Values of `Any` resources
+    resources: Vec<Vec<u8>>,
+    type_url: String,
+    nonce: String,
+}
+
+/// Call `f` for each field of message, skipping fields not handled by `f`.
+fn read_fields<F>(bytes: &[u8], mut f: F) -> ProtobufResult<()>
+    where F : FnMut(u32, WireType, &mut CodedInputStream) -> ProtobufResult<bool>
+{
+    let mut is = CodedInputStream::from_bytes(bytes);
+    while !is.eof()? {
+        let (field, wire_type) = is.read_tag_unpack()?;
+        if !f(field, wire_type, &mut is)? {
+            is.skip_field(wire_type)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_discovery_response(bytes: &[u8]) -> ProtobufResult<DiscoveryResponse> {
+    let mut r = DiscoveryResponse::default();
+    read_fields(bytes, |field, wire_type, is| {
+        match (field, wire_type) {
+            (1, WireType::WireTypeLengthDelimited) => r.version_info = is.read_string()?,
+            (2, WireType::WireTypeLengthDelimited) => {
+                // google.protobuf.Any
+                let mut value = Vec::new();
+                read_fields(&is.read_bytes()?, |field, wire_type, is| {
+                    match (field, wire_type) {
+                        (2, WireType::WireTypeLengthDelimited) => value = is.read_bytes()?,
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+                r.resources.push(value);
+            }
+            (4, WireType::WireTypeLengthDelimited) => r.type_url = is.read_string()?,
+            (5, WireType::WireTypeLengthDelimited) => r.nonce = is.read_string()?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    })?;
+    Ok(r)
+}
+
+/// Parse `SocketAddress` from `Endpoint` message.
+fn parse_endpoint(bytes: &[u8]) -> ProtobufResult<Option<SocketAddr>> {
+    // Endpoint.address.socket_address
+    let mut address = Vec::new();
+    read_fields(bytes, |field, wire_type, is| {
+        match (field, wire_type) {
+            (1, WireType::WireTypeLengthDelimited) => {
+                read_fields(&is.read_bytes()?, |field, wire_type, is| {
+                    match (field, wire_type) {
+                        (1, WireType::WireTypeLengthDelimited) => address = is.read_bytes()?,
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    })?;
+
+    let mut host = String::new();
+    let mut port = 0;
+    read_fields(&address, |field, wire_type, is| {
+        match (field, wire_type) {
+            (2, WireType::WireTypeLengthDelimited) => host = is.read_string()?,
+            (3, WireType::WireTypeVarint) => port = is.read_uint32()?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    })?;
+
+    match host.parse::<IpAddr>() {
+        Ok(ip) if port <= u16::max_value() as u32 => Ok(Some(SocketAddr::new(ip, port as u16))),
+        _ => {
+            warn!("ignoring endpoint {}:{}, only IP addresses are supported", host, port);
+            Ok(None)
+        }
+    }
+}
+
+/// Parse `ClusterLoadAssignment` into cluster name and healthy endpoints.
+fn parse_cluster_load_assignment(bytes: &[u8]) -> ProtobufResult<(String, Vec<WeightedAddr>)> {
+    let mut cluster_name = String::new();
+    let mut addrs = Vec::new();
+    read_fields(bytes, |field, wire_type, is| {
+        match (field, wire_type) {
+            (1, WireType::WireTypeLengthDelimited) => cluster_name = is.read_string()?,
+            // LocalityLbEndpoints
+            (2, WireType::WireTypeLengthDelimited) => {
+                read_fields(&is.read_bytes()?, |field, wire_type, is| {
+                    match (field, wire_type) {
+                        // LbEndpoint
+                        (2, WireType::WireTypeLengthDelimited) => {
+                            let mut endpoint = None;
+                            let mut health = HEALTH_UNKNOWN;
+                            let mut weight = 1;
+                            read_fields(&is.read_bytes()?, |field, wire_type, is| {
+                                match (field, wire_type) {
+                                    (1, WireType::WireTypeLengthDelimited) => {
+                                        endpoint = parse_endpoint(&is.read_bytes()?)?;
+                                    }
+                                    (2, WireType::WireTypeVarint) => health = is.read_uint32()?,
+                                    // google.protobuf.UInt32Value
+                                    (4, WireType::WireTypeLengthDelimited) => {
+                                        read_fields(&is.read_bytes()?, |field, wire_type, is| {
+                                            match (field, wire_type) {
+                                                (1, WireType::WireTypeVarint) => weight = is.read_uint32()?,
+                                                _ => return Ok(false),
+                                            }
+                                            Ok(true)
+                                        })?;
+                                    }
+                                    _ => return Ok(false),
+                                }
+                                Ok(true)
+                            })?;
+                            match (endpoint, health) {
+                                (Some(addr), HEALTH_UNKNOWN) |
+                                (Some(addr), HEALTH_HEALTHY) |
+                                (Some(addr), HEALTH_DEGRADED) => {
+                                    addrs.push(WeightedAddr { addr: addr, weight: weight });
+                                }
+                                _ => {}
+                            }
+                        }
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    })?;
+    Ok((cluster_name, addrs))
+}
+
+/// Parse `Cluster` into cluster name, discovery type and EDS service name.
+fn parse_cluster(bytes: &[u8]) -> ProtobufResult<(String, u32, String)> {
+    let mut name = String::new();
+    // STATIC
+    let mut discovery_type = 0;
+    let mut service_name = String::new();
+    read_fields(bytes, |field, wire_type, is| {
+        match (field, wire_type) {
+            (1, WireType::WireTypeLengthDelimited) => name = is.read_string()?,
+            (2, WireType::WireTypeVarint) => discovery_type = is.read_uint32()?,
+            // EdsClusterConfig
+            (3, WireType::WireTypeLengthDelimited) => {
+                read_fields(&is.read_bytes()?, |field, wire_type, is| {
+                    match (field, wire_type) {
+                        (2, WireType::WireTypeLengthDelimited) => service_name = is.read_string()?,
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    })?;
+    Ok((name, discovery_type, service_name))
+}
+
+/// Find EDS service name of cluster in CDS response, `None` if response doesn't contain it.
+fn cluster_service_name(response: &DiscoveryResponse, cluster: &str)
+    -> ProtobufResult<Option<String>>
+{
+    for resource in &response.resources {
+        let (name, discovery_type, service_name) = parse_cluster(resource)?;
+        if name != cluster {
+            continue;
+        }
+        if discovery_type != DISCOVERY_TYPE_EDS {
+            return Err(ProtobufError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("cluster {} is not of EDS type, only EDS clusters are supported", cluster))));
+        }
+        // service name defaults to cluster name
+        return Ok(Some(if service_name.is_empty() { name } else { service_name }));
+    }
+    Ok(None)
+}
+
+/// Find addresses of cluster in EDS response, `None` if response doesn't contain it.
+fn cluster_addresses(response: &DiscoveryResponse, cluster: &str)
+    -> ProtobufResult<Option<Vec<WeightedAddr>>>
+{
+    for resource in &response.resources {
+        let (name, addrs) = parse_cluster_load_assignment(resource)?;
+        if name == cluster {
+            return Ok(Some(addrs));
+        }
+    }
+    Ok(None)
+}
+
+
+/// Subscribe to cluster and its endpoints, calling `f` with each update
+/// of endpoints until it returns `false` or stream fails.
+fn watch_cluster<F>(client: &Client, node_id: &str, cluster: &str, mut f: F) -> result::Result<()>
+    where F : FnMut(Vec<WeightedAddr>) -> bool
+{
+    let (tx, rx) = mpsc::unbounded();
+    let send = |type_url: &str, resource_names: &[String], version_info: &str, nonce: &str, error_detail: Option<String>|
+        -> result::Result<()>
+    {
+        let req = DiscoveryRequest {
+            version_info: version_info,
+            node_id: node_id,
+            resource_names: resource_names,
+            type_url: type_url,
+            response_nonce: nonce,
+            error_detail: error_detail,
+        };
+        tx.unbounded_send(Bytes::from(req.write_to_bytes()?))
+            .map_err(|_| Error::Other("xDS stream closed"))
+    };
+
+    let req = StreamingRequest::new(rx.map_err(|()| Error::Other("unreachable")));
+    let responses = client.call_bytes(RequestOptions::new(), req, ADS_METHOD).wait_drop_metadata();
+
+    let clusters = [cluster.to_owned()];
+    send(CDS_TYPE_URL, &clusters, "", "", None)?;
+    // version of last accepted response of each type
+    let mut cds_version = String::new();
+    let mut eds_version = String::new();
+    let mut eds_nonce = String::new();
+    // EDS service name of the cluster, once it is known from CDS
+    let mut service_names: Vec<String> = Vec::new();
+
+    for response in responses {
+        let response = parse_discovery_response(&response?)?;
+        if response.type_url == CDS_TYPE_URL {
+            match cluster_service_name(&response, cluster) {
+                Ok(service_name) => {
+                    cds_version = response.version_info;
+                    send(CDS_TYPE_URL, &clusters, &cds_version, &response.nonce, None)?;
+                    if let Some(service_name) = service_name {
+                        if service_names.first() != Some(&service_name) {
+                            service_names = vec![service_name];
+                            send(EDS_TYPE_URL, &service_names, &eds_version, &eds_nonce, None)?;
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("rejecting xDS response: {}", e);
+                    send(CDS_TYPE_URL, &clusters, &cds_version, &response.nonce, Some(format!("{}", e)))?;
+                }
+            }
+        } else if response.type_url == EDS_TYPE_URL {
+            let service_name = match service_names.first() {
+                Some(service_name) => service_name.clone(),
+                None => continue,
+            };
+            eds_nonce = response.nonce.clone();
+            match cluster_addresses(&response, &service_name) {
+                Ok(addrs) => {
+                    eds_version = response.version_info;
+                    send(EDS_TYPE_URL, &service_names, &eds_version, &eds_nonce, None)?;
+                    if let Some(addrs) = addrs {
+                        if !f(addrs) {
+                            return Ok(());
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("rejecting xDS response: {}", e);
+                    send(EDS_TYPE_URL, &service_names, &eds_version, &eds_nonce, Some(format!("{}", e)))?;
+                }
+            }
+        }
+    }
+    Err(Error::Other("xDS stream ended"))
+}
+
+/// Delay before subscribing again after stream failure.
+const RESUBSCRIBE_DELAY_SECS: u64 = 1;
+
+/// Watch cluster like `watch_cluster`, subscribing again on a new stream
+/// after `delay` when stream fails, until `f` returns `false`
+/// or `is_closed` returns `true`.
+fn watch_cluster_resubscribing<C, F>(
+    client: &Client, node_id: &str, cluster: &str, delay: Duration, is_closed: C, mut f: F)
+    where
+        C : Fn() -> bool,
+        F : FnMut(Vec<WeightedAddr>) -> bool,
+{
+    while !is_closed() {
+        match watch_cluster(client, node_id, cluster, &mut f) {
+            Ok(()) => return,
+            Err(e) => {
+                warn!("xDS stream of cluster {} failed: {:?}", cluster, e);
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+
+/// Resolver which gets cluster endpoints from xDS management server.
+///
+/// Host name of a channel is used as cluster name, port is ignored.
+/// Management server is contacted with a client of this crate,
+/// so any channel can be used to connect to it.
+///
+/// Each watched channel has a `grpc-xds-watch` thread blocked on
+/// the ADS stream. The thread exits with the first response received
+/// after the channel is closed. It is not joined because that
+/// may take until the management server sends the next update.
+pub struct XdsResolver {
+    client: Client,
+    node_id: String,
+    pool: CpuPool,
+}
+
+impl fmt::Debug for XdsResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("XdsResolver")
+            .field("node_id", &self.node_id)
+            .finish()
+    }
+}
+
+impl XdsResolver {
+    /// `node_id` identifies this client to management server.
+    pub fn new(management_server: Channel, node_id: &str) -> XdsResolver {
+        XdsResolver {
+            client: Client::with_channel(management_server),
+            node_id: node_id.to_owned(),
+            pool: futures_cpupool::Builder::new()
+                .name_prefix("grpc-xds-")
+                .pool_size(1)
+                .create(),
+        }
+    }
+}
+
+impl Resolver for XdsResolver {
+    fn resolve(&self, host: &str, port: u16) -> GrpcFuture<Vec<SocketAddr>> {
+        Box::new(self.resolve_weighted(host, port).map(|addrs| {
+            addrs.into_iter().map(|a| a.addr).collect()
+        }))
+    }
+
+    fn resolve_weighted(&self, host: &str, _port: u16) -> GrpcFuture<Vec<WeightedAddr>> {
+        let client = self.client.clone();
+        let node_id = self.node_id.clone();
+        let cluster = host.to_owned();
+        Box::new(self.pool.spawn_fn(move || {
+            let mut r = None;
+            watch_cluster(&client, &node_id, &cluster, |addrs| {
+                r = Some(addrs);
+                false
+            })?;
+            Ok(r.expect("addresses"))
+        }))
+    }
+
+    fn watch(&self, host: &str, _port: u16, updates: AddressUpdates) {
+        let client = self.client.clone();
+        let node_id = self.node_id.clone();
+        let cluster = host.to_owned();
+
+        let spawned = thread::Builder::new()
+            .name("grpc-xds-watch".to_owned())
+            .spawn(move || {
+                watch_cluster_resubscribing(
+                    &client, &node_id, &cluster, Duration::from_secs(RESUBSCRIBE_DELAY_SECS),
+                    || updates.is_closed(),
+                    |addrs| {
+                        debug!("addresses of cluster {} changed: {:?}", cluster, addrs);
+                        updates.update_weighted(addrs)
+                    });
+            });
+        if let Err(e) = spawned {
+            warn!("failed to start xDS watch thread: {}", e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use futures::stream;
+
+    use client::ClientConf;
+    use for_test::MarshallerBytes;
+    use method::GrpcStreaming;
+    use method::MethodDescriptor;
+    use resp::StreamingResponse;
+    use server::ServerBuilder;
+    use server::ServerServiceDefinition;
+    use server_method::MethodHandlerBidi;
+    use server_method::ServerMethod;
+
+    fn message<F>(f: F) -> Vec<u8>
+        where F : FnOnce(&mut CodedOutputStream) -> ProtobufResult<()>
+    {
+        let mut r = Vec::new();
+        {
+            let mut os = CodedOutputStream::vec(&mut r);
+            f(&mut os).unwrap();
+            os.flush().unwrap();
+        }
+        r
+    }
+
+    fn lb_endpoint(host: &str, port: u32, health: u32, weight: Option<u32>) -> Vec<u8> {
+        let socket_address = message(|os| {
+            os.write_string(2, host)?;
+            os.write_uint32(3, port)
+        });
+        let address = message(|os| os.write_bytes(1, &socket_address));
+        let endpoint = message(|os| os.write_bytes(1, &address));
+        message(|os| {
+            os.write_bytes(1, &endpoint)?;
+            os.write_uint32(2, health)?;
+            if let Some(weight) = weight {
+                os.write_bytes(4, &message(|os| os.write_uint32(1, weight)))?;
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn parse_eds_response() {
+        let locality = message(|os| {
+            os.write_bytes(2, &lb_endpoint("10.0.0.1", 80, HEALTH_HEALTHY, None))?;
+            os.write_bytes(2, &lb_endpoint("10.0.0.2", 80, 2, None))?;
+            os.write_bytes(2, &lb_endpoint("10.0.0.3", 81, HEALTH_UNKNOWN, Some(5)))
+        });
+        let assignment = message(|os| {
+            os.write_string(1, "backend")?;
+            os.write_bytes(2, &locality)
+        });
+        let any = message(|os| {
+            os.write_string(1, EDS_TYPE_URL)?;
+            os.write_bytes(2, &assignment)
+        });
+        let response = message(|os| {
+            os.write_string(1, "v1")?;
+            os.write_bytes(2, &any)?;
+            os.write_string(4, EDS_TYPE_URL)?;
+            os.write_string(5, "n1")
+        });
+
+        let response = parse_discovery_response(&response).unwrap();
+        assert_eq!("v1", response.version_info);
+        assert_eq!("n1", response.nonce);
+        assert_eq!(EDS_TYPE_URL, response.type_url);
+        assert_eq!(
+            Some(vec![
+                WeightedAddr::new("10.0.0.1:80".parse().unwrap()),
+                WeightedAddr { addr: "10.0.0.3:81".parse().unwrap(), weight: 5 },
+            ]),
+            cluster_addresses(&response, "backend").unwrap());
+        assert_eq!(None, cluster_addresses(&response, "other").unwrap());
+    }
+
+    fn discovery_response(type_url: &str, version_info: &str, nonce: &str, resources: &[Vec<u8>]) -> Vec<u8> {
+        message(|os| {
+            os.write_string(1, version_info)?;
+            for resource in resources {
+                os.write_bytes(2, &message(|os| {
+                    os.write_string(1, type_url)?;
+                    os.write_bytes(2, resource)
+                }))?;
+            }
+            os.write_string(4, type_url)?;
+            os.write_string(5, nonce)
+        })
+    }
+
+    fn cds_response(version_info: &str, nonce: &str) -> Vec<u8> {
+        let cluster = message(|os| {
+            os.write_string(1, "backend")?;
+            os.write_uint32(2, DISCOVERY_TYPE_EDS)?;
+            os.write_bytes(3, &message(|os| os.write_string(2, "backend-eds")))
+        });
+        discovery_response(CDS_TYPE_URL, version_info, nonce, &[cluster])
+    }
+
+    fn eds_response(version_info: &str, nonce: &str, host: &str) -> Vec<u8> {
+        let assignment = message(|os| {
+            os.write_string(1, "backend-eds")?;
+            os.write_bytes(2, &message(|os| os.write_bytes(2, &lb_endpoint(host, 80, HEALTH_HEALTHY, None))))
+        });
+        discovery_response(EDS_TYPE_URL, version_info, nonce, &[assignment])
+    }
+
+    /// Fields of `DiscoveryRequest` checked by test.
+    #[derive(Debug, PartialEq)]
+    struct Request {
+        type_url: String,
+        resource_names: Vec<String>,
+        version_info: String,
+        nonce: String,
+        nack: bool,
+    }
+
+    fn request(type_url: &str, resource_name: &str, version_info: &str, nonce: &str, nack: bool) -> Request {
+        Request {
+            type_url: type_url.to_owned(),
+            resource_names: vec![resource_name.to_owned()],
+            version_info: version_info.to_owned(),
+            nonce: nonce.to_owned(),
+            nack: nack,
+        }
+    }
+
+    fn parse_request(bytes: &[u8]) -> Request {
+        let mut r = request("", "", "", "", false);
+        r.resource_names.clear();
+        read_fields(bytes, |field, _, is| {
+            match field {
+                1 => r.version_info = is.read_string()?,
+                3 => r.resource_names.push(is.read_string()?),
+                4 => r.type_url = is.read_string()?,
+                5 => r.nonce = is.read_string()?,
+                6 => {
+                    is.read_bytes()?;
+                    r.nack = true;
+                }
+                _ => return Ok(false),
+            }
+            Ok(true)
+        }).unwrap();
+        r
+    }
+
+    /// Responses of management server to `n`-th request of `stream`,
+    /// `None` to close the stream.
+    fn script(stream: usize, n: usize) -> Option<Vec<Vec<u8>>> {
+        match (stream, n) {
+            (0, 0) => Some(vec![cds_response("1", "c1")]),
+            (0, 2) => Some(vec![discovery_response(EDS_TYPE_URL, "bad", "e1", &[vec![0xff]])]),
+            (0, 3) => Some(vec![eds_response("2", "e2", "10.0.0.1")]),
+            (0, 4) => None,
+            (1, 0) => Some(vec![cds_response("1", "c3")]),
+            (1, 2) => Some(vec![eds_response("3", "e4", "10.0.0.2")]),
+            _ => Some(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn ads_stream() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let streams = Arc::new(AtomicUsize::new(0));
+
+        let mut server = ServerBuilder::new_plain();
+        server.http.set_port(0);
+        {
+            let requests = requests.clone();
+            let method = Arc::new(MethodDescriptor {
+                name: ADS_METHOD.to_owned(),
+                streaming: GrpcStreaming::Bidi,
+                options: Default::default(),
+                req_marshaller: Box::new(MarshallerBytes),
+                resp_marshaller: Box::new(MarshallerBytes),
+            });
+            server.add_service(ServerServiceDefinition::new(
+                "/envoy.service.discovery.v3.AggregatedDiscoveryService",
+                vec![ServerMethod::new(method, MethodHandlerBidi::new(move |_o, req: StreamingRequest<Vec<u8>>| {
+                    let stream_index = streams.fetch_add(1, Ordering::SeqCst);
+                    let requests = requests.clone();
+                    let mut n = 0;
+                    let responses = req.0
+                        .map(move |req| {
+                            requests.lock().unwrap().push((stream_index, parse_request(&req)));
+                            n += 1;
+                            script(stream_index, n - 1)
+                        })
+                        .take_while(|responses| Ok(responses.is_some()))
+                        .map(|responses| stream::iter_ok(responses.unwrap()))
+                        .flatten();
+                    StreamingResponse::no_metadata(responses)
+                }))]));
+        }
+        let server = server.build().expect("server");
+        let port = server.local_addr().port().expect("port");
+
+        let client = Client::new_plain("127.0.0.1", port, ClientConf::new()).expect("client");
+
+        let mut updates = Vec::new();
+        watch_cluster_resubscribing(
+            &client, "node", "backend", Duration::from_millis(10), || false,
+            |addrs| {
+                updates.push(addrs);
+                updates.len() < 2
+            });
+
+        assert_eq!(
+            vec![
+                vec![WeightedAddr::new("10.0.0.1:80".parse().unwrap())],
+                vec![WeightedAddr::new("10.0.0.2:80".parse().unwrap())],
+            ],
+            updates);
+
+        // ACK of the last response may be not received yet
+        let requests: Vec<_> = requests.lock().unwrap().drain(..).take(8).collect();
+        assert_eq!(
+            vec![
+                (0, request(CDS_TYPE_URL, "backend", "", "", false)),
+                (0, request(CDS_TYPE_URL, "backend", "1", "c1", false)),
+                (0, request(EDS_TYPE_URL, "backend-eds", "", "", false)),
+                (0, request(EDS_TYPE_URL, "backend-eds", "", "e1", true)),
+                (0, request(EDS_TYPE_URL, "backend-eds", "2", "e2", false)),
+                // subscribed again on new stream after the first one ended
+                (1, request(CDS_TYPE_URL, "backend", "", "", false)),
+                (1, request(CDS_TYPE_URL, "backend", "1", "c3", false)),
+                (1, request(EDS_TYPE_URL, "backend-eds", "", "", false)),
+            ],
+            requests);
+    }
+
+    #[test]
+    fn cluster_of_other_type_is_rejected() {
+        let cluster = message(|os| os.write_string(1, "backend"));
+        let response = parse_discovery_response(&discovery_response(CDS_TYPE_URL, "1", "c1", &[cluster])).unwrap();
+        assert!(cluster_service_name(&response, "backend").is_err());
+        assert_eq!(None, cluster_service_name(&response, "other").unwrap());
+    }
+}
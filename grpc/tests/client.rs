@@ -1,13 +1,28 @@
 #[macro_use]
 extern crate log;
 extern crate futures;
+extern crate bytes;
+extern crate env_logger;
 
 extern crate grpc;
 
 mod test_misc;
 
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::Future;
+use futures::Stream;
+
 use grpc::*;
-use grpc::rt::GrpcStreaming;
+use grpc::rt::*;
 
 use test_misc::*;
 
@@ -25,3 +40,549 @@ fn server_is_not_running() {
         assert!(result.is_err(), result);
     }
 }
+
+fn named_server(name: &'static str) -> Server {
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_service(ServerServiceDefinition::new("/test", vec![
+        ServerMethod::new(
+            string_string_method("/test/Name", GrpcStreaming::Unary),
+            MethodHandlerUnary::new(move |_o, _s| SingleResponse::completed(name.to_owned()))),
+    ]));
+    server.build().expect("server")
+}
+
+fn call_name(client: &Client) -> String {
+    client.call_unary(
+        RequestOptions::new(),
+        String::new(),
+        string_string_method("/test/Name", GrpcStreaming::Unary))
+            .wait_drop_metadata()
+            .unwrap()
+}
+
+fn write_endpoints(path: &Path, server: &Server) {
+    let port = server.local_addr().port().expect("port");
+    let json = format!(r#"{{"backend": ["{}:{}"]}}"#, BIND_HOST, port);
+    // rename, so resolver never reads partially written file
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, json).unwrap();
+    fs::rename(&tmp, path).unwrap();
+}
+
+#[test]
+fn file_resolver_updates() {
+    drop(env_logger::try_init());
+
+    let a = named_server("a");
+    let b = named_server("b");
+
+    let path = env::temp_dir().join(format!("grpc-endpoints-{}.json", process::id()));
+    write_endpoints(&path, &a);
+
+    let mut resolver = FileResolver::new(&path);
+    resolver.set_poll_interval(Duration::from_millis(20));
+    let mut conf = ClientConf::new();
+    conf.resolver = Some(Arc::new(resolver));
+    let client = Client::new_plain("backend", 0, conf).expect("client");
+
+    assert_eq!("a", call_name(&client));
+
+    write_endpoints(&path, &b);
+    let start = Instant::now();
+    while call_name(&client) != "b" {
+        assert!(start.elapsed() < Duration::from_secs(10), "addresses not updated");
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    drop(fs::remove_file(&path));
+}
+
+#[test]
+fn outlier_ejected() {
+    drop(env_logger::try_init());
+
+    let healthy = named_server("healthy");
+    let mut failing = ServerBuilder::new_plain();
+    failing.http.set_port(0);
+    failing.add_unary_handler("/test/Name", |_o, _req| {
+        SingleResponse::err(Error::GrpcMessage(GrpcMessageError {
+            grpc_status: GrpcStatus::Unavailable as i32,
+            grpc_message: "failing".to_owned(),
+        }))
+    });
+    let failing = failing.build().expect("server");
+
+    let path = env::temp_dir().join(format!("grpc-outlier-{}.json", process::id()));
+    fs::write(&path, format!(
+        r#"{{"backend": ["{}:{}", "{}:{}"]}}"#,
+        BIND_HOST, failing.local_addr().port().expect("port"),
+        BIND_HOST, healthy.local_addr().port().expect("port"))).unwrap();
+
+    let mut conf = ClientConf::new();
+    conf.resolver = Some(Arc::new(FileResolver::new(&path)));
+    conf.balancing_policy = BalancingPolicy::RoundRobin;
+    conf.outlier_detection = Some(OutlierDetectionConf {
+        consecutive_failures: Some(1),
+        max_ejection_percent: 50,
+        ..Default::default()
+    });
+    let client = Client::new_plain("backend", 0, conf).expect("client");
+
+    let call = || {
+        client.call_unary(
+            RequestOptions::new(),
+            String::new(),
+            string_string_method("/test/Name", GrpcStreaming::Unary))
+                .wait_drop_metadata()
+    };
+
+    // the first call to failing server ejects it
+    let mut failures = 0;
+    for _ in 0..10 {
+        if call().is_err() {
+            failures += 1;
+        }
+    }
+    assert_eq!(1, failures);
+
+    drop(fs::remove_file(&path));
+}
+
+#[test]
+fn ring_hash_affinity() {
+    drop(env_logger::try_init());
+
+    let servers = vec![named_server("a"), named_server("b"), named_server("c")];
+
+    let path = env::temp_dir().join(format!("grpc-ring-hash-{}.json", process::id()));
+    let addrs: Vec<_> = servers.iter()
+        .map(|s| format!(r#""{}:{}""#, BIND_HOST, s.local_addr().port().expect("port")))
+        .collect();
+    fs::write(&path, format!(r#"{{"backend": [{}]}}"#, addrs.join(", "))).unwrap();
+
+    let mut conf = ClientConf::new();
+    conf.resolver = Some(Arc::new(FileResolver::new(&path)));
+    conf.service_config = Some(
+        r#"{"loadBalancingConfig": [{"ring_hash_experimental": {"requestHashHeader": "session-id"}}]}"#
+            .to_owned());
+    let client = Client::new_plain("backend", 0, conf).expect("client");
+
+    let call = |session: &str| {
+        let mut options = RequestOptions::new();
+        options.metadata.add(MetadataKey::from("session-id"), session.to_owned().into());
+        client.call_unary(
+            options,
+            String::new(),
+            string_string_method("/test/Name", GrpcStreaming::Unary))
+                .wait_drop_metadata()
+                .unwrap()
+    };
+
+    for session in &["s1", "s2", "s3", "s4"] {
+        let first = call(session);
+        for _ in 0..5 {
+            assert_eq!(first, call(session));
+        }
+    }
+
+    drop(fs::remove_file(&path));
+}
+
+#[test]
+fn warm_up() {
+    let server = named_server("warm");
+    let port = server.local_addr().port().expect("port");
+
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    client.warm_up().wait().expect("warm up");
+    assert_eq!("warm", call_name(&client));
+
+    let mut conf = ClientConf::new();
+    conf.eager_connect = true;
+    let client = Client::new_plain(BIND_HOST, port, conf).expect("client");
+    assert_eq!("warm", call_name(&client));
+}
+
+#[test]
+fn eager_connect_does_not_block() {
+    // accepted by OS, but nobody answers HTTP/2 handshake
+    let listener = TcpListener::bind((BIND_HOST, 0)).expect("bind");
+    let port = listener.local_addr().expect("local_addr").port();
+
+    let mut conf = ClientConf::new();
+    conf.eager_connect = true;
+    conf.connect_timeout = Some(Duration::from_secs(1));
+    conf.handshake_timeout = Some(Duration::from_secs(3));
+    let start = Instant::now();
+    let _client = Client::new_plain(BIND_HOST, port, conf).expect("client");
+    assert!(start.elapsed() < Duration::from_secs(1), "{:?}", start.elapsed());
+}
+
+#[test]
+fn unavailable_error_describes_subchannel() {
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_unary_handler("/test/Name", |_o, _req| {
+        SingleResponse::err(Error::GrpcMessage(GrpcMessageError {
+            grpc_status: GrpcStatus::Unavailable as i32,
+            grpc_message: "overloaded".to_owned(),
+        }))
+    });
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    let r = client.call_unary(
+        RequestOptions::new(),
+        String::new(),
+        string_string_method("/test/Name", GrpcStreaming::Unary))
+            .wait_drop_metadata();
+    match r {
+        Err(Error::GrpcMessage(e)) => {
+            assert_eq!(GrpcStatus::Unavailable as i32, e.grpc_status);
+            assert!(e.grpc_message.starts_with("overloaded; "), "{}", e.grpc_message);
+            assert!(e.grpc_message.contains(&format!("subchannel: {}:{}", BIND_HOST, port)),
+                "{}", e.grpc_message);
+            assert!(e.grpc_message.contains("state: READY"), "{}", e.grpc_message);
+        }
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn connection_refused_is_transport_error() {
+    // nothing listens on this port
+    let channel = Channel::new_plain(BIND_HOST, 2, ClientConf::new()).expect("channel");
+    let client = Client::with_channel(channel);
+    let r = client.call_unary(
+        RequestOptions::new(),
+        String::new(),
+        string_string_method("/test/Name", GrpcStreaming::Unary))
+            .wait_drop_metadata();
+    match r {
+        Err(e) => {
+            assert_eq!(ErrorKind::Transport, e.kind(), "{:?}", e);
+            assert!(format!("{}", e).contains(&format!("subchannel: {}:2", BIND_HOST)), "{}", e);
+            assert!(std::error::Error::source(&e).is_some(), "{:?}", e);
+        }
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn connect_timeout() {
+    drop(env_logger::try_init());
+
+    let mut conf = ClientConf::new();
+    conf.connect_timeout = Some(Duration::from_millis(200));
+    // non-routable address: connection attempts hang or fail quickly
+    let client = Client::new_plain("10.255.255.1", 1, conf).expect("client");
+
+    let start = Instant::now();
+    let r = client.call_unary(
+        RequestOptions::new().with_timeout(Duration::from_secs(10)),
+        String::new(),
+        string_string_method("/test/Name", GrpcStreaming::Unary))
+            .wait_drop_metadata();
+    match r {
+        Err(ref e) if e.grpc_status() == GrpcStatus::Unavailable as i32 => {}
+        r => panic!("expecting connection failure: {:?}", r),
+    }
+    assert!(start.elapsed() < Duration::from_secs(5), "{:?}", start.elapsed());
+}
+
+#[test]
+fn re_resolve_when_all_addresses_fail() {
+    drop(env_logger::try_init());
+
+    let a = named_server("a");
+    let b = named_server("b");
+
+    let path = env::temp_dir().join(format!("grpc-re-resolve-{}.json", process::id()));
+    write_endpoints(&path, &a);
+
+    // watch doesn't notice changes during the test
+    let mut resolver = FileResolver::new(&path);
+    resolver.set_poll_interval(Duration::from_secs(3600));
+    let mut conf = ClientConf::new();
+    conf.resolver = Some(Arc::new(resolver));
+    conf.min_re_resolution_interval = Some(Duration::from_millis(0));
+    let client = Client::new_plain("backend", 0, conf).expect("client");
+
+    assert_eq!("a", call_name(&client));
+
+    write_endpoints(&path, &b);
+    drop(a);
+
+    let start = Instant::now();
+    loop {
+        let r = client.call_unary(
+            RequestOptions::new(),
+            String::new(),
+            string_string_method("/test/Name", GrpcStreaming::Unary))
+                .wait_drop_metadata();
+        if let Ok(ref name) = r {
+            if name == "b" {
+                break;
+            }
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "not resolved again: {:?}", r);
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    drop(fs::remove_file(&path));
+}
+
+#[test]
+fn corked_client_streaming() {
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_service(ServerServiceDefinition::new("/test", vec![
+        ServerMethod::new(
+            string_string_method("/test/Concat", GrpcStreaming::ClientStreaming),
+            MethodHandlerClientStreaming::new(|_o, req: StreamingRequest<String>| {
+                SingleResponse::no_metadata(req.0.fold(String::new(), |mut s, message| {
+                    s.push_str(&message);
+                    futures::finished::<_, Error>(s)
+                }))
+            })),
+    ]));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let mut conf = ClientConf::new();
+    conf.write_strategy = WriteStrategy::batch(Duration::from_millis(10));
+    let client = Client::new_plain(BIND_HOST, port, conf).expect("client");
+
+    let cork = Cork::new();
+    cork.cork();
+    let mut options = RequestOptions::new();
+    options.cork = Some(cork.clone());
+
+    let (tx, rx) = futures::sync::mpsc::unbounded();
+    let response = client.call_client_streaming(
+        options,
+        StreamingRequest::new(rx.map_err(|()| Error::Other("unreachable"))),
+        string_string_method("/test/Concat", GrpcStreaming::ClientStreaming));
+
+    for s in &["a", "b", "c"] {
+        tx.unbounded_send(s.to_string()).unwrap();
+    }
+    cork.uncork();
+    drop(tx);
+
+    assert_eq!("abc", response.wait_drop_metadata().unwrap());
+}
+
+#[test]
+fn request_stream_error_is_preserved() {
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_service(ServerServiceDefinition::new("/test", vec![
+        ServerMethod::new(
+            string_string_method("/test/Count", GrpcStreaming::ClientStreaming),
+            MethodHandlerClientStreaming::new(|_o, req: StreamingRequest<String>| {
+                SingleResponse::no_metadata(req.0.fold(0, |n, _| futures::finished::<_, Error>(n + 1))
+                    .map(|n: u32| n.to_string()))
+            })),
+    ]));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+
+    let req = futures::stream::iter_result(vec![
+        Ok("a".to_owned()),
+        Err(Error::GrpcMessage(GrpcMessageError {
+            grpc_status: GrpcStatus::Argument as i32,
+            grpc_message: "bad request".to_owned(),
+        })),
+    ]);
+    let response = client.call_client_streaming(
+        RequestOptions::new(),
+        StreamingRequest::new(req),
+        string_string_method("/test/Count", GrpcStreaming::ClientStreaming));
+
+    match response.wait_drop_metadata() {
+        Err(ref e) if e.grpc_status() == GrpcStatus::Argument as i32 => {}
+        r => panic!("expecting request error: {:?}", r),
+    }
+}
+
+#[derive(Debug, Default)]
+struct RecordingStatsHandler {
+    events: std::sync::Mutex<Vec<String>>,
+}
+
+impl StatsHandler for RecordingStatsHandler {
+    fn handle(&self, event: &CallEvent) {
+        self.events.lock().unwrap().push(format!("{:?}", event));
+    }
+}
+
+#[test]
+fn stats_handler() {
+    let server = named_server("stats");
+    let port = server.local_addr().port().expect("port");
+
+    let handler = Arc::new(RecordingStatsHandler::default());
+    let mut conf = ClientConf::new();
+    conf.stats_handlers.push(handler.clone());
+    let client = Client::new_plain(BIND_HOST, port, conf).expect("client");
+    assert_eq!("stats", call_name(&client));
+
+    let events = handler.events.lock().unwrap().clone();
+    // DATA frames may be split
+    let (data, events): (Vec<String>, Vec<String>) =
+        events.into_iter().partition(|e| e.starts_with("ResponseData"));
+    assert!(!data.is_empty());
+    assert_eq!(vec![
+        "Start { method: \"/test/Name\" }",
+        "RequestMessage { bytes: 0, wire_bytes: 5 }",
+        "ResponseHeaders",
+        "ResponseMessage { bytes: 5 }",
+        "End { error: None }",
+    ], events);
+}
+
+#[test]
+fn shutdown() {
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_unary_handler("/test/Hang", |_o, _req| {
+        SingleResponse::no_metadata(futures::future::empty())
+    });
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    let call = || {
+        client.call_unary(
+            RequestOptions::new(),
+            String::new(),
+            string_string_method("/test/Hang", GrpcStreaming::Unary))
+                .drop_metadata()
+    };
+    let is_shut_down = |r: Result<String, Error>| match r {
+        Err(Error::GrpcMessage(ref e)) => {
+            e.grpc_status == GrpcStatus::Unavailable as i32 && e.grpc_message == "client shut down"
+        }
+        _ => false,
+    };
+
+    let in_progress = call();
+    client.warm_up().wait().expect("warm up");
+    client.shutdown();
+    assert!(is_shut_down(in_progress.wait()));
+    assert!(is_shut_down(call().wait()));
+}
+
+/// Transport which sends request messages back as response.
+struct EchoTransport;
+
+impl ClientTransport for EchoTransport {
+    fn call(&self, _method: &str, _o: RequestOptions, req: StreamingRequest<bytes::Bytes>)
+        -> StreamingResponse<bytes::Bytes>
+    {
+        StreamingResponse::no_metadata(req.0)
+    }
+}
+
+#[test]
+fn custom_transport() {
+    let channel = Channel::with_transport(Arc::new(EchoTransport));
+    let client = Client::with_channel(channel);
+    client.warm_up().wait().expect("warm up");
+    let r = client.call_unary(
+        RequestOptions::new(),
+        "echo".to_owned(),
+        string_string_method("/test/Echo", GrpcStreaming::Unary))
+            .wait_drop_metadata();
+    assert_eq!("echo", r.unwrap());
+}
+
+#[test]
+fn record_and_replay() {
+    let path = env::temp_dir().join(format!("grpc-session-{}.jsonl", process::id()));
+
+    {
+        let server = named_server("recorded");
+        let port = server.local_addr().port().expect("port");
+        let channel = Channel::new_plain(BIND_HOST, port, ClientConf::new()).expect("channel");
+        let recording = RecordingTransport::create(channel.transport(), &path).expect("create");
+        let client = Client::with_channel(Channel::with_transport(Arc::new(recording)));
+        assert_eq!("recorded", call_name(&client));
+    }
+
+    // server is stopped
+    let replay = ReplayTransport::open(&path).expect("open");
+    let client = Client::with_channel(Channel::with_transport(Arc::new(replay)));
+    assert_eq!("recorded", call_name(&client));
+    match client.call_unary(
+        RequestOptions::new(),
+        String::new(),
+        string_string_method("/test/Name", GrpcStreaming::Unary))
+            .wait_drop_metadata()
+    {
+        Err(Error::GrpcMessage(ref e)) if e.grpc_status == GrpcStatus::Unimplemented as i32 => {}
+        r => panic!("{:?}", r),
+    }
+
+    drop(fs::remove_file(&path));
+}
+
+#[test]
+fn call_outlives_client() {
+    let server = named_server("spawned");
+    let port = server.local_addr().port().expect("port");
+
+    let response = {
+        let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+        client.call_unary(
+            RequestOptions::new(),
+            String::new(),
+            string_string_method("/test/Name", GrpcStreaming::Unary))
+                .drop_metadata()
+    };
+
+    let thread = thread::spawn(move || response.wait());
+    assert_eq!("spawned", thread.join().unwrap().unwrap());
+}
+
+#[test]
+fn channel_pool() {
+    drop(env_logger::try_init());
+
+    let a = named_server("a");
+    let b = named_server("b");
+    let port_a = a.local_addr().port().expect("port");
+    let port_b = b.local_addr().port().expect("port");
+
+    let pool = ChannelPool::new(Default::default())
+        .with_idle_timeout(Duration::from_millis(0));
+
+    let a1 = Client::with_channel(pool.channel_plain(BIND_HOST, port_a).unwrap());
+    let a2 = Client::with_channel(pool.channel_plain(BIND_HOST, port_a).unwrap());
+    let b1 = Client::with_channel(pool.channel_plain(BIND_HOST, port_b).unwrap());
+    assert_eq!(2, pool.len());
+
+    assert_eq!("a", call_name(&a1));
+    assert_eq!("a", call_name(&a2));
+    assert_eq!("b", call_name(&b1));
+    assert_eq!(1, a1.channel().connections().len());
+
+    // channels used by clients are not evicted
+    assert_eq!(0, pool.evict_idle());
+
+    drop(a1);
+    assert_eq!(0, pool.evict_idle());
+    drop(a2);
+    assert_eq!(1, pool.evict_idle());
+    assert_eq!(1, pool.len());
+
+    // evicted channel is recreated
+    let a3 = Client::with_channel(pool.channel_plain(BIND_HOST, port_a).unwrap());
+    assert_eq!("a", call_name(&a3));
+    assert_eq!(2, pool.len());
+}
@@ -0,0 +1,353 @@
+extern crate futures;
+extern crate grpc;
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+mod test_misc;
+
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use grpc::*;
+use grpc::rt::*;
+
+use test_misc::*;
+
+
+/// Not really a compression, but enough to check negotiation
+#[derive(Debug, Default)]
+struct XorCodec {
+    compressed: AtomicUsize,
+    decompressed: AtomicUsize,
+}
+
+impl Codec for XorCodec {
+    fn name(&self) -> &str {
+        "xor"
+    }
+
+    fn compress(&self, data: &[u8], out: &mut Write) -> io::Result<()> {
+        self.compressed.fetch_add(1, Ordering::SeqCst);
+        let xored: Vec<u8> = data.iter().map(|b| b ^ 0x55).collect();
+        out.write_all(&xored)
+    }
+
+    fn decompress(&self, data: &[u8], out: &mut Write) -> io::Result<()> {
+        self.decompressed.fetch_add(1, Ordering::SeqCst);
+        let xored: Vec<u8> = data.iter().map(|b| b ^ 0x55).collect();
+        out.write_all(&xored)
+    }
+}
+
+fn echo_server(codec: Arc<XorCodec>, compression: Option<&str>) -> Server {
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.conf.codecs.push(codec);
+    server.conf.compression = compression.map(|c| c.to_owned());
+    server.add_service(ServerServiceDefinition::new("/test", vec![
+        ServerMethod::new(
+            string_string_method("/test/Echo", GrpcStreaming::Unary),
+            MethodHandlerUnary::new(|_o, s| SingleResponse::completed(s))),
+    ]));
+    server.build().expect("server")
+}
+
+fn call_echo(client: &Client) -> Result<String> {
+    client.call_unary(
+        RequestOptions::new(),
+        "abc".to_owned(),
+        string_string_method("/test/Echo", GrpcStreaming::Unary))
+            .wait_drop_metadata()
+}
+
+#[test]
+fn compressed_both_ways() {
+    drop(env_logger::try_init());
+
+    let server_codec = Arc::new(XorCodec::default());
+    let server = echo_server(server_codec.clone(), Some("xor"));
+    let port = server.local_addr().port().expect("port");
+
+    let client_codec = Arc::new(XorCodec::default());
+    let mut conf = ClientConf::new();
+    conf.codecs.push(client_codec.clone());
+    conf.compression = Some("xor".to_owned());
+    let client = Client::new_plain(BIND_HOST, port, conf).expect("client");
+
+    assert_eq!("abc", call_echo(&client).unwrap());
+
+    assert_eq!(1, client_codec.compressed.load(Ordering::SeqCst));
+    assert_eq!(1, server_codec.decompressed.load(Ordering::SeqCst));
+    assert_eq!(1, server_codec.compressed.load(Ordering::SeqCst));
+    assert_eq!(1, client_codec.decompressed.load(Ordering::SeqCst));
+}
+
+#[test]
+fn small_messages_uncompressed() {
+    drop(env_logger::try_init());
+
+    let server_codec = Arc::new(XorCodec::default());
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.conf.codecs.push(server_codec.clone());
+    server.conf.compression = Some("xor".to_owned());
+    server.conf.compression_min_message_size = Some(5);
+    server.add_service(ServerServiceDefinition::new("/test", vec![
+        ServerMethod::new(
+            string_string_method("/test/Split", GrpcStreaming::ServerStreaming),
+            MethodHandlerServerStreaming::new(|_o, s: String| {
+                // a large message and a small one
+                StreamingResponse::iter(vec![s.clone(), s[..1].to_owned()].into_iter())
+            })),
+    ]));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let client_codec = Arc::new(XorCodec::default());
+    let mut conf = ClientConf::new();
+    conf.codecs.push(client_codec.clone());
+    conf.compression = Some("xor".to_owned());
+    conf.compression_min_message_size = Some(100);
+    let client = Client::new_plain(BIND_HOST, port, conf).expect("client");
+
+    let responses: Vec<String> = client.call_server_streaming(
+        RequestOptions::new(),
+        "abcdef".to_owned(),
+        string_string_method("/test/Split", GrpcStreaming::ServerStreaming))
+            .wait_drop_metadata()
+            .collect::<Result<_>>()
+            .unwrap();
+    assert_eq!(vec!["abcdef".to_owned(), "a".to_owned()], responses);
+
+    assert_eq!(0, client_codec.compressed.load(Ordering::SeqCst));
+    assert_eq!(1, server_codec.compressed.load(Ordering::SeqCst));
+    assert_eq!(1, client_codec.decompressed.load(Ordering::SeqCst));
+}
+
+#[test]
+fn compression_per_message() {
+    drop(env_logger::try_init());
+
+    // snapshots are compressed, deltas are not
+    let is_snapshot = || MessageCompression::new(|m| m.len() > 3);
+
+    let server_codec = Arc::new(XorCodec::default());
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.conf.codecs.push(server_codec.clone());
+    server.conf.compression = Some("xor".to_owned());
+    server.conf.message_compression = Some(is_snapshot());
+    server.add_service(ServerServiceDefinition::new("/test", vec![
+        ServerMethod::new(
+            string_string_method("/test/Echo", GrpcStreaming::Bidi),
+            MethodHandlerBidi::new(|_o, req: StreamingRequest<String>| {
+                StreamingResponse::no_metadata(req.0)
+            })),
+    ]));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let client_codec = Arc::new(XorCodec::default());
+    let mut conf = ClientConf::new();
+    conf.codecs.push(client_codec.clone());
+    conf.compression = Some("xor".to_owned());
+    let client = Client::new_plain(BIND_HOST, port, conf).expect("client");
+
+    let messages = vec!["snapshot".to_owned(), "d1".to_owned(), "d2".to_owned()];
+    let responses: Vec<String> = client.call_bidi(
+        RequestOptions::new().with_message_compression(is_snapshot()),
+        StreamingRequest::iter(messages.clone()),
+        string_string_method("/test/Echo", GrpcStreaming::Bidi))
+            .wait_drop_metadata()
+            .collect::<Result<_>>()
+            .unwrap();
+    assert_eq!(messages, responses);
+
+    assert_eq!(1, client_codec.compressed.load(Ordering::SeqCst));
+    assert_eq!(1, server_codec.decompressed.load(Ordering::SeqCst));
+    assert_eq!(1, server_codec.compressed.load(Ordering::SeqCst));
+    assert_eq!(1, client_codec.decompressed.load(Ordering::SeqCst));
+}
+
+#[test]
+fn compression_per_call() {
+    drop(env_logger::try_init());
+
+    let server = echo_server(Arc::new(XorCodec::default()), None);
+    let port = server.local_addr().port().expect("port");
+
+    let client_codec = Arc::new(XorCodec::default());
+    let mut conf = ClientConf::new();
+    conf.codecs.push(client_codec.clone());
+    let client = Client::new_plain(BIND_HOST, port, conf).expect("client");
+    let method = string_string_method("/test/Echo", GrpcStreaming::Unary);
+
+    let r = client.call_unary(
+        RequestOptions::new().with_compression("xor"), "abc".to_owned(), method.clone())
+            .wait_drop_metadata();
+    assert_eq!("abc", r.unwrap());
+    assert_eq!(1, client_codec.compressed.load(Ordering::SeqCst));
+
+    let r = client.call_unary(
+        RequestOptions::new().with_compression("unknown"), "abc".to_owned(), method.clone())
+            .wait_drop_metadata();
+    match r {
+        Err(Error::GrpcMessage(GrpcMessageError { grpc_status, .. })) => {
+            assert_eq!(GrpcStatus::Unimplemented as i32, grpc_status);
+        }
+        r => panic!("expecting error, got {:?}", r),
+    }
+}
+
+#[test]
+fn compression_per_response() {
+    drop(env_logger::try_init());
+
+    let server_codec = Arc::new(XorCodec::default());
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.conf.codecs.push(server_codec.clone());
+    server.add_service(ServerServiceDefinition::new("/test", vec![
+        ServerMethod::new(
+            string_string_method("/test/Echo", GrpcStreaming::Unary),
+            MethodHandlerUnary::new(|_o, s: String| {
+                let mut metadata = Metadata::new();
+                metadata.add(MetadataKey::from("x-compression"), s.clone().into());
+                let mut trailers = Metadata::new();
+                trailers.add(MetadataKey::from("x-trailer"), "t".into());
+                Response::new(s.clone())
+                    .with_metadata(metadata)
+                    .with_trailers(trailers)
+                    .with_compression(&s)
+                    .into()
+            })),
+    ]));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let client_codec = Arc::new(XorCodec::default());
+    let mut conf = ClientConf::new();
+    conf.codecs.push(client_codec.clone());
+    let client = Client::new_plain(BIND_HOST, port, conf).expect("client");
+    let method = string_string_method("/test/Echo", GrpcStreaming::Unary);
+
+    let (metadata, r, trailers) = client.call_unary(RequestOptions::new(), "xor".to_owned(), method.clone())
+        .wait()
+        .unwrap();
+    assert_eq!("xor", r);
+    assert_eq!(Some(&b"xor"[..]), metadata.get("x-compression"));
+    assert_eq!(None, metadata.get("grpc-encoding"));
+    assert_eq!(Some(&b"t"[..]), trailers.get("x-trailer"));
+    assert_eq!(1, server_codec.compressed.load(Ordering::SeqCst));
+    assert_eq!(1, client_codec.decompressed.load(Ordering::SeqCst));
+
+    let r = client.call_unary(RequestOptions::new(), "identity".to_owned(), method.clone())
+        .wait_drop_metadata();
+    assert_eq!("identity", r.unwrap());
+    assert_eq!(1, server_codec.compressed.load(Ordering::SeqCst));
+}
+
+#[test]
+fn server_does_not_compress_for_client_without_codec() {
+    drop(env_logger::try_init());
+
+    let server_codec = Arc::new(XorCodec::default());
+    let server = echo_server(server_codec.clone(), Some("xor"));
+    let port = server.local_addr().port().expect("port");
+
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+
+    assert_eq!("abc", call_echo(&client).unwrap());
+
+    assert_eq!(0, server_codec.compressed.load(Ordering::SeqCst));
+}
+
+#[test]
+fn unknown_request_encoding() {
+    drop(env_logger::try_init());
+
+    let server = echo_server(Arc::new(XorCodec::default()), None);
+    let port = server.local_addr().port().expect("port");
+
+    // the same codec under the name server doesn't know
+    let mut conf = ClientConf::new();
+    conf.codecs.push(Arc::new(RenamedCodec(XorCodec::default())));
+    conf.compression = Some("renamed".to_owned());
+    let client = Client::new_plain(BIND_HOST, port, conf).expect("client");
+
+    match call_echo(&client) {
+        Err(Error::GrpcMessage(GrpcMessageError { grpc_status, .. })) => {
+            assert_eq!(GrpcStatus::Unimplemented as i32, grpc_status);
+        }
+        r => panic!("expecting error, got {:?}", r),
+    }
+}
+
+#[derive(Debug)]
+struct RenamedCodec(XorCodec);
+
+impl Codec for RenamedCodec {
+    fn name(&self) -> &str {
+        "renamed"
+    }
+
+    fn compress(&self, data: &[u8], out: &mut Write) -> io::Result<()> {
+        self.0.compress(data, out)
+    }
+
+    fn decompress(&self, data: &[u8], out: &mut Write) -> io::Result<()> {
+        self.0.decompress(data, out)
+    }
+}
+
+/// Echo large message compressed with built-in codec both ways
+#[allow(dead_code)]
+fn builtin_codec_round_trip(name: &str) {
+    drop(env_logger::try_init());
+
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.conf.compression = Some(name.to_owned());
+    server.add_service(ServerServiceDefinition::new("/test", vec![
+        ServerMethod::new(
+            string_string_method("/test/Echo", GrpcStreaming::Unary),
+            MethodHandlerUnary::new(|_o, s| SingleResponse::completed(s))),
+    ]));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let mut conf = ClientConf::new();
+    conf.compression = Some(name.to_owned());
+    let client = Client::new_plain(BIND_HOST, port, conf).expect("client");
+
+    // unknown codec would fail the call with `UNIMPLEMENTED`
+    let message = "abcdefgh".repeat(1000);
+    let r = client.call_unary(
+        RequestOptions::new(),
+        message.clone(),
+        string_string_method("/test/Echo", GrpcStreaming::Unary))
+            .wait_drop_metadata();
+    assert_eq!(message, r.unwrap());
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzip() {
+    builtin_codec_round_trip("gzip");
+}
+
+#[cfg(feature = "snappy")]
+#[test]
+fn snappy() {
+    builtin_codec_round_trip("snappy");
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd() {
+    builtin_codec_round_trip("zstd");
+}
@@ -0,0 +1,231 @@
+//! Flow control of client and server with slow readers and writers.
+//!
+//! Windows are managed by `httpbis` and cannot be configured from here,
+//! so messages are made large relative to the default 64KiB window:
+//! a few messages fill it. Tests assert that a producer can't run
+//! more than `MAX_AHEAD` messages ahead of a slow consumer,
+//! and that calls complete once the consumer catches up.
+
+extern crate futures;
+extern crate grpc;
+extern crate env_logger;
+
+mod test_misc;
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use futures::future::Future;
+use futures::stream::Stream;
+use futures::sync::oneshot;
+
+use grpc::*;
+use grpc::rt::*;
+
+use test_misc::*;
+
+
+const MESSAGE_SIZE: usize = 16 * 1024;
+const MESSAGES: usize = 512;
+/// Messages which may be buffered between producer and consumer:
+/// windows of both sides plus transport buffers, well under `MESSAGES`.
+const MAX_AHEAD: usize = 2 * 1024 * 1024 / MESSAGE_SIZE;
+
+
+/// Counts messages produced and consumed, remembering maximal distance.
+#[derive(Default)]
+struct Progress {
+    produced: AtomicUsize,
+    consumed: AtomicUsize,
+    max_ahead: AtomicUsize,
+}
+
+impl Progress {
+    fn produce(&self) -> String {
+        self.produced.fetch_add(1, Ordering::SeqCst);
+        "x".repeat(MESSAGE_SIZE)
+    }
+
+    fn consume(&self, message: &str) {
+        assert_eq!(MESSAGE_SIZE, message.len());
+        let consumed = self.consumed.fetch_add(1, Ordering::SeqCst) + 1;
+        let ahead = self.produced.load(Ordering::SeqCst).saturating_sub(consumed);
+        let mut max_ahead = self.max_ahead.load(Ordering::SeqCst);
+        while ahead > max_ahead {
+            match self.max_ahead.compare_exchange(max_ahead, ahead, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(..) => break,
+                Err(current) => max_ahead = current,
+            }
+        }
+    }
+
+    fn produced(&self) -> usize {
+        self.produced.load(Ordering::SeqCst)
+    }
+
+    fn assert_bounded(&self) {
+        let max_ahead = self.max_ahead.load(Ordering::SeqCst);
+        assert!(max_ahead <= MAX_AHEAD,
+            "producer was {} messages ahead, expecting at most {}", max_ahead, MAX_AHEAD);
+    }
+}
+
+fn new_server<H>(name: &str, handler: H) -> Server
+    where
+        H : MethodHandler<String, String> + GrpcStreamingFlavor + Sync + Send + 'static,
+{
+    let method = ServerMethod::new(
+        string_string_method(name, <H as GrpcStreamingFlavor>::streaming()),
+        handler);
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_service(ServerServiceDefinition::new("/test", vec![method]));
+    server.build().expect("server")
+}
+
+fn new_client(server: &Server) -> Client {
+    let port = server.local_addr().port().expect("port");
+    Client::new_plain(BIND_HOST, port, Default::default()).expect("client")
+}
+
+/// Server streaming `/test/Download` of `MESSAGES` messages counted by `progress`.
+fn download_server(progress: Arc<Progress>) -> Server {
+    new_server("/test/Download", MethodHandlerServerStreaming::new(move |_o, _req: String| {
+        let progress = progress.clone();
+        StreamingResponse::iter((0..MESSAGES).map(move |_| progress.produce()))
+    }))
+}
+
+/// Client streaming `/test/Upload` consumed by `consume` in separate thread.
+fn upload_server<F>(consume: F) -> Server
+    where F : Fn(&str) + Sync + Send + 'static
+{
+    let consume = Arc::new(consume);
+    new_server("/test/Upload", MethodHandlerClientStreaming::new(move |_o, req: StreamingRequest<String>| {
+        let consume = consume.clone();
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let mut count = 0;
+            for message in req.0.wait() {
+                consume(&message.expect("message"));
+                count += 1;
+            }
+            drop(tx.send(count.to_string()));
+        });
+        SingleResponse::no_metadata(rx.map_err(Error::from))
+    }))
+}
+
+#[test]
+fn slow_client_reader() {
+    drop(env_logger::try_init());
+
+    let progress = Arc::new(Progress::default());
+    let server = download_server(progress.clone());
+    let client = new_client(&server);
+
+    let responses = client.call_server_streaming(
+        RequestOptions::new(),
+        String::new(),
+        string_string_method("/test/Download", GrpcStreaming::ServerStreaming));
+
+    let mut count = 0;
+    for message in responses.wait_drop_metadata() {
+        thread::sleep(Duration::from_millis(1));
+        progress.consume(&message.expect("message"));
+        count += 1;
+    }
+    assert_eq!(MESSAGES, count);
+    progress.assert_bounded();
+}
+
+#[test]
+fn stalled_client_reader_resumes() {
+    drop(env_logger::try_init());
+
+    let progress = Arc::new(Progress::default());
+    let server = download_server(progress.clone());
+    let client = new_client(&server);
+
+    let mut responses = client.call_server_streaming(
+        RequestOptions::new(),
+        String::new(),
+        string_string_method("/test/Download", GrpcStreaming::ServerStreaming))
+            .wait_drop_metadata();
+
+    progress.consume(&responses.next().expect("first").expect("first"));
+
+    // server has plenty of time to fill all buffers
+    thread::sleep(Duration::from_millis(500));
+    let produced = progress.produced();
+    assert!(produced <= MAX_AHEAD + 1, "produced {} while reader stalled", produced);
+
+    let mut count = 1;
+    for message in responses {
+        progress.consume(&message.expect("message"));
+        count += 1;
+    }
+    assert_eq!(MESSAGES, count);
+    progress.assert_bounded();
+}
+
+#[test]
+fn slow_server_reader() {
+    drop(env_logger::try_init());
+
+    let progress = Arc::new(Progress::default());
+    let progress_server = progress.clone();
+    let server = upload_server(move |message| {
+        thread::sleep(Duration::from_millis(1));
+        progress_server.consume(message);
+    });
+    let client = new_client(&server);
+
+    let progress_client = progress.clone();
+    let req = StreamingRequest::iter((0..MESSAGES).map(move |_| progress_client.produce()));
+    let count = client.call_client_streaming(
+        RequestOptions::new(),
+        req,
+        string_string_method("/test/Upload", GrpcStreaming::ClientStreaming))
+            .wait_drop_metadata()
+            .expect("call");
+
+    assert_eq!(MESSAGES.to_string(), count);
+    progress.assert_bounded();
+}
+
+#[test]
+fn slow_client_writer() {
+    drop(env_logger::try_init());
+
+    let progress = Arc::new(Progress::default());
+    let progress_server = progress.clone();
+    let server = upload_server(move |message| progress_server.consume(message));
+    let client = new_client(&server);
+
+    let progress_client = progress.clone();
+
+    // each message is produced only after server received previous one,
+    // so the call hangs if a partially filled window is held back
+    let req = StreamingRequest::new(stream_thread_spawn_iter(move || {
+        (0..MESSAGES).map(move |i| {
+            while progress_client.consumed.load(Ordering::SeqCst) < i {
+                thread::sleep(Duration::from_millis(1));
+            }
+            progress_client.produce()
+        })
+    }));
+
+    let count = client.call_client_streaming(
+        RequestOptions::new(),
+        req,
+        string_string_method("/test/Upload", GrpcStreaming::ClientStreaming))
+            .wait_drop_metadata()
+            .expect("call");
+
+    assert_eq!(MESSAGES.to_string(), count);
+    progress.assert_bounded();
+}
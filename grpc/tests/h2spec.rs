@@ -0,0 +1,77 @@
+//! HTTP/2 conformance of server checked with `h2spec`.
+//!
+//! Test is ignored by default, because it needs `h2spec` binary,
+//! found in `PATH` or in `H2SPEC` env var. CI installs it and runs
+//! the test with `cargo test -p grpc --test h2spec -- --ignored`.
+//! Minimum share of passed tests can be set with `H2SPEC_MIN_PASS_RATE`.
+
+extern crate grpc;
+
+mod test_misc;
+
+use std::env;
+use std::process::Command;
+
+use grpc::*;
+use grpc::rt::*;
+
+use test_misc::*;
+
+
+/// Share of tests which currently pass, raise when `httpbis` is improved.
+const DEFAULT_MIN_PASS_RATE: f64 = 0.8;
+
+/// Parse summary line like `146 tests, 137 passed, 1 skipped, 8 failed`.
+fn parse_summary(output: &str) -> Option<(u32, u32)> {
+    for line in output.lines().rev() {
+        let words: Vec<&str> = line.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|w| !w.is_empty())
+            .collect();
+        if words.len() >= 4 && words[1] == "tests" && words[3] == "passed" {
+            if let (Ok(tests), Ok(passed)) = (words[0].parse(), words[2].parse()) {
+                return Some((tests, passed));
+            }
+        }
+    }
+    None
+}
+
+#[test]
+#[ignore]
+fn h2spec() {
+    let h2spec = env::var("H2SPEC").unwrap_or_else(|_| "h2spec".to_owned());
+    let min_pass_rate = env::var("H2SPEC_MIN_PASS_RATE").ok()
+        .map(|r| r.parse().expect("H2SPEC_MIN_PASS_RATE"))
+        .unwrap_or(DEFAULT_MIN_PASS_RATE);
+
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_service(ServerServiceDefinition::new("/test", vec![
+        ServerMethod::new(
+            string_string_method("/test/Echo", GrpcStreaming::Unary),
+            MethodHandlerUnary::new(|_o, s| SingleResponse::completed(s))),
+    ]));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let output = Command::new(&h2spec)
+        .args(&["-h", BIND_HOST, "-p", &port.to_string(), "--timeout", "2"])
+        .output()
+        .unwrap_or_else(|e| panic!("failed to run {}: {}", h2spec, e));
+
+    // h2spec exits with non-zero code if any test fails
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (tests, passed) = parse_summary(&stdout)
+        .unwrap_or_else(|| panic!("no summary in h2spec output: {}", stdout));
+    let pass_rate = passed as f64 / tests as f64;
+    println!("h2spec: {} of {} tests passed", passed, tests);
+    assert!(pass_rate >= min_pass_rate,
+        "h2spec pass rate {:.2} is below {:.2}:\n{}", pass_rate, min_pass_rate, stdout);
+}
+
+#[test]
+fn h2spec_summary() {
+    let output = "Failures:\n...\nFinished in 2.0123 seconds\n146 tests, 137 passed, 1 skipped, 8 failed\n";
+    assert_eq!(Some((146, 137)), parse_summary(output));
+    assert_eq!(None, parse_summary("error"));
+}
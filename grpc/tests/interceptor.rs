@@ -0,0 +1,646 @@
+extern crate futures;
+extern crate bytes;
+extern crate grpc;
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+mod test_misc;
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use futures::Future;
+
+use grpc::*;
+use grpc::rt::*;
+use grpc::for_test::MarshallerString;
+
+use test_misc::*;
+
+
+fn echo_server<F>(configure: F) -> Server
+    where F : FnOnce(&mut ServerBuilder)
+{
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_service(ServerServiceDefinition::new("/test", vec![
+        ServerMethod::new(
+            string_string_method("/test/Echo", GrpcStreaming::Unary),
+            MethodHandlerUnary::new(|_o, s| SingleResponse::completed(s))),
+    ]));
+    configure(&mut server);
+    server.build().expect("server")
+}
+
+fn call_echo(client: &Client) -> Result<String> {
+    client.call_unary(
+        RequestOptions::new(),
+        "abc".to_owned(),
+        string_string_method("/test/Echo", GrpcStreaming::Unary))
+            .wait_drop_metadata()
+}
+
+fn expect_status(r: Result<String>, status: GrpcStatus) {
+    match r {
+        Err(Error::GrpcMessage(GrpcMessageError { grpc_status, .. })) => {
+            assert_eq!(status as i32, grpc_status);
+        }
+        r => panic!("expecting error, got {:?}", r),
+    }
+}
+
+fn chaos_errors() -> Arc<ChaosInterceptor> {
+    Arc::new(ChaosInterceptor::new(ChaosConf {
+        error_percent: 100,
+        error_status: GrpcStatus::ResourceExhausted as i32,
+        ..Default::default()
+    }))
+}
+
+#[test]
+fn chaos_server() {
+    drop(env_logger::try_init());
+
+    let server = echo_server(|s| s.add_interceptor(chaos_errors()));
+    let port = server.local_addr().port().expect("port");
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+
+    expect_status(call_echo(&client), GrpcStatus::ResourceExhausted);
+}
+
+#[test]
+fn chaos_client() {
+    drop(env_logger::try_init());
+
+    let server = echo_server(|_| {});
+    let port = server.local_addr().port().expect("port");
+    let mut client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    assert_eq!("abc", call_echo(&client).unwrap());
+
+    client.add_interceptor(chaos_errors());
+    expect_status(call_echo(&client), GrpcStatus::ResourceExhausted);
+}
+
+#[test]
+fn chaos_latency() {
+    drop(env_logger::try_init());
+
+    let server = echo_server(|s| s.add_interceptor(Arc::new(ChaosInterceptor::new(ChaosConf {
+        latency_percent: 100,
+        latency: ::std::time::Duration::from_millis(50),
+        ..Default::default()
+    }))));
+    let port = server.local_addr().port().expect("port");
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+
+    let start = ::std::time::Instant::now();
+    assert_eq!("abc", call_echo(&client).unwrap());
+    assert!(start.elapsed() >= ::std::time::Duration::from_millis(50));
+}
+
+#[test]
+fn caching_client() {
+    drop(env_logger::try_init());
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let get_method = Arc::new(MethodDescriptor {
+        name: "/test/Get".to_owned(),
+        streaming: GrpcStreaming::Unary,
+        options: MethodOptions {
+            idempotency_level: IdempotencyLevel::NoSideEffects,
+            ..Default::default()
+        },
+        req_marshaller: Box::new(MarshallerString),
+        resp_marshaller: Box::new(MarshallerString),
+    });
+
+    let server = {
+        let calls = calls.clone();
+        let get_method = get_method.clone();
+        echo_server(move |s| s.add_method(ServerMethod::new(
+            get_method,
+            MethodHandlerUnary::new(move |_o, s: String| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let mut metadata = Metadata::new();
+                metadata.add(MetadataKey::from("cache-control"), "max-age=60".into());
+                SingleResponse::completed_with_metadata(metadata, s)
+            }))))
+    };
+    let port = server.local_addr().port().expect("port");
+    let mut client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    client.add_interceptor(Arc::new(CachingInterceptor::new(Arc::new(LruResponseCache::new(10)))));
+
+    let get = |req: &str| {
+        client.call_unary(RequestOptions::new(), req.to_owned(), get_method.clone())
+            .wait_drop_metadata()
+            .unwrap()
+    };
+
+    assert_eq!("a", get("a"));
+    assert_eq!("a", get("a"));
+    assert_eq!(1, calls.load(Ordering::SeqCst));
+    assert_eq!("b", get("b"));
+    assert_eq!(2, calls.load(Ordering::SeqCst));
+
+    // methods with side effects are not cached
+    assert_eq!("abc", call_echo(&client).unwrap());
+    assert_eq!("abc", call_echo(&client).unwrap());
+}
+
+#[test]
+fn deduplication() {
+    drop(env_logger::try_init());
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let server = {
+        let calls = calls.clone();
+        echo_server(move |s| {
+            s.add_interceptor(Arc::new(DeduplicationInterceptor::new(Duration::from_secs(60))));
+            s.add_method(ServerMethod::new(
+                string_string_method("/test/Count", GrpcStreaming::Unary),
+                {
+                    let calls = calls.clone();
+                    MethodHandlerUnary::new(move |_o, _s| {
+                        let n = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                        SingleResponse::completed(n.to_string())
+                    })
+                }));
+            s.add_method(ServerMethod::new(
+                string_string_method("/test/CountStream", GrpcStreaming::ServerStreaming),
+                MethodHandlerServerStreaming::new(move |_o, _s| {
+                    let n = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                    StreamingResponse::iter(vec![n.to_string(), n.to_string()])
+                })));
+        })
+    };
+    let port = server.local_addr().port().expect("port");
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+
+    let count = |id: Option<&'static str>| {
+        let mut o = RequestOptions::new();
+        if let Some(id) = id {
+            o.metadata.add(MetadataKey::from(DEFAULT_IDEMPOTENCY_KEY), id.into());
+        }
+        client.call_unary(o, String::new(), string_string_method("/test/Count", GrpcStreaming::Unary))
+            .wait_drop_metadata()
+            .unwrap()
+    };
+
+    assert_eq!("1", count(Some("x")));
+    assert_eq!("1", count(Some("x")));
+    assert_eq!("2", count(Some("y")));
+    assert_eq!("3", count(None));
+    assert_eq!("4", count(None));
+    assert_eq!(4, calls.load(Ordering::SeqCst));
+
+    // key reused with different request
+    let mut o = RequestOptions::new();
+    o.metadata.add(MetadataKey::from(DEFAULT_IDEMPOTENCY_KEY), "x".into());
+    let r = client.call_unary(o, "other".to_owned(), string_string_method("/test/Count", GrpcStreaming::Unary))
+        .wait_drop_metadata();
+    expect_status(r, GrpcStatus::Argument);
+
+    let count_stream = || {
+        let mut o = RequestOptions::new();
+        o.metadata.add(MetadataKey::from(DEFAULT_IDEMPOTENCY_KEY), "z".into());
+        client.call_server_streaming(
+            o, String::new(), string_string_method("/test/CountStream", GrpcStreaming::ServerStreaming))
+            .wait_drop_metadata()
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    };
+
+    assert_eq!(vec!["5".to_owned(), "5".to_owned()], count_stream());
+    assert_eq!(vec!["5".to_owned(), "5".to_owned()], count_stream());
+    assert_eq!(5, calls.load(Ordering::SeqCst));
+}
+
+#[test]
+fn authorization() {
+    drop(env_logger::try_init());
+
+    let denied = echo_server(|s| {
+        let mut policy = AccessPolicy::allow_by_default();
+        policy.deny("/test/*");
+        s.add_interceptor(Arc::new(AuthorizationInterceptor::new(Arc::new(policy))));
+    });
+    let port = denied.local_addr().port().expect("port");
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    expect_status(call_echo(&client), GrpcStatus::PermissionDenied);
+
+    let authenticated = echo_server(|s| {
+        let mut policy = AccessPolicy::allow_by_default();
+        policy.require_audience("/test/Echo", "test");
+        s.add_interceptor(Arc::new(AuthorizationInterceptor::new(Arc::new(policy))));
+    });
+    let port = authenticated.local_addr().port().expect("port");
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    expect_status(call_echo(&client), GrpcStatus::Unauthenticated);
+}
+
+#[test]
+fn rate_limit() {
+    drop(env_logger::try_init());
+
+    let server = echo_server(|s| s.add_interceptor(Arc::new(RateLimitInterceptor::new(RateLimitConf {
+        rate: 0.1,
+        burst: 1,
+        ..Default::default()
+    }))));
+    let port = server.local_addr().port().expect("port");
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    assert_eq!("abc", call_echo(&client).unwrap());
+
+    let resp = client.call_unary(
+        RequestOptions::new(),
+        "abc".to_owned(),
+        string_string_method("/test/Echo", GrpcStreaming::Unary));
+    let (metadata, result) = resp.0.wait().expect("headers");
+    assert_eq!(Some(&b"10"[..]), metadata.get(RETRY_AFTER_METADATA_KEY));
+    expect_status(result.wait().map(|(r, _trailing)| r), GrpcStatus::ResourceExhausted);
+}
+
+#[test]
+fn concurrency_limit() {
+    drop(env_logger::try_init());
+
+    let server = echo_server(|s| {
+        s.add_interceptor(Arc::new(ConcurrencyLimitInterceptor::new(ConcurrencyLimitConf {
+            initial_limit: 1,
+            min_limit: 1,
+            max_limit: 1,
+            ..Default::default()
+        })));
+        s.add_interceptor(Arc::new(ChaosInterceptor::new(ChaosConf {
+            latency_percent: 100,
+            latency: Duration::from_millis(200),
+            ..Default::default()
+        })));
+    });
+    let port = server.local_addr().port().expect("port");
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+
+    let first = client.call_unary(
+        RequestOptions::new(),
+        "abc".to_owned(),
+        string_string_method("/test/Echo", GrpcStreaming::Unary));
+    thread::sleep(Duration::from_millis(50));
+    expect_status(call_echo(&client), GrpcStatus::Unavailable);
+    assert_eq!("abc", first.wait_drop_metadata().unwrap());
+
+    assert_eq!("abc", call_echo(&client).unwrap());
+}
+
+#[test]
+fn request_id() {
+    drop(env_logger::try_init());
+
+    // responds with request ID
+    let server = echo_server(|s| {
+        s.add_interceptor(Arc::new(RequestIdInterceptor::new(Default::default())));
+        s.add_method(ServerMethod::new(
+            string_string_method("/test/RequestId", GrpcStreaming::Unary),
+            MethodHandlerUnary::new(|o: RequestOptions, _s| {
+                SingleResponse::completed(o.request_id.unwrap_or_default())
+            })));
+    });
+    let port = server.local_addr().port().expect("port");
+
+    let call = |client: &Client, o: RequestOptions| {
+        let (metadata, resp) = client.call_unary(
+            o,
+            String::new(),
+            string_string_method("/test/RequestId", GrpcStreaming::Unary))
+                .0.wait()
+                .unwrap();
+        let request_id = resp.wait().unwrap().0;
+        assert_eq!(Some(request_id.as_bytes()), metadata.get(REQUEST_ID_METADATA_KEY));
+        request_id
+    };
+
+    // generated by server
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    assert_eq!(36, call(&client, RequestOptions::new()).len());
+
+    let mut client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    client.add_interceptor(Arc::new(RequestIdInterceptor::new(RequestIdConf {
+        format: RequestIdFormat::Ulid,
+        ..Default::default()
+    })));
+    assert_eq!(26, call(&client, RequestOptions::new()).len());
+
+    let o = RequestOptions {
+        request_id: Some("parent".to_owned()),
+        ..Default::default()
+    };
+    assert_eq!("parent", call(&client.for_request(&o), RequestOptions::new()));
+}
+
+#[test]
+fn load_report() {
+    drop(env_logger::try_init());
+
+    let server = echo_server(|s| {
+        s.add_interceptor(Arc::new(LoadReportInterceptor::with_server_metrics(|r| {
+            r.cpu_utilization = 0.5;
+            r.rps_fractional = 10.0;
+        })));
+        s.add_method(ServerMethod::new(
+            string_string_method("/test/Cost", GrpcStreaming::Unary),
+            MethodHandlerUnary::new(|o: RequestOptions, s| {
+                o.load_report.unwrap().update(|r| { r.request_cost.insert("db".to_owned(), 2.0); });
+                SingleResponse::completed(s)
+            })));
+    });
+    let port = server.local_addr().port().expect("port");
+
+    let conf = ClientConf {
+        balancing_policy: BalancingPolicy::LoadWeightedRoundRobin,
+        ..ClientConf::new()
+    };
+    let client = Client::new_plain(BIND_HOST, port, conf).expect("client");
+    let (_, _, trailing) = client.call_unary(
+        RequestOptions::new(),
+        "abc".to_owned(),
+        string_string_method("/test/Cost", GrpcStreaming::Unary))
+            .join_metadata_result()
+            .wait()
+            .unwrap();
+
+    let report = LoadReport::from_metadata(&trailing).expect("load report");
+    assert_eq!(0.5, report.cpu_utilization);
+    assert_eq!(Some(&2.0), report.request_cost.get("db"));
+
+    let connections = client.channel().connections();
+    assert_eq!(Some(report), connections[0].load_report);
+}
+
+#[derive(Default)]
+struct CountingTokenSource {
+    fetched: AtomicUsize,
+}
+
+impl TokenSource for CountingTokenSource {
+    fn fetch_token(&self) -> GrpcFuture<AccessToken> {
+        let n = self.fetched.fetch_add(1, Ordering::SeqCst);
+        Box::new(futures::future::ok(AccessToken {
+            token: format!("t{}", n),
+            expires: Some(::std::time::Instant::now() + Duration::from_secs(3600)),
+        }))
+    }
+}
+
+#[test]
+fn call_credentials() {
+    drop(env_logger::try_init());
+
+    // responds with authorization header
+    let server = echo_server(|s| s.add_method(ServerMethod::new(
+        string_string_method("/test/Authorization", GrpcStreaming::Unary),
+        MethodHandlerUnary::new(|o: RequestOptions, _s| {
+            let authorization = o.metadata.get("authorization").unwrap_or(b"");
+            SingleResponse::completed(String::from_utf8(authorization.to_vec()).unwrap())
+        }))));
+    let port = server.local_addr().port().expect("port");
+
+    let call = |client: &Client| {
+        client.call_unary(
+            RequestOptions::new(),
+            String::new(),
+            string_string_method("/test/Authorization", GrpcStreaming::Unary))
+                .wait_drop_metadata()
+                .unwrap()
+    };
+
+    let mut client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    client.add_call_credentials(Arc::new(BearerTokenCredentials::new("secret")));
+    assert_eq!("Bearer secret", call(&client));
+
+    let source = Arc::new(CountingTokenSource::default());
+    let mut client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    client.add_call_credentials(Arc::new(RefreshingTokenCredentials::new(source.clone())));
+    assert_eq!("Bearer t0", call(&client));
+    assert_eq!("Bearer t0", call(&client));
+    assert_eq!(1, source.fetched.load(Ordering::SeqCst));
+}
+
+/// Fails the first `failures` calls with `UNAVAILABLE`.
+struct FlakyInterceptor {
+    failures: usize,
+    calls: AtomicUsize,
+}
+
+impl ClientInterceptor for FlakyInterceptor {
+    fn intercept(
+        &self,
+        _method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>
+    {
+        if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+            return StreamingResponse::err(Error::GrpcMessage(GrpcMessageError {
+                grpc_status: GrpcStatus::Unavailable as i32,
+                grpc_message: "flaky".to_owned(),
+            }));
+        }
+        next.call(o, req)
+    }
+}
+
+fn flaky_client(port: u16, retry: RetryInterceptor, failures: usize) -> (Client, Arc<FlakyInterceptor>) {
+    let flaky = Arc::new(FlakyInterceptor { failures, calls: AtomicUsize::new(0) });
+    let mut client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    client.add_interceptor(Arc::new(retry));
+    client.add_interceptor(flaky.clone());
+    (client, flaky)
+}
+
+#[test]
+fn retry() {
+    drop(env_logger::try_init());
+
+    let server = echo_server(|_| {});
+    let port = server.local_addr().port().expect("port");
+    let policy = RetryPolicy {
+        initial_backoff: Duration::from_millis(10),
+        ..Default::default()
+    };
+
+    let (client, flaky) = flaky_client(port, RetryInterceptor::new(policy.clone(), None), 2);
+    assert_eq!("abc", call_echo(&client).unwrap());
+    assert_eq!(3, flaky.calls.load(Ordering::SeqCst));
+
+    // no more attempts than `max_attempts`
+    let (client, flaky) = flaky_client(port, RetryInterceptor::new(policy, None), 10);
+    expect_status(call_echo(&client), GrpcStatus::Unavailable);
+    assert_eq!(3, flaky.calls.load(Ordering::SeqCst));
+}
+
+#[test]
+fn retry_throttling() {
+    drop(env_logger::try_init());
+
+    let server = echo_server(|_| {});
+    let port = server.local_addr().port().expect("port");
+    let retry = RetryInterceptor::from_service_config(r#"{
+        "methodConfig": [{
+            "name": [{"service": "test"}],
+            "retryPolicy": {
+                "maxAttempts": 5,
+                "initialBackoff": "0.01s",
+                "maxBackoff": "0.01s",
+                "backoffMultiplier": 1,
+                "retryableStatusCodes": ["UNAVAILABLE"]
+            }
+        }],
+        "retryThrottling": {"maxTokens": 4, "tokenRatio": 1}
+    }"#).unwrap().unwrap();
+
+    // the second failure leaves half of tokens, which stops retries
+    let (client, flaky) = flaky_client(port, retry, 10);
+    expect_status(call_echo(&client), GrpcStatus::Unavailable);
+    assert_eq!(2, flaky.calls.load(Ordering::SeqCst));
+}
+
+#[test]
+fn circuit_breaker() {
+    drop(env_logger::try_init());
+
+    let server = echo_server(|_| {});
+    let port = server.local_addr().port().expect("port");
+
+    let flaky = Arc::new(FlakyInterceptor { failures: 2, calls: AtomicUsize::new(0) });
+    let mut client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    client.add_interceptor(Arc::new(CircuitBreakerInterceptor::new(CircuitBreakerConf {
+        min_calls: 2,
+        open_duration: Duration::from_millis(100),
+        ..Default::default()
+    })));
+    client.add_interceptor(flaky.clone());
+
+    expect_status(call_echo(&client), GrpcStatus::Unavailable);
+    expect_status(call_echo(&client), GrpcStatus::Unavailable);
+
+    // fails fast while open
+    expect_status(call_echo(&client), GrpcStatus::Unavailable);
+    assert_eq!(2, flaky.calls.load(Ordering::SeqCst));
+
+    // probe call closes circuit
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!("abc", call_echo(&client).unwrap());
+    assert_eq!("abc", call_echo(&client).unwrap());
+}
+
+/// Records priorities of calls passed to it.
+struct PriorityRecorder {
+    priorities: Mutex<Vec<CallPriority>>,
+}
+
+impl ClientInterceptor for PriorityRecorder {
+    fn intercept(
+        &self,
+        _method: &str,
+        o: RequestOptions,
+        req: StreamingRequest<Bytes>,
+        next: ClientNext)
+        -> StreamingResponse<Bytes>
+    {
+        self.priorities.lock().unwrap().push(o.priority);
+        next.call(o, req)
+    }
+}
+
+#[test]
+fn priority() {
+    drop(env_logger::try_init());
+
+    let server = echo_server(|s| {
+        s.add_interceptor(Arc::new(PriorityInterceptor::new(1)));
+        s.add_method(ServerMethod::new(
+            string_string_method("/test/Priority", GrpcStreaming::Unary),
+            MethodHandlerUnary::new(|o: RequestOptions, _s| {
+                SingleResponse::completed(format!("{:?}", o.priority))
+            })));
+    });
+    let port = server.local_addr().port().expect("port");
+    let recorder = Arc::new(PriorityRecorder { priorities: Default::default() });
+    let mut client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    client.add_interceptor(Arc::new(PriorityInterceptor::new(1)));
+    client.add_interceptor(recorder.clone());
+
+    let call = |method: &str, priority: CallPriority| {
+        let mut o = RequestOptions::new();
+        o.priority = priority;
+        client.call_unary(o, "abc".to_owned(), string_string_method(method, GrpcStreaming::Unary))
+    };
+
+    // priority is passed to server
+    assert_eq!("High", call("/test/Priority", CallPriority::High).wait_drop_metadata().unwrap());
+    recorder.priorities.lock().unwrap().clear();
+
+    // the first call takes the only slot, others wait for it
+    let first = call("/test/Echo", CallPriority::Normal);
+    let waiting: Vec<_> = vec![CallPriority::Low, CallPriority::High]
+        .into_iter()
+        .map(|p| call("/test/Echo", p))
+        .map(|r| thread::spawn(move || r.wait_drop_metadata().unwrap()))
+        .collect();
+    assert_eq!("abc", first.wait_drop_metadata().unwrap());
+    for t in waiting {
+        assert_eq!("abc", t.join().unwrap());
+    }
+
+    assert_eq!(
+        vec![CallPriority::Normal, CallPriority::High, CallPriority::Low],
+        *recorder.priorities.lock().unwrap());
+}
+
+#[derive(Default)]
+struct RecordingInspector {
+    messages: Mutex<Vec<String>>,
+}
+
+impl MessageInspector for RecordingInspector {
+    fn sent(&self, method: &MethodInfo, message: &[u8]) {
+        assert_eq!(GrpcStreaming::Unary, method.streaming);
+        let message = String::from_utf8_lossy(message);
+        self.messages.lock().unwrap().push(format!("sent {} {}", method.name, message));
+    }
+
+    fn received(&self, method: &MethodInfo, message: &[u8]) {
+        let message = String::from_utf8_lossy(message);
+        self.messages.lock().unwrap().push(format!("received {} {}", method.name, message));
+    }
+}
+
+#[test]
+fn message_inspector() {
+    drop(env_logger::try_init());
+
+    let server_inspector = Arc::new(RecordingInspector::default());
+    let server = {
+        let server_inspector = server_inspector.clone();
+        echo_server(move |s| s.add_message_inspector(server_inspector))
+    };
+    let port = server.local_addr().port().expect("port");
+
+    let client_inspector = Arc::new(RecordingInspector::default());
+    let mut client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    client.add_message_inspector(client_inspector.clone());
+
+    assert_eq!("abc", call_echo(&client).unwrap());
+
+    let expected = vec!["sent /test/Echo abc", "received /test/Echo abc"];
+    assert_eq!(expected, *client_inspector.messages.lock().unwrap());
+    let expected = vec!["received /test/Echo abc", "sent /test/Echo abc"];
+    assert_eq!(expected, *server_inspector.messages.lock().unwrap());
+}
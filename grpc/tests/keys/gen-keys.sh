@@ -0,0 +1,38 @@
+#!/bin/sh -e
+
+# Keys and certificates used by tests, regenerate with this script
+
+cd $(dirname $0)
+
+# RSA keys signing JWT in jwt.rs tests,
+# public parts are copied to JWKS there
+
+openssl genrsa -traditional -out jwt-a.pem 2048
+openssl genrsa -traditional -out jwt-b.pem 2048
+
+
+# Root CA and server certificate for `localhost` used by tests/tls.rs
+
+openssl req -x509 -new -nodes -newkey rsa:2048 -keyout root-ca.key \
+    -subj '/CN=grpc-rust test CA' -addext 'basicConstraints=critical,CA:TRUE' \
+    -sha256 -days 36500 -out root-ca.crt
+openssl x509 -outform der -in root-ca.crt -out tls-root-ca.der
+
+openssl req -new -nodes -newkey rsa:2048 -keyout localhost.key -subj '/CN=localhost' -out localhost.csr
+printf 'subjectAltName=DNS:localhost\nbasicConstraints=CA:FALSE\nextendedKeyUsage=serverAuth\n' > localhost.ext
+openssl x509 -req -in localhost.csr -CA root-ca.crt -CAkey root-ca.key -CAcreateserial \
+    -extfile localhost.ext -sha256 -days 36500 -out localhost.crt
+
+openssl x509 -outform der -in localhost.crt -out tls-localhost.der
+openssl pkcs8 -topk8 -nocrypt -outform der -in localhost.key -out tls-localhost.key.der
+# legacy algorithms, so that older OpenSSL used by native-tls can read it
+openssl pkcs12 -export -certpbe PBE-SHA1-3DES -keypbe PBE-SHA1-3DES -macalg sha1 \
+    -inkey localhost.key -in localhost.crt -out tls-localhost.p12 -password pass:test
+
+
+# Cleanup
+
+rm localhost.crt localhost.csr localhost.ext localhost.key root-ca.crt root-ca.key root-ca.srl
+
+
+# vim: set ts=4 sw=4 et:
@@ -3,12 +3,27 @@ extern crate log;
 extern crate env_logger;
 
 extern crate futures;
+extern crate bytes;
+extern crate httpbis;
 extern crate grpc;
 
 mod test_misc;
 
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use futures::stream;
+
+use httpbis::DataOrTrailers;
+use httpbis::Header;
+use httpbis::Headers;
+use httpbis::HttpStreamAfterHeaders;
+use httpbis::Service;
+
 use grpc::*;
 use grpc::rt::*;
+use grpc::well_known_types::Duration;
 
 use test_misc::*;
 
@@ -59,5 +74,239 @@ fn multiple_services() {
     assert_eq!(
         "zyx".to_owned(),
         client.call_unary(
-            RequestOptions::new(), "xyz".to_owned(), reverse).wait_drop_metadata().unwrap());
+            RequestOptions::new(), "xyz".to_owned(), reverse.clone()).wait_drop_metadata().unwrap());
+
+    // client sharing connection with the first one
+    let client2 = Client::with_channel(client.channel());
+
+    assert_eq!(
+        "cba".to_owned(),
+        client2.call_unary(
+            RequestOptions::new(), "abc".to_owned(), reverse).wait_drop_metadata().unwrap());
+}
+
+#[test]
+fn unary_handler() {
+    drop(env_logger::try_init());
+
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+
+    let echo = string_string_method("/foo/echo", GrpcStreaming::Unary);
+    let raw = string_string_method("/foo/raw", GrpcStreaming::Unary);
+
+    server.add_service(ServerServiceDefinition::new("/foo", vec![
+        ServerMethod::new(
+            echo.clone(),
+            MethodHandlerUnary::new(echo_fn))
+    ]));
+    server.add_unary_handler("/foo/raw", |_o, req| SingleResponse::completed(req));
+
+    let server = server.build().expect("server");
+
+    let port = server.local_addr().port().expect("port");
+
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new())
+        .expect("client");
+
+    assert_eq!(
+        "abc".to_owned(),
+        client.call_unary(
+            RequestOptions::new(), "abc".to_owned(), echo).wait_drop_metadata().unwrap());
+
+    assert_eq!(
+        "xyz".to_owned(),
+        client.call_unary(
+            RequestOptions::new(), "xyz".to_owned(), raw).wait_drop_metadata().unwrap());
+
+    assert_eq!(
+        &b"def"[..],
+        &client.call_unary_bytes(
+            RequestOptions::new(), Bytes::from_static(b"def"), "/foo/echo").wait_drop_metadata().unwrap()[..]);
+}
+
+#[test]
+fn multiple_ports() {
+    drop(env_logger::try_init());
+
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_plain_port(0);
+
+    let echo = string_string_method("/foo/echo", GrpcStreaming::Unary);
+
+    server.add_service(ServerServiceDefinition::new("/foo", vec![
+        ServerMethod::new(
+            echo.clone(),
+            MethodHandlerUnary::new(echo_fn))
+    ]));
+
+    let server = server.build().expect("server");
+
+    let addrs = server.local_addrs();
+    assert_eq!(2, addrs.len());
+    assert_eq!(server.local_addr().port(), addrs[0].port());
+
+    for addr in addrs {
+        let port = addr.port().expect("port");
+        let client = Client::new_plain(BIND_HOST, port, ClientConf::new())
+            .expect("client");
+        assert_eq!(
+            "abc".to_owned(),
+            client.call_unary(RequestOptions::new(), "abc".to_owned(), echo.clone())
+                .wait_drop_metadata()
+                .unwrap());
+    }
+    assert!(server.is_alive());
+}
+
+// SO_REUSEPORT is unix-only
+#[cfg(unix)]
+#[test]
+fn reuse_port() {
+    drop(env_logger::try_init());
+
+    let echo = string_string_method("/foo/echo", GrpcStreaming::Unary);
+
+    let new_server = |port| {
+        let mut server = ServerBuilder::new_plain();
+        server.http.set_port(port);
+        server.http.conf.reuse_port = Some(true);
+        server.add_service(ServerServiceDefinition::new("/foo", vec![
+            ServerMethod::new(
+                echo.clone(),
+                MethodHandlerUnary::new(echo_fn))
+        ]));
+        server.build().expect("server")
+    };
+
+    let first = new_server(0);
+    let port = first.local_addr().port().expect("port");
+    // binding the same port fails without SO_REUSEPORT
+    let second = new_server(port);
+    assert_eq!(port, second.local_addr().port().expect("port"));
+
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new())
+        .expect("client");
+    assert_eq!(
+        "abc".to_owned(),
+        client.call_unary(RequestOptions::new(), "abc".to_owned(), echo.clone())
+            .wait_drop_metadata()
+            .unwrap());
+}
+
+#[test]
+fn trailers_are_last() {
+    drop(env_logger::try_init());
+
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_service(ServerServiceDefinition::new("/test", vec![
+        ServerMethod::new(
+            string_string_method("/test/Trailers", GrpcStreaming::ServerStreaming),
+            MethodHandlerServerStreaming::new(|_o, _req: String| {
+                let mut trailing = Metadata::new();
+                trailing.add(MetadataKey::from(":status"), Bytes::from_static(b"500"));
+                trailing.add(MetadataKey::from("grpc-status"), Bytes::from_static(b"13"));
+                trailing.add(MetadataKey::from("x-trailer"), Bytes::from_static(b"t"));
+                StreamingResponse::completed_with_metadata_and_trailing_metadata(
+                    Metadata::new(), vec!["a".to_owned()], trailing)
+            })),
+    ]));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let client = httpbis::Client::new_plain(BIND_HOST, port, Default::default()).expect("client");
+    let headers = Headers(vec![
+        Header::new(":method", "POST"),
+        Header::new(":path", "/test/Trailers"),
+        Header::new(":authority", "localhost"),
+        Header::new(":scheme", "http"),
+        Header::new("content-type", "application/grpc"),
+        Header::new("te", "trailers"),
+    ]);
+    // empty message
+    let body = stream::once(Ok(Bytes::from_static(b"\x00\x00\x00\x00\x00")));
+    let (headers, parts) = client.start_request(headers, HttpStreamAfterHeaders::bytes(body))
+        .0.wait().expect("headers");
+    assert_eq!(Some("200"), headers.get_opt(":status"));
+
+    let mut parts: Vec<DataOrTrailers> = parts.collect().wait().expect("parts");
+    let trailers = match parts.pop() {
+        Some(DataOrTrailers::Trailers(trailers)) => trailers,
+        _ => panic!("response must end with trailers"),
+    };
+    assert!(parts.iter().all(|p| match *p {
+        DataOrTrailers::Data(..) => true,
+        DataOrTrailers::Trailers(..) => false,
+    }));
+
+    assert!(trailers.0.iter().all(|h| !h.name().starts_with(b":")));
+    assert_eq!(1, trailers.0.iter().filter(|h| h.name() == b"grpc-status").count());
+    assert_eq!(Some("0"), trailers.get_opt("grpc-status"));
+    assert_eq!(Some("t"), trailers.get_opt("x-trailer"));
+}
+
+#[test]
+fn debug_service() {
+    drop(env_logger::try_init());
+
+    let debug = Arc::new(DebugService::new());
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_debug_service(debug.clone());
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    debug.add_channel("self", client.channel());
+
+    let state = || {
+        let state = client.call_unary_bytes(RequestOptions::new(), Bytes::new(), DEBUG_STATE_PATH)
+            .wait_drop_metadata()
+            .unwrap();
+        String::from_utf8(state.to_vec()).unwrap()
+    };
+
+    // the call reading state is in progress
+    let s = state();
+    assert!(s.contains(r#""server_calls":{"/grpc.debug.Debug/State":1}"#), "{}", s);
+    assert!(s.contains(&format!(r#""addr":"{}:{}""#, BIND_HOST, port)), "{}", s);
+    // state is updated after the first call finishes
+    let s = state();
+    assert!(s.contains(r#""state":"READY""#), "{}", s);
+}
+
+#[test]
+fn json_marshaller() {
+    drop(env_logger::try_init());
+
+    let method = Arc::new(MethodDescriptor {
+        name: "/test/Json".to_owned(),
+        streaming: GrpcStreaming::Unary,
+        options: Default::default(),
+        req_marshaller: Box::new(grpc::protobuf::MarshallerJson),
+        resp_marshaller: Box::new(grpc::protobuf::MarshallerJson),
+    });
+
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.add_method(ServerMethod::new(
+        method.clone(),
+        MethodHandlerUnary::new(|o: RequestOptions, d: Duration| {
+            assert_eq!(Some("json".to_owned()), o.content_subtype);
+            let mut r = Duration::new();
+            r.set_seconds(d.get_seconds() * 2);
+            r.set_nanos(d.get_nanos() * 2);
+            SingleResponse::completed(r)
+        })));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let client = Client::new_plain(BIND_HOST, port, ClientConf::new()).expect("client");
+    let mut req = Duration::new();
+    req.set_seconds(1);
+    req.set_nanos(250_000_000);
+    let resp = client.call_unary(RequestOptions::new(), req, method).wait_drop_metadata().unwrap();
+    assert_eq!((2, 500_000_000), (resp.get_seconds(), resp.get_nanos()));
 }
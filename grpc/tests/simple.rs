@@ -1,4 +1,5 @@
 extern crate futures;
+extern crate bytes;
 extern crate tokio_core;
 extern crate tokio_tls_api;
 extern crate grpc;
@@ -8,14 +9,24 @@ extern crate env_logger;
 
 mod test_misc;
 
+use std::io::Cursor;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 use futures::future::*;
 use futures::Sink;
 use futures::stream::Stream;
 
+use bytes::Bytes;
+
 use grpc::*;
 use grpc::rt::*;
+use grpc::transfer::*;
 
 use test_misc::*;
 
@@ -223,6 +234,34 @@ fn server_streaming() {
     assert!(rs.next().is_none());
 }
 
+#[test]
+fn initial_metadata_before_messages() {
+    let test_sync = Arc::new(TestSync::new());
+    let test_sync_server = test_sync.clone();
+
+    let tester = TesterServerStreaming::new(move |_m, s| {
+        let sync = test_sync_server.clone();
+        let mut metadata = Metadata::new();
+        metadata.add(MetadataKey::from("session-id"), Bytes::from("17"));
+        StreamingResponse::metadata_and_stream(metadata, test_misc::stream_thread_spawn_iter(move || {
+            // first message is sent after client received metadata
+            sync.take(1);
+            Some(s).into_iter()
+        }))
+    });
+
+    let (metadata, stream) = tester.client.call_server_streaming(
+        RequestOptions::new(),
+        "x".to_owned(),
+        string_string_method(&tester.name, GrpcStreaming::ServerStreaming)).split_metadata();
+
+    let metadata = metadata.wait().unwrap();
+    assert_eq!(Some(&b"17"[..]), metadata.get("session-id"));
+    test_sync.take(0);
+
+    assert_eq!(vec!["x".to_owned()], stream.drop_metadata().collect().wait().unwrap());
+}
+
 #[test]
 fn client_streaming() {
     let tester = TesterClientStreaming::new(move |_m, s| {
@@ -240,3 +279,477 @@ fn client_streaming() {
 
     assert_eq!("aabbcc", result.wait().unwrap());
 }
+
+#[test]
+fn pipe_to_backend() {
+    let backend = TesterClientStreaming::new(move |_m, s| {
+        SingleResponse::no_metadata(s.0.fold(String::new(), |mut s, message| {
+            s.push_str(&message);
+            futures::finished::<_, Error>(s)
+        }))
+    });
+
+    let client = backend.client.clone();
+    let name = backend.name.clone();
+    let proxy = TesterClientStreaming::new(move |_m, req: StreamingRequest<String>| {
+        let (tx, rx) = futures::sync::mpsc::channel(0);
+        let forwarded = pipe(req.0, tx);
+        let response = client.call_client_streaming(
+            RequestOptions::new(),
+            StreamingRequest::new(rx.map_err(|()| unreachable!())),
+            string_string_method(&name, GrpcStreaming::ClientStreaming)).drop_metadata();
+        SingleResponse::no_metadata(forwarded.join(response).map(|((), r)| r))
+    });
+
+    let (tx, result) = proxy.call();
+    let tx = tx.send("aa".to_owned()).wait().ok().expect("aa");
+    let tx = tx.send("bb".to_owned()).wait().ok().expect("bb");
+    drop(tx);
+
+    assert_eq!("aabb", result.wait().unwrap());
+}
+
+#[test]
+fn call_stats() {
+    let tester = TesterUnary::new(|_m, s| SingleResponse::completed(s));
+
+    let call_stats = CallStatsCollector::new();
+    let mut o = RequestOptions::new();
+    o.call_stats = Some(call_stats.clone());
+
+    let r = tester.client.call_unary(
+        o, "abc".to_owned(), string_string_method(&tester.name, GrpcStreaming::Unary));
+    assert_eq!("abc", r.wait_drop_metadata().unwrap());
+
+    let stats = call_stats.get();
+    assert_eq!(3, stats.request_bytes);
+    assert_eq!(3, stats.response_bytes);
+    assert_eq!(8, stats.request_wire_bytes);
+    assert_eq!(8, stats.response_wire_bytes);
+    assert!(stats.time_to_first_byte.is_some());
+    assert!(stats.total_time.is_some());
+}
+
+#[test]
+fn call_unary_with_stats() {
+    let tester = TesterUnary::new(|_m, s| SingleResponse::completed(s));
+
+    let r = tester.client.call_unary_with_stats(
+        RequestOptions::new(), "abc".to_owned(), string_string_method(&tester.name, GrpcStreaming::Unary));
+    let (message, stats) = r.wait_drop_metadata().unwrap();
+    assert_eq!("abc", message);
+    assert_eq!(1, stats.attempts);
+    assert_eq!(3, stats.response_bytes);
+    assert!(stats.total_time.is_some());
+}
+
+#[test]
+fn buffer_pool() {
+    let pool = Arc::new(BufferPool::new(65536, 4));
+
+    let mut server = ServerBuilder::new_plain();
+    server.http.set_port(0);
+    server.conf.buffer_pool = Some(pool.clone());
+    server.add_service(ServerServiceDefinition::new("/test", vec![ServerMethod::new(
+        string_string_method("/test/ServerStreaming", GrpcStreaming::ServerStreaming),
+        MethodHandlerServerStreaming::new(|_m, s: String| {
+            StreamingResponse::iter_with_metadata(
+                Metadata::new(), (0..100).map(move |i| format!("{}{}", s, i)))
+        }),
+    )]));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let mut conf = ClientConf::new();
+    conf.buffer_pool = Some(pool.clone());
+    let client = Client::new_plain(BIND_HOST, port, conf).unwrap();
+
+    let (_, items, _) = client.call_server_streaming(
+        RequestOptions::new(),
+        "x".to_owned(),
+        string_string_method("/test/ServerStreaming", GrpcStreaming::ServerStreaming))
+            .collect()
+            .wait()
+            .unwrap();
+    let expected: Vec<String> = (0..100).map(|i| format!("x{}", i)).collect();
+    assert_eq!(expected, items);
+
+    // frames share few buffers
+    assert!(pool.stats().hits >= 90, "{:?}", pool.stats());
+}
+
+/// Regression check that concurrent calls don't wait for each other;
+/// stream allocation itself is implemented in `httpbis`.
+#[test]
+fn concurrent_unary() {
+    drop(env_logger::try_init());
+
+    const THREADS: usize = 10;
+    const CALLS_PER_THREAD: usize = 200;
+
+    // responses are sent only after all requests are received,
+    // so test hangs if calls wait for each other
+    let received = AtomicUsize::new(0);
+    let (all_received_tx, all_received_rx) = futures::sync::oneshot::channel::<()>();
+    let all_received_tx = Mutex::new(Some(all_received_tx));
+    let all_received = all_received_rx.shared();
+
+    let tester = TesterUnary::new(move |_m, s| {
+        if received.fetch_add(1, Ordering::SeqCst) + 1 == THREADS * CALLS_PER_THREAD {
+            drop(all_received_tx.lock().unwrap().take().unwrap().send(()));
+        }
+        SingleResponse::no_metadata(all_received.clone().then(move |_| Ok::<_, Error>(s)))
+    });
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let client = tester.client.clone();
+            let method = string_string_method(&tester.name, GrpcStreaming::Unary);
+            thread::spawn(move || {
+                let calls: Vec<_> = (0..CALLS_PER_THREAD)
+                    .map(|i| {
+                        client.call_unary(RequestOptions::new(), format!("{} {}", t, i), method.clone())
+                            .drop_metadata()
+                    })
+                    .collect();
+                join_all(calls).wait().unwrap()
+            })
+        })
+        .collect();
+
+    for (t, thread) in threads.into_iter().enumerate() {
+        let expected: Vec<String> = (0..CALLS_PER_THREAD).map(|i| format!("{} {}", t, i)).collect();
+        assert_eq!(expected, thread.join().unwrap());
+    }
+}
+
+#[test]
+fn deadline() {
+    drop(env_logger::try_init());
+
+    let tester = TesterUnary::new(|_m, _s| SingleResponse::no_metadata(empty()));
+
+    let o = RequestOptions {
+        deadline: Some(Instant::now() + Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let r = tester.client.call_unary(o, "aa".to_owned(), string_string_method(&tester.name, GrpcStreaming::Unary));
+    match r.wait_drop_metadata() {
+        Err(Error::GrpcMessage(ref e)) => assert_eq!(GrpcStatus::DeadlineExceeded as i32, e.grpc_status),
+        r => panic!("expecting deadline exceeded: {:?}", r),
+    }
+}
+
+#[test]
+fn deadline_propagation() {
+    drop(env_logger::try_init());
+
+    // responds with milliseconds remaining until deadline
+    let backend = TesterUnary::new(|o, _s| {
+        let remaining = o.deadline.map(|d| d.duration_since(Instant::now()).as_millis());
+        SingleResponse::completed(format!("{:?}", remaining))
+    });
+    let backend_client = backend.client.clone();
+    let backend_method = string_string_method(&backend.name, GrpcStreaming::Unary);
+
+    let frontend = TesterUnary::new(move |o, s| {
+        let resp = backend_client.for_request(&o).call_unary(RequestOptions::new(), s, backend_method.clone());
+        SingleResponse::no_metadata(resp.drop_metadata())
+    });
+
+    assert_eq!("None", frontend.call("aa").wait().unwrap());
+
+    let o = RequestOptions {
+        deadline: Some(Instant::now() + Duration::from_secs(10)),
+        ..Default::default()
+    };
+    let r = frontend.client.call_unary(o, "aa".to_owned(), string_string_method(&frontend.name, GrpcStreaming::Unary));
+    let remaining = r.wait_drop_metadata().unwrap();
+    let remaining: u64 = remaining["Some(".len()..remaining.len() - 1].parse().unwrap();
+    assert!(remaining > 5000 && remaining <= 10000, "{}", remaining);
+}
+
+#[test]
+fn message_timeout() {
+    drop(env_logger::try_init());
+
+    // messages every 50ms for 500ms, then nothing for a second
+    let tester = TesterServerStreaming::new(|_m, s| {
+        StreamingResponse::no_metadata(test_misc::stream_thread_spawn_iter(move || {
+            (0..11).map(move |i| {
+                thread::sleep(Duration::from_millis(if i == 10 { 1000 } else { 50 }));
+                format!("{}{}", s, i)
+            })
+        }))
+    });
+
+    let o = RequestOptions::new().with_message_timeout(Duration::from_millis(300));
+    let mut rs = tester.client.call_server_streaming(
+        o, "x".to_owned(), string_string_method(&tester.name, GrpcStreaming::ServerStreaming))
+            .wait_drop_metadata();
+
+    // call is longer than timeout, but messages are not
+    for i in 0..10 {
+        assert_eq!(format!("x{}", i), rs.next().unwrap().unwrap());
+    }
+    match rs.next() {
+        Some(Err(Error::GrpcMessage(ref e))) => assert_eq!(GrpcStatus::DeadlineExceeded as i32, e.grpc_status),
+        r => panic!("expecting deadline exceeded: {:?}", r),
+    }
+}
+
+#[test]
+fn keepalive() {
+    drop(env_logger::try_init());
+
+    // empty messages are keepalives
+    let tester = TesterServerStreaming::new(|_m, s| {
+        let resp = StreamingResponse::no_metadata(test_misc::stream_thread_spawn_iter(move || {
+            (0..2).map(move |i| {
+                thread::sleep(Duration::from_millis(if i == 1 { 1000 } else { 0 }));
+                format!("{}{}", s, i)
+            })
+        }));
+        with_keepalive(resp, Duration::from_millis(100), String::new)
+    });
+
+    // without keepalives the call would exceed message timeout
+    let o = RequestOptions::new().with_message_timeout(Duration::from_millis(500));
+    let rs: Vec<String> = tester.client.call_server_streaming(
+        o, "x".to_owned(), string_string_method(&tester.name, GrpcStreaming::ServerStreaming))
+            .wait_drop_metadata()
+            .collect::<Result<_, _>>()
+            .expect("call");
+
+    let messages: Vec<&str> = rs.iter().map(String::as_str).filter(|m| !m.is_empty()).collect();
+    assert_eq!(vec!["x0", "x1"], messages);
+    let keepalives = rs.len() - messages.len();
+    assert!(keepalives >= 5 && keepalives <= 11, "{}", keepalives);
+    assert_eq!("x1", rs.last().unwrap());
+}
+
+#[test]
+fn wait_for_ready() {
+    // nothing listens on this port
+    let client = Client::new_plain(BIND_HOST, 2, Default::default()).unwrap();
+    let method = string_string_method("/test/Unary", GrpcStreaming::Unary);
+
+    let options = RequestOptions::new()
+        .with_timeout(Duration::from_millis(300))
+        .with_wait_for_ready(true);
+    match client.call_unary(options, "aa".to_owned(), method).wait_drop_metadata() {
+        Err(Error::GrpcMessage(ref e)) if e.grpc_status == GrpcStatus::DeadlineExceeded as i32 => {}
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn cancellation() {
+    drop(env_logger::try_init());
+
+    let (cancelled_tx, cancelled_rx) = std::sync::mpsc::channel();
+    let cancelled_tx = Mutex::new(cancelled_tx);
+    let tester = TesterUnary::new(move |o, _s| {
+        let cancelled_tx = cancelled_tx.lock().unwrap().clone();
+        thread::spawn(move || {
+            o.cancelled().wait().unwrap();
+            cancelled_tx.send(()).unwrap();
+        });
+        SingleResponse::no_metadata(empty())
+    });
+
+    let cancellation = Cancellation::new();
+    let o = RequestOptions {
+        cancellation: Some(cancellation.clone()),
+        ..Default::default()
+    };
+    let r = tester.client.call_unary(o, "aa".to_owned(), string_string_method(&tester.name, GrpcStreaming::Unary));
+    thread::sleep(Duration::from_millis(100));
+    cancellation.cancel();
+    match r.wait_drop_metadata() {
+        Err(Error::GrpcMessage(ref e)) => assert_eq!(GrpcStatus::Cancelled as i32, e.grpc_status),
+        r => panic!("expecting cancelled: {:?}", r),
+    }
+
+    // handler is notified when client resets the stream
+    cancelled_rx.recv_timeout(Duration::from_secs(5)).expect("handler notified");
+}
+
+#[test]
+fn empty_messages() {
+    let tester = TesterUnary::new(|_m, s| SingleResponse::completed(s));
+    assert_eq!("", tester.call("").wait().unwrap());
+
+    let tester = TesterServerStreaming::new(|_m, s| {
+        StreamingResponse::completed(vec![s.clone(), s])
+    });
+    assert_eq!(vec!["", ""], tester.call("").collect().wait().unwrap());
+
+    let tester = TesterClientStreaming::new(|_m, s| {
+        SingleResponse::no_metadata(s.0.fold(String::new(), |mut s, message| {
+            s.push_str(&format!("[{}]", message));
+            futures::finished::<_, Error>(s)
+        }))
+    });
+    let (tx, result) = tester.call();
+    let tx = tx.send(String::new()).wait().ok().expect("send");
+    let tx = tx.send(String::new()).wait().ok().expect("send");
+    drop(tx);
+    assert_eq!("[][]", result.wait().unwrap());
+}
+
+#[test]
+fn empty_streams() {
+    let server = new_server("/test", "/Bidi", MethodHandlerBidi::new(|_m, req: StreamingRequest<String>| {
+        StreamingResponse::no_metadata(req.0)
+    }));
+    let port = server.local_addr().port().expect("port");
+    let client = Client::new_plain(BIND_HOST, port, Default::default()).unwrap();
+    let method = string_string_method("/test/Bidi", GrpcStreaming::Bidi);
+
+    let r = client.call_bidi(RequestOptions::new(), StreamingRequest::empty(), method.clone());
+    assert_eq!(Vec::<String>::new(), r.drop_metadata().collect().wait().unwrap());
+
+    let r = client.call_bidi(RequestOptions::new(), StreamingRequest::iter(vec![String::new()]), method);
+    assert_eq!(vec![""], r.drop_metadata().collect().wait().unwrap());
+
+    let tester = TesterServerStreaming::new(|_m, _s| StreamingResponse::empty());
+    assert_eq!(Vec::<String>::new(), tester.call("x").collect().wait().unwrap());
+}
+
+/// Chunk as text message: data prefixed with `d` or checksum prefixed with `c`
+fn chunk_to_string(chunk: Chunk) -> String {
+    match chunk.crc32 {
+        Some(crc32) => format!("c{:08x}", crc32),
+        None => format!("d{}", String::from_utf8(chunk.data.to_vec()).unwrap()),
+    }
+}
+
+fn string_to_chunk(s: String) -> Chunk {
+    match s.chars().next() {
+        Some('c') => Chunk { data: Bytes::new(), crc32: Some(u32::from_str_radix(&s[1..], 16).unwrap()) },
+        _ => Chunk { data: Bytes::from(&s[1..]), crc32: None },
+    }
+}
+
+#[test]
+fn upload() {
+    let tester = TesterClientStreaming::new(|_m, req| {
+        SingleResponse::no_metadata(write_request(req, BlockingIo(Vec::new()), string_to_chunk)
+            .map(|(writer, len)| format!("{} {}", len, String::from_utf8(writer.0).unwrap())))
+    });
+
+    let req = upload_request(BlockingIo(Cursor::new(b"hello world".to_vec())), 4, chunk_to_string);
+    let r = tester.client.call_client_streaming(
+        RequestOptions::new(),
+        req,
+        string_string_method(&tester.name, GrpcStreaming::ClientStreaming));
+    assert_eq!("11 hello world", r.wait_drop_metadata().unwrap());
+
+    let req = StreamingRequest::iter(vec!["dhello".to_owned(), "c00000000".to_owned()]);
+    let r = tester.client.call_client_streaming(
+        RequestOptions::new(),
+        req,
+        string_string_method(&tester.name, GrpcStreaming::ClientStreaming));
+    match r.wait_drop_metadata() {
+        Err(Error::GrpcMessage(ref e)) => assert_eq!(GrpcStatus::DataLoss as i32, e.grpc_status),
+        r => panic!("expecting checksum mismatch: {:?}", r),
+    }
+}
+
+#[test]
+fn download() {
+    let tester = TesterServerStreaming::new(|_m, s| {
+        download_response(BlockingIo(Cursor::new(s.into_bytes())), 4, chunk_to_string)
+    });
+
+    let resp = tester.client.call_server_streaming(
+        RequestOptions::new(),
+        "hello world".to_owned(),
+        string_string_method(&tester.name, GrpcStreaming::ServerStreaming));
+    let (writer, len) = write_response(resp, BlockingIo(Vec::new()), string_to_chunk).wait().unwrap();
+    assert_eq!(11, len);
+    assert_eq!(b"hello world", &writer.0[..]);
+}
+
+/// Writer appending to shared data while its call is the current one,
+/// so a cancelled call can't write after a new one started.
+struct UploadWriter {
+    data: Arc<Mutex<Vec<u8>>>,
+    call: usize,
+    current_call: Arc<AtomicUsize>,
+}
+
+impl std::io::Write for UploadWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.call != self.current_call.load(Ordering::SeqCst) {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "upload resumed by another call"));
+        }
+        self.data.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn resumable_upload() {
+    drop(env_logger::try_init());
+
+    let data = Arc::new(Mutex::new(Vec::new()));
+    let current_call = Arc::new(AtomicUsize::new(0));
+    let server = {
+        let data = data.clone();
+        new_server("/test", "/Upload", MethodHandlerBidi::new(move |o, req: StreamingRequest<String>| {
+            let (upload_id, offset) = upload_position(&o.metadata).unwrap();
+            assert!(upload_id.is_none() || upload_id == Some("u1".to_owned()));
+            data.lock().unwrap().truncate(offset as usize);
+            let writer = UploadWriter {
+                data: data.clone(),
+                call: current_call.fetch_add(1, Ordering::SeqCst) + 1,
+                current_call: current_call.clone(),
+            };
+            resumable_upload_response(
+                "u1", offset, req, BlockingIo(writer), 4,
+                |m: String| Chunk { data: Bytes::from(m), crc32: None },
+                |offset| offset.to_string())
+        }))
+    };
+    let port = server.local_addr().port().expect("port");
+    let client = Client::new_plain(BIND_HOST, port, Default::default()).unwrap();
+    let method = string_string_method("/test/Upload", GrpcStreaming::Bidi);
+
+    // first call fails after the first acknowledgement
+    let offsets = Arc::new(Mutex::new(Vec::new()));
+    let offsets_call = offsets.clone();
+    let mut upload = ResumableUpload::new(
+        move |o: RequestOptions, req| {
+            let offset = String::from_utf8(o.metadata.get(UPLOAD_OFFSET_METADATA_KEY).unwrap().to_vec()).unwrap();
+            let first = offsets_call.lock().unwrap().is_empty();
+            offsets_call.lock().unwrap().push(offset);
+            let resp = client.call_bidi(o, req, method.clone());
+            if !first {
+                return resp;
+            }
+            StreamingResponse::new(resp.0.and_then(|(metadata, stream)| {
+                let lost = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "connection lost");
+                let acks = stream.drop_metadata().take(1).chain(futures::stream::once(Err(Error::Io(lost))));
+                StreamingResponse::metadata_and_stream(metadata, acks).0
+            }))
+        },
+        |chunk| String::from_utf8(chunk.data.to_vec()).unwrap(),
+        |ack: String| ack.parse().unwrap());
+    upload.chunk_size = 4;
+    let (upload_id, len) = upload.upload(
+        RequestOptions::new(), BlockingIo(Cursor::new(b"hello resumable world".to_vec())))
+            .wait()
+            .unwrap();
+
+    assert_eq!("u1", upload_id);
+    assert_eq!(21, len);
+    assert_eq!(vec!["0", "4"], *offsets.lock().unwrap());
+    assert_eq!(&b"hello resumable world"[..], &data.lock().unwrap()[..]);
+}
+
@@ -16,6 +16,7 @@ pub fn string_string_method(name: &str, streaming: GrpcStreaming)
     Arc::new(MethodDescriptor {
        name: name.to_owned(),
        streaming: streaming,
+       options: Default::default(),
        req_marshaller: Box::new(MarshallerString),
        resp_marshaller: Box::new(MarshallerString),
    })
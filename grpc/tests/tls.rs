@@ -0,0 +1,140 @@
+//! Calls over TLS with implementations enabled by `rustls` and `native-tls` features.
+//!
+//! Certificates are generated by `keys/gen-keys.sh`.
+
+#![cfg(any(feature = "rustls", feature = "native-tls"))]
+
+extern crate grpc;
+extern crate httpbis;
+extern crate tls_api;
+extern crate env_logger;
+extern crate futures;
+
+mod test_misc;
+
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::Future;
+
+use tls_api::TlsConnector;
+use tls_api::TlsConnectorBuilder;
+use tls_api::TlsAcceptorBuilder;
+
+use grpc::*;
+use grpc::rt::*;
+
+use test_misc::*;
+
+
+/// Server certificate is issued to this name
+const HOST: &str = "localhost";
+
+const ROOT_CA: &[u8] = include_bytes!("keys/tls-root-ca.der");
+#[cfg(feature = "rustls")]
+const CERT: &[u8] = include_bytes!("keys/tls-localhost.der");
+#[cfg(feature = "rustls")]
+const KEY: &[u8] = include_bytes!("keys/tls-localhost.key.der");
+#[cfg(feature = "native-tls")]
+const PKCS12: &[u8] = include_bytes!("keys/tls-localhost.p12");
+
+#[cfg(feature = "rustls")]
+fn rustls_acceptor() -> tls::rustls::TlsAcceptor {
+    tls::rustls::TlsAcceptorBuilder::from_certs_and_key(&[CERT], KEY)
+        .expect("acceptor builder")
+        .build()
+        .expect("acceptor")
+}
+
+#[cfg(feature = "native-tls")]
+fn native_tls_acceptor() -> tls::native_tls::TlsAcceptor {
+    tls::native_tls::TlsAcceptorBuilder::from_pkcs12(PKCS12, "test")
+        .expect("acceptor builder")
+        .build()
+        .expect("acceptor")
+}
+
+/// Connector trusting test root CA
+fn connector<C : TlsConnector>() -> C {
+    let mut builder = C::builder().expect("connector builder");
+    builder.add_root_certificate(tls_api::Certificate::from_der(ROOT_CA.to_vec()))
+        .expect("add_root_certificate");
+    builder.build().expect("connector")
+}
+
+fn echo_round_trip<A, C>(acceptor: A, connector: C)
+    where A : tls_api::TlsAcceptor, C : TlsConnector
+{
+    drop(env_logger::try_init());
+
+    let mut server = ServerBuilder::<A>::new();
+    server.http.set_port(0);
+    server.http.set_tls(acceptor);
+    server.add_service(ServerServiceDefinition::new("/test", vec![
+        ServerMethod::new(
+            string_string_method("/test/Echo", GrpcStreaming::Unary),
+            MethodHandlerUnary::new(|_o, s| SingleResponse::completed(s))),
+    ]));
+    let server = server.build().expect("server");
+    let port = server.local_addr().port().expect("port");
+
+    let addr = SocketAddr::new(BIND_HOST.parse().unwrap(), port);
+    let tls = httpbis::ClientTlsOption::Tls(HOST.to_owned(), Arc::new(connector));
+    let client = Client::new_expl(&addr, HOST, tls, ClientConf::new()).expect("client");
+
+    let r = client.call_unary(
+        RequestOptions::new(),
+        "abc".to_owned(),
+        string_string_method("/test/Echo", GrpcStreaming::Unary))
+            .wait_drop_metadata();
+    assert_eq!("abc", r.unwrap());
+}
+
+#[cfg(feature = "rustls")]
+#[test]
+fn rustls() {
+    echo_round_trip(rustls_acceptor(), connector::<tls::rustls::TlsConnector>());
+}
+
+#[cfg(feature = "native-tls")]
+#[test]
+fn native_tls() {
+    echo_round_trip(native_tls_acceptor(), connector::<tls::native_tls::TlsConnector>());
+}
+
+#[cfg(feature = "rustls")]
+#[test]
+fn default_tls() {
+    echo_round_trip(rustls_acceptor(), connector::<tls::DefaultTlsConnector>());
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+#[test]
+fn default_tls() {
+    echo_round_trip(native_tls_acceptor(), connector::<tls::DefaultTlsConnector>());
+}
+
+#[test]
+fn handshake_timeout() {
+    drop(env_logger::try_init());
+
+    // accepted by OS, but nobody answers TLS handshake
+    let listener = TcpListener::bind((BIND_HOST, 0)).expect("bind");
+    let addr = listener.local_addr().expect("local_addr");
+
+    let mut conf = ClientConf::new();
+    conf.connect_timeout = Some(Duration::from_secs(1));
+    conf.handshake_timeout = Some(Duration::from_millis(200));
+    let tls = httpbis::ClientTlsOption::Tls(HOST.to_owned(), Arc::new(connector::<tls::DefaultTlsConnector>()));
+    let client = Client::new_expl(&addr, HOST, tls, conf).expect("client");
+
+    let start = Instant::now();
+    match client.warm_up().wait() {
+        Err(ref e) if e.kind() == ErrorKind::Transport => {}
+        r => panic!("expecting handshake timeout: {:?}", r),
+    }
+    assert!(start.elapsed() < Duration::from_secs(5), "{:?}", start.elapsed());
+}
@@ -18,6 +18,67 @@ const (
 	address     = "localhost:23432"
 )
 
+func connect() *grpc.ClientConn {
+    conn, err := grpc.Dial(address, grpc.WithInsecure())
+   	if err != nil {
+   		log.Fatalf("did not connect: %v", err)
+    }
+    return conn
+}
+
+func env_u32(name string) uint32 {
+    s := os.Getenv(name)
+    if s == "" {
+        return 0
+    }
+    v, err := strconv.ParseUint(s, 10, 32)
+    if err != nil {
+        log.Fatalf("failed to parse %s as u32: %v", name, err)
+    }
+    return uint32(v)
+}
+
+// same environment variables as Rust client, see faults.rs
+type client_faults struct {
+    server pb.Faults
+    cancel_percent uint32
+    reconnect_percent uint32
+}
+
+func client_faults_from_env() client_faults {
+    return client_faults{
+        server: pb.Faults{
+            MaxDelayMs: env_u32("LONG_TESTS_MAX_DELAY_MS"),
+            ErrorPercent: env_u32("LONG_TESTS_ERROR_PERCENT"),
+        },
+        cancel_percent: env_u32("LONG_TESTS_CANCEL_PERCENT"),
+        reconnect_percent: env_u32("LONG_TESTS_RECONNECT_PERCENT"),
+    }
+}
+
+func roll(percent uint32) bool {
+    return uint32(rand.Intn(100)) < percent
+}
+
+// count of iterations by outcome
+type outcomes struct {
+    ok uint64
+    canceled uint64
+    injected_errors uint64
+}
+
+func (o *outcomes) error(err error) {
+    if grpc.ErrorDesc(err) == "injected fault" {
+        o.injected_errors += 1
+    } else {
+        log.Fatalf("unexpected error: %v", err)
+    }
+}
+
+func (o *outcomes) print() {
+    log.Printf("done: %d ok, %d canceled, %d injected errors", o.ok, o.canceled, o.injected_errors)
+}
+
 func single_num_arg_or(cmd_args []string, or int) int {
     if len(cmd_args) > 1 {
         log.Fatalf("too many char_count params: %s", len(cmd_args));
@@ -85,39 +146,121 @@ func run_char_count(c pb.LongTestsClient, cmd_args []string) {
     log.Printf("successfully got correct answer")
 }
 
-func run_random_strings(c pb.LongTestsClient, cmd_args []string) {
+func run_random_strings(c pb.LongTestsClient, faults client_faults, cmd_args []string) {
     count := single_num_arg_or(cmd_args, 10)
 
     log.Printf("requesting %d string from server", count)
 
-    client, err := c.RandomStrings(context.Background(), &pb.RandomStringsRequest{ Count: uint64(count) })
+    ctx, cancel := context.WithCancel(context.Background())
+    defer cancel()
+
+    client, err := c.RandomStrings(ctx, &pb.RandomStringsRequest{ Count: uint64(count), Faults: &faults.server })
     if err != nil {
         log.Fatalf("failed to execute request: %v", err)
     }
 
+    cancel_stream := roll(faults.cancel_percent)
+
+    o := outcomes{}
     for {
         _, err := client.Recv()
         if err == io.EOF {
             log.Printf("got eof")
+            o.ok += 1
             break
         }
         if err != nil {
-            log.Fatalf("could not read response part: %v", err)
+            o.error(err)
+            break
+        }
+        if cancel_stream {
+            // canceling context resets the stream
+            cancel()
+            o.canceled += 1
+            break
         }
     }
+
+    o.print()
 }
 
+func run_echo_stream(conn *grpc.ClientConn, faults client_faults, cmd_args []string) {
+    count := single_num_arg_or(cmd_args, 1)
 
-func main() {
-    conn, err := grpc.Dial(address, grpc.WithInsecure())
-   	if err != nil {
-   		log.Fatalf("did not connect: %v", err)
+    log.Printf("running %d iterations of echo_stream", count)
+
+    c := pb.NewLongTestsClient(conn)
+
+    o := outcomes{}
+    for i := 0; i < count; i += 1 {
+        ctx, cancel := context.WithCancel(context.Background())
+
+        client, err := c.EchoStream(ctx)
+        if err != nil {
+            log.Fatalf("failed to start request: %v", err)
+        }
+
+        i := i
+        n := i % 20
+        go func() {
+            for j := 0; j < n; j += 1 {
+                payload := fmt.Sprintf("payload %d %d", i, j)
+                if client.Send(&pb.EchoStreamRequest{Payload: payload, Faults: &faults.server}) != nil {
+                    // error is reported by Recv
+                    return
+                }
+            }
+            client.CloseSend()
+        }()
+
+        cancel_stream := roll(faults.cancel_percent)
+
+        received := 0
+        for {
+            r, err := client.Recv()
+            if err == io.EOF {
+                if received != n {
+                    log.Fatalf("expected %d messages, got %d", n, received)
+                }
+                o.ok += 1
+                break
+            }
+            if err != nil {
+                o.error(err)
+                break
+            }
+            if r.Payload != fmt.Sprintf("payload %d %d", i, received) {
+                log.Fatalf("wrong payload: %v", r)
+            }
+            received += 1
+            if cancel_stream {
+                o.canceled += 1
+                break
+            }
+        }
+
+        cancel()
+
+        if roll(faults.reconnect_percent) {
+            conn.Close()
+            conn = connect()
+            c = pb.NewLongTestsClient(conn)
+        }
     }
 
-    defer conn.Close()
+    conn.Close()
+
+    o.print()
+}
+
+
+func main() {
+    conn := connect()
 
     c := pb.NewLongTestsClient(conn)
 
+    faults := client_faults_from_env()
+
     if len(os.Args) < 2 {
         log.Fatalf("too few args")
     }
@@ -134,7 +277,10 @@ func main() {
         run_char_count(c, cmd_args)
         return
     case "random_strings":
-        run_random_strings(c, cmd_args)
+        run_random_strings(c, faults, cmd_args)
+        return
+    case "echo_stream":
+        run_echo_stream(conn, faults, cmd_args)
         return
     default:
         log.Fatalf("unknown command: %s", cmd)
@@ -15,6 +15,9 @@ It has these top-level messages:
 	CharCountResponse
 	RandomStringsRequest
 	RandomStringsResponse
+	EchoStreamRequest
+	EchoStreamResponse
+	Faults
 */
 package long_tests_pb
 
@@ -75,7 +78,8 @@ func (*CharCountResponse) ProtoMessage()               {}
 func (*CharCountResponse) Descriptor() ([]byte, []int) { return fileDescriptor0, []int{3} }
 
 type RandomStringsRequest struct {
-	Count uint64 `protobuf:"varint,1,opt,name=count" json:"count,omitempty"`
+	Count  uint64  `protobuf:"varint,1,opt,name=count" json:"count,omitempty"`
+	Faults *Faults `protobuf:"bytes,2,opt,name=faults" json:"faults,omitempty"`
 }
 
 func (m *RandomStringsRequest) Reset()                    { *m = RandomStringsRequest{} }
@@ -83,6 +87,13 @@ func (m *RandomStringsRequest) String() string            { return proto.Compact
 func (*RandomStringsRequest) ProtoMessage()               {}
 func (*RandomStringsRequest) Descriptor() ([]byte, []int) { return fileDescriptor0, []int{4} }
 
+func (m *RandomStringsRequest) GetFaults() *Faults {
+	if m != nil {
+		return m.Faults
+	}
+	return nil
+}
+
 type RandomStringsResponse struct {
 	S string `protobuf:"bytes,1,opt,name=s" json:"s,omitempty"`
 }
@@ -92,6 +103,42 @@ func (m *RandomStringsResponse) String() string            { return proto.Compac
 func (*RandomStringsResponse) ProtoMessage()               {}
 func (*RandomStringsResponse) Descriptor() ([]byte, []int) { return fileDescriptor0, []int{5} }
 
+type EchoStreamRequest struct {
+	Payload string  `protobuf:"bytes,1,opt,name=payload" json:"payload,omitempty"`
+	Faults  *Faults `protobuf:"bytes,2,opt,name=faults" json:"faults,omitempty"`
+}
+
+func (m *EchoStreamRequest) Reset()                    { *m = EchoStreamRequest{} }
+func (m *EchoStreamRequest) String() string            { return proto.CompactTextString(m) }
+func (*EchoStreamRequest) ProtoMessage()               {}
+func (*EchoStreamRequest) Descriptor() ([]byte, []int) { return fileDescriptor0, []int{6} }
+
+func (m *EchoStreamRequest) GetFaults() *Faults {
+	if m != nil {
+		return m.Faults
+	}
+	return nil
+}
+
+type EchoStreamResponse struct {
+	Payload string `protobuf:"bytes,1,opt,name=payload" json:"payload,omitempty"`
+}
+
+func (m *EchoStreamResponse) Reset()                    { *m = EchoStreamResponse{} }
+func (m *EchoStreamResponse) String() string            { return proto.CompactTextString(m) }
+func (*EchoStreamResponse) ProtoMessage()               {}
+func (*EchoStreamResponse) Descriptor() ([]byte, []int) { return fileDescriptor0, []int{7} }
+
+type Faults struct {
+	MaxDelayMs   uint32 `protobuf:"varint,1,opt,name=max_delay_ms,json=maxDelayMs" json:"max_delay_ms,omitempty"`
+	ErrorPercent uint32 `protobuf:"varint,2,opt,name=error_percent,json=errorPercent" json:"error_percent,omitempty"`
+}
+
+func (m *Faults) Reset()                    { *m = Faults{} }
+func (m *Faults) String() string            { return proto.CompactTextString(m) }
+func (*Faults) ProtoMessage()               {}
+func (*Faults) Descriptor() ([]byte, []int) { return fileDescriptor0, []int{8} }
+
 func init() {
 	proto.RegisterType((*EchoRequest)(nil), "EchoRequest")
 	proto.RegisterType((*EchoResponse)(nil), "EchoResponse")
@@ -99,6 +146,9 @@ func init() {
 	proto.RegisterType((*CharCountResponse)(nil), "CharCountResponse")
 	proto.RegisterType((*RandomStringsRequest)(nil), "RandomStringsRequest")
 	proto.RegisterType((*RandomStringsResponse)(nil), "RandomStringsResponse")
+	proto.RegisterType((*EchoStreamRequest)(nil), "EchoStreamRequest")
+	proto.RegisterType((*EchoStreamResponse)(nil), "EchoStreamResponse")
+	proto.RegisterType((*Faults)(nil), "Faults")
 }
 
 // Reference imports to suppress errors if they are not otherwise used.
@@ -118,6 +168,8 @@ type LongTestsClient interface {
 	CharCount(ctx context.Context, opts ...grpc.CallOption) (LongTests_CharCountClient, error)
 	// server streaming
 	RandomStrings(ctx context.Context, in *RandomStringsRequest, opts ...grpc.CallOption) (LongTests_RandomStringsClient, error)
+	// bidi streaming
+	EchoStream(ctx context.Context, opts ...grpc.CallOption) (LongTests_EchoStreamClient, error)
 }
 
 type longTestsClient struct {
@@ -203,6 +255,37 @@ func (x *longTestsRandomStringsClient) Recv() (*RandomStringsResponse, error) {
 	return m, nil
 }
 
+func (c *longTestsClient) EchoStream(ctx context.Context, opts ...grpc.CallOption) (LongTests_EchoStreamClient, error) {
+	stream, err := grpc.NewClientStream(ctx, &_LongTests_serviceDesc.Streams[2], c.cc, "/LongTests/echo_stream", opts...)
+	if err != nil {
+		return nil, err
+	}
+	x := &longTestsEchoStreamClient{stream}
+	return x, nil
+}
+
+type LongTests_EchoStreamClient interface {
+	Send(*EchoStreamRequest) error
+	Recv() (*EchoStreamResponse, error)
+	grpc.ClientStream
+}
+
+type longTestsEchoStreamClient struct {
+	grpc.ClientStream
+}
+
+func (x *longTestsEchoStreamClient) Send(m *EchoStreamRequest) error {
+	return x.ClientStream.SendMsg(m)
+}
+
+func (x *longTestsEchoStreamClient) Recv() (*EchoStreamResponse, error) {
+	m := new(EchoStreamResponse)
+	if err := x.ClientStream.RecvMsg(m); err != nil {
+		return nil, err
+	}
+	return m, nil
+}
+
 // Server API for LongTests service
 
 type LongTestsServer interface {
@@ -212,6 +295,8 @@ type LongTestsServer interface {
 	CharCount(LongTests_CharCountServer) error
 	// server streaming
 	RandomStrings(*RandomStringsRequest, LongTests_RandomStringsServer) error
+	// bidi streaming
+	EchoStream(LongTests_EchoStreamServer) error
 }
 
 func RegisterLongTestsServer(s *grpc.Server, srv LongTestsServer) {
@@ -283,6 +368,32 @@ func (x *longTestsRandomStringsServer) Send(m *RandomStringsResponse) error {
 	return x.ServerStream.SendMsg(m)
 }
 
+func _LongTests_EchoStream_Handler(srv interface{}, stream grpc.ServerStream) error {
+	return srv.(LongTestsServer).EchoStream(&longTestsEchoStreamServer{stream})
+}
+
+type LongTests_EchoStreamServer interface {
+	Send(*EchoStreamResponse) error
+	Recv() (*EchoStreamRequest, error)
+	grpc.ServerStream
+}
+
+type longTestsEchoStreamServer struct {
+	grpc.ServerStream
+}
+
+func (x *longTestsEchoStreamServer) Send(m *EchoStreamResponse) error {
+	return x.ServerStream.SendMsg(m)
+}
+
+func (x *longTestsEchoStreamServer) Recv() (*EchoStreamRequest, error) {
+	m := new(EchoStreamRequest)
+	if err := x.ServerStream.RecvMsg(m); err != nil {
+		return nil, err
+	}
+	return m, nil
+}
+
 var _LongTests_serviceDesc = grpc.ServiceDesc{
 	ServiceName: "LongTests",
 	HandlerType: (*LongTestsServer)(nil),
@@ -303,6 +414,12 @@ var _LongTests_serviceDesc = grpc.ServiceDesc{
 			Handler:       _LongTests_RandomStrings_Handler,
 			ServerStreams: true,
 		},
+		{
+			StreamName:    "echo_stream",
+			Handler:       _LongTests_EchoStream_Handler,
+			ServerStreams: true,
+			ClientStreams: true,
+		},
 	},
 	Metadata: fileDescriptor0,
 }
@@ -310,22 +427,28 @@ var _LongTests_serviceDesc = grpc.ServiceDesc{
 func init() { proto.RegisterFile("long_tests_pb.proto", fileDescriptor0) }
 
 var fileDescriptor0 = []byte{
-	// 262 bytes of a gzipped FileDescriptorProto
-	0x1f, 0x8b, 0x08, 0x00, 0x00, 0x09, 0x6e, 0x88, 0x02, 0xff, 0x6c, 0x91, 0xdf, 0x4a, 0xc3, 0x30,
-	0x14, 0xc6, 0x89, 0x54, 0xa5, 0xc7, 0x2a, 0x2e, 0x6e, 0x32, 0x0a, 0x82, 0x44, 0xd4, 0x5e, 0x48,
-	0x90, 0x89, 0x0f, 0x20, 0xc3, 0x3b, 0xaf, 0xaa, 0xf7, 0x25, 0xcb, 0x42, 0x2b, 0xcc, 0x9c, 0x98,
-	0x93, 0x5d, 0xf8, 0x4a, 0x3e, 0xa5, 0xac, 0x7f, 0x20, 0x9b, 0xbb, 0xeb, 0x29, 0xbf, 0x7c, 0xf9,
-	0x7d, 0x27, 0x70, 0xb1, 0x42, 0x5b, 0x57, 0xc1, 0x50, 0xa0, 0xca, 0x2d, 0xa4, 0xf3, 0x18, 0x50,
-	0xdc, 0xc3, 0xc9, 0xab, 0x6e, 0xb0, 0x34, 0xdf, 0x6b, 0x43, 0x81, 0x4f, 0xe1, 0xd8, 0xa9, 0x9f,
-	0x15, 0xaa, 0xe5, 0x94, 0x5d, 0xb3, 0x22, 0x2d, 0x87, 0x51, 0x14, 0x90, 0x75, 0x20, 0x39, 0xb4,
-	0x64, 0x62, 0xf2, 0x60, 0x9b, 0xbc, 0x83, 0xf3, 0x79, 0xa3, 0xfc, 0x1c, 0xd7, 0x36, 0x0c, 0xb9,
-	0x1c, 0x12, 0xa7, 0x7c, 0xe8, 0x43, 0xdb, 0x6f, 0x31, 0x83, 0x51, 0xc4, 0xf5, 0xb1, 0x57, 0x00,
-	0xba, 0x51, 0xbe, 0xd2, 0x9b, 0xbf, 0x2d, 0x9e, 0x94, 0xa9, 0x1e, 0x30, 0xf1, 0x00, 0xe3, 0x52,
-	0xd9, 0x25, 0x7e, 0xbd, 0x07, 0xff, 0x69, 0x6b, 0x1a, 0xf2, 0xc7, 0x70, 0x18, 0x9f, 0xe8, 0x06,
-	0x71, 0x0b, 0x93, 0x1d, 0xba, 0xbf, 0x25, 0x03, 0x46, 0xbd, 0x0b, 0xa3, 0xd9, 0x2f, 0x83, 0xf4,
-	0x0d, 0x6d, 0xfd, 0xb1, 0x59, 0x0d, 0xbf, 0x81, 0xc4, 0xe8, 0x06, 0x79, 0x26, 0xa3, 0xc5, 0xe4,
-	0xa7, 0x72, 0xab, 0xfd, 0x73, 0xac, 0xc9, 0x47, 0x72, 0xb7, 0x70, 0xce, 0xe5, 0xbf, 0x6e, 0x05,
-	0xe3, 0x2f, 0x70, 0xe6, 0x5b, 0xa1, 0x8a, 0x3a, 0x23, 0x3e, 0x91, 0xfb, 0xfa, 0xe4, 0x97, 0x72,
-	0xaf, 0xf8, 0x23, 0x5b, 0x1c, 0xb5, 0xef, 0xf6, 0xf4, 0x17, 0x00, 0x00, 0xff, 0xff, 0x7f, 0xcb,
-	0x96, 0x48, 0xce, 0x01, 0x00, 0x00,
+	// 367 bytes of a gzipped FileDescriptorProto
+	0x1f, 0x8b, 0x08, 0x00, 0x00, 0x09, 0x6e, 0x88, 0x02, 0xff, 0x85, 0x52, 0x41, 0x4f, 0xc2, 0x30,
+	0x18, 0x4d, 0x09, 0x42, 0xf8, 0x18, 0x46, 0x0a, 0x18, 0xb2, 0xc4, 0x48, 0x66, 0xd4, 0x9d, 0x1a,
+	0x82, 0xf1, 0xe2, 0xcd, 0xa0, 0x9e, 0x44, 0xcd, 0xf4, 0xbe, 0x94, 0x51, 0xc1, 0x64, 0x5b, 0x47,
+	0x5b, 0x12, 0xf9, 0xc3, 0xfe, 0x0e, 0xbb, 0x6e, 0x4b, 0x06, 0x23, 0xe1, 0xd6, 0xef, 0xed, 0xf5,
+	0xed, 0xf5, 0xbd, 0x0f, 0x7a, 0x21, 0x8f, 0x97, 0xbe, 0x62, 0x52, 0x49, 0x3f, 0x99, 0x93, 0x44,
+	0x70, 0xc5, 0x9d, 0x5b, 0x68, 0x3f, 0x07, 0x2b, 0xee, 0xb1, 0xf5, 0x46, 0x7f, 0xc0, 0x43, 0x68,
+	0x26, 0x74, 0x1b, 0x72, 0xba, 0x18, 0xa2, 0x11, 0x72, 0x5b, 0x5e, 0x31, 0x3a, 0x2e, 0x58, 0x19,
+	0x51, 0x26, 0x3c, 0x96, 0xac, 0xcc, 0xac, 0xed, 0x32, 0x6f, 0xe0, 0x6c, 0xba, 0xa2, 0x62, 0xca,
+	0x37, 0xb1, 0x2a, 0x74, 0x31, 0xd4, 0x13, 0x2a, 0x54, 0x2e, 0x6a, 0xce, 0xce, 0x04, 0xba, 0x25,
+	0x5e, 0x2e, 0x7b, 0x01, 0x10, 0x68, 0xd0, 0x0f, 0x52, 0xd4, 0xd0, 0xeb, 0x5e, 0x2b, 0x28, 0x68,
+	0xce, 0x0c, 0xfa, 0x1e, 0x8d, 0x17, 0x3c, 0xfa, 0x54, 0xe2, 0x27, 0x5e, 0xca, 0x42, 0xbf, 0x0f,
+	0x27, 0xe5, 0x1b, 0xd9, 0x80, 0x2f, 0xa1, 0xf1, 0x4d, 0x37, 0xa1, 0x92, 0xc6, 0x62, 0x7b, 0xd2,
+	0x24, 0x2f, 0x66, 0xf4, 0x72, 0xd8, 0xb9, 0x86, 0xc1, 0x9e, 0x5c, 0x6e, 0xc3, 0x02, 0x24, 0x73,
+	0xb3, 0x48, 0x3a, 0x6f, 0xd0, 0x4d, 0xdf, 0xae, 0x49, 0x8c, 0x46, 0x47, 0xa3, 0x3a, 0xfe, 0x5b,
+	0x02, 0xb8, 0xac, 0x57, 0x4d, 0x74, 0x2f, 0xfb, 0x77, 0x68, 0x64, 0x0a, 0x78, 0x04, 0x56, 0x44,
+	0x7f, 0xfd, 0x05, 0x0b, 0xe9, 0xd6, 0x8f, 0x32, 0x8b, 0x1d, 0x0f, 0x34, 0xf6, 0x94, 0x42, 0x33,
+	0x89, 0xaf, 0xa0, 0xc3, 0x84, 0xe0, 0xc2, 0x4f, 0x98, 0x08, 0x98, 0x4e, 0xa4, 0x66, 0x28, 0x96,
+	0x01, 0x3f, 0x32, 0x6c, 0xf2, 0x87, 0xa0, 0xf5, 0xaa, 0xb7, 0xe1, 0x2b, 0x5d, 0x06, 0x7d, 0xa5,
+	0xce, 0xb4, 0x1d, 0x6c, 0x91, 0xd2, 0x2a, 0xd8, 0x1d, 0xb2, 0xd3, 0xf7, 0x7d, 0xb9, 0x18, 0xdc,
+	0x25, 0xfb, 0x15, 0xdb, 0x98, 0x54, 0xda, 0x74, 0x11, 0x7e, 0x84, 0x53, 0x61, 0x12, 0xf6, 0x65,
+	0x16, 0x31, 0x1e, 0x90, 0x43, 0x0d, 0xda, 0xe7, 0xe4, 0x60, 0x13, 0x63, 0x84, 0x1f, 0xa0, 0x9d,
+	0xda, 0x4b, 0x05, 0x74, 0x5c, 0x18, 0x93, 0x4a, 0x17, 0x76, 0x8f, 0x54, 0xf3, 0x74, 0xd1, 0x18,
+	0xcd, 0x1b, 0x66, 0xcb, 0xef, 0xfe, 0x01, 0x5f, 0x6e, 0xe2, 0x87, 0xfc, 0x02, 0x00, 0x00,
 }
@@ -16,6 +16,9 @@ import (
     //"math/rand"
     //"io"
     "io"
+    "math/rand"
+    "time"
+    "google.golang.org/grpc/codes"
 )
 
 const (
@@ -24,6 +27,23 @@ const (
 
 type server struct{}
 
+// same message as in Rust faults.rs, so clients can tell injected errors from real ones
+const injectedErrorMessage = "injected fault"
+
+// sleep random time and maybe fail, as requested by client
+func inject(faults *pb.Faults) error {
+    if faults == nil {
+        return nil
+    }
+    if faults.MaxDelayMs != 0 {
+        time.Sleep(time.Duration(rand.Intn(int(faults.MaxDelayMs) + 1)) * time.Millisecond)
+    }
+    if uint32(rand.Intn(100)) < faults.ErrorPercent {
+        return grpc.Errorf(codes.Unavailable, injectedErrorMessage)
+    }
+    return nil
+}
+
 func (s *server) Echo(context context.Context, req *pb.EchoRequest) (*pb.EchoResponse, error) {
     return &pb.EchoResponse{Payload: req.Payload}, nil
 }
@@ -45,6 +65,9 @@ func (s *server) CharCount(req pb.LongTests_CharCountServer) error {
 
 func (s *server) RandomStrings(req *pb.RandomStringsRequest, resp pb.LongTests_RandomStringsServer) error {
     for i := 0; i < int(req.Count); i += 1 {
+        if err := inject(req.Faults); err != nil {
+            return err
+        }
         err := resp.Send(&pb.RandomStringsResponse{S: "aabb"})
         if err != nil {
             return err
@@ -53,6 +76,25 @@ func (s *server) RandomStrings(req *pb.RandomStringsRequest, resp pb.LongTests_R
     return nil
 }
 
+func (s *server) EchoStream(stream pb.LongTests_EchoStreamServer) error {
+    for {
+        m, err := stream.Recv()
+        if err == io.EOF {
+            return nil
+        }
+        if err != nil {
+            return err
+        }
+        if err := inject(m.Faults); err != nil {
+            return err
+        }
+        err = stream.Send(&pb.EchoStreamResponse{Payload: m.Payload})
+        if err != nil {
+            return err
+        }
+    }
+}
+
 
 func main() {
     lis, err := net.Listen("tcp", port)
@@ -6,6 +6,8 @@ extern crate futures;
 
 use long_tests::long_tests_pb::*;
 use long_tests::long_tests_pb_grpc::*;
+use long_tests::faults;
+use long_tests::faults::ClientFaults;
 
 use std::env;
 
@@ -20,6 +22,33 @@ fn single_num_arg_or(cmd_args: &[String], or: u64) -> u64 {
     }
 }
 
+fn connect() -> LongTestsClient {
+    LongTestsClient::new_plain("localhost", 23432, Default::default()).expect("init")
+}
+
+/// Count of iterations by outcome
+#[derive(Default)]
+struct Outcomes {
+    ok: u64,
+    canceled: u64,
+    injected_errors: u64,
+}
+
+impl Outcomes {
+    fn error(&mut self, e: grpc::Error) {
+        if faults::is_injected_error(&e) {
+            self.injected_errors += 1;
+        } else {
+            panic!("unexpected error: {:?}", e);
+        }
+    }
+
+    fn print(&self) {
+        println!("done: {} ok, {} canceled, {} injected errors",
+            self.ok, self.canceled, self.injected_errors);
+    }
+}
+
 
 fn run_echo(client: LongTestsClient, cmd_args: &[String]) {
     let count = single_num_arg_or(cmd_args, 1);
@@ -40,6 +69,130 @@ fn run_echo(client: LongTestsClient, cmd_args: &[String]) {
     println!("done");
 }
 
+fn run_char_count(client: LongTestsClient, cmd_args: &[String]) {
+    let count = single_num_arg_or(cmd_args, 10);
+
+    println!("sending {} messages to count", count);
+
+    let s = "abcdefghijklmnopqrstuvwxyz0123456789";
+    let parts: Vec<CharCountRequest> = (0..count)
+        .map(|i| {
+            let mut req = CharCountRequest::new();
+            req.set_part(s[..(i as usize % s.len())].to_owned());
+            req
+        })
+        .collect();
+    let expected: u64 = parts.iter().map(|p| p.get_part().len() as u64).sum();
+
+    let r = client.char_count(grpc::RequestOptions::new(), grpc::StreamingRequest::iter(parts))
+        .wait_drop_metadata()
+        .expect("failed to get char_count response");
+
+    assert_eq!(expected, r.get_char_count());
+
+    println!("done");
+}
+
+fn run_random_strings(mut client: LongTestsClient, faults: &ClientFaults, cmd_args: &[String]) {
+    let count = single_num_arg_or(cmd_args, 1);
+
+    println!("running {} iterations of random_strings", count);
+
+    let mut outcomes = Outcomes::default();
+
+    for i in 0..count {
+        let mut req = RandomStringsRequest::new();
+        req.set_count(i % 100);
+        req.set_faults(faults.server.clone());
+
+        let cancel = faults.should_cancel();
+
+        let mut received = 0;
+        let mut error = None;
+        for r in client.random_strings(grpc::RequestOptions::new(), req).wait_drop_metadata() {
+            match r {
+                Ok(_) => received += 1,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+            if cancel {
+                // dropping iterator resets the stream
+                break;
+            }
+        }
+
+        match error {
+            Some(e) => outcomes.error(e),
+            None if cancel => outcomes.canceled += 1,
+            None => {
+                assert_eq!(i % 100, received);
+                outcomes.ok += 1;
+            }
+        }
+
+        if faults.should_reconnect() {
+            client = connect();
+        }
+    }
+
+    outcomes.print();
+}
+
+fn run_echo_stream(mut client: LongTestsClient, faults: &ClientFaults, cmd_args: &[String]) {
+    let count = single_num_arg_or(cmd_args, 1);
+
+    println!("running {} iterations of echo_stream", count);
+
+    let mut outcomes = Outcomes::default();
+
+    for i in 0..count {
+        let payloads: Vec<String> = (0..(i % 20)).map(|j| format!("payload {} {}", i, j)).collect();
+        let reqs: Vec<EchoStreamRequest> = payloads.iter()
+            .map(|p| {
+                let mut req = EchoStreamRequest::new();
+                req.set_payload(p.clone());
+                req.set_faults(faults.server.clone());
+                req
+            })
+            .collect();
+
+        let cancel = faults.should_cancel();
+
+        let mut received = Vec::new();
+        let mut error = None;
+        let resp = client.echo_stream(grpc::RequestOptions::new(), grpc::StreamingRequest::iter(reqs));
+        for r in resp.wait_drop_metadata() {
+            match r {
+                Ok(mut r) => received.push(r.take_payload()),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+            if cancel {
+                break;
+            }
+        }
+
+        match error {
+            Some(e) => outcomes.error(e),
+            None if cancel => outcomes.canceled += 1,
+            None => {
+                assert_eq!(payloads, received);
+                outcomes.ok += 1;
+            }
+        }
+
+        if faults.should_reconnect() {
+            client = connect();
+        }
+    }
+
+    outcomes.print();
+}
+
 
 fn main() {
     env_logger::init().unwrap();
@@ -49,12 +202,20 @@ fn main() {
         panic!("too few args")
     }
 
-    let client = LongTestsClient::new_plain("localhost", 23432, Default::default()).expect("init");
+    let faults = ClientFaults::from_env();
+
+    let client = connect();
 
     let cmd = &args[1];
     let cmd_args = &args[2..];
     if cmd == "echo" {
         run_echo(client, cmd_args);
+    } else if cmd == "char_count" {
+        run_char_count(client, cmd_args);
+    } else if cmd == "random_strings" {
+        run_random_strings(client, &faults, cmd_args);
+    } else if cmd == "echo_stream" {
+        run_echo_stream(client, &faults, cmd_args);
     } else {
         panic!("unknown command: {}", cmd);
     }
@@ -1,21 +1,27 @@
 use std::thread;
-use std::iter;
 
 extern crate env_logger;
 
 extern crate grpc;
 extern crate long_tests;
 extern crate futures;
+extern crate futures_cpupool;
 
+use futures::stream;
 use futures::stream::Stream;
 use futures::Future;
 
+use futures_cpupool::CpuPool;
+
 use long_tests::long_tests_pb::*;
 use long_tests::long_tests_pb_grpc::*;
+use long_tests::faults;
 
 use grpc::*;
 
 struct LongTestsServerImpl {
+    /// Used to delay responses without blocking event loop
+    pool: CpuPool,
 }
 
 impl LongTests for LongTestsServerImpl {
@@ -41,18 +47,32 @@ impl LongTests for LongTestsServerImpl {
         grpc::SingleResponse::no_metadata(r)
     }
 
-    fn random_strings(&self, _o: grpc::RequestOptions, p: RandomStringsRequest)
+    fn random_strings(&self, _o: grpc::RequestOptions, mut p: RandomStringsRequest)
         -> grpc::StreamingResponse<RandomStringsResponse>
     {
-        let iter = iter::repeat(())
-            .map(|_| {
+        let pool = self.pool.clone();
+        let faults = p.take_faults();
+        let s = stream::iter_ok(0..p.count)
+            .and_then(move |_| {
                 let s = "aabb".to_owned();
                 let mut resp = RandomStringsResponse::new();
                 resp.set_s(s);
-                resp
-            })
-            .take(p.count as usize);
-        grpc::StreamingResponse::iter(iter)
+                faults::inject(&pool, &faults, resp)
+            });
+        grpc::StreamingResponse::no_metadata(s)
+    }
+
+    fn echo_stream(&self, _o: grpc::RequestOptions, p: grpc::StreamingRequest<EchoStreamRequest>)
+        -> grpc::StreamingResponse<EchoStreamResponse>
+    {
+        let pool = self.pool.clone();
+        let s = p.0
+            .and_then(move |mut req| {
+                let mut resp = EchoStreamResponse::new();
+                resp.set_payload(req.take_payload());
+                faults::inject(&pool, req.get_faults(), resp)
+            });
+        grpc::StreamingResponse::no_metadata(s)
     }
 }
 
@@ -61,7 +81,7 @@ fn main() {
 
     let mut server = ServerBuilder::new_plain();
     server.http.set_addr(long_tests::TEST_HOST).expect("set_addr");
-    server.add_service(LongTestsServer::new_service_def(LongTestsServerImpl {}));
+    server.add_service(LongTestsServer::new_service_def(LongTestsServerImpl { pool: CpuPool::new(4) }));
     let _server = server.build().expect("server");
 
     loop {
@@ -0,0 +1,97 @@
+//! Fault injection for soak testing.
+
+use std::env;
+use std::thread;
+use std::time::Duration;
+
+use rand;
+use rand::Rng;
+
+use futures_cpupool::CpuPool;
+
+use grpc;
+
+use long_tests_pb::Faults;
+
+
+const INJECTED_ERROR_MESSAGE: &'static str = "injected fault";
+
+/// Error returned by server when error is injected.
+pub fn injected_error() -> grpc::Error {
+    grpc::Error::GrpcMessage(grpc::GrpcMessageError {
+        grpc_status: grpc::GrpcStatus::Unavailable as i32,
+        grpc_message: INJECTED_ERROR_MESSAGE.to_owned(),
+    })
+}
+
+/// Check if error is produced by `injected_error`.
+pub fn is_injected_error(e: &grpc::Error) -> bool {
+    match e {
+        &grpc::Error::GrpcMessage(ref m) => m.grpc_message == INJECTED_ERROR_MESSAGE,
+        _ => false,
+    }
+}
+
+/// Resolve to `value` after random delay or fail, as specified by faults.
+///
+/// Delay is done in the pool to keep event loop responsive.
+pub fn inject<T : Send + 'static>(pool: &CpuPool, faults: &Faults, value: T)
+    -> grpc::GrpcFuture<T>
+{
+    let max_delay_ms = faults.get_max_delay_ms() as u64;
+    let error_percent = faults.get_error_percent();
+    Box::new(pool.spawn_fn(move || {
+        let mut rng = rand::thread_rng();
+        if max_delay_ms != 0 {
+            thread::sleep(Duration::from_millis(rng.gen_range(0, max_delay_ms + 1)));
+        }
+        if rng.gen_range(0, 100) < error_percent {
+            return Err(injected_error());
+        }
+        Ok(value)
+    }))
+}
+
+fn env_u32(name: &str) -> u32 {
+    match env::var(name) {
+        Ok(s) => s.parse().unwrap_or_else(|_| panic!("failed to parse {} as u32", name)),
+        Err(_) => 0,
+    }
+}
+
+/// Faults configured by client from environment.
+///
+/// * `LONG_TESTS_MAX_DELAY_MS`, `LONG_TESTS_ERROR_PERCENT`: faults requested from server
+/// * `LONG_TESTS_CANCEL_PERCENT`: chance client drops a stream before reading it fully
+/// * `LONG_TESTS_RECONNECT_PERCENT`: chance client drops connection after an iteration
+#[derive(Default, Debug, Clone)]
+pub struct ClientFaults {
+    pub server: Faults,
+    pub cancel_percent: u32,
+    pub reconnect_percent: u32,
+}
+
+impl ClientFaults {
+    pub fn from_env() -> ClientFaults {
+        let mut server = Faults::new();
+        server.set_max_delay_ms(env_u32("LONG_TESTS_MAX_DELAY_MS"));
+        server.set_error_percent(env_u32("LONG_TESTS_ERROR_PERCENT"));
+        ClientFaults {
+            server: server,
+            cancel_percent: env_u32("LONG_TESTS_CANCEL_PERCENT"),
+            reconnect_percent: env_u32("LONG_TESTS_RECONNECT_PERCENT"),
+        }
+    }
+
+    fn roll(percent: u32) -> bool {
+        rand::thread_rng().gen_range(0, 100) < percent
+    }
+
+    pub fn should_cancel(&self) -> bool {
+        ClientFaults::roll(self.cancel_percent)
+    }
+
+    pub fn should_reconnect(&self) -> bool {
+        ClientFaults::roll(self.reconnect_percent)
+    }
+}
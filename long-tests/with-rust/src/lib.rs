@@ -3,8 +3,11 @@ extern crate futures;
 extern crate futures_cpupool;
 extern crate grpc;
 extern crate tls_api;
+extern crate rand;
 
 pub mod long_tests_pb;
 pub mod long_tests_pb_grpc;
 
+pub mod faults;
+
 pub const TEST_HOST: &'static str = "localhost:23432";
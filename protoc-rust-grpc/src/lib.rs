@@ -26,6 +26,11 @@ pub struct Args<'a> {
     pub input: &'a[&'a str],
     /// Generate rust-protobuf files along with rust-gprc
     pub rust_protobuf: bool,
+    /// rust-protobuf code generation options, e. g. `serde_derive`
+    /// to derive `Serialize` and `Deserialize` for messages
+    pub rust_protobuf_customize: protoc_rust::Customize,
+    /// Code generation options
+    pub customize: grpc_compiler::codegen::Customize,
 }
 
 pub fn run(args: Args) -> Result<()> {
@@ -36,10 +41,15 @@ pub fn run(args: Args) -> Result<()> {
     }
 
     if args.rust_protobuf {
+        let mut rust_protobuf_customize = args.rust_protobuf_customize.clone();
+        if rust_protobuf_customize.serde_derive.is_none() {
+            rust_protobuf_customize.serde_derive = args.customize.serde;
+        }
         protoc_rust::run(protoc_rust::Args {
             out_dir: args.out_dir,
             includes: args.includes,
             input: args.input,
+            customize: rust_protobuf_customize,
             ..Default::default()
         })?;
     }
@@ -85,7 +95,8 @@ pub fn run(args: Args) -> Result<()> {
             format!("file {:?} is not found in includes {:?}", file, args.includes)));
     }
 
-    let gen_result = grpc_compiler::codegen::gen(fds.get_file(), &files_to_generate);
+    let gen_result = grpc_compiler::codegen::gen_customized(
+        fds.get_file(), &files_to_generate, &args.customize);
 
     for r in gen_result {
         let r: protobuf::compiler_plugin::GenResult = r;